@@ -0,0 +1,410 @@
+//! Access logging.
+//!
+//! An [`AccessLog`] writes one line per completed request: client IP, method, path,
+//! status, response size in bytes, duration and upstream, in the Common or Combined
+//! Log Format, or as JSON. The line is written once the response body was sent to the
+//! client, so the size and duration are the final ones. Install it with
+//! [`crate::service::ServiceBuilder::access_log`].
+
+use std::{
+    io::Write,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use http::{HeaderMap, StatusCode, Version, header, request};
+use http_body_util::{BodyExt as _, combinators::BoxBody};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use tracing::{info, warn};
+
+use crate::{error::BroxyError, service::ProxyResponse};
+
+/// Target of the tracing events carrying the access log lines.
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+/// Layout of the access log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Common Log Format: `client - - [time] "request line" status bytes`
+    #[default]
+    Common,
+    /// Combined Log Format: the common format followed by `"referer" "user-agent"`
+    Combined,
+    /// A JSON object per line, with the duration and upstream in addition
+    Json,
+}
+
+/// Where the access log lines go.
+enum Output {
+    /// Info events of the [`ACCESS_LOG_TARGET`] target
+    Tracing,
+    /// A file, stdout, or any other writer
+    Writer(Mutex<Box<dyn Write + Send>>),
+}
+
+/// Access log of the requests processed by services.
+///
+/// Requests the service answers without contacting the upstream, e.g. when it's at
+/// its concurrency limit, are logged too. Requests failing without a response are
+/// logged with a `-` status.
+///
+/// Share one access log between the services writing to the same destination.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use broxy_core::{
+///     access_log::{AccessLog, AccessLogFormat},
+///     load_balancer::LoadBalancer,
+///     service::Service,
+///     upstream::Upstream,
+/// };
+///
+/// let access_log = Arc::new(AccessLog::with_writer(AccessLogFormat::Combined, std::io::stdout()));
+/// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+/// let service = Service::builder()
+///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+///     .access_log(access_log)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct AccessLog {
+    format: AccessLogFormat,
+    output: Output,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    /// Creates an access log emitting its lines as info events of the
+    /// [`ACCESS_LOG_TARGET`] target, so they can be filtered or routed by the subscriber.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - Layout of the lines
+    ///
+    /// # Returns
+    ///
+    /// A new `AccessLog` instance
+    pub fn new(format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            output: Output::Tracing,
+        }
+    }
+
+    /// Creates an access log writing its lines to a writer, e.g. a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - Layout of the lines
+    /// * `writer` - Destination of the lines, written one at a time
+    ///
+    /// # Returns
+    ///
+    /// A new `AccessLog` instance
+    pub fn with_writer(format: AccessLogFormat, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            format,
+            output: Output::Writer(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    /// Starts the entry of a request, before it's processed.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - Address of the client
+    /// * `header` - The request header parts
+    /// * `upstream` - The upstream the request is forwarded to
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        from: &SocketAddr,
+        header: &request::Parts,
+        upstream: String,
+    ) -> PendingEntry {
+        let field = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        PendingEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            time: SystemTime::now(),
+            client: *from,
+            method: header.method.to_string(),
+            path: header
+                .uri
+                .path_and_query()
+                .map_or_else(|| header.uri.path().to_string(), ToString::to_string),
+            version: header.version,
+            referer: field(&header.headers, header::REFERER),
+            user_agent: field(&header.headers, header::USER_AGENT),
+            upstream,
+        }
+    }
+
+    /// Writes the line of a completed request.
+    fn write(&self, entry: &PendingEntry, status: Option<StatusCode>, bytes: u64) {
+        let line = self.format(entry, status, bytes, entry.started.elapsed());
+        match &self.output {
+            Output::Tracing => info!(target: ACCESS_LOG_TARGET, "{}", line),
+            Output::Writer(writer) => {
+                let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = writeln!(writer, "{line}") {
+                    warn!("Failed to write access log: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Formats the line of a completed request.
+    fn format(
+        &self,
+        entry: &PendingEntry,
+        status: Option<StatusCode>,
+        bytes: u64,
+        duration: Duration,
+    ) -> String {
+        let since_epoch = entry.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (year, month, day, hour, minute, second) = civil_time(since_epoch.as_secs());
+        if self.format == AccessLogFormat::Json {
+            let time = format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z");
+            return serde_json::json!({
+                "time": time,
+                "client": entry.client.ip().to_string(),
+                "method": entry.method,
+                "path": entry.path,
+                "protocol": format!("{:?}", entry.version),
+                "status": status.map(|status| status.as_u16()),
+                "bytes": bytes,
+                "duration_ms": duration.as_secs_f64() * 1000.0,
+                "upstream": entry.upstream,
+                "referer": entry.referer,
+                "user_agent": entry.user_agent,
+            })
+            .to_string();
+        }
+
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let mut line = format!(
+            "{} - - [{day:02}/{}/{year:04}:{hour:02}:{minute:02}:{second:02} +0000] \"{} {} {:?}\" {} {}",
+            entry.client.ip(),
+            MONTHS[month as usize - 1],
+            entry.method,
+            entry.path,
+            entry.version,
+            status.map_or_else(|| "-".to_string(), |status| status.as_u16().to_string()),
+            bytes,
+        );
+        if self.format == AccessLogFormat::Combined {
+            let quoted =
+                |value: &Option<String>| value.as_deref().unwrap_or("-").replace('"', "\\\"");
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                quoted(&entry.referer),
+                quoted(&entry.user_agent)
+            ));
+        }
+        line
+    }
+}
+
+/// Entry of a request being processed, written once it completes.
+pub(crate) struct PendingEntry {
+    log: Arc<AccessLog>,
+    started: Instant,
+    time: SystemTime,
+    client: SocketAddr,
+    method: String,
+    path: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    upstream: String,
+}
+
+impl PendingEntry {
+    /// Completes the entry with the result of the request.
+    ///
+    /// A failed request is logged right away. A response is logged once its body
+    /// was sent to the end, or dropped.
+    pub(crate) fn finish(
+        self,
+        result: Result<ProxyResponse, BroxyError>,
+    ) -> Result<ProxyResponse, BroxyError> {
+        match result {
+            Ok(response) => {
+                let status = response.status();
+                Ok(response.map(|body| {
+                    LoggedBody {
+                        inner: body,
+                        status,
+                        bytes: 0,
+                        entry: Some(self),
+                    }
+                    .boxed()
+                }))
+            }
+            Err(e) => {
+                self.log.write(&self, None, 0);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Response body counting the bytes sent, logging the request once it ends.
+struct LoggedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    status: StatusCode,
+    bytes: u64,
+    /// Taken once the entry is written
+    entry: Option<PendingEntry>,
+}
+
+impl LoggedBody {
+    /// Writes the entry, unless it already was.
+    fn write(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            entry.log.write(&entry, Some(self.status), self.bytes);
+        }
+    }
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.bytes += data.len() as u64;
+                }
+            }
+            Some(Err(_)) | None => self.write(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.write();
+    }
+}
+
+/// Splits seconds since the Unix epoch into UTC year, month, day, hour, minute and second.
+fn civil_time(seconds: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (days, seconds) = ((seconds / 86_400) as i64, seconds % 86_400);
+    // Days to civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::ServiceBundle,
+        test_support::{proxy, send, service_to, upstream},
+    };
+    use hyper::Response;
+
+    /// A writer collecting the lines in memory.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_line_is_written_per_request_in_the_chosen_format() {
+        let upstream = upstream(|_| async { Response::new("hello".to_string()) }).await;
+        let request = async |format: AccessLogFormat| {
+            let lines = Lines::default();
+            let access_log = Arc::new(AccessLog::with_writer(format, lines.clone()));
+            let service = service_to(&[upstream])
+                .access_log(access_log)
+                .build()
+                .unwrap();
+            send(
+                proxy(ServiceBundle::new(vec![service])).await,
+                "GET /greet?name=broxy HTTP/1.1\r\nhost: localhost\r\nuser-agent: curl/8.0\r\nconnection: close\r\n\r\n",
+            )
+            .await;
+            let lines = lines.0.lock().unwrap().clone();
+            String::from_utf8(lines).unwrap()
+        };
+
+        let line = request(AccessLogFormat::Common).await;
+        assert!(line.starts_with("127.0.0.1 - - ["), "{line}");
+        assert!(
+            line.ends_with("] \"GET /greet?name=broxy HTTP/1.1\" 200 5\n"),
+            "{line}"
+        );
+
+        let line = request(AccessLogFormat::Combined).await;
+        assert!(line.ends_with("\" 200 5 \"-\" \"curl/8.0\"\n"), "{line}");
+
+        let line = request(AccessLogFormat::Json).await;
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["client"], "127.0.0.1");
+        assert_eq!(entry["method"], "GET");
+        assert_eq!(entry["path"], "/greet?name=broxy");
+        assert_eq!(entry["status"], 200);
+        assert_eq!(entry["bytes"], 5);
+        assert_eq!(entry["upstream"], upstream.to_string());
+        assert!(entry["duration_ms"].is_number());
+    }
+}
@@ -0,0 +1,178 @@
+//! HTTP basic authentication.
+//!
+//! [`BasicAuth`] checks the `Authorization: Basic ...` header of incoming requests and
+//! answers `401 Unauthorized` itself when the credentials are missing or wrong.
+//! It's installed as a middleware, see [`BasicAuth::into_middleware`].
+
+use std::sync::Arc;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use http::{HeaderValue, StatusCode, header, request};
+use subtle::ConstantTimeEq as _;
+
+use crate::{
+    middleware::{Middleware, MiddlewareAction, MiddlewareIncomingFunction},
+    service::empty_response,
+};
+
+/// Function checking a username and a password.
+pub type CredentialsVerifier = fn(&str, &str) -> bool;
+
+/// Source of the valid credentials.
+#[derive(Debug, Clone)]
+enum Credentials {
+    /// Fixed `(username, password)` pairs
+    List(Vec<(String, String)>),
+    /// Custom check, e.g. against a password hash
+    Verifier(CredentialsVerifier),
+}
+
+/// Basic authentication policy of a service.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{
+///     auth::BasicAuth,
+///     hyper::{Request, StatusCode},
+///     middleware::MiddlewareAction,
+/// };
+///
+/// let middleware = BasicAuth::new("admin", vec![("alice".to_string(), "secret".to_string())])
+///     .into_middleware();
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "127.0.0.1:8080".parse().unwrap();
+/// let authenticate = |authorization: Option<&str>| {
+///     let mut request = Request::get("/admin");
+///     if let Some(authorization) = authorization {
+///         request = request.header("authorization", authorization);
+///     }
+///     let (mut parts, _) = request.body(()).unwrap().into_parts();
+///     middleware.process_incoming(&from, &upstream, &mut parts, None).unwrap()
+/// };
+///
+/// // alice:secret
+/// let valid = authenticate(Some("Basic YWxpY2U6c2VjcmV0"));
+/// assert!(matches!(valid, MiddlewareAction::Continue));
+///
+/// // alice:wrong
+/// let MiddlewareAction::Respond(wrong) = authenticate(Some("Basic YWxpY2U6d3Jvbmc=")) else {
+///     panic!("wrong credentials accepted");
+/// };
+/// assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+///
+/// let MiddlewareAction::Respond(missing) = authenticate(None) else {
+///     panic!("missing credentials accepted");
+/// };
+/// assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+/// assert_eq!(missing.headers()["www-authenticate"], r#"Basic realm="admin""#);
+/// ```
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    /// `WWW-Authenticate` header sent with `401 Unauthorized`
+    challenge: HeaderValue,
+    credentials: Credentials,
+}
+
+impl BasicAuth {
+    /// Creates a policy accepting a fixed set of credentials.
+    ///
+    /// # Arguments
+    ///
+    /// * `realm` - Protection space shown by browsers when asking for credentials
+    /// * `credentials` - Valid `(username, password)` pairs
+    ///
+    /// # Returns
+    ///
+    /// A new `BasicAuth` instance
+    pub fn new(realm: &str, credentials: Vec<(String, String)>) -> Self {
+        Self {
+            challenge: challenge(realm),
+            credentials: Credentials::List(credentials),
+        }
+    }
+
+    /// Creates a policy checking credentials with a function.
+    ///
+    /// The function should compare secrets in constant time.
+    ///
+    /// # Arguments
+    ///
+    /// * `realm` - Protection space shown by browsers when asking for credentials
+    /// * `verifier` - Function returning `true` for a valid username and password
+    ///
+    /// # Returns
+    ///
+    /// A new `BasicAuth` instance
+    pub fn with_verifier(realm: &str, verifier: CredentialsVerifier) -> Self {
+        Self {
+            challenge: challenge(realm),
+            credentials: Credentials::Verifier(verifier),
+        }
+    }
+
+    /// Builds the middleware applying the policy.
+    ///
+    /// To combine it with other middleware, pass [`MiddlewareIncomingFunction::BasicAuth`]
+    /// to [`Middleware::new`].
+    pub fn into_middleware(self) -> Middleware {
+        Middleware::new(
+            vec![MiddlewareIncomingFunction::BasicAuth(Arc::new(self))],
+            vec![],
+        )
+    }
+
+    /// Lets requests with valid credentials through, answers the others.
+    pub(crate) fn process_request(&self, parts: &request::Parts) -> MiddlewareAction {
+        let authenticated = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| {
+                let (scheme, encoded) = authorization.trim().split_once(' ')?;
+                scheme
+                    .eq_ignore_ascii_case("basic")
+                    .then(|| STANDARD.decode(encoded.trim()).ok())?
+            })
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .is_some_and(|decoded| {
+                decoded
+                    .split_once(':')
+                    .is_some_and(|(username, password)| self.verify(username, password))
+            });
+        if authenticated {
+            return MiddlewareAction::Continue;
+        }
+
+        let mut response = empty_response(StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, self.challenge.clone());
+        MiddlewareAction::Respond(response)
+    }
+
+    /// Checks credentials, comparing them against every pair in constant time.
+    fn verify(&self, username: &str, password: &str) -> bool {
+        match &self.credentials {
+            Credentials::List(credentials) => {
+                let valid =
+                    credentials
+                        .iter()
+                        .fold(0u8, |valid, (valid_username, valid_password)| {
+                            let matches = valid_username.as_bytes().ct_eq(username.as_bytes())
+                                & valid_password.as_bytes().ct_eq(password.as_bytes());
+                            valid | matches.unwrap_u8()
+                        });
+                valid == 1
+            }
+            Credentials::Verifier(verifier) => verifier(username, password),
+        }
+    }
+}
+
+/// Builds the `WWW-Authenticate` header of a realm.
+fn challenge(realm: &str) -> HeaderValue {
+    let realm = realm.replace(['\\', '"'], "");
+    HeaderValue::from_str(&format!("Basic realm=\"{realm}\""))
+        .unwrap_or(HeaderValue::from_static("Basic"))
+}
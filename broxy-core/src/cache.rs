@@ -0,0 +1,955 @@
+//! In-memory response caching.
+//!
+//! Responses to safe methods (`GET`/`HEAD`) are stored with an expiry derived from the
+//! `Cache-Control: max-age`/`s-maxage` directives or the `Expires` header, and served
+//! directly without contacting the upstream until they expire. The cache is bounded
+//! and evicts the least recently used entry when full. Responses to requests with an
+//! `Authorization` header are only stored if marked `public`, `s-maxage` or
+//! `must-revalidate`, as RFC 9111 §3.5 requires of shared caches. Responses setting a
+//! cookie are never stored, so that one client's session isn't handed to the others.
+//!
+//! Clients sending an `If-None-Match` header matching the `ETag` of a cached response
+//! get a `304 Not Modified`. Once a response with an `ETag` expires, the next request
+//! asks the upstream to confirm it is still current with `If-None-Match`, and a
+//! `304 Not Modified` answer keeps serving the cached body for another lifetime.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use broxy_core::{
+//!     cache::ResponseCache,
+//!     hyper::header::ACCEPT_LANGUAGE,
+//!     load_balancer::LoadBalancer,
+//!     service::Service,
+//!     upstream::Upstream,
+//! };
+//!
+//! let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+//! let service = Service::builder()
+//!     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+//!     // Up to 1024 responses, cached by language as well
+//!     .cache(ResponseCache::new(1024).with_vary([ACCEPT_LANGUAGE]))
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode, Version, header,
+    request::Parts, uri::PathAndQuery,
+};
+use http_body_util::{BodyExt as _, Full};
+use hyper::body::Bytes;
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::{filter, service::ProxyResponse};
+
+/// Header added to every response of a caching service, `HIT`, `MISS` or
+/// `REVALIDATED` when the upstream confirmed an expired response is still current.
+pub const X_CACHE: &str = "x-cache";
+
+/// Default maximum size of a single cached response body, in bytes.
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 1024 * 1024;
+
+/// Bounded in-memory cache of upstream responses with LRU eviction.
+#[derive(Debug)]
+pub struct ResponseCache {
+    /// Maximum amount of cached responses
+    capacity: usize,
+    /// Maximum size of a single cached response body
+    max_entry_size: u64,
+    /// Request headers every response varies on, whether the upstream says so or not
+    vary: Vec<HeaderName>,
+    /// Whether identical requests wait for the one in flight instead of reaching the upstream
+    coalescing: bool,
+    /// Requests in flight to the upstream by key, closed once their response is stored
+    flights: Mutex<HashMap<String, watch::Receiver<()>>>,
+    inner: Mutex<CacheInner>,
+}
+
+/// Role of a request among identical requests in flight, see [`ResponseCache::with_coalescing`].
+pub(crate) enum Flight {
+    /// The request reaches the upstream, the identical ones wait for it
+    Leader(FlightGuard),
+    /// The request waits for an identical one
+    Follower(FlightWait),
+}
+
+/// Held by the request in flight, releases the identical requests when dropped.
+pub(crate) struct FlightGuard {
+    cache: Arc<ResponseCache>,
+    key: String,
+    _finished: watch::Sender<()>,
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.cache.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Waits for the request in flight.
+pub(crate) struct FlightWait(watch::Receiver<()>);
+
+impl FlightWait {
+    /// Waits until the response of the request in flight is stored, or it failed.
+    pub(crate) async fn finished(mut self) {
+        // Only ever fails, once the sender is dropped
+        let _ = self.0.changed().await;
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    /// Cached responses keyed by method, host and path
+    entries: HashMap<String, CacheEntry>,
+    /// Keys ordered by last use, the first one is evicted first
+    lru: BTreeMap<u64, String>,
+    /// Monotonic counter used to order entries by last use
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    expires_at: Instant,
+    /// Request headers listed in the response `Vary` header, with the values they had
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    /// Position of the entry in the LRU order
+    tick: u64,
+}
+
+/// Key and vary information of a request that may be served from the cache.
+#[derive(Debug)]
+pub(crate) struct CacheRequest {
+    key: String,
+    headers: HeaderMap,
+    /// `ETag` of the expired response the request asks the upstream to confirm
+    revalidating: Option<HeaderValue>,
+}
+
+impl ResponseCache {
+    /// Creates a new response cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Maximum amount of cached responses
+    ///
+    /// # Returns
+    ///
+    /// A new, empty `ResponseCache`
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "Cache capacity should be greater than 0");
+        Self {
+            capacity,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            vary: Vec::new(),
+            coalescing: false,
+            flights: Mutex::new(HashMap::new()),
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Sets request headers every response is cached by, in addition to the ones
+    /// listed in the `Vary` header of the response.
+    ///
+    /// Useful for upstreams that adapt their responses to a header, e.g.
+    /// `Accept-Language`, without sending `Vary`.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers` - Names of the request headers
+    pub fn with_vary(mut self, headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.vary = headers.into_iter().collect();
+        self
+    }
+
+    /// Sets the maximum size of a single cached response body.
+    ///
+    /// Only responses with a `Content-Length` up to this size are cached,
+    /// others are streamed through untouched.
+    pub fn with_max_entry_size(mut self, max_entry_size: u64) -> Self {
+        self.max_entry_size = max_entry_size;
+        self
+    }
+
+    /// Coalesces identical requests arriving while one is in flight to the upstream.
+    ///
+    /// The later requests wait for the first one, then are served its response from
+    /// the cache, so a burst of requests for an expired or uncached response reaches
+    /// the upstream once. If the response can't be cached, e.g. it's marked `no-store`,
+    /// the waiting requests are all sent to the upstream once the first one is done.
+    ///
+    /// # Arguments
+    ///
+    /// * `coalescing` - Whether to coalesce requests, disabled by default
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::cache::ResponseCache;
+    ///
+    /// let cache = ResponseCache::new(1024).with_coalescing(true);
+    /// ```
+    pub fn with_coalescing(mut self, coalescing: bool) -> Self {
+        self.coalescing = coalescing;
+        self
+    }
+
+    /// Returns the amount of responses currently cached.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// Checks whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Decides whether a request may be served from or stored in the cache.
+    ///
+    /// # Returns
+    ///
+    /// `Some(CacheRequest)` for safe methods without `no-store`/`no-cache`
+    /// request directives, `None` otherwise. Requests are keyed by their host too,
+    /// as origin-form URIs don't carry it.
+    pub(crate) fn request(header: &Parts) -> Option<CacheRequest> {
+        if header.method != Method::GET && header.method != Method::HEAD {
+            return None;
+        }
+        let directives = CacheControl::parse(&header.headers);
+        if directives.no_store || directives.no_cache {
+            return None;
+        }
+        let host = filter::request_host(header).unwrap_or_default();
+        let path = header
+            .uri
+            .path_and_query()
+            .map_or("/", PathAndQuery::as_str);
+        Some(CacheRequest {
+            key: format!("{} {}{}", header.method, host.to_ascii_lowercase(), path),
+            headers: header.headers.clone(),
+            revalidating: None,
+        })
+    }
+
+    /// Looks up a fresh cached response for the request.
+    ///
+    /// # Returns
+    ///
+    /// The cached response with `X-Cache: HIT` and an `Age` header, `304 Not Modified`
+    /// if the request's `If-None-Match` matches its `ETag`, or `None` on a miss.
+    pub(crate) fn lookup(&self, request: &CacheRequest) -> Option<ProxyResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        let entry = inner.entries.get(&request.key)?;
+        if entry.expires_at <= now {
+            debug!("Cached response for {} expired", request.key);
+            // Kept to be revalidated, see `revalidate`
+            if !entry.headers.contains_key(header::ETAG) {
+                inner.remove(&request.key);
+            }
+            return None;
+        }
+        if !entry.matches(request) {
+            return None;
+        }
+
+        let not_modified = entry
+            .headers
+            .get(header::ETAG)
+            .is_some_and(|etag| if_none_match(&request.headers, etag));
+        let response = entry.response(now, not_modified, "HIT");
+        inner.touch(&request.key);
+        debug!("Serving {} from cache", request.key);
+        Some(response)
+    }
+
+    /// Registers a request about to be sent to the upstream, if coalescing is enabled.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Flight::Follower` if an identical request is already in flight,
+    /// `Flight::Leader` otherwise, or `None` if coalescing is disabled.
+    pub(crate) fn join_flight(self: &Arc<Self>, request: &CacheRequest) -> Option<Flight> {
+        if !self.coalescing {
+            return None;
+        }
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(leader) = flights.get(&request.key) {
+            debug!("Waiting for the request in flight for {}", request.key);
+            return Some(Flight::Follower(FlightWait(leader.clone())));
+        }
+        let (finished, leader) = watch::channel(());
+        flights.insert(request.key.clone(), leader);
+        Some(Flight::Leader(FlightGuard {
+            cache: self.clone(),
+            key: request.key.clone(),
+            _finished: finished,
+        }))
+    }
+
+    /// Makes the request conditional if it's for an expired response with an `ETag`,
+    /// so the upstream can confirm it with `304 Not Modified` instead of sending it again.
+    ///
+    /// Requests already conditional are left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request as seen by the cache, remembering the revalidated `ETag`
+    /// * `header` - The request sent to the upstream
+    pub(crate) fn revalidate(&self, request: &mut CacheRequest, header: &mut Parts) {
+        if header.headers.contains_key(header::IF_NONE_MATCH)
+            || header.headers.contains_key(header::IF_MODIFIED_SINCE)
+        {
+            return;
+        }
+        let inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.get(&request.key) else {
+            return;
+        };
+        if entry.expires_at > Instant::now() || !entry.matches(request) {
+            return;
+        }
+        if let Some(etag) = entry.headers.get(header::ETAG) {
+            debug!("Revalidating {} with ETag {:?}", request.key, etag);
+            header.headers.insert(header::IF_NONE_MATCH, etag.clone());
+            request.revalidating = Some(etag.clone());
+        }
+    }
+
+    /// Refreshes an expired response the upstream confirmed with `304 Not Modified`.
+    ///
+    /// # Returns
+    ///
+    /// The cached response with `X-Cache: REVALIDATED`, or `None` if it was evicted meanwhile.
+    fn refresh(&self, request: &CacheRequest, headers: &HeaderMap) -> Option<ProxyResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get_mut(&request.key)?;
+        if entry.headers.get(header::ETAG) != request.revalidating.as_ref() {
+            return None;
+        }
+        for name in [
+            header::CACHE_CONTROL,
+            header::EXPIRES,
+            header::DATE,
+            header::ETAG,
+        ] {
+            if let Some(value) = headers.get(&name) {
+                entry.headers.insert(name, value.clone());
+            }
+        }
+        let now = Instant::now();
+        let response = entry.response(now, false, "REVALIDATED");
+        match self.ttl(&entry.status, &entry.headers) {
+            Some(ttl) => {
+                debug!("Revalidated {} for {:?}", request.key, ttl);
+                entry.stored_at = now;
+                entry.expires_at = now + ttl;
+                inner.touch(&request.key);
+            }
+            None => {
+                debug!("Revalidated {} is no longer cacheable", request.key);
+                inner.remove(&request.key);
+            }
+        }
+        Some(response)
+    }
+
+    /// Stores the response if it's cacheable and returns it to be sent to the client.
+    ///
+    /// Cacheable responses are buffered, every response gets `X-Cache: MISS`. Responses
+    /// to requests with `Authorization` are only cacheable if explicitly shared.
+    pub(crate) async fn store(
+        &self,
+        request: CacheRequest,
+        response: ProxyResponse,
+    ) -> Result<ProxyResponse, hyper::Error> {
+        let (mut parts, body) = response.into_parts();
+        if parts.status == StatusCode::NOT_MODIFIED
+            && request.revalidating.is_some()
+            && let Some(response) = self.refresh(&request, &parts.headers)
+        {
+            return Ok(response);
+        }
+        parts
+            .headers
+            .insert(X_CACHE, HeaderValue::from_static("MISS"));
+
+        let shared = !request.headers.contains_key(header::AUTHORIZATION)
+            || CacheControl::parse(&parts.headers).shares_authorized();
+        let Some(ttl) = self.ttl(&parts.status, &parts.headers).filter(|_| shared) else {
+            return Ok(Response::from_parts(parts, body));
+        };
+
+        let body = body.collect().await?.to_bytes();
+        let mut vary = self.vary.clone();
+        vary.extend(
+            parts
+                .headers
+                .get_all(header::VARY)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok()),
+        );
+        vary.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        vary.dedup();
+        let vary = vary
+            .into_iter()
+            .map(|name| {
+                let value = request.headers.get(&name).cloned();
+                (name, value)
+            })
+            .collect();
+
+        let mut headers = parts.headers.clone();
+        headers.remove(X_CACHE);
+        let now = Instant::now();
+        let entry = CacheEntry {
+            status: parts.status,
+            version: parts.version,
+            headers,
+            body: body.clone(),
+            stored_at: now,
+            expires_at: now + ttl,
+            vary,
+            tick: 0,
+        };
+        debug!("Caching {} for {:?}", request.key, ttl);
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(request.key, entry, self.capacity);
+
+        Ok(Response::from_parts(
+            parts,
+            Full::new(body).map_err(|never| match never {}).boxed(),
+        ))
+    }
+
+    /// Computes for how long a response may be cached.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the response is not cacheable.
+    fn ttl(&self, status: &StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        if !matches!(
+            status.as_u16(),
+            200 | 203 | 204 | 300 | 301 | 404 | 405 | 410 | 414 | 501
+        ) {
+            return None;
+        }
+        let size = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())?;
+        if size > self.max_entry_size {
+            return None;
+        }
+        if headers
+            .get_all(header::VARY)
+            .iter()
+            .any(|value| value.as_bytes().trim_ascii() == b"*")
+        {
+            return None;
+        }
+        // The cookie is meant for the client that got the response
+        if headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+
+        let directives = CacheControl::parse(headers);
+        if directives.no_store || directives.no_cache || directives.private {
+            return None;
+        }
+        if let Some(max_age) = directives.s_maxage.or(directives.max_age) {
+            return (max_age > 0).then(|| Duration::from_secs(max_age));
+        }
+
+        let expires =
+            httpdate::parse_http_date(headers.get(header::EXPIRES)?.to_str().ok()?).ok()?;
+        let date = headers
+            .get(header::DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .unwrap_or_else(SystemTime::now);
+        expires
+            .duration_since(date)
+            .ok()
+            .filter(|ttl| !ttl.is_zero())
+    }
+}
+
+impl CacheEntry {
+    /// Checks if the request headers the response varies on have the same values.
+    fn matches(&self, request: &CacheRequest) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.headers.get(name) == value.as_ref())
+    }
+
+    /// Builds the response served from the entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Time the response is served, to compute its `Age`
+    /// * `not_modified` - Whether to answer `304 Not Modified` without body
+    /// * `cache_status` - Value of the `X-Cache` header
+    fn response(
+        &self,
+        now: Instant,
+        not_modified: bool,
+        cache_status: &'static str,
+    ) -> ProxyResponse {
+        let body = if not_modified {
+            Bytes::new()
+        } else {
+            self.body.clone()
+        };
+        let mut response = Response::new(Full::new(body).map_err(|never| match never {}).boxed());
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers.clone();
+        if not_modified {
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+        } else {
+            *response.status_mut() = self.status;
+        }
+        response.headers_mut().insert(
+            header::AGE,
+            now.saturating_duration_since(self.stored_at)
+                .as_secs()
+                .into(),
+        );
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static(cache_status));
+        response
+    }
+}
+
+/// Checks if an `If-None-Match` request header matches an `ETag`, with the weak
+/// comparison GET and HEAD requests use.
+fn if_none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let weak = |tag: &[u8]| tag.strip_prefix(b"W/").unwrap_or(tag).to_vec();
+    let etag = weak(etag.as_bytes().trim_ascii());
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&byte| byte == b','))
+        .map(|tag| tag.trim_ascii())
+        .any(|tag| tag == b"*" || weak(tag) == etag)
+}
+
+impl CacheInner {
+    fn insert(&mut self, key: String, mut entry: CacheEntry, capacity: usize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            debug!("Evicting {} from cache", oldest);
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            if let Some(key) = self.lru.remove(&entry.tick) {
+                self.lru.insert(tick, key);
+            }
+            entry.tick = tick;
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+        }
+    }
+}
+
+/// Subset of the `Cache-Control` directives relevant to a shared cache.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    must_revalidate: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        for directive in headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" => directives.must_revalidate = true,
+                "max-age" => directives.max_age = value.and_then(|v| v.parse().ok()),
+                "s-maxage" => directives.s_maxage = value.and_then(|v| v.parse().ok()),
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Checks if a response to a request with `Authorization` may be stored, RFC 9111 §3.5.
+    fn shares_authorized(&self) -> bool {
+        self.public || self.must_revalidate || self.s_maxage.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::BasicAuth,
+        service::ServiceBundle,
+        test_support::{get, proxy, send, service_to, upstream},
+    };
+    use hyper::{Request, body::Incoming};
+    use std::{
+        net::SocketAddr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    #[test]
+    fn ttl_follows_cache_control_and_expires() {
+        let cache = ResponseCache::new(1).with_max_entry_size(5);
+        let ttl = |status: u16, headers: &[(&str, &str)]| {
+            let mut map = HeaderMap::new();
+            map.insert(header::CONTENT_LENGTH, HeaderValue::from_static("5"));
+            for (name, value) in headers {
+                map.insert(
+                    HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                );
+            }
+            cache.ttl(&StatusCode::from_u16(status).unwrap(), &map)
+        };
+        let seconds = |seconds: u64| Some(Duration::from_secs(seconds));
+
+        assert_eq!(ttl(200, &[("cache-control", "max-age=60")]), seconds(60));
+        assert_eq!(
+            ttl(200, &[("cache-control", "max-age=60, s-maxage=10")]),
+            seconds(10)
+        );
+        assert_eq!(
+            ttl(
+                200,
+                &[
+                    ("date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                    ("expires", "Wed, 21 Oct 2015 07:30:00 GMT"),
+                ]
+            ),
+            seconds(120)
+        );
+        assert_eq!(ttl(404, &[("cache-control", "max-age=60")]), seconds(60));
+
+        for headers in [
+            &[("cache-control", "no-store, max-age=60")][..],
+            &[("cache-control", "private, max-age=60")],
+            &[("cache-control", "max-age=0")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "session=1")],
+            &[],
+        ] {
+            assert_eq!(ttl(200, headers), None, "{headers:?}");
+        }
+        assert_eq!(ttl(500, &[("cache-control", "max-age=60")]), None);
+        assert_eq!(
+            ttl(
+                200,
+                &[("cache-control", "max-age=60"), ("content-length", "6")]
+            ),
+            None
+        );
+    }
+
+    /// Starts an upstream counting its requests.
+    ///
+    /// It answers `hello`, tagged `"v1"`, with a `Cache-Control` depending on the
+    /// path: `max-age=1` for `/short`, `no-store` for `/no-store`,
+    /// `public, max-age=60` for `/public`, `s-maxage=60` for `/shared`, and
+    /// `max-age=60` otherwise. It confirms `"v1"` with `304 Not Modified`.
+    async fn counting_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let upstream = upstream(move |request: Request<Incoming>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let cache_control = match request.uri().path() {
+                "/short" => "max-age=1",
+                "/no-store" => "no-store",
+                "/public" => "public, max-age=60",
+                "/shared" => "s-maxage=60",
+                _ => "max-age=60",
+            };
+            let confirmed = request.headers().get(header::IF_NONE_MATCH)
+                == Some(&HeaderValue::from_static("\"v1\""));
+            let mut response = Response::builder()
+                .header(header::CACHE_CONTROL, cache_control)
+                .header(header::ETAG, "\"v1\"");
+            if request.uri().path() == "/session" {
+                let session = counter.load(Ordering::SeqCst);
+                response = response.header(header::SET_COOKIE, format!("session={session}"));
+            }
+            async move {
+                match confirmed {
+                    true => response
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(String::new()),
+                    false => response.body("hello".to_string()),
+                }
+                .unwrap()
+            }
+        })
+        .await;
+        (upstream, requests)
+    }
+
+    /// Starts a proxy caching the responses of a [`counting_upstream`].
+    async fn caching_proxy(cache: ResponseCache) -> (SocketAddr, Arc<AtomicUsize>) {
+        let (upstream, requests) = counting_upstream().await;
+        let service = service_to(&[upstream]).cache(cache).build().unwrap();
+        (proxy(ServiceBundle::new(vec![service])).await, requests)
+    }
+
+    /// Sends a `GET` request for `path` with extra `headers`, each ending with CRLF.
+    async fn get_with(address: SocketAddr, path: &str, headers: &str) -> String {
+        let request =
+            format!("GET {path} HTTP/1.1\r\nhost: localhost\r\n{headers}connection: close\r\n\r\n");
+        send(address, request).await
+    }
+
+    #[tokio::test]
+    async fn fresh_responses_are_served_from_the_cache() {
+        let cache = ResponseCache::new(16).with_vary([header::ACCEPT_LANGUAGE]);
+        let (address, requests) = caching_proxy(cache).await;
+
+        assert!(get(address, "/long").await.contains("x-cache: MISS"));
+        let response = get(address, "/long").await;
+        assert!(response.contains("x-cache: HIT"), "{response}");
+        assert!(response.ends_with("hello"));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Cached by the configured request headers
+        let french = get_with(address, "/long", "accept-language: fr\r\n").await;
+        assert!(french.contains("x-cache: MISS"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Never cached when marked `no-store`
+        for _ in 0..2 {
+            assert!(get(address, "/no-store").await.contains("x-cache: MISS"));
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn authorized_responses_are_only_cached_when_shared() {
+        let (address, requests) = caching_proxy(ResponseCache::new(16)).await;
+        let authorized = |path| get_with(address, path, "authorization: Bearer secret\r\n");
+
+        for _ in 0..2 {
+            assert!(authorized("/long").await.contains("x-cache: MISS"));
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        for path in ["/public", "/shared"] {
+            assert!(authorized(path).await.contains("x-cache: MISS"));
+            let response = authorized(path).await;
+            assert!(response.contains("x-cache: HIT"), "{response}");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Unauthorized requests are still cached
+        get(address, "/long").await;
+        assert!(get(address, "/long").await.contains("x-cache: HIT"));
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn cached_responses_dont_skip_basic_authentication() {
+        let (upstream, requests) = counting_upstream().await;
+        let auth = BasicAuth::new("cache", vec![("user".to_string(), "secret".to_string())]);
+        let service = service_to(&[upstream])
+            .middleware(auth.into_middleware())
+            .cache(ResponseCache::new(16).with_coalescing(true))
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        // `user:secret`
+        let authorized = || {
+            get_with(
+                address,
+                "/public",
+                "authorization: Basic dXNlcjpzZWNyZXQ=\r\n",
+            )
+        };
+
+        // A response cacheable by shared caches, fetched with credentials
+        let response = authorized().await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("hello"));
+
+        // Is never served to clients without them, alone or in flight with an authorized one
+        let response = get(address, "/public").await;
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized"),
+            "{response}"
+        );
+        let (response, refused) = tokio::join!(authorized(), get(address, "/public"));
+        assert!(response.ends_with("hello"));
+        assert!(
+            refused.starts_with("HTTP/1.1 401 Unauthorized"),
+            "{refused}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn responses_are_cached_per_host() {
+        let (address, requests) = caching_proxy(ResponseCache::new(16)).await;
+        let get_from = async |host: &str| {
+            let request =
+                format!("GET /long HTTP/1.1\r\nhost: {host}\r\nconnection: close\r\n\r\n");
+            send(address, request).await
+        };
+
+        assert!(get_from("a.test").await.contains("x-cache: MISS"));
+        assert!(get_from("b.test").await.contains("x-cache: MISS"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // Hosts are case insensitive, and their port isn't part of the key
+        assert!(get_from("A.test:8080").await.contains("x-cache: HIT"));
+        assert!(get_from("b.test").await.contains("x-cache: HIT"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn responses_setting_cookies_are_not_cached() {
+        let (address, requests) = caching_proxy(ResponseCache::new(16)).await;
+
+        let first = get(address, "/session").await;
+        assert!(first.contains("set-cookie: session=1"), "{first}");
+        // Every client gets its own session from the upstream
+        let second = get(address, "/session").await;
+        assert!(second.contains("x-cache: MISS"), "{second}");
+        assert!(second.contains("set-cookie: session=2"), "{second}");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_response_is_evicted() {
+        let (address, requests) = caching_proxy(ResponseCache::new(2)).await;
+        let cache_status = async |path: &str| {
+            let response = get(address, path).await;
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("x-cache: "))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(cache_status("/a").await, "MISS");
+        assert_eq!(cache_status("/b").await, "MISS");
+        assert_eq!(cache_status("/a").await, "HIT");
+        // Evicts `/b`, `/a` being used since
+        assert_eq!(cache_status("/c").await, "MISS");
+        assert_eq!(cache_status("/a").await, "HIT");
+        assert_eq!(cache_status("/b").await, "MISS");
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn clients_holding_the_cached_version_get_not_modified() {
+        let (address, requests) = caching_proxy(ResponseCache::new(16)).await;
+
+        get(address, "/long").await;
+        let response = get_with(address, "/long", "if-none-match: W/\"v1\"\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 304 Not Modified"),
+            "{response}"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_responses_are_revalidated() {
+        let (address, requests) = caching_proxy(ResponseCache::new(16)).await;
+
+        assert!(get(address, "/short").await.contains("x-cache: MISS"));
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = get(address, "/short").await;
+        assert!(response.contains("x-cache: REVALIDATED"), "{response}");
+        assert!(response.ends_with("hello"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // Fresh again
+        assert!(get(address, "/short").await.contains("x-cache: HIT"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn identical_requests_in_flight_are_coalesced() {
+        // A slow upstream counting its requests
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let upstream = upstream(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Response::builder()
+                    .header(header::CACHE_CONTROL, "max-age=60")
+                    .body("hello".to_string())
+                    .unwrap()
+            }
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .cache(ResponseCache::new(16).with_coalescing(true))
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        let clients = (0..50).map(|_| tokio::spawn(get(address, "/report")));
+        for response in futures::future::join_all(clients).await {
+            assert!(response.unwrap().ends_with("hello"));
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
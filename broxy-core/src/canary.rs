@@ -0,0 +1,91 @@
+//! Traffic splitting between a stable and a canary upstream group.
+//!
+//! A [`Canary`] diverts a share of the requests matched by a service to a second
+//! load balancer, e.g. 5% to a new release while the rest keeps going to the
+//! stable one. Install it with [`crate::service::ServiceBuilder::canary`].
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{
+    load_balancer::{LoadBalancer, mix},
+    upstream::Upstream,
+    utils::fnv1a,
+};
+
+/// A canary upstream group receiving a share of the requests of a service.
+#[derive(Debug)]
+pub struct Canary {
+    /// Load balancer of the canary upstream group
+    load_balancer: Arc<LoadBalancer>,
+    /// Fraction of requests sent to the canary, between `0.0` and `1.0`
+    weight: f64,
+    /// Whether the group is chosen from the client IP instead of per request
+    sticky: bool,
+    /// Number of requests split so far
+    seen: AtomicU64,
+}
+
+impl Canary {
+    /// Creates a new canary.
+    ///
+    /// # Arguments
+    ///
+    /// * `load_balancer` - Load balancer of the canary upstream group
+    /// * `weight` - Fraction of requests sent to the canary, clamped between `0.0` and `1.0`
+    ///
+    /// # Returns
+    ///
+    /// A new `Canary` instance
+    pub fn new(load_balancer: Arc<LoadBalancer>, weight: f64) -> Self {
+        Self {
+            load_balancer,
+            weight: weight.clamp(0.0, 1.0),
+            sticky: false,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Sets whether every request of a client goes to the same group.
+    ///
+    /// When enabled the group is chosen by hashing the client IP, so `weight` of the
+    /// clients, rather than of the requests, are sent to the canary. Disabled by default.
+    pub fn with_sticky(mut self, sticky: bool) -> Self {
+        self.sticky = sticky;
+        self
+    }
+
+    /// Returns the load balancer of the canary upstream group.
+    pub fn load_balancer(&self) -> &Arc<LoadBalancer> {
+        &self.load_balancer
+    }
+
+    /// Decides whether a request is sent to the canary.
+    ///
+    /// Splitting is deterministic: a request goes to the canary every time the running
+    /// count multiplied by the weight crosses an integer, so exactly `weight` of the
+    /// requests reach it over time. Sticky canaries compare a hash of the client IP
+    /// with the weight instead.
+    pub(crate) fn selects(&self, from: &SocketAddr) -> bool {
+        if self.sticky {
+            let hash = match from.ip() {
+                IpAddr::V4(ip) => mix(fnv1a(&ip.octets())),
+                IpAddr::V6(ip) => mix(fnv1a(&ip.octets())),
+            };
+            // The top 53 bits, as a fraction between 0 and 1
+            return ((hash >> 11) as f64 / (1u64 << 53) as f64) < self.weight;
+        }
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.weight).floor() > (seen * self.weight).floor()
+    }
+
+    /// Checks if an upstream belongs to the canary group.
+    pub(crate) fn contains(&self, upstream: &Upstream) -> bool {
+        self.load_balancer.find_upstream(upstream.id()).is_some()
+    }
+}
@@ -0,0 +1,377 @@
+//! Request and response compression.
+//!
+//! [`gzip`] and [`brotli`] are outgoing middleware compressing the response body when
+//! the client accepts their coding, [`compress`] and [`compress_prefer_gzip`] pick
+//! whichever of the two the client accepts. Install them with e.g.
+//! `MiddlewareOutgoingFunction::InternalWithBody(compression::compress)`.
+//! Compressed request bodies are inflated by `MiddlewareIncomingFunction::Decompress`.
+
+use std::{
+    io::{Read, Write as _},
+    net::SocketAddr,
+};
+
+use brotli::CompressorWriter;
+use flate2::{
+    Compression,
+    read::{GzDecoder, ZlibDecoder},
+    write::GzEncoder,
+};
+use http::{HeaderMap, HeaderValue, Method, StatusCode, header, request, response};
+
+use crate::{
+    middleware::{MiddlewareAction, OriginalRequest},
+    service::empty_response,
+};
+
+/// Bodies smaller than this are sent uncompressed, the gain doesn't pay for the CPU time.
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Brotli quality, fast enough to compress responses on the fly.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Base 2 logarithm of the Brotli window size.
+const BROTLI_WINDOW: u32 = 22;
+
+/// Media types that are already compressed.
+const COMPRESSED_TYPES: &[&str] = &[
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/zstd",
+    "application/wasm",
+];
+
+/// Outgoing middleware compressing the response body with gzip.
+///
+/// The body is compressed when the request's `Accept-Encoding` allows gzip, and it's
+/// at least [`MIN_COMPRESS_SIZE`] bytes long, and it isn't already encoded or of
+/// an already compressed media type. `Content-Encoding` and `Content-Length`
+/// are updated accordingly.
+///
+/// # Arguments
+///
+/// * `parts` - The response header parts, carrying the [`OriginalRequest`]
+/// * `body` - The complete response body
+///
+/// # Returns
+///
+/// Returns `Ok(())`, whether the body was compressed or not, or an error if compression fails.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{
+///     compression::gzip,
+///     hyper::{Request, Response},
+///     middleware::OriginalRequest,
+/// };
+///
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "10.0.0.1:80".parse().unwrap();
+/// let respond = |accept_encoding: &str, content_type: &str, body: &[u8]| {
+///     let (request, _) = Request::get("/")
+///         .header("accept-encoding", accept_encoding)
+///         .body(())
+///         .unwrap()
+///         .into_parts();
+///     let (mut parts, _) = Response::builder()
+///         .header("content-type", content_type)
+///         .body(())
+///         .unwrap()
+///         .into_parts();
+///     parts.extensions.insert(OriginalRequest::new(&request));
+///     let mut body = body.to_vec();
+///     gzip(&from, &upstream, &mut parts, &mut body).unwrap();
+///     (parts, body)
+/// };
+///
+/// let text = "hello ".repeat(1000);
+/// let (parts, body) = respond("br, gzip", "text/plain", text.as_bytes());
+/// assert_eq!(parts.headers["content-encoding"], "gzip");
+/// assert_eq!(parts.headers["content-length"], body.len().to_string());
+/// assert!(body.len() < text.len());
+///
+/// let (parts, body) = respond("gzip;q=0, br", "text/plain", text.as_bytes());
+/// assert!(parts.headers.get("content-encoding").is_none());
+/// assert_eq!(body, text.as_bytes());
+///
+/// let (parts, _) = respond("gzip", "image/png", text.as_bytes());
+/// assert!(parts.headers.get("content-encoding").is_none());
+///
+/// let (parts, _) = respond("gzip", "text/plain", b"tiny");
+/// assert!(parts.headers.get("content-encoding").is_none());
+/// ```
+pub fn gzip(
+    _: &SocketAddr,
+    _: &SocketAddr,
+    parts: &mut response::Parts,
+    body: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    negotiate(parts, body, &["gzip"])
+}
+
+/// Outgoing middleware compressing the response body with Brotli.
+///
+/// Applies the same rules as [`gzip`], for the `br` coding. Brotli compresses text
+/// noticeably better than gzip, but not every client supports it.
+///
+/// # Example
+///
+/// ```
+/// use std::io::Read as _;
+/// use broxy_core::{
+///     compression::brotli,
+///     hyper::{Request, Response},
+///     middleware::OriginalRequest,
+/// };
+///
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "10.0.0.1:80".parse().unwrap();
+/// let (request, _) = Request::get("/")
+///     .header("accept-encoding", "br")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// let (mut parts, _) = Response::builder()
+///     .header("content-type", "text/html")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// parts.extensions.insert(OriginalRequest::new(&request));
+///
+/// let text = "<p>hello</p>".repeat(1000);
+/// let mut body = text.as_bytes().to_vec();
+/// brotli(&from, &upstream, &mut parts, &mut body).unwrap();
+/// assert_eq!(parts.headers["content-encoding"], "br");
+/// assert_eq!(parts.headers["content-length"], body.len().to_string());
+///
+/// let mut decompressed = String::new();
+/// ::brotli::Decompressor::new(body.as_slice(), 4096)
+///     .read_to_string(&mut decompressed)
+///     .unwrap();
+/// assert_eq!(decompressed, text);
+/// ```
+pub fn brotli(
+    _: &SocketAddr,
+    _: &SocketAddr,
+    parts: &mut response::Parts,
+    body: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    negotiate(parts, body, &["br"])
+}
+
+/// Outgoing middleware compressing the response body with Brotli or gzip.
+///
+/// Brotli is preferred when the client accepts both, see [`compress_prefer_gzip`] for
+/// the opposite. Otherwise applies the same rules as [`gzip`].
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{
+///     compression::{compress, compress_prefer_gzip},
+///     hyper::{Request, Response},
+///     middleware::{MiddlewareOutgoingFunction, OriginalRequest},
+/// };
+///
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "10.0.0.1:80".parse().unwrap();
+/// let text = "hello ".repeat(1000);
+/// let encoding = |middleware, accept_encoding: &str| {
+///     let (request, _) = Request::get("/")
+///         .header("accept-encoding", accept_encoding)
+///         .body(())
+///         .unwrap()
+///         .into_parts();
+///     let (mut parts, _) = Response::builder()
+///         .header("content-type", "text/plain")
+///         .body(())
+///         .unwrap()
+///         .into_parts();
+///     parts.extensions.insert(OriginalRequest::new(&request));
+///     let mut body = text.as_bytes().to_vec();
+///     MiddlewareOutgoingFunction::InternalWithBody(middleware)
+///         .process(&from, &upstream, &mut parts, &mut Some(&mut body))
+///         .unwrap();
+///     parts.headers.get("content-encoding").cloned()
+/// };
+///
+/// assert_eq!(encoding(compress, "br").unwrap(), "br");
+/// assert_eq!(encoding(compress, "gzip").unwrap(), "gzip");
+/// assert_eq!(encoding(compress, "gzip, deflate, br").unwrap(), "br");
+/// assert_eq!(encoding(compress, "br;q=0, gzip").unwrap(), "gzip");
+/// assert_eq!(encoding(compress, "deflate"), None);
+/// assert_eq!(encoding(compress_prefer_gzip, "gzip, br").unwrap(), "gzip");
+/// assert_eq!(encoding(compress_prefer_gzip, "br").unwrap(), "br");
+/// ```
+pub fn compress(
+    _: &SocketAddr,
+    _: &SocketAddr,
+    parts: &mut response::Parts,
+    body: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    negotiate(parts, body, &["br", "gzip"])
+}
+
+/// Outgoing middleware compressing the response body with gzip or Brotli.
+///
+/// Like [`compress`], but gzip is preferred when the client accepts both.
+pub fn compress_prefer_gzip(
+    _: &SocketAddr,
+    _: &SocketAddr,
+    parts: &mut response::Parts,
+    body: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    negotiate(parts, body, &["gzip", "br"])
+}
+
+/// Compresses the body with the first of the codings the client accepts, if any.
+fn negotiate(
+    parts: &mut response::Parts,
+    body: &mut Vec<u8>,
+    codings: &[&'static str],
+) -> anyhow::Result<()> {
+    let Some(&coding) = codings
+        .iter()
+        .find(|coding| should_compress(parts, body, coding))
+    else {
+        return Ok(());
+    };
+
+    let compressed = Vec::with_capacity(body.len() / 2);
+    *body = if coding == "br" {
+        let mut encoder = CompressorWriter::new(compressed, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+        encoder.write_all(body)?;
+        encoder.into_inner()
+    } else {
+        let mut encoder = GzEncoder::new(compressed, Compression::default());
+        encoder.write_all(body)?;
+        encoder.finish()?
+    };
+
+    set_encoding(parts, body, coding);
+    Ok(())
+}
+
+/// Checks if a response should be compressed with the given coding.
+pub(crate) fn should_compress(parts: &response::Parts, body: &[u8], coding: &str) -> bool {
+    let Some(request) = parts.extensions.get::<OriginalRequest>() else {
+        return false;
+    };
+    if request.method == Method::HEAD
+        || parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED
+        || body.len() < MIN_COMPRESS_SIZE
+        || parts.headers.contains_key(header::CONTENT_ENCODING)
+    {
+        return false;
+    }
+    let compressed = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            let content_type = content_type.trim_start().to_ascii_lowercase();
+            COMPRESSED_TYPES
+                .iter()
+                .any(|compressed| content_type.starts_with(compressed))
+        });
+    !compressed && accepts(&request.headers, coding)
+}
+
+/// Marks the response as encoded with the given coding, after its body was compressed.
+pub(crate) fn set_encoding(parts: &mut response::Parts, body: &[u8], coding: &'static str) {
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, body.len().into());
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+}
+
+/// Checks if the `Accept-Encoding` header of a request allows a content coding.
+///
+/// The coding is allowed when it's listed, or `*` is, without `q=0`.
+pub(crate) fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    let mut wildcard = false;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for entry in value.split(',') {
+            let mut params = entry.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let allowed = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .all(|quality| quality.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+            if name.eq_ignore_ascii_case(coding) {
+                return allowed;
+            }
+            if name == "*" {
+                wildcard = allowed;
+            }
+        }
+    }
+    wildcard
+}
+
+/// Inflates a `gzip` or `deflate` encoded request body in place.
+///
+/// Bodies with another encoding are left untouched.
+///
+/// # Returns
+///
+/// Returns `MiddlewareAction::Respond` with `413 Payload Too Large` if the body
+/// inflates to more than `max_size` bytes, `MiddlewareAction::Continue` otherwise,
+/// or an error if the body isn't validly encoded.
+pub(crate) fn decompress_request(
+    parts: &mut request::Parts,
+    body: &mut Vec<u8>,
+    max_size: u64,
+) -> anyhow::Result<MiddlewareAction> {
+    let Some(encoding) = parts
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+    else {
+        return Ok(MiddlewareAction::Continue);
+    };
+    let decoder: Box<dyn Read + '_> = match encoding.trim() {
+        encoding if encoding.eq_ignore_ascii_case("gzip") => Box::new(GzDecoder::new(&body[..])),
+        encoding if encoding.eq_ignore_ascii_case("deflate") => {
+            Box::new(ZlibDecoder::new(&body[..]))
+        }
+        _ => return Ok(MiddlewareAction::Continue),
+    };
+
+    // Reading one byte past the limit tells an oversized body from one of exactly `max_size`
+    let mut inflated = Vec::new();
+    decoder
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > max_size {
+        return Ok(MiddlewareAction::Respond(empty_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )));
+    }
+
+    *body = inflated;
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, body.len().into());
+    Ok(MiddlewareAction::Continue)
+}
@@ -0,0 +1,1262 @@
+//! Configuration structures for the proxy.
+//!
+//! A [`Config`] is read from a YAML file with [`Config::load`]. Its HTTP rules are
+//! turned into services with [`Config::services`], then its entry points into
+//! listening servers with [`Config::build`]. The routing and certificates of running
+//! servers can be changed without restarting them with [`Config::reload`]. Mistakes in a
+//! configuration are reported all at once by [`Config::validate`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use http::HeaderName;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        crypto::CryptoProvider,
+        server::{ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+    },
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    canary,
+    error::BroxyError,
+    filter::Filter,
+    load_balancer::LoadBalancer,
+    middleware::{
+        ExternalMiddleware, INCOMING_SYMBOL, Middleware, MiddlewareIncomingFunction,
+        MiddlewareOutgoingFunction, OUTGOING_SYMBOL,
+    },
+    server::{Server, ServerOptions},
+    service::{Service, ServiceBundle},
+    tls::{self, ReloadableResolver, SniResolver},
+    upstream,
+};
+
+/// A mistake found in a configuration by [`Config::validate`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// A rule refers to an entry point that isn't defined
+    #[error("rule {rule} refers to unknown entry point {entry_point}")]
+    UnknownEntryPoint {
+        /// Name of the rule
+        rule: String,
+        /// The missing entry point
+        entry_point: String,
+    },
+    /// A rule passes requests to an upstream group that isn't defined
+    #[error("rule {rule} refers to unknown upstream {upstream}")]
+    UnknownUpstream {
+        /// Name of the rule
+        rule: String,
+        /// The missing upstream group
+        upstream: String,
+    },
+    /// A path or domain name pattern isn't a valid regex
+    #[error("invalid pattern {pattern} in {location}: {source}")]
+    InvalidPattern {
+        /// Rule or entry point the pattern belongs to
+        location: String,
+        /// The pattern
+        pattern: String,
+        /// Why it doesn't compile
+        source: regex::Error,
+    },
+    /// An upstream group lists no server
+    #[error("upstream {upstream} has no server")]
+    EmptyUpstream {
+        /// Name of the upstream group
+        upstream: String,
+    },
+    /// A server of an upstream group isn't a valid address or host name and port
+    #[error("invalid server {server} in upstream {upstream}: {reason}")]
+    InvalidServer {
+        /// Name of the upstream group
+        upstream: String,
+        /// The server as written in the configuration
+        server: String,
+        /// Why it doesn't parse
+        reason: String,
+    },
+    /// An upstream group names an unknown load balancing strategy
+    #[error("unknown load balancing strategy {strategy} in upstream {upstream}")]
+    UnknownStrategy {
+        /// Name of the upstream group
+        upstream: String,
+        /// The strategy
+        strategy: String,
+    },
+    /// An upstream group doesn't list one weight per server
+    #[error("upstream {upstream} has {weights} weights for {servers} servers")]
+    InvalidWeights {
+        /// Name of the upstream group
+        upstream: String,
+        /// Amount of weights
+        weights: usize,
+        /// Amount of servers
+        servers: usize,
+    },
+    /// The certificate or private key of an entry point can't be loaded
+    #[error("invalid TLS settings of entry point {entry_point}: {reason}")]
+    Tls {
+        /// Name of the entry point
+        entry_point: String,
+        /// Why the certificate or private key can't be used
+        reason: String,
+    },
+}
+
+impl From<ConfigError> for BroxyError {
+    fn from(error: ConfigError) -> Self {
+        BroxyError::Config(error.to_string())
+    }
+}
+
+/// Main configuration structure for the Broxy proxy server.
+///
+/// This struct contains all the configuration options for the proxy,
+/// including entry points, HTTP routing rules, and upstream server definitions.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::config::Config;
+/// use std::path::Path;
+///
+/// # async fn run() -> Result<(), broxy_core::error::BroxyError> {
+/// let config = Config::load(Path::new("broxy.yaml"))?;
+/// let services = unsafe { config.services() }?;
+/// let servers = config.build(&services).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    /// Entry points define the network interfaces and ports the proxy listens on
+    pub entry_points: HashMap<String, EntryPoint>,
+    /// HTTP routing rules that determine how requests are processed
+    pub http: HashMap<String, Http>,
+    /// Upstream server definitions for load balancing and routing
+    pub upstream: HashMap<String, Upstream>,
+}
+
+/// Configuration for a network entry point where the proxy accepts connections.
+///
+/// Entry points define the listening address, optional domain name matching,
+/// and SSL/TLS configuration.
+#[derive(Serialize, Deserialize)]
+pub struct EntryPoint {
+    /// The network address (IP and port) to listen on
+    pub address: SocketAddr,
+    /// Optional regex pattern for matching domain names
+    pub domain_name: Option<String>,
+    /// SSL/TLS configuration for secure connections
+    pub ssl: Option<Ssl>,
+    /// Whether connections start with a PROXY protocol header, for entry points
+    /// behind a TCP load balancer, see [`ServerOptions::proxy_protocol`]
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Optional maximum size of the headers of a request, in bytes, see
+    /// [`ServerOptions::max_header_bytes`]
+    pub max_header_bytes: Option<usize>,
+}
+
+/// SSL/TLS configuration for secure entry points.
+///
+/// This struct defines the certificate and private key files
+/// needed for SSL/TLS termination, and how client certificates are verified.
+#[derive(Serialize, Deserialize)]
+pub struct Ssl {
+    /// Path to the SSL certificate file, presented to clients whose server name
+    /// matches none of `certificates`
+    pub certificate: String,
+    /// Path to the SSL private key file
+    pub private_key: String,
+    /// Certificates of other domains served by the entry point, selected by the
+    /// server name clients send (SNI), see [`crate::tls::SniResolver`]
+    #[serde(default)]
+    pub certificates: Vec<SslCertificate>,
+    /// Path to the CA bundle client certificates are verified with
+    pub client_ca: Option<String>,
+    /// Whether clients present a certificate, requires `client_ca` unless it's `none`
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// Header the verified client certificate is forwarded in, e.g. `x-client-cert`,
+    /// see [`crate::utils::describe_certificate`]
+    pub client_cert_header: Option<String>,
+    /// Protocols offered to clients with ALPN, in order of preference, among `h2` and
+    /// `http/1.1`. Defaults to [`DEFAULT_ALPN_PROTOCOLS`], an empty list disables ALPN
+    #[serde(default)]
+    pub alpn_protocols: Option<Vec<String>>,
+}
+
+/// Protocols offered with ALPN by default, HTTP/2 preferred.
+pub const DEFAULT_ALPN_PROTOCOLS: [&str; 2] = ["h2", "http/1.1"];
+
+/// Certificate of the domains served by an entry point.
+#[derive(Serialize, Deserialize)]
+pub struct SslCertificate {
+    /// Path to the certificate file
+    pub certificate: String,
+    /// Path to the private key file
+    pub private_key: String,
+    /// Host names the certificate is presented for, e.g. `example.com` or
+    /// `*.example.com`. Defaults to the DNS names of the certificate
+    pub hosts: Option<Vec<String>>,
+}
+
+/// Verification of client certificates, mutual TLS.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Clients aren't asked for a certificate
+    #[default]
+    None,
+    /// Clients may present a certificate, which must verify if they do
+    Optional,
+    /// Clients must present a certificate that verifies, others are rejected
+    /// during the handshake
+    Require,
+}
+
+/// HTTP routing rule configuration.
+///
+/// This struct defines how HTTP requests are routed to upstream servers,
+/// including path matching, middleware processing, and load balancing.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Http {
+    /// The entry point name this rule applies to
+    pub entry_point: String,
+    /// Regex pattern for matching request paths
+    pub path: String,
+    /// Optional list of middleware modules to apply
+    pub middleware: Option<Vec<PathBuf>>,
+    /// The upstream server group name to forward requests to
+    pub pass_to: String,
+    /// Optional canary upstream group receiving a share of the requests
+    pub canary: Option<Canary>,
+    /// Name of the cookie pinning clients to an upstream, sticky sessions are
+    /// disabled when not set
+    pub sticky_cookie: Option<String>,
+}
+
+/// Canary configuration of an HTTP routing rule.
+///
+/// A share of the requests matched by the rule is sent to the canary upstream
+/// group instead of the one named by the rule's `pass_to`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Canary {
+    /// The upstream server group name receiving the canary traffic
+    pub pass_to: String,
+    /// Fraction of requests sent to the canary group, between `0.0` and `1.0`
+    pub weight: f64,
+    /// Whether every request of a client goes to the same group, chosen from its IP
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+/// Upstream server group configuration.
+///
+/// This struct defines a group of backend servers that can handle requests,
+/// along with the load balancing strategy to use.
+#[derive(Serialize, Deserialize)]
+pub struct Upstream {
+    /// List of server addresses in this upstream group, e.g. `10.0.0.1:8080` or
+    /// `api.internal:8080`, prefixed with `https://` to connect over TLS
+    pub servers: Vec<String>,
+    /// Optional load balancing strategy name, `round_robin` (default), `ip_hash`, `p2c`
+    /// or `weighted_least_connections`
+    pub loadbalancer_strategy: Option<String>,
+    /// Optional weights of the servers, in the order of `servers`, see
+    /// [`upstream::Upstream::with_weight`]
+    #[serde(default)]
+    pub weights: Option<Vec<u32>>,
+    /// Optional maximum amount of requests in flight to each server of the group
+    pub max_concurrent: Option<usize>,
+}
+
+impl Config {
+    /// Reads a configuration from a YAML file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the configuration file
+    ///
+    /// # Returns
+    ///
+    /// Returns the parsed `Config`, or `BroxyError::Config` if the file can't be read
+    /// or isn't a valid configuration.
+    pub fn load(path: &Path) -> Result<Self, BroxyError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| BroxyError::Config(format!("failed to read {}: {e}", path.display())))?;
+        serde_yaml::from_str(&content)
+            .map_err(|e| BroxyError::Config(format!("failed to parse {}: {e}", path.display())))
+    }
+
+    /// Checks the configuration for mistakes, without loading anything but certificates.
+    ///
+    /// Rules must refer to defined entry points and upstream groups, path and domain
+    /// name patterns must compile, upstream groups must list valid server addresses
+    /// and strategies, and the certificate and private key of TLS entry points must load.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` for a valid configuration, or every mistake found otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::config::{Config, ConfigError};
+    ///
+    /// let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+    /// let config = |yaml: &str| serde_yaml::from_str::<Config>(yaml).unwrap();
+    ///
+    /// let valid = config(&format!(
+    ///     r#"
+    /// entry_points:
+    ///   web:
+    ///     address: 127.0.0.1:443
+    ///     domain_name: ^(www\.)?example\.com$
+    ///     ssl:
+    ///       certificate: {fixtures}/upstream.pem
+    ///       private_key: {fixtures}/upstream.key
+    /// http:
+    ///   api:
+    ///     entry_point: web
+    ///     path: ^/api
+    ///     pass_to: backend
+    /// upstream:
+    ///   backend:
+    ///     servers: ["10.0.0.1:8080", "https://10.0.0.2:8443"]
+    ///     loadbalancer_strategy: weighted_least_connections
+    ///     weights: [2, 1]
+    /// "#
+    /// ));
+    /// assert!(valid.validate().is_ok());
+    ///
+    /// let invalid = config(
+    ///     r#"
+    /// entry_points:
+    ///   web:
+    ///     address: 127.0.0.1:443
+    ///     domain_name: (example
+    ///     ssl:
+    ///       certificate: /missing/cert.pem
+    ///       private_key: /missing/key.pem
+    /// http:
+    ///   api:
+    ///     entry_point: webb
+    ///     path: ^/api/[
+    ///     pass_to: backnd
+    /// upstream:
+    ///   backend:
+    ///     servers: ["10.0.0.1", "10.0.0.2:8080"]
+    ///     loadbalancer_strategy: random
+    ///   spare:
+    ///     servers: []
+    ///     weights: [2]
+    /// "#,
+    /// );
+    /// let errors = invalid.validate().unwrap_err();
+    /// assert_eq!(errors.len(), 9);
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::UnknownEntryPoint { rule, entry_point } if rule == "api" && entry_point == "webb"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::UnknownUpstream { upstream, .. } if upstream == "backnd"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::InvalidPattern { pattern, .. } if pattern == "^/api/["
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::InvalidPattern { location, .. } if location == "entry point web"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::InvalidServer { server, .. } if server == "10.0.0.1"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::UnknownStrategy { strategy, .. } if strategy == "random"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::EmptyUpstream { upstream } if upstream == "spare"
+    /// )));
+    /// assert!(errors.iter().any(|e| matches!(
+    ///     e,
+    ///     ConfigError::InvalidWeights { weights: 1, servers: 0, .. }
+    /// )));
+    /// let tls = errors.iter().find(|e| matches!(e, ConfigError::Tls { .. })).unwrap();
+    /// assert!(tls.to_string().contains("/missing/cert.pem"));
+    /// ```
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        for (name, entry_point) in self.entry_points.iter().collect::<BTreeMap<_, _>>() {
+            if let Some(domain_name) = &entry_point.domain_name
+                && let Err(e) = regex(&domain_name.to_lowercase(), format!("entry point {name}"))
+            {
+                errors.push(e);
+            }
+            if let Some(ssl) = &entry_point.ssl
+                && let Err(reason) = ssl
+                    .resolver()
+                    .and_then(|resolver| ssl.server_config(Arc::new(resolver)))
+                    .and(ssl.client_cert_header())
+            {
+                errors.push(ConfigError::Tls {
+                    entry_point: name.clone(),
+                    reason,
+                });
+            }
+        }
+
+        for (name, rule) in self.http.iter().collect::<BTreeMap<_, _>>() {
+            if !self.entry_points.contains_key(&rule.entry_point) {
+                errors.push(ConfigError::UnknownEntryPoint {
+                    rule: name.clone(),
+                    entry_point: rule.entry_point.clone(),
+                });
+            }
+            let canary = rule.canary.as_ref().map(|canary| &canary.pass_to);
+            for pass_to in std::iter::once(&rule.pass_to).chain(canary) {
+                if !self.upstream.contains_key(pass_to) {
+                    errors.push(ConfigError::UnknownUpstream {
+                        rule: name.clone(),
+                        upstream: pass_to.clone(),
+                    });
+                }
+            }
+            if let Err(e) = regex(&rule.path, format!("rule {name}")) {
+                errors.push(e);
+            }
+        }
+
+        for (name, group) in self.upstream.iter().collect::<BTreeMap<_, _>>() {
+            if group.servers.is_empty() {
+                errors.push(ConfigError::EmptyUpstream {
+                    upstream: name.clone(),
+                });
+            }
+            errors.extend(
+                group
+                    .servers
+                    .iter()
+                    .filter_map(|server| parse_server(name, server).err()),
+            );
+            if let Err(e) = group.strategy(name) {
+                errors.push(e);
+            }
+            if let Err(e) = group.weights(name) {
+                errors.push(e);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Builds the services of every entry point from the HTTP rules.
+    ///
+    /// Every rule becomes a service matching its `path`, and the `domain_name` of its
+    /// entry point if set, forwarding to the upstream group named by `pass_to`. Rules
+    /// of an entry point are tried in the order of their names. Rules passing to the
+    /// same upstream group share its load balancer.
+    ///
+    /// # Returns
+    ///
+    /// Returns the services keyed by entry point name, or `BroxyError::Config` listing
+    /// the mistakes found by [`Config::validate`], or if a middleware library can't be loaded.
+    ///
+    /// # Safety
+    ///
+    /// The middleware libraries listed by the rules are loaded, see [`ExternalMiddleware::load`].
+    pub unsafe fn services(&self) -> Result<HashMap<String, Arc<[Service]>>, BroxyError> {
+        self.validate().map_err(|errors| {
+            let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+            BroxyError::Config(errors.join("; "))
+        })?;
+
+        let mut load_balancers = HashMap::new();
+        for (name, group) in &self.upstream {
+            load_balancers.insert(name.as_str(), Arc::new(group.load_balancer(name)?));
+        }
+
+        let mut services: HashMap<String, Vec<Service>> = self
+            .entry_points
+            .keys()
+            .map(|name| (name.clone(), Vec::new()))
+            .collect();
+        let rules = self.http.iter().collect::<BTreeMap<_, _>>();
+        for (name, rule) in rules {
+            let (Some(entry_point), Some(entry_point_services)) = (
+                self.entry_points.get(&rule.entry_point),
+                services.get_mut(&rule.entry_point),
+            ) else {
+                return Err(ConfigError::UnknownEntryPoint {
+                    rule: name.clone(),
+                    entry_point: rule.entry_point.clone(),
+                }
+                .into());
+            };
+            let load_balancer = load_balancers.get(rule.pass_to.as_str()).ok_or_else(|| {
+                ConfigError::UnknownUpstream {
+                    rule: name.clone(),
+                    upstream: rule.pass_to.clone(),
+                }
+            })?;
+
+            let mut service = Service::builder()
+                .load_balancer(load_balancer.clone())
+                .filter(Filter::Path(regex(&rule.path, format!("rule {name}"))?));
+            if let Some(domain_name) = &entry_point.domain_name {
+                let domain_name = regex(
+                    &domain_name.to_lowercase(),
+                    format!("entry point {}", rule.entry_point),
+                )?;
+                service = service.filter(Filter::HostInsensitive(domain_name));
+            }
+            if let Some(paths) = &rule.middleware {
+                service = service.middleware(unsafe { load_middleware(paths) }?);
+            }
+            if let Some(name) = &rule.sticky_cookie {
+                service = service.sticky_cookie(name);
+            }
+            if let Some(canary) = &rule.canary {
+                let load_balancer =
+                    load_balancers.get(canary.pass_to.as_str()).ok_or_else(|| {
+                        ConfigError::UnknownUpstream {
+                            rule: name.clone(),
+                            upstream: canary.pass_to.clone(),
+                        }
+                    })?;
+                service = service.canary(
+                    canary::Canary::new(load_balancer.clone(), canary.weight)
+                        .with_sticky(canary.sticky),
+                );
+            }
+            debug!(
+                "Built service {} for entry point {}",
+                name, rule.entry_point
+            );
+            entry_point_services.push(service.build()?);
+        }
+        Ok(services
+            .into_iter()
+            .map(|(name, services)| (name, services.into()))
+            .collect())
+    }
+
+    /// Binds a server for every entry point.
+    ///
+    /// # Arguments
+    ///
+    /// * `services` - Services built by [`Config::services`]
+    ///
+    /// # Returns
+    ///
+    /// Returns the servers keyed by entry point name, `BroxyError::Config` if the
+    /// certificate or private key of an entry point can't be loaded, or
+    /// `BroxyError::Io` if binding fails.
+    pub async fn build(
+        &self,
+        services: &HashMap<String, Arc<[Service]>>,
+    ) -> Result<HashMap<String, Server>, BroxyError> {
+        let mut servers = HashMap::new();
+        for (name, entry_point) in &self.entry_points {
+            let services = services.get(name).cloned().unwrap_or_else(|| Arc::new([]));
+            let bundle = ServiceBundle::new(services);
+            let mut server = Server::builder()
+                .address(entry_point.address)
+                .services(bundle)
+                .options(ServerOptions {
+                    proxy_protocol: entry_point.proxy_protocol,
+                    max_header_bytes: entry_point.max_header_bytes,
+                    ..ServerOptions::default()
+                });
+            if let Some(ssl) = &entry_point.ssl {
+                let (acceptor, certificates) = ssl.reloadable_tls_acceptor()?;
+                server = server.tls_acceptor(acceptor).certificates(certificates);
+                if let Some(header) = ssl.client_cert_header().map_err(BroxyError::Config)? {
+                    server = server.client_cert_header(header);
+                }
+            }
+            let server = server.build().await?;
+            info!("Entry point {} listening on {}", name, server.local_addr()?);
+            servers.insert(name.clone(), server);
+        }
+        Ok(servers)
+    }
+
+    /// Replaces the services of running servers with the ones of this configuration.
+    ///
+    /// Every service is built and every certificate loaded before any server is touched,
+    /// so an invalid configuration leaves the servers routing with the previous one.
+    /// Requests in flight finish with the services that received them, see
+    /// [`Server::replace_services`].
+    ///
+    /// The certificates of TLS entry points are read from their files again, so renewed
+    /// ones are presented to the next handshakes, see [`Server::reload_certificates`].
+    /// Otherwise only the routing is reloaded: entry points added to the configuration
+    /// aren't started, and changing the address or the other TLS settings of an entry
+    /// point requires a restart.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - The running servers keyed by entry point name, see [`Config::build`]
+    ///
+    /// # Returns
+    ///
+    /// Returns `BroxyError::Config` if the configuration is invalid, see
+    /// [`Config::services`], or lacks the entry point of a running server.
+    ///
+    /// # Safety
+    ///
+    /// The middleware libraries listed by the rules are loaded, see [`ExternalMiddleware::load`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{config::Config, error::BroxyError, server::Server};
+    /// use std::{collections::HashMap, path::Path};
+    ///
+    /// fn on_sighup(servers: &HashMap<String, Server>) -> Result<(), BroxyError> {
+    ///     let config = Config::load(Path::new("broxy.yaml"))?;
+    ///     // The middleware libraries of the configuration are trusted
+    ///     unsafe { config.reload(servers) }
+    /// }
+    /// ```
+    pub unsafe fn reload(&self, servers: &HashMap<String, Server>) -> Result<(), BroxyError> {
+        let services = unsafe { self.services() }?;
+        if let Some(name) = servers
+            .keys()
+            .find(|name| !self.entry_points.contains_key(*name))
+        {
+            return Err(BroxyError::Config(format!(
+                "entry point {name} of a running server was removed"
+            )));
+        }
+        for name in self.entry_points.keys() {
+            if !servers.contains_key(name) {
+                warn!("Entry point {} isn't started until a restart", name);
+            }
+        }
+
+        // Every certificate is loaded before any server is changed
+        let mut certificates = Vec::new();
+        for (name, server) in servers {
+            let ssl = self
+                .entry_points
+                .get(name)
+                .and_then(|entry_point| entry_point.ssl.as_ref());
+            match (ssl, server.is_tls()) {
+                (Some(ssl), true) => certificates.push((server, ssl.sni_resolver()?)),
+                (None, false) => {}
+                _ => warn!("TLS of entry point {} isn't changed until a restart", name),
+            }
+        }
+
+        for (server, resolver) in certificates {
+            server.reload_certificates(resolver)?;
+        }
+        for (name, server) in servers {
+            let services = services.get(name).cloned().unwrap_or_else(|| Arc::new([]));
+            debug!(
+                "Reloading entry point {} with {} services",
+                name,
+                services.len()
+            );
+            server.replace_services(ServiceBundle::new(services));
+        }
+        info!("Configuration reloaded");
+        Ok(())
+    }
+}
+
+impl Ssl {
+    /// Builds the TLS acceptor terminating connections with the certificate.
+    ///
+    /// # Returns
+    ///
+    /// Returns the acceptor, or `BroxyError::Config` if the certificate or private key
+    /// can't be read or don't match.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::config::{ClientAuth, Ssl};
+    ///
+    /// let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+    /// let ssl = Ssl {
+    ///     certificate: format!("{fixtures}/upstream.pem"),
+    ///     private_key: format!("{fixtures}/upstream.key"),
+    ///     certificates: Vec::new(),
+    ///     client_ca: None,
+    ///     client_auth: ClientAuth::None,
+    ///     client_cert_header: None,
+    ///     alpn_protocols: None,
+    /// };
+    /// let acceptor = ssl.tls_acceptor().unwrap();
+    /// ```
+    pub fn tls_acceptor(&self) -> Result<TlsAcceptor, BroxyError> {
+        let resolver = self.resolver().map_err(BroxyError::Config)?;
+        let config = self
+            .server_config(Arc::new(resolver))
+            .map_err(BroxyError::Config)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Builds a TLS acceptor whose certificates can be replaced while it's running.
+    ///
+    /// # Returns
+    ///
+    /// Returns the acceptor and its certificates, to reload with
+    /// [`ReloadableResolver::reload`], or `BroxyError::Config` if the certificates
+    /// can't be loaded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{config::Ssl, error::BroxyError, server::Server, service::ServiceBundle};
+    ///
+    /// # async fn build(ssl: Ssl, services: ServiceBundle) -> Result<Server, BroxyError> {
+    /// let (acceptor, certificates) = ssl.reloadable_tls_acceptor()?;
+    /// let server = Server::builder()
+    ///     .address("127.0.0.1:8443".parse().unwrap())
+    ///     .services(services)
+    ///     .tls_acceptor(acceptor)
+    ///     .certificates(certificates)
+    ///     .build()
+    ///     .await?;
+    /// # Ok(server)
+    /// # }
+    /// ```
+    pub fn reloadable_tls_acceptor(
+        &self,
+    ) -> Result<(TlsAcceptor, Arc<ReloadableResolver>), BroxyError> {
+        let resolver = Arc::new(ReloadableResolver::new(self.sni_resolver()?));
+        let config = self
+            .server_config(resolver.clone())
+            .map_err(BroxyError::Config)?;
+        Ok((TlsAcceptor::from(Arc::new(config)), resolver))
+    }
+
+    /// Loads the certificates and private keys, reading them from their files again.
+    ///
+    /// # Returns
+    ///
+    /// Returns the resolver selecting the certificate of a connection, or
+    /// `BroxyError::Config` if a certificate or private key can't be loaded.
+    pub fn sni_resolver(&self) -> Result<SniResolver, BroxyError> {
+        self.resolver().map_err(BroxyError::Config)
+    }
+
+    /// Builds the TLS configuration presenting the certificates of a resolver.
+    ///
+    /// # Returns
+    ///
+    /// Returns the configuration, or why it can't be built.
+    fn server_config(&self, resolver: Arc<dyn ResolvesServerCert>) -> Result<ServerConfig, String> {
+        let builder = ServerConfig::builder();
+        let builder = match (self.client_auth, &self.client_ca) {
+            (ClientAuth::None, _) => builder.with_no_client_auth(),
+            (_, None) => return Err("client_auth requires a client_ca".to_string()),
+            (mode, Some(path)) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in rustls_pemfile::certs(&mut &read(path)?[..]) {
+                    let certificate =
+                        certificate.map_err(|e| format!("invalid client CA {path}: {e}"))?;
+                    roots
+                        .add(certificate)
+                        .map_err(|e| format!("invalid client CA {path}: {e}"))?;
+                }
+                if roots.is_empty() {
+                    return Err(format!("no certificate in client CA {path}"));
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = match mode {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    _ => verifier,
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|e| format!("invalid client CA {path}: {e}"))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+        let mut config = builder.with_cert_resolver(resolver);
+        config.alpn_protocols = match &self.alpn_protocols {
+            Some(protocols) => protocols
+                .iter()
+                .map(|protocol| match protocol.as_str() {
+                    "h2" | "http/1.1" => Ok(protocol.as_bytes().to_vec()),
+                    _ => Err(format!("unsupported ALPN protocol {protocol}")),
+                })
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_ALPN_PROTOCOLS
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect(),
+        };
+        Ok(config)
+    }
+
+    /// Loads the certificates and private keys into a resolver.
+    ///
+    /// # Returns
+    ///
+    /// Returns the resolver, or why a certificate can't be loaded.
+    fn resolver(&self) -> Result<SniResolver, String> {
+        let provider = ServerConfig::builder().crypto_provider().clone();
+        let mut resolver = SniResolver::new().with_default(Arc::new(certified_key(
+            &self.certificate,
+            &self.private_key,
+            &provider,
+        )?));
+        for certificate in &self.certificates {
+            let key = Arc::new(certified_key(
+                &certificate.certificate,
+                &certificate.private_key,
+                &provider,
+            )?);
+            let hosts = match &certificate.hosts {
+                Some(hosts) => hosts.clone(),
+                None => key
+                    .end_entity_cert()
+                    .map(|certificate| tls::dns_names(certificate))
+                    .unwrap_or_default(),
+            };
+            if hosts.is_empty() {
+                return Err(format!(
+                    "no host name for certificate {}",
+                    certificate.certificate
+                ));
+            }
+            for host in hosts {
+                resolver.add(&host, key.clone());
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Parses the name of the header the client certificate is forwarded in.
+    ///
+    /// # Returns
+    ///
+    /// Returns the header name if one is set, or why it isn't a valid header name.
+    fn client_cert_header(&self) -> Result<Option<HeaderName>, String> {
+        self.client_cert_header
+            .as_deref()
+            .map(|name| {
+                HeaderName::try_from(name)
+                    .map_err(|e| format!("invalid client_cert_header {name}: {e}"))
+            })
+            .transpose()
+    }
+}
+
+impl Upstream {
+    /// Builds the load balancer of the group.
+    fn load_balancer(&self, name: &str) -> Result<LoadBalancer, ConfigError> {
+        if self.servers.is_empty() {
+            return Err(ConfigError::EmptyUpstream {
+                upstream: name.to_string(),
+            });
+        }
+        let weights = self.weights(name)?;
+        let servers = self
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(index, server)| {
+                let mut server = parse_server(name, server)?;
+                if let Some(weights) = weights {
+                    server = server.with_weight(weights[index]);
+                }
+                Ok(match self.max_concurrent {
+                    Some(max_concurrent) => server.with_max_concurrent(max_concurrent),
+                    None => server,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(match self.strategy(name)? {
+            Strategy::RoundRobin => LoadBalancer::new(servers),
+            Strategy::IpHash => LoadBalancer::new_ip_hash(servers),
+            Strategy::PowerOfTwo => LoadBalancer::new_p2c(servers),
+            Strategy::WeightedLeastConnections => {
+                LoadBalancer::new_weighted_least_connections(servers)
+            }
+        })
+    }
+
+    /// Checks there's one weight per server, if the group sets weights.
+    fn weights(&self, name: &str) -> Result<Option<&[u32]>, ConfigError> {
+        match &self.weights {
+            Some(weights) if weights.len() != self.servers.len() => {
+                Err(ConfigError::InvalidWeights {
+                    upstream: name.to_string(),
+                    weights: weights.len(),
+                    servers: self.servers.len(),
+                })
+            }
+            weights => Ok(weights.as_deref()),
+        }
+    }
+
+    /// Parses the load balancing strategy of the group.
+    fn strategy(&self, name: &str) -> Result<Strategy, ConfigError> {
+        match self.loadbalancer_strategy.as_deref() {
+            None | Some("round_robin") => Ok(Strategy::RoundRobin),
+            Some("ip_hash") => Ok(Strategy::IpHash),
+            Some("p2c") => Ok(Strategy::PowerOfTwo),
+            Some("weighted_least_connections") => Ok(Strategy::WeightedLeastConnections),
+            Some(strategy) => Err(ConfigError::UnknownStrategy {
+                upstream: name.to_string(),
+                strategy: strategy.to_string(),
+            }),
+        }
+    }
+}
+
+/// Load balancing strategies an upstream group can name.
+enum Strategy {
+    RoundRobin,
+    IpHash,
+    PowerOfTwo,
+    WeightedLeastConnections,
+}
+
+/// Parses a server of an upstream group, connecting over TLS if prefixed with `https://`.
+fn parse_server(upstream: &str, server: &str) -> Result<upstream::Upstream, ConfigError> {
+    let (address, use_ssl) = match server.strip_prefix("https://") {
+        Some(address) => (address, true),
+        None => (server.strip_prefix("http://").unwrap_or(server), false),
+    };
+    upstream::Upstream::from_hostname(address, use_ssl).map_err(|e| ConfigError::InvalidServer {
+        upstream: upstream.to_string(),
+        server: server.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Reads a file of the TLS configuration.
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {path}: {e}"))
+}
+
+/// Loads a certificate chain and its private key.
+///
+/// # Returns
+///
+/// Returns the certified key, or why it can't be loaded.
+fn certified_key(
+    certificate: &str,
+    private_key: &str,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let certificates = rustls_pemfile::certs(&mut &read(certificate)?[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate {certificate}: {e}"))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {certificate}"));
+    }
+    let key = rustls_pemfile::private_key(&mut &read(private_key)?[..])
+        .ok()
+        .flatten()
+        .ok_or_else(|| format!("invalid private key {private_key}"))?;
+    CertifiedKey::from_der(certificates, key, provider)
+        .map_err(|e| format!("invalid certificate {certificate}: {e}"))
+}
+
+/// Compiles a pattern of the configuration.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern
+/// * `location` - Rule or entry point the pattern belongs to, for errors
+fn regex(pattern: &str, location: String) -> Result<Regex, ConfigError> {
+    Regex::new(pattern).map_err(|source| ConfigError::InvalidPattern {
+        location,
+        pattern: pattern.to_string(),
+        source,
+    })
+}
+
+/// Loads the middleware functions exported by the libraries, in order.
+///
+/// A library may export an incoming function, an outgoing one, or both.
+///
+/// # Safety
+///
+/// See [`ExternalMiddleware::load`].
+unsafe fn load_middleware(paths: &[PathBuf]) -> Result<Middleware, BroxyError> {
+    let mut incoming = Vec::new();
+    let mut outgoing = Vec::new();
+    for path in paths {
+        let incoming_function = unsafe { ExternalMiddleware::load(path, INCOMING_SYMBOL) };
+        let outgoing_function = unsafe { ExternalMiddleware::load(path, OUTGOING_SYMBOL) };
+        if let (Err(e), Err(_)) = (&incoming_function, &outgoing_function) {
+            return Err(BroxyError::Config(format!(
+                "failed to load middleware {}: {e}",
+                path.display()
+            )));
+        }
+        incoming.extend(
+            incoming_function
+                .ok()
+                .map(MiddlewareIncomingFunction::External),
+        );
+        outgoing.extend(
+            outgoing_function
+                .ok()
+                .map(MiddlewareOutgoingFunction::External),
+        );
+    }
+    Ok(Middleware::new(incoming, outgoing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fixture, get, serve};
+    use hyper::Response;
+
+    #[tokio::test]
+    async fn requests_are_routed_by_their_path() {
+        let upstream = |body: &'static str| {
+            crate::test_support::upstream(move |_| async move { Response::new(body.to_string()) })
+        };
+        let users = upstream("users").await;
+        let orders = upstream("orders").await;
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+entry_points:
+  web:
+    address: 127.0.0.1:0
+http:
+  users:
+    entry_point: web
+    path: ^/users
+    pass_to: users
+  orders:
+    entry_point: web
+    path: ^/orders
+    pass_to: orders
+upstream:
+  users:
+    servers: ["{users}"]
+  orders:
+    servers: ["{orders}"]
+    loadbalancer_strategy: ip_hash
+"#
+        ))
+        .unwrap();
+        let services = unsafe { config.services() }.unwrap();
+        let mut servers = config.build(&services).await.unwrap();
+        let web = serve(servers.remove("web").unwrap());
+
+        for (path, expected) in [("/users/1", "users"), ("/orders?page=2", "orders")] {
+            assert!(get(web, path).await.ends_with(expected));
+        }
+        // No rule matches
+        assert!(
+            get(web, "/other")
+                .await
+                .starts_with("HTTP/1.1 404 Not Found")
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_shifts_traffic_unless_the_configuration_is_invalid() {
+        let upstream = |body: &'static str| {
+            crate::test_support::upstream(move |_| async move { Response::new(body.to_string()) })
+        };
+        let blue = upstream("blue").await;
+        let green = upstream("green").await;
+        let config = |pass_to: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                r#"
+entry_points:
+  web:
+    address: 127.0.0.1:0
+http:
+  api:
+    entry_point: web
+    path: ^/api
+    pass_to: {pass_to}
+upstream:
+  blue:
+    servers: ["{blue}"]
+  green:
+    servers: ["{green}"]
+"#
+            ))
+            .unwrap()
+        };
+        let initial = config("blue");
+        let servers = Arc::new(
+            initial
+                .build(&unsafe { initial.services() }.unwrap())
+                .await
+                .unwrap(),
+        );
+        let address = servers["web"].local_addr().unwrap();
+        let accepting = servers.clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = accepting["web"].accept().await;
+            }
+        });
+        assert!(get(address, "/api").await.ends_with("blue"));
+
+        // Traffic shifts to the other group once reloaded
+        unsafe { config("green").reload(&servers) }.unwrap();
+        assert!(get(address, "/api").await.ends_with("green"));
+
+        // An invalid configuration is rejected, the current routing is kept
+        assert!(unsafe { config("purple").reload(&servers) }.is_err());
+        assert!(get(address, "/api").await.ends_with("green"));
+    }
+
+    /// Connects to an acceptor echoing what it receives, trusting the fixture CA.
+    async fn connect(
+        acceptor: TlsAcceptor,
+        server_name: &'static str,
+    ) -> tokio_rustls::client::TlsStream<tokio::net::TcpStream> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tokio_rustls::{
+            TlsConnector,
+            rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(conn).await.unwrap();
+            let mut buffer = [0; 64];
+            let read = stream.read(&mut buffer).await.unwrap();
+            stream.write_all(&buffer[..read]).await.unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        for certificate in rustls_pemfile::certs(&mut &fixture("ca.pem")[..]) {
+            roots.add(certificate.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(server_name).unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    /// Returns the first certificate of a fixture file.
+    fn certificate(name: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut &fixture(name)[..])
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    /// Returns the certificate presented by the server of `stream`.
+    fn presented(stream: &tokio_rustls::client::TlsStream<tokio::net::TcpStream>) -> Vec<u8> {
+        stream.get_ref().1.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[tokio::test]
+    async fn certificates_are_picked_by_server_name() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let ssl = Ssl {
+            certificate: format!("{fixtures}/upstream.pem"),
+            private_key: format!("{fixtures}/upstream.key"),
+            certificates: vec![
+                SslCertificate {
+                    certificate: format!("{fixtures}/site-a.pem"),
+                    private_key: format!("{fixtures}/site-a.key"),
+                    hosts: Some(vec!["site-a.test".to_string()]),
+                },
+                // Presented for the DNS names of the certificate, `*.wildcard.test`
+                SslCertificate {
+                    certificate: format!("{fixtures}/wildcard.pem"),
+                    private_key: format!("{fixtures}/wildcard.key"),
+                    hosts: None,
+                },
+            ],
+            client_ca: None,
+            client_auth: ClientAuth::None,
+            client_cert_header: None,
+            alpn_protocols: None,
+        };
+        let acceptor = ssl.tls_acceptor().unwrap();
+        let presented_for =
+            async |server_name| presented(&connect(acceptor.clone(), server_name).await);
+
+        assert_eq!(
+            presented_for("site-a.test").await,
+            certificate("site-a.pem")
+        );
+        assert_eq!(
+            presented_for("API.wildcard.test").await,
+            certificate("wildcard.pem")
+        );
+        // Unknown names get the default certificate
+        assert_eq!(
+            presented_for("upstream.test").await,
+            certificate("upstream.pem")
+        );
+    }
+
+    #[tokio::test]
+    async fn reloaded_certificates_are_presented_to_new_handshakes() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let ssl = |name: &str| {
+            let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+            Ssl {
+                certificate: format!("{fixtures}/{name}.pem"),
+                private_key: format!("{fixtures}/{name}.key"),
+                certificates: Vec::new(),
+                client_ca: None,
+                client_auth: ClientAuth::None,
+                client_cert_header: None,
+                alpn_protocols: None,
+            }
+        };
+
+        let (acceptor, certificates) = ssl("upstream").reloadable_tls_acceptor().unwrap();
+        let mut established = connect(acceptor.clone(), "upstream.test").await;
+        assert_eq!(presented(&established), certificate("upstream.pem"));
+
+        // New handshakes present the replacing certificate
+        certificates.reload(ssl("site-a").sni_resolver().unwrap());
+        let replaced = connect(acceptor, "site-a.test").await;
+        assert_eq!(presented(&replaced), certificate("site-a.pem"));
+
+        // The established connection keeps its session
+        established.write_all(b"still there").await.unwrap();
+        let mut echoed = [0; 11];
+        established.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"still there");
+    }
+}
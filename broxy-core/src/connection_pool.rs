@@ -0,0 +1,267 @@
+//! Reuse of upstream connections.
+//!
+//! Without a pool every forwarded request opens a new connection to the upstream,
+//! paying for the TCP handshake each time. A [`ConnectionPool`] keeps the HTTP/1.1
+//! connections idle after a response, keyed by upstream address, and hands them
+//! out to the next requests. HTTP/2 connections are multiplexed, so they're shared
+//! by every request to the upstream instead. Install it with
+//! [`crate::service::ServiceBuilder::connection_pool`].
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use http_body_util::combinators::UnsyncBoxBody;
+use hyper::{
+    Request, Response,
+    body::{Bytes, Incoming},
+    client::conn::{TrySendError, http1, http2},
+};
+use tracing::debug;
+
+/// Default maximum amount of idle connections kept per upstream.
+pub const DEFAULT_MAX_IDLE_PER_HOST: usize = 32;
+
+/// Default time an idle connection is kept before being closed.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Body of a request sent to an upstream, whatever it was built from.
+pub(crate) type RequestBody = UnsyncBoxBody<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Sending half of an upstream connection.
+#[derive(Debug)]
+pub(crate) enum Sender {
+    Http1(http1::SendRequest<RequestBody>),
+    /// Shared by every request to the upstream, each one using a clone
+    Http2(http2::SendRequest<RequestBody>),
+}
+
+impl Sender {
+    /// Checks if the connection was closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        match self {
+            Sender::Http1(sender) => sender.is_closed(),
+            Sender::Http2(sender) => sender.is_closed(),
+        }
+    }
+
+    /// Waits until the connection can send a request.
+    pub(crate) async fn ready(&mut self) -> hyper::Result<()> {
+        match self {
+            Sender::Http1(sender) => sender.ready().await,
+            Sender::Http2(sender) => sender.ready().await,
+        }
+    }
+
+    /// Returns a clone of a multiplexed connection, to be shared.
+    pub(crate) fn share(&self) -> Option<Sender> {
+        match self {
+            Sender::Http1(_) => None,
+            Sender::Http2(sender) => Some(Sender::Http2(sender.clone())),
+        }
+    }
+
+    /// Sends a request over the connection.
+    pub(crate) async fn send_request(
+        &mut self,
+        request: Request<RequestBody>,
+    ) -> hyper::Result<Response<Incoming>> {
+        match self {
+            Sender::Http1(sender) => sender.send_request(request).await,
+            Sender::Http2(sender) => sender.send_request(request).await,
+        }
+    }
+
+    /// Sends a request over the connection, getting it back if it couldn't be sent.
+    pub(crate) async fn try_send_request(
+        &mut self,
+        request: Request<RequestBody>,
+    ) -> Result<Response<Incoming>, TrySendError<Request<RequestBody>>> {
+        match self {
+            Sender::Http1(sender) => sender.try_send_request(request).await,
+            Sender::Http2(sender) => sender.try_send_request(request).await,
+        }
+    }
+}
+
+/// Pool of idle upstream connections.
+///
+/// Connections are only returned to the pool once their response body was read to
+/// the end, and are reused most recent first. Connections the upstream closed while
+/// idle are skipped, a new one is opened instead.
+///
+/// Share one pool between the services forwarding to the same upstreams.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use broxy_core::{
+///     connection_pool::ConnectionPool,
+///     load_balancer::LoadBalancer,
+///     service::Service,
+///     upstream::Upstream,
+/// };
+///
+/// let pool = Arc::new(ConnectionPool::default());
+/// let load_balancer = Arc::new(LoadBalancer::new(vec![Upstream::new(
+///     "127.0.0.1:8080".parse().unwrap(),
+///     false,
+/// )]));
+/// let api = Service::builder()
+///     .load_balancer(load_balancer.clone())
+///     .connection_pool(pool.clone())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ConnectionPool {
+    idle: DashMap<SocketAddr, Vec<(Sender, Instant)>>,
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_IDLE_PER_HOST, DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl ConnectionPool {
+    /// Creates an empty connection pool.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_idle_per_host` - Maximum amount of idle connections kept per upstream,
+    ///   connections released beyond it are closed
+    /// * `idle_timeout` - Time an idle connection is kept before being closed
+    ///
+    /// # Returns
+    ///
+    /// A new `ConnectionPool` instance
+    pub fn new(max_idle_per_host: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: DashMap::new(),
+            max_idle_per_host,
+            idle_timeout,
+        }
+    }
+
+    /// Returns the amount of idle connections kept for an upstream,
+    /// shared HTTP/2 connections included.
+    pub fn idle_connections(&self, address: &SocketAddr) -> usize {
+        self.idle
+            .get(address)
+            .map_or(0, |connections| connections.len())
+    }
+
+    /// Takes an idle connection to the upstream, ready to send a request.
+    ///
+    /// # Returns
+    ///
+    /// Returns `None` if no usable connection is idle.
+    pub(crate) async fn checkout(&self, address: SocketAddr) -> Option<Sender> {
+        loop {
+            let (mut sender, idle_since) = {
+                let mut connections = self.idle.get_mut(&address)?;
+                match connections.last_mut()? {
+                    (Sender::Http2(shared), idle_since)
+                        if !shared.is_closed() && idle_since.elapsed() < self.idle_timeout =>
+                    {
+                        *idle_since = Instant::now();
+                        (Sender::Http2(shared.clone()), *idle_since)
+                    }
+                    _ => connections.pop()?,
+                }
+            };
+            if sender.is_closed() || idle_since.elapsed() >= self.idle_timeout {
+                debug!("Dropping stale connection to {}", address);
+                continue;
+            }
+            if sender.ready().await.is_ok() {
+                debug!("Reusing idle connection to {}", address);
+                return Some(sender);
+            }
+        }
+    }
+
+    /// Returns a connection whose response was read to the end.
+    ///
+    /// HTTP/2 connections stay in the pool while in use, releasing them does nothing.
+    pub(crate) fn release(&self, address: SocketAddr, sender: Sender) {
+        if let Sender::Http1(_) = sender {
+            self.insert(address, sender);
+        }
+    }
+
+    /// Adds a connection to the pool, unless it's closed or the pool is full.
+    pub(crate) fn insert(&self, address: SocketAddr, sender: Sender) {
+        if sender.is_closed() {
+            return;
+        }
+        let mut connections = self.idle.entry(address).or_default();
+        connections.retain(|(sender, idle_since)| {
+            !sender.is_closed() && idle_since.elapsed() < self.idle_timeout
+        });
+        if connections.len() < self.max_idle_per_host {
+            connections.push((sender, Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_balancer::LoadBalancer,
+        service::{Service, ServiceBundle},
+        test_support::{get, proxy},
+        upstream::Upstream,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn requests_reuse_idle_connections() {
+        use hyper::{server::conn::http1, service::service_fn};
+        use hyper_util::rt::TokioIo;
+        use std::{
+            convert::Infallible,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        // An upstream counting the connections it accepts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::Relaxed);
+                let service = service_fn(|_| async {
+                    Ok::<_, Infallible>(hyper::Response::new("pong".to_string()))
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(conn), service));
+            }
+        });
+        let pool = Arc::new(ConnectionPool::default());
+        let service = Service::builder()
+            .load_balancer(Arc::new(LoadBalancer::new(vec![Upstream::new(
+                address, false,
+            )])))
+            .connection_pool(pool.clone())
+            .build()
+            .unwrap();
+        let proxy = proxy(ServiceBundle::new(vec![service])).await;
+
+        for _ in 0..3 {
+            assert!(get(proxy, "/").await.ends_with("pong"));
+        }
+
+        // Every request went over the same upstream connection
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.idle_connections(&address), 1);
+    }
+}
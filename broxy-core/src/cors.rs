@@ -0,0 +1,204 @@
+//! Cross-Origin Resource Sharing (CORS).
+//!
+//! [`Cors`] answers preflight `OPTIONS` requests itself and adds the
+//! `Access-Control-Allow-*` headers to the responses of allowed origins.
+//! It's installed as a middleware, see [`Cors::into_middleware`].
+
+use std::{sync::Arc, time::Duration};
+
+use http::{HeaderName, HeaderValue, Method, StatusCode, header, request, response};
+
+use crate::{
+    middleware::{
+        Middleware, MiddlewareAction, MiddlewareIncomingFunction, MiddlewareOutgoingFunction,
+        OriginalRequest,
+    },
+    service::empty_response,
+};
+
+/// An origin allowed to access the services.
+#[derive(Debug, Clone)]
+pub enum CorsOrigin {
+    /// Origin matching exactly, e.g. `https://example.com`
+    Exact(String),
+    /// Origins matching the regex pattern, e.g. `^https://[a-z]+\.example\.com$`
+    Regex(regex::Regex),
+}
+
+impl CorsOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            CorsOrigin::Exact(allowed) => allowed == origin,
+            CorsOrigin::Regex(allowed) => allowed.is_match(origin),
+        }
+    }
+}
+
+/// CORS policy of a service.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use broxy_core::{
+///     cors::{Cors, CorsOrigin},
+///     hyper::{Method, Request, Response, StatusCode},
+///     middleware::{MiddlewareAction, OriginalRequest},
+/// };
+///
+/// let middleware = Cors::new(vec![CorsOrigin::Exact("https://example.com".to_string())])
+///     .with_methods(vec![Method::GET, Method::POST])
+///     .with_headers(vec!["content-type".parse().unwrap()])
+///     .with_max_age(Duration::from_secs(600))
+///     .into_middleware();
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "10.0.0.1:80".parse().unwrap();
+///
+/// // Preflight requests are answered without contacting the upstream
+/// let (mut preflight, _) = Request::options("/api")
+///     .header("origin", "https://example.com")
+///     .header("access-control-request-method", "POST")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// let MiddlewareAction::Respond(response) =
+///     middleware.process_incoming(&from, &upstream, &mut preflight, None).unwrap()
+/// else {
+///     panic!("preflight not answered");
+/// };
+/// assert_eq!(response.status(), StatusCode::NO_CONTENT);
+/// assert_eq!(response.headers()["access-control-allow-methods"], "GET, POST");
+/// assert_eq!(response.headers()["access-control-max-age"], "600");
+///
+/// // Responses to allowed origins get the CORS headers
+/// let response_for = |origin: &str| {
+///     let (request, _) = Request::get("/api").header("origin", origin).body(()).unwrap().into_parts();
+///     let (mut response, _) = Response::new(()).into_parts();
+///     response.extensions.insert(OriginalRequest::new(&request));
+///     middleware.process_outgoing(&from, &upstream, &mut response, None).unwrap();
+///     response
+/// };
+/// let allowed = response_for("https://example.com");
+/// assert_eq!(allowed.headers["access-control-allow-origin"], "https://example.com");
+/// let disallowed = response_for("https://evil.example");
+/// assert!(disallowed.headers.get("access-control-allow-origin").is_none());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<CorsOrigin>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Creates a CORS policy allowing `GET`, `HEAD` and `POST` requests from the given origins.
+    ///
+    /// # Arguments
+    ///
+    /// * `origins` - Origins allowed to access the services
+    ///
+    /// # Returns
+    ///
+    /// A new `Cors` instance
+    pub fn new(origins: Vec<CorsOrigin>) -> Self {
+        Self {
+            origins,
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Vec::new(),
+            max_age: None,
+        }
+    }
+
+    /// Sets the methods allowed in cross-origin requests.
+    pub fn with_methods(mut self, methods: Vec<Method>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// Sets the request headers allowed in cross-origin requests.
+    pub fn with_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Sets how long browsers may cache the answer to a preflight request.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Builds the middleware applying the policy.
+    ///
+    /// To combine it with other middleware, pass the `Cors` variants of
+    /// [`MiddlewareIncomingFunction`] and [`MiddlewareOutgoingFunction`] to [`Middleware::new`].
+    pub fn into_middleware(self) -> Middleware {
+        let cors = Arc::new(self);
+        Middleware::new(
+            vec![MiddlewareIncomingFunction::Cors(cors.clone())],
+            vec![MiddlewareOutgoingFunction::Cors(cors)],
+        )
+    }
+
+    /// Returns the origin of the request, if it's allowed.
+    fn allowed_origin<'a>(&self, headers: &'a http::HeaderMap) -> Option<&'a HeaderValue> {
+        headers.get(header::ORIGIN).filter(|origin| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| self.origins.iter().any(|allowed| allowed.matches(origin)))
+        })
+    }
+
+    /// Answers preflight requests, other requests pass through.
+    pub(crate) fn process_request(&self, parts: &request::Parts) -> MiddlewareAction {
+        if parts.method != Method::OPTIONS
+            || !parts.headers.contains_key(header::ORIGIN)
+            || !parts
+                .headers
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return MiddlewareAction::Continue;
+        }
+
+        let Some(origin) = self.allowed_origin(&parts.headers) else {
+            return MiddlewareAction::Respond(empty_response(StatusCode::FORBIDDEN));
+        };
+        let mut response = empty_response(StatusCode::NO_CONTENT);
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+        if let Ok(methods) = HeaderValue::from_str(&join(&self.methods, Method::as_str)) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if !self.headers.is_empty()
+            && let Ok(allowed) = HeaderValue::from_str(&join(&self.headers, HeaderName::as_str))
+        {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+        MiddlewareAction::Respond(response)
+    }
+
+    /// Adds the CORS headers to the response of an allowed origin.
+    pub(crate) fn process_response(&self, parts: &mut response::Parts) {
+        let Some(request) = parts.extensions.get::<OriginalRequest>() else {
+            return;
+        };
+        let Some(origin) = self.allowed_origin(&request.headers).cloned() else {
+            return;
+        };
+        parts
+            .headers
+            .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        parts
+            .headers
+            .append(header::VARY, HeaderValue::from_static("origin"));
+    }
+}
+
+/// Joins values into a comma separated header value.
+fn join<T>(values: &[T], as_str: fn(&T) -> &str) -> String {
+    values.iter().map(as_str).collect::<Vec<_>>().join(", ")
+}
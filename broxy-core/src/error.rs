@@ -0,0 +1,67 @@
+//! Error types returned by the public API.
+//!
+//! Every fallible operation in the proxy returns a [`BroxyError`], so library consumers
+//! can react differently to each failure mode (unreachable upstream, rejected request,
+//! failing middleware, etc.) instead of inspecting opaque `anyhow` errors.
+
+use std::net::SocketAddr;
+
+/// Errors that can occur while filtering, processing or serving requests.
+#[derive(Debug, thiserror::Error)]
+pub enum BroxyError {
+    /// Failed to open a connection to the upstream server
+    #[error("failed to connect to upstream {address}: {source}")]
+    UpstreamConnect {
+        /// Address of the upstream server
+        address: SocketAddr,
+        /// The underlying connection error
+        source: std::io::Error,
+    },
+    /// The upstream server did not respond in time
+    #[error("upstream {address} timed out")]
+    UpstreamTimeout {
+        /// Address of the upstream server
+        address: SocketAddr,
+    },
+    /// The HTTP handshake with the upstream server failed
+    #[error("HTTP handshake with upstream failed: {0}")]
+    Handshake(#[source] hyper::Error),
+    /// Sending the request to, or reading the response from, the upstream failed
+    #[error("upstream request failed: {0}")]
+    Upstream(#[source] hyper::Error),
+    /// Failed to read a request or response body
+    #[error("failed to read body: {0}")]
+    Body(#[source] hyper::Error),
+    /// The body is larger than the configured limit
+    #[error("body of {size} bytes exceeds the limit of {limit} bytes")]
+    BodyTooLarge {
+        /// Size of the body, as known at the time of the check
+        size: u64,
+        /// The configured limit
+        limit: u64,
+    },
+    /// The request was rejected by a filter
+    #[error("request rejected by filter")]
+    FilterRejected,
+    /// A filter failed to evaluate the request
+    #[error("filter failed: {0:#}")]
+    Filter(anyhow::Error),
+    /// A middleware failed to process the request or response
+    #[error("middleware failed: {0:#}")]
+    Middleware(anyhow::Error),
+    /// Failed to load an external library or one of its symbols
+    #[error("failed to load external library: {0}")]
+    Library(#[from] libloading::Error),
+    /// Invalid configuration, e.g. a builder missing a required option
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    /// An I/O error, e.g. while binding or accepting connections
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Any other error
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Result type used across the public API.
+pub type Result<T, E = BroxyError> = std::result::Result<T, E>;
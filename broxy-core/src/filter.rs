@@ -1,18 +1,91 @@
 use std::{
+    borrow::Cow,
     collections::HashSet,
     net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::Arc,
 };
 
 use http::request::Parts;
 use hyper::body::Incoming;
+use ipnet::IpNet;
+
+use crate::{
+    error::BroxyError, geoip::GeoIpFilter, rate_limit::RateLimiter, service::ProxyResponse,
+};
 
 /// Type alias for external C function filters that operate on request bodies.
 ///
 /// This function type is used for integrating with external filtering libraries
 /// written in C or other languages that can be called via FFI.
+///
+/// The function receives a pointer to the request body and its length in bytes and
+/// returns `true` to accept the request or `false` to reject it. The pointer is only
+/// valid for the duration of the call: the function must not free it, write through it
+/// or keep it. It's called concurrently from several threads, so it must be thread-safe,
+/// and it must not unwind across the FFI boundary.
 pub type FilterBody = unsafe extern "C" fn(*const u8, u64) -> bool;
 
+/// Body filter function loaded from a dynamic library, see [`FilterBody`] for its contract.
+///
+/// The library stays loaded as long as any clone of the filter exists.
+#[derive(Debug, Clone)]
+pub struct ExternalBodyFilter {
+    /// Keeps the library loaded while `function` can be called
+    _library: Arc<libloading::Library>,
+    function: FilterBody,
+}
+
+impl ExternalBodyFilter {
+    /// Loads a body filter from a dynamic library.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the dynamic library, e.g. `libfilter.so`
+    /// * `symbol` - Name of the exported function, e.g. `b"filter_body"`
+    ///
+    /// # Returns
+    ///
+    /// Returns the loaded filter, or `BroxyError::Library` if the library or the
+    /// symbol can't be loaded.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization routines, and the symbol must be
+    /// a function following the [`FilterBody`] contract.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use broxy_core::filter::{BodyFilter, ExternalBodyFilter};
+    ///
+    /// let filter = unsafe { ExternalBodyFilter::load("./libfilter.so", b"filter_body") }.unwrap();
+    /// let body_filter = BodyFilter::External(filter);
+    /// ```
+    pub unsafe fn load(
+        path: impl AsRef<std::ffi::OsStr>,
+        symbol: &[u8],
+    ) -> Result<Self, BroxyError> {
+        let library = unsafe { libloading::Library::new(path) }?;
+        let function = *unsafe { library.get::<FilterBody>(symbol) }?;
+        Ok(Self {
+            _library: Arc::new(library),
+            function,
+        })
+    }
+
+    /// Calls the loaded function with the body.
+    fn call(&self, body: &[u8]) -> bool {
+        // SAFETY: the library is still loaded and the caller of `load` guaranteed
+        // that the function follows the `FilterBody` contract
+        unsafe { (self.function)(body.as_ptr(), body.len() as u64) }
+    }
+}
+
+/// Boxed future returned by asynchronous body filters.
+pub type IncomingFilterFuture =
+    Pin<Box<dyn Future<Output = anyhow::Result<Option<Vec<u8>>>> + Send>>;
+
 /// Request filtering criteria for matching HTTP requests.
 ///
 /// Filters are used to determine whether a request should be processed
@@ -24,11 +97,119 @@ pub enum Filter {
     Method(hyper::Method),
     /// Filter by host header using regex pattern matching
     Host(regex::Regex),
+    /// Filter by host header using regex pattern matching, the host is lowercased
+    /// before matching, so the pattern should be lowercase
+    ///
+    /// ```
+    /// use broxy_core::{filter::Filter, hyper::Request};
+    ///
+    /// let filter = Filter::HostInsensitive(regex::Regex::new(r"^(example\.com|\[::1\])$").unwrap());
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let passes = |request: Request<()>| filter.filter(&from, &request.into_parts().0).unwrap();
+    ///
+    /// assert!(passes(Request::get("http://EXAMPLE.com/").body(()).unwrap()));
+    /// assert!(passes(
+    ///     Request::get("/users").header("host", "Example.COM:8080").body(()).unwrap()
+    /// ));
+    /// assert!(passes(Request::get("http://[::1]:8080/").body(()).unwrap()));
+    /// assert!(passes(Request::get("/").header("host", "[::1]:8080").body(()).unwrap()));
+    /// assert!(!passes(Request::get("http://example.org/").body(()).unwrap()));
+    /// ```
+    HostInsensitive(regex::Regex),
+    /// Filter by exact host, compared case-insensitively and without the port.
+    /// A [`crate::service::ServiceBundle`] indexes the services with it by host, so they're
+    /// found without trying every service in turn
+    ///
+    /// ```
+    /// use broxy_core::{filter::Filter, hyper::Request};
+    ///
+    /// let filter = Filter::HostExact("example.com".to_string());
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let passes = |request: Request<()>| filter.filter(&from, &request.into_parts().0).unwrap();
+    ///
+    /// assert!(passes(Request::get("/").header("host", "Example.COM:8080").body(()).unwrap()));
+    /// assert!(!passes(Request::get("/").header("host", "www.example.com").body(()).unwrap()));
+    /// ```
+    HostExact(String),
     /// Filter by request path using regex pattern matching
     Path(regex::Regex),
+    /// Filter by request path prefix, matching whole path segments: `/api` matches
+    /// `/api` and `/api/users` but not `/apis`. See
+    /// [`crate::service::ServiceBundle::with_longest_prefix`] to route to the longest one
+    ///
+    /// ```
+    /// use broxy_core::{filter::Filter, hyper::Request};
+    ///
+    /// let filter = Filter::PathPrefix("/api".to_string());
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let passes = |path: &str| {
+    ///     let (header, _) = Request::get(path).body(()).unwrap().into_parts();
+    ///     filter.filter(&from, &header).unwrap()
+    /// };
+    ///
+    /// assert!(passes("/api"));
+    /// assert!(passes("/api/users?page=2"));
+    /// assert!(!passes("/apis"));
+    /// assert!(!passes("/"));
+    /// ```
+    PathPrefix(String),
+    /// Filter by the media type of the `Content-Type` header using regex pattern matching,
+    /// parameters such as `charset` are stripped. Requests without the header don't match
+    ///
+    /// ```
+    /// use broxy_core::{filter::Filter, hyper::Request};
+    ///
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let passes = |filter: &Filter, content_type: Option<&str>| {
+    ///     let mut request = Request::post("/upload");
+    ///     if let Some(content_type) = content_type {
+    ///         request = request.header("content-type", content_type);
+    ///     }
+    ///     filter.filter(&from, &request.body(()).unwrap().into_parts().0).unwrap()
+    /// };
+    ///
+    /// let json = Filter::ContentType(regex::Regex::new("^application/json$").unwrap());
+    /// assert!(passes(&json, Some("application/json; charset=utf-8")));
+    /// assert!(!passes(&json, Some("application/x-www-form-urlencoded")));
+    /// assert!(!passes(&json, None));
+    ///
+    /// let application = Filter::ContentType(regex::Regex::new("^application/").unwrap());
+    /// assert!(passes(&application, Some("application/x-www-form-urlencoded")));
+    /// assert!(!passes(&application, Some("multipart/form-data; boundary=x")));
+    /// ```
+    ContentType(regex::Regex),
 
     BlackList(HashSet<IpAddr>),
     WhiteList(HashSet<IpAddr>),
+    /// Reject clients whose address is in any of the IPv4 or IPv6 ranges
+    BlackListCidr(Vec<IpNet>),
+    /// Only accept clients whose address is in any of the IPv4 or IPv6 ranges
+    ///
+    /// ```
+    /// use broxy_core::{filter::Filter, hyper::Request};
+    ///
+    /// let filter = Filter::WhiteListCidr(vec![
+    ///     "10.1.0.0/16".parse().unwrap(),
+    ///     "2001:db8::/32".parse().unwrap(),
+    /// ]);
+    /// let (header, _) = Request::new(()).into_parts();
+    /// let passes = |from: &str| filter.filter(&from.parse().unwrap(), &header).unwrap();
+    ///
+    /// assert!(passes("10.1.0.0:80"));
+    /// assert!(passes("10.1.255.255:80"));
+    /// assert!(passes("[::ffff:10.1.2.3]:80"));
+    /// assert!(!passes("10.0.255.255:80"));
+    /// assert!(!passes("10.2.0.0:80"));
+    /// assert!(passes("[2001:db8:ffff:ffff:ffff:ffff:ffff:ffff]:80"));
+    /// assert!(!passes("[2001:db9::]:80"));
+    /// ```
+    WhiteListCidr(Vec<IpNet>),
+    /// Reject clients sending more requests than allowed by the rate limiter,
+    /// the limiter is shared by every clone of the filter
+    RateLimit(Arc<RateLimiter>),
+    /// Accept or reject clients by the country of their address, the database is
+    /// shared by every clone of the filter
+    GeoIp(Arc<GeoIpFilter>),
 
     CustomFunction(fn(&SocketAddr, &Parts) -> anyhow::Result<bool>), //Body(libloading::Symbol<'static, FilterBody>),
 }
@@ -43,24 +224,65 @@ impl Filter {
     /// # Returns
     ///
     /// Returns `Ok(true)` if the request matches the filter criteria,
-    /// `Ok(false)` if it doesn't match, or an error if filtering fails.
-    pub fn filter(&self, from: &SocketAddr, header: &Parts) -> anyhow::Result<bool> {
+    /// `Ok(false)` if it doesn't match, or `BroxyError::Filter` if filtering fails.
+    pub fn filter(&self, from: &SocketAddr, header: &Parts) -> Result<bool, BroxyError> {
         Ok(match self {
             Filter::Method(method) => header.method.eq(method),
-            Filter::Host(host_regex) => host_regex.is_match(
-                header
-                    .uri
-                    .host()
-                    .ok_or(anyhow::anyhow!("Host is empty: {:?}", header))?,
-            ),
+            Filter::Host(host_regex) => host_regex.is_match(&request_host(header)?),
+            Filter::HostInsensitive(host_regex) => {
+                host_regex.is_match(&request_host(header)?.to_ascii_lowercase())
+            }
+            Filter::HostExact(host) => request_host(header)?.eq_ignore_ascii_case(host),
             Filter::Path(path_regex) => path_regex.is_match(header.uri.path()),
+            Filter::PathPrefix(prefix) => header
+                .uri
+                .path()
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+                }),
+            Filter::ContentType(type_regex) => header
+                .headers
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .and_then(|content_type| content_type.split(';').next())
+                .is_some_and(|media_type| type_regex.is_match(media_type.trim())),
             Filter::BlackList(ip_addrs) => ip_addrs.get(&from.ip()).is_none(),
             Filter::WhiteList(ip_addrs) => ip_addrs.get(&from.ip()).is_some(),
-            Filter::CustomFunction(function) => function(from, header)?,
+            Filter::BlackListCidr(networks) => {
+                let ip = from.ip().to_canonical();
+                !networks.iter().any(|network| network.contains(&ip))
+            }
+            Filter::WhiteListCidr(networks) => {
+                let ip = from.ip().to_canonical();
+                networks.iter().any(|network| network.contains(&ip))
+            }
+            Filter::RateLimit(limiter) => limiter.check(from.ip()),
+            Filter::GeoIp(geoip) => geoip.check(from.ip()),
+            Filter::CustomFunction(function) => {
+                function(from, header).map_err(BroxyError::Filter)?
+            }
         })
     }
 }
 
+/// Reads the host of a request, without the port.
+///
+/// Origin-form requests (`GET /path`) only carry the host in the `Host` header,
+/// it's used when the URI has no host.
+pub(crate) fn request_host(header: &Parts) -> Result<Cow<'_, str>, BroxyError> {
+    if let Some(host) = header.uri.host() {
+        return Ok(Cow::Borrowed(host));
+    }
+    header
+        .headers
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<http::uri::Authority>().ok())
+        .map(|authority| Cow::Owned(authority.host().to_owned()))
+        .ok_or_else(|| BroxyError::Filter(anyhow::anyhow!("Host is empty: {:?}", header)))
+}
+
 /// Body filtering strategies for processing request bodies.
 ///
 /// Body filters can operate on incoming request bodies to determine
@@ -68,13 +290,54 @@ impl Filter {
 #[derive(Debug, Clone)]
 pub enum BodyFilter {
     /// Asynchronous body filter that processes the full incoming body stream
-    InternalIncoming(
-        fn(Incoming) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Vec<u8>>>> + Send>>,
-    ),
+    InternalIncoming(fn(Incoming) -> IncomingFilterFuture),
     /// Synchronous body filter that processes the complete body as bytes
     InternalFullBody(fn(&SocketAddr, &[u8]) -> anyhow::Result<bool>),
-    /// External body filter (not yet implemented)
-    External,
+    /// Synchronous body filter that may rewrite the complete body in place
+    /// before it's forwarded, e.g. to redact a field
+    InternalFullBodyRewrite(fn(&SocketAddr, &mut Vec<u8>) -> anyhow::Result<bool>),
+    /// Synchronous body filter that may craft the response sent when it rejects a request,
+    /// e.g. a JSON-RPC error object
+    InternalFullBodyWithResponse(fn(&SocketAddr, &[u8]) -> anyhow::Result<FilterOutcome>),
+    /// Parses the body as JSON and matches the value at a JSON pointer (RFC 6901)
+    /// against a regex. String values are matched without their quotes, other values
+    /// as serialized JSON. Bodies without a value at the pointer are rejected
+    ///
+    /// ```
+    /// use broxy_core::filter::BodyFilter;
+    ///
+    /// let filter = |pointer: &str, value: &str| BodyFilter::JsonMatch {
+    ///     pointer: pointer.to_string(),
+    ///     value: regex::Regex::new(value).unwrap(),
+    ///     error_on_invalid: false,
+    /// };
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let body = br#"{"params": [{"to": "0xabc", "gas": 21000, "data": null}], "id": 1}"#;
+    ///
+    /// assert!(filter("/params/0/to", "^0x").filter(&from, body).unwrap());
+    /// assert!(filter("/params/0/gas", "^21000$").filter(&from, body).unwrap());
+    /// assert!(filter("/params/0/data", "^null$").filter(&from, body).unwrap());
+    /// assert!(!filter("/params/1/to", ".*").filter(&from, body).unwrap());
+    /// assert!(!filter("/method", ".*").filter(&from, body).unwrap());
+    /// assert!(!filter("/id", ".*").filter(&from, b"not json").unwrap());
+    ///
+    /// let strict = BodyFilter::JsonMatch {
+    ///     pointer: "/id".to_string(),
+    ///     value: regex::Regex::new(".*").unwrap(),
+    ///     error_on_invalid: true,
+    /// };
+    /// assert!(strict.filter(&from, b"not json").is_err());
+    /// ```
+    JsonMatch {
+        /// JSON pointer to the matched value, e.g. `/params/0/to`
+        pointer: String,
+        /// Pattern the value has to match
+        value: regex::Regex,
+        /// Fail with `BroxyError::Filter` on invalid JSON instead of rejecting the request
+        error_on_invalid: bool,
+    },
+    /// Body filter loaded from a dynamic library
+    External(ExternalBodyFilter),
 }
 
 impl BodyFilter {
@@ -90,14 +353,86 @@ impl BodyFilter {
     /// # Returns
     ///
     /// Returns `Ok(true)` if the body passes the filter, `Ok(false)` if it's rejected,
-    /// or an error if filtering fails.
-    pub fn filter(&self, from: &SocketAddr, body: &[u8]) -> anyhow::Result<bool> {
+    /// or `BroxyError::Filter` if filtering fails.
+    pub fn filter(&self, from: &SocketAddr, body: &[u8]) -> Result<bool, BroxyError> {
+        match self {
+            BodyFilter::InternalFullBody(func) => func(from, body).map_err(BroxyError::Filter),
+            BodyFilter::JsonMatch {
+                pointer,
+                value,
+                error_on_invalid,
+            } => {
+                let json = match serde_json::from_slice::<serde_json::Value>(body) {
+                    Ok(json) => json,
+                    Err(e) if *error_on_invalid => {
+                        return Err(BroxyError::Filter(e.into()));
+                    }
+                    Err(_) => return Ok(false),
+                };
+                Ok(match json.pointer(pointer) {
+                    Some(serde_json::Value::String(string)) => value.is_match(string),
+                    Some(other) => value.is_match(&other.to_string()),
+                    None => false,
+                })
+            }
+            BodyFilter::External(external) => Ok(external.call(body)),
+            BodyFilter::InternalIncoming(_) => Err(BroxyError::Filter(anyhow::anyhow!(
+                "Expected to be called by `filter_async`"
+            ))),
+            BodyFilter::InternalFullBodyRewrite(_) => Err(BroxyError::Filter(anyhow::anyhow!(
+                "Expected to be called by `rewrite`"
+            ))),
+            BodyFilter::InternalFullBodyWithResponse(_) => Err(BroxyError::Filter(
+                anyhow::anyhow!("Expected to be called by `apply`"),
+            )),
+        }
+    }
+
+    /// Applies the body filter to a request body, allowing it to be rewritten.
+    ///
+    /// Filters that can't rewrite the body are applied as with [`BodyFilter::filter`].
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The complete request body, as it will be forwarded
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(true)` if the body passes the filter, `Ok(false)` if it's rejected,
+    /// or `BroxyError::Filter` if filtering fails.
+    pub fn rewrite(&self, from: &SocketAddr, body: &mut Vec<u8>) -> Result<bool, BroxyError> {
         match self {
-            BodyFilter::InternalFullBody(func) => func(from, body),
-            BodyFilter::External => unimplemented!(),
-            BodyFilter::InternalIncoming(_) => {
-                Err(anyhow::anyhow!("Expected to be called by `filter_async`"))
+            BodyFilter::InternalFullBodyRewrite(func) => {
+                func(from, body).map_err(BroxyError::Filter)
             }
+            _ => self.filter(from, body),
+        }
+    }
+
+    /// Applies the body filter to a request body, allowing it to be rewritten
+    /// and to supply the rejection response.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The complete request body, as it will be forwarded
+    ///
+    /// # Returns
+    ///
+    /// Returns the `FilterOutcome`, or `BroxyError::Filter` if filtering fails.
+    pub fn apply(
+        &self,
+        from: &SocketAddr,
+        body: &mut Vec<u8>,
+    ) -> Result<FilterOutcome, BroxyError> {
+        match self {
+            BodyFilter::InternalFullBodyWithResponse(func) => {
+                func(from, body).map_err(BroxyError::Filter)
+            }
+            _ => Ok(if self.rewrite(from, body)? {
+                FilterOutcome::Pass
+            } else {
+                FilterOutcome::Reject
+            }),
         }
     }
 
@@ -113,12 +448,15 @@ impl BodyFilter {
     /// # Returns
     ///
     /// Returns a future that resolves to `Ok(Some(body_bytes))` if the body passes
-    /// the filter, `Ok(None)` if the body should be rejected, or an error if filtering fails.
-    pub async fn filter_async(&self, incoming: Incoming) -> anyhow::Result<Option<Vec<u8>>> {
+    /// the filter, `Ok(None)` if the body should be rejected, or `BroxyError::Filter`
+    /// if filtering fails.
+    pub async fn filter_async(&self, incoming: Incoming) -> Result<Option<Vec<u8>>, BroxyError> {
         if let Self::InternalIncoming(filter_incoming) = self {
-            filter_incoming(incoming).await
+            filter_incoming(incoming).await.map_err(BroxyError::Filter)
         } else {
-            Err(anyhow::anyhow!("Expected to be called by `filter`"))
+            Err(BroxyError::Filter(anyhow::anyhow!(
+                "Expected to be called by `filter`"
+            )))
         }
     }
 
@@ -130,27 +468,44 @@ impl BodyFilter {
     /// `false` if it uses synchronous processing.
     #[inline]
     pub fn use_async(&self) -> bool {
-        if let Self::InternalIncoming(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Self::InternalIncoming(_))
     }
 }
 
-/// Raw pointer wrapper for body filters to enable FFI integration.
-///
-/// This struct provides a safe way to pass body filters to external code
-/// while maintaining thread safety guarantees.
-#[derive(Debug, Clone)]
-pub struct BodyFilters {
-    /// Raw pointer to an array of body filters
-    pub filters: *const BodyFilter,
-    /// Number of filters in the array
-    pub len: usize,
+/// Result of applying a body filter.
+#[derive(Debug)]
+pub enum FilterOutcome {
+    /// The body passes the filter
+    Pass,
+    /// The request is rejected with the service's default rejection response,
+    /// `403 Forbidden` unless configured otherwise
+    Reject,
+    /// The request is rejected with the given response
+    RejectWith(ProxyResponse),
 }
 
-// SAFETY: This is safe because BodyFilter is Send and Sync
-unsafe impl Send for BodyFilters {}
-// SAFETY: This is safe because BodyFilter is Send and Sync
-unsafe impl Sync for BodyFilters {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::example_plugin;
+
+    #[test]
+    fn external_body_filters_are_called() {
+        let filter = unsafe { ExternalBodyFilter::load(example_plugin(), b"filter_body") }.unwrap();
+        let filter = BodyFilter::External(filter);
+        let from = "127.0.0.1:50000".parse().unwrap();
+        let apply = |body: &str| filter.apply(&from, &mut body.as_bytes().to_vec()).unwrap();
+
+        assert!(matches!(apply(r#"{"item": 1}"#), FilterOutcome::Pass));
+        assert!(matches!(apply("a forbidden word"), FilterOutcome::Reject));
+        assert!(matches!(apply(""), FilterOutcome::Pass));
+    }
+
+    #[test]
+    fn missing_symbols_are_refused() {
+        let missing = unsafe { ExternalBodyFilter::load(example_plugin(), b"missing_filter") };
+        assert!(matches!(missing, Err(BroxyError::Library(_))));
+        let missing = unsafe { ExternalBodyFilter::load("/missing/libfilter.so", b"filter_body") };
+        assert!(matches!(missing, Err(BroxyError::Library(_))));
+    }
+}
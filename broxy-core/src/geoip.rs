@@ -0,0 +1,220 @@
+//! Filtering of clients by country.
+//!
+//! The country of a client is looked up in a MaxMind database, e.g. GeoLite2 Country,
+//! by its IP address. The database is read once when the filter is created and shared
+//! by every clone of [`crate::filter::Filter::GeoIp`].
+
+use std::{collections::HashSet, net::IpAddr, path::Path};
+
+use maxminddb::{Reader, geoip2};
+
+use crate::error::BroxyError;
+
+/// Countries allowed or denied, looked up in a MaxMind database.
+///
+/// Countries are ISO 3166-1 alpha-2 codes, e.g. `SE`. Clients whose address isn't
+/// in the database, or has no country, are unknown: they're denied by an allow list
+/// and allowed by a deny list, unless set otherwise with [`GeoIpFilter::unknown`].
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::geoip::GeoIpFilter;
+///
+/// let filter = GeoIpFilter::allow("fixtures/geoip.mmdb", ["SE"]).unwrap();
+///
+/// assert_eq!(filter.country("89.160.20.128".parse().unwrap()).as_deref(), Some("SE"));
+/// assert!(filter.check("89.160.20.128".parse().unwrap()));
+/// assert!(!filter.check("81.2.69.160".parse().unwrap()));
+/// assert!(!filter.check("192.0.2.1".parse().unwrap()));
+///
+/// let filter = filter.unknown(true);
+/// assert!(filter.check("192.0.2.1".parse().unwrap()));
+/// ```
+pub struct GeoIpFilter {
+    reader: Reader<Vec<u8>>,
+    countries: HashSet<String>,
+    /// Whether `countries` are allowed, or denied
+    allow: bool,
+    /// Whether clients of unknown country are allowed
+    unknown: bool,
+}
+
+impl GeoIpFilter {
+    /// Creates a filter only allowing clients from the given countries.
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - Path to the MaxMind database, e.g. `GeoLite2-Country.mmdb`
+    /// * `countries` - ISO codes of the allowed countries
+    ///
+    /// # Returns
+    ///
+    /// Returns the filter, or `BroxyError::Config` if the database can't be read.
+    pub fn allow(
+        database: impl AsRef<Path>,
+        countries: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Self, BroxyError> {
+        Self::open(database.as_ref(), countries, true)
+    }
+
+    /// Creates a filter rejecting clients from the given countries.
+    ///
+    /// # Arguments
+    ///
+    /// * `database` - Path to the MaxMind database, e.g. `GeoLite2-Country.mmdb`
+    /// * `countries` - ISO codes of the denied countries
+    ///
+    /// # Returns
+    ///
+    /// Returns the filter, or `BroxyError::Config` if the database can't be read.
+    pub fn deny(
+        database: impl AsRef<Path>,
+        countries: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Self, BroxyError> {
+        Self::open(database.as_ref(), countries, false)
+    }
+
+    fn open(
+        database: &Path,
+        countries: impl IntoIterator<Item = impl Into<String>>,
+        allow: bool,
+    ) -> Result<Self, BroxyError> {
+        let reader = Reader::open_readfile(database).map_err(|e| {
+            BroxyError::Config(format!(
+                "invalid GeoIP database {}: {}",
+                database.display(),
+                e
+            ))
+        })?;
+        Ok(Self {
+            reader,
+            countries: countries
+                .into_iter()
+                .map(|country| country.into().to_ascii_uppercase())
+                .collect(),
+            allow,
+            unknown: !allow,
+        })
+    }
+
+    /// Sets whether clients of unknown country are allowed.
+    pub fn unknown(mut self, allow: bool) -> Self {
+        self.unknown = allow;
+        self
+    }
+
+    /// Looks up the country of an address.
+    ///
+    /// # Returns
+    ///
+    /// The ISO code of the country, or `None` if the address has no known country
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+
+    /// Checks whether a client is allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - IP address of the client
+    ///
+    /// # Returns
+    ///
+    /// `true` if the client is allowed, `false` otherwise
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let record = self.reader.lookup::<geoip2::Country>(ip.to_canonical());
+        match record
+            .ok()
+            .and_then(|record| record.country)
+            .and_then(|country| country.iso_code)
+        {
+            Some(country) => self.countries.contains(country) == self.allow,
+            None => self.unknown,
+        }
+    }
+}
+
+impl std::fmt::Debug for GeoIpFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIpFilter")
+            .field("database", &self.reader.metadata.database_type)
+            .field("countries", &self.countries)
+            .field("allow", &self.allow)
+            .field("unknown", &self.unknown)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Filter;
+    use std::sync::Arc;
+
+    const DATABASE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/geoip.mmdb");
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn countries_are_resolved() {
+        let filter = GeoIpFilter::allow(DATABASE, ["SE"]).unwrap();
+
+        assert_eq!(filter.country(ip("81.2.69.1")).as_deref(), Some("GB"));
+        assert_eq!(filter.country(ip("89.160.20.255")).as_deref(), Some("SE"));
+        assert_eq!(
+            filter.country(ip("::ffff:89.160.20.1")).as_deref(),
+            Some("SE")
+        );
+        assert_eq!(filter.country(ip("2001:db8::1")).as_deref(), Some("DE"));
+        assert_eq!(filter.country(ip("10.1.2.3")), None);
+        assert_eq!(filter.country(ip("89.160.21.1")), None);
+    }
+
+    #[test]
+    fn allow_list_denies_unknown_clients_by_default() {
+        let filter = GeoIpFilter::allow(DATABASE, ["se", "DE"]).unwrap();
+
+        assert!(filter.check(ip("89.160.20.1")));
+        assert!(filter.check(ip("2001:db8::1")));
+        assert!(!filter.check(ip("81.2.69.1")));
+        assert!(!filter.check(ip("10.1.2.3")));
+        assert!(!filter.check(ip("192.0.2.1")));
+
+        let filter = filter.unknown(true);
+        assert!(filter.check(ip("10.1.2.3")));
+        assert!(!filter.check(ip("81.2.69.1")));
+    }
+
+    #[test]
+    fn deny_list_allows_unknown_clients_by_default() {
+        let filter = Filter::GeoIp(Arc::new(GeoIpFilter::deny(DATABASE, ["GB"]).unwrap()));
+        let (header, _) = hyper::Request::new(()).into_parts();
+        let passes = |from: &str| filter.filter(&from.parse().unwrap(), &header).unwrap();
+
+        assert!(!passes("81.2.69.1:50000"));
+        assert!(passes("89.160.20.1:50000"));
+        assert!(passes("192.0.2.1:50000"));
+
+        let filter = GeoIpFilter::deny(DATABASE, ["GB"]).unwrap().unknown(false);
+        assert!(!filter.check(ip("192.0.2.1")));
+        assert!(filter.check(ip("89.160.20.1")));
+    }
+
+    #[test]
+    fn invalid_databases_are_refused() {
+        assert!(matches!(
+            GeoIpFilter::allow(
+                concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/ca.pem"),
+                ["SE"]
+            ),
+            Err(BroxyError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+//! Proxying of gRPC calls.
+//!
+//! gRPC runs over HTTP/2 and sends its status in the response trailers, e.g.
+//! `grpc-status: 0`. Requests forwarded without a body filter, a body-reading
+//! middleware or a mirror are streamed frame by frame, trailers included, so unary
+//! and streaming calls go through as long as the upstream is spoken to over
+//! [`crate::upstream::HttpVersion::Http2`]. Clients connect with HTTP/2, over TLS
+//! with ALPN or in cleartext with prior knowledge.
+//!
+//! gRPC clients expect failures as a status rather than as an HTTP error, so services
+//! built with [`crate::service::ServiceBuilder::grpc`] answer the gRPC requests that
+//! failed with a trailers-only response carrying the status matching the error.
+//!
+//! # Example
+//!
+//! ```
+//! use std::sync::Arc;
+//! use broxy_core::{
+//!     load_balancer::LoadBalancer,
+//!     service::Service,
+//!     upstream::{HttpVersion, Upstream},
+//! };
+//!
+//! let upstream = Upstream::new("127.0.0.1:50051".parse().unwrap(), false)
+//!     .with_http_version(HttpVersion::Http2);
+//! let service = Service::builder()
+//!     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+//!     .grpc(true)
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use tracing::warn;
+
+use crate::service::{ProcessFuture, ProxyResponse, empty_response};
+
+/// `UNKNOWN` gRPC status code.
+pub const UNKNOWN: u32 = 2;
+
+/// `INTERNAL` gRPC status code.
+pub const INTERNAL: u32 = 13;
+
+/// `UNAVAILABLE` gRPC status code.
+pub const UNAVAILABLE: u32 = 14;
+
+/// Checks whether a request is a gRPC call, from its content type.
+///
+/// # Arguments
+///
+/// * `headers` - The request headers
+///
+/// # Returns
+///
+/// Returns `true` for `application/grpc` and its variants, e.g. `application/grpc+proto`.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type
+                .strip_prefix("application/grpc")
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+        })
+}
+
+/// Maps the HTTP status of a response without a gRPC status to a gRPC status code,
+/// as specified by gRPC for HTTP errors.
+///
+/// # Arguments
+///
+/// * `status` - The HTTP status of the response
+///
+/// # Returns
+///
+/// Returns the gRPC status code.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{grpc, hyper::StatusCode};
+///
+/// assert_eq!(grpc::status_from_http(StatusCode::SERVICE_UNAVAILABLE), grpc::UNAVAILABLE);
+/// assert_eq!(grpc::status_from_http(StatusCode::UNAUTHORIZED), 16);
+/// assert_eq!(grpc::status_from_http(StatusCode::IM_A_TEAPOT), grpc::UNKNOWN);
+/// ```
+pub fn status_from_http(status: StatusCode) -> u32 {
+    match status {
+        StatusCode::BAD_REQUEST => INTERNAL,
+        StatusCode::UNAUTHORIZED => 16,
+        StatusCode::FORBIDDEN => 7,
+        StatusCode::NOT_FOUND => 12,
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => UNAVAILABLE,
+        _ => UNKNOWN,
+    }
+}
+
+/// Builds a trailers-only gRPC response, carrying its status in its headers.
+///
+/// # Arguments
+///
+/// * `code` - The gRPC status code
+/// * `message` - The status message, sent percent-encoded in `grpc-message`
+pub fn status_response(code: u32, message: &str) -> ProxyResponse {
+    let mut response = empty_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+/// Answers the failures of a gRPC call with a gRPC status.
+///
+/// Errors, e.g. failing to connect to the upstream, become `UNAVAILABLE`, responses without a `grpc-status` and with an
+/// HTTP error status are mapped with [`status_from_http`].
+pub(crate) fn answer_failures(future: ProcessFuture) -> ProcessFuture {
+    Box::pin(async move {
+        match future.await {
+            Ok(response)
+                if response.status() != StatusCode::OK
+                    && !response.headers().contains_key("grpc-status") =>
+            {
+                let status = response.status();
+                Ok(status_response(
+                    status_from_http(status),
+                    &status.to_string(),
+                ))
+            }
+            Ok(response) => Ok(response),
+            Err(e) => {
+                warn!("gRPC call failed, returning UNAVAILABLE: {}", e);
+                Ok(status_response(UNAVAILABLE, &e.to_string()))
+            }
+        }
+    })
+}
+
+/// Percent-encodes a status message, as required by `grpc-message`.
+fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => char::from(byte).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        load_balancer::LoadBalancer,
+        service::{Service, ServiceBundle},
+        test_support::{proxy, refused_address, upstream},
+        upstream::{HttpVersion, Upstream},
+    };
+    use futures::{SinkExt as _, channel::mpsc};
+    use http::HeaderMap;
+    use http_body_util::{BodyExt as _, StreamBody};
+    use hyper::{
+        Request, Response,
+        body::{Bytes, Frame, Incoming},
+        client::conn::http2::{self, SendRequest},
+    };
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+    use tokio::net::TcpStream;
+
+    type Frames = mpsc::Sender<Result<Frame<Bytes>, Infallible>>;
+    type Body = StreamBody<mpsc::Receiver<Result<Frame<Bytes>, Infallible>>>;
+
+    /// Connects an HTTP/2 client, with prior knowledge, to a gRPC proxy in front of `upstream`.
+    async fn connect(upstream: SocketAddr) -> SendRequest<Body> {
+        let upstream = Upstream::new(upstream, false).with_http_version(HttpVersion::Http2);
+        let service = Service::builder()
+            .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+            .grpc(true)
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let conn = TcpStream::connect(address).await.unwrap();
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(conn))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    #[tokio::test]
+    async fn streaming_calls_are_forwarded_frame_by_frame() {
+        // A gRPC echo service, streaming back every message as soon as it's received
+        let upstream = upstream(|request: Request<Incoming>| async move {
+            let (mut tx, rx): (Frames, _) = mpsc::channel(8);
+            tokio::spawn(async move {
+                let mut body = request.into_body();
+                while let Some(frame) = body.frame().await {
+                    let frame = frame.unwrap();
+                    if let Some(data) = frame.data_ref() {
+                        tx.send(Ok(Frame::data(data.clone()))).await.unwrap();
+                    } else if let Ok(trailers) = frame.into_trailers() {
+                        // Echo the metadata the client sent in its trailers
+                        let mut status = HeaderMap::new();
+                        status.insert("grpc-status", "0".parse().unwrap());
+                        status.insert("x-client-trailer", trailers["x-client-trailer"].clone());
+                        tx.send(Ok(Frame::trailers(status))).await.unwrap();
+                    }
+                }
+            });
+            Response::builder()
+                .header("content-type", "application/grpc")
+                .body(StreamBody::new(rx))
+                .unwrap()
+        })
+        .await;
+
+        let mut sender = connect(upstream).await;
+        let (mut tx, rx): (Frames, _) = mpsc::channel(8);
+        let request = Request::post("http://localhost/echo.Echo/Chat")
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(StreamBody::new(rx))
+            .unwrap();
+        let first = Bytes::from_static(b"\0\0\0\0\x05hello");
+        tx.send(Ok(Frame::data(first.clone()))).await.unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "application/grpc");
+        let mut body = response.into_body();
+
+        // Every message is answered before the next one is sent
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), first);
+        let second = Bytes::from_static(b"\0\0\0\0\x05world");
+        tx.send(Ok(Frame::data(second.clone()))).await.unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), second);
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-client-trailer", "bye".parse().unwrap());
+        tx.send(Ok(Frame::trailers(trailers))).await.unwrap();
+        let trailers = body
+            .frame()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-client-trailer"], "bye");
+    }
+
+    #[tokio::test]
+    async fn failed_calls_get_a_grpc_status() {
+        let mut sender = connect(refused_address()).await;
+        let request = Request::post("http://localhost/echo.Echo/Chat")
+            .header("content-type", "application/grpc")
+            .body(StreamBody::new(mpsc::channel(0).1))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["grpc-status"], "14");
+    }
+}
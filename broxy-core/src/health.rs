@@ -0,0 +1,167 @@
+//! Active health checking of upstream servers.
+//!
+//! A background task periodically sends `GET <health_check_path>` to every upstream
+//! that has a health check path configured, see [`crate::upstream::Upstream::with_health_check`].
+//! An upstream is marked down after a number of consecutive failed checks and up again
+//! after a number of consecutive successful ones. The load balancer skips upstreams
+//! that are marked down.
+
+use std::{sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use futures::future::join_all;
+use http::{Request, header};
+use http_body_util::Empty;
+use hyper::body::Bytes;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::{load_balancer::Pool, service::send_to_upstream, upstream::Upstream};
+
+/// Settings of the active health checks.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Time between two checks of the same upstream
+    pub interval: Duration,
+    /// Time after which a check without a response is considered failed
+    pub timeout: Duration,
+    /// Amount of consecutive failed checks after which an upstream is marked down
+    pub unhealthy_threshold: u32,
+    /// Amount of consecutive successful checks after which an upstream is marked up again
+    pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+/// Spawns the task checking the upstreams of the pool.
+///
+/// The pool is read again before every round, so upstreams added or removed
+/// at runtime are picked up.
+pub(crate) fn spawn(pool: Arc<ArcSwap<Pool>>, config: HealthCheck) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let pool = pool.load_full();
+            join_all(
+                pool.servers
+                    .iter()
+                    .filter(|upstream| upstream.health_check_path.is_some())
+                    .map(|upstream| check(upstream, &config)),
+            )
+            .await;
+        }
+    })
+}
+
+/// Checks a single upstream and updates its state.
+async fn check(upstream: &Upstream, config: &HealthCheck) {
+    let Some(path) = upstream.health_check_path.as_deref() else {
+        return;
+    };
+    let request = match Request::get(path)
+        .header(header::HOST, upstream.authority())
+        .body(Empty::<Bytes>::new())
+    {
+        Ok(request) => request,
+        Err(e) => {
+            warn!(
+                "Invalid health check path {} for {}: {}",
+                path, upstream.address, e
+            );
+            return;
+        }
+    };
+
+    let passed =
+        match tokio::time::timeout(config.timeout, send_to_upstream(upstream, None, request)).await
+        {
+            Ok(Ok(response)) => response.status().is_success(),
+            Ok(Err(e)) => {
+                debug!("Health check of {} failed: {}", upstream.address, e);
+                false
+            }
+            Err(_) => {
+                debug!("Health check of {} timed out", upstream.address);
+                false
+            }
+        };
+    upstream.stats.record_check(upstream, passed, config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{load_balancer::LoadBalancer, test_support::upstream};
+    use hyper::{Response, StatusCode};
+    use std::sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    #[tokio::test]
+    async fn upstreams_are_marked_down_and_up_by_their_checks() {
+        // Status answered to every check, in order, then `200 OK`
+        const STATUSES: [u16; 7] = [200, 200, 500, 500, 500, 200, 200];
+        let load_balancer = Arc::new(OnceLock::<Arc<LoadBalancer>>::new());
+        let checks = Arc::new(AtomicUsize::new(0));
+        // Health of the upstream seen by every check, i.e. after the previous ones
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let address = upstream({
+            let (load_balancer, checks, seen) =
+                (load_balancer.clone(), checks.clone(), seen.clone());
+            move |_| {
+                let check = checks.fetch_add(1, Ordering::Relaxed);
+                if let Some(load_balancer) = load_balancer.get() {
+                    seen.lock()
+                        .unwrap()
+                        .push(load_balancer.upstreams()[0].healthy);
+                }
+                let status = STATUSES.get(check).copied().unwrap_or(200);
+                let mut response = Response::new(String::new());
+                *response.status_mut() = StatusCode::from_u16(status).unwrap();
+                async move { response }
+            }
+        })
+        .await;
+        load_balancer
+            .set(Arc::new(LoadBalancer::new(vec![
+                Upstream::new(address, false).with_health_check("/health"),
+            ])))
+            .unwrap();
+        let checking = load_balancer
+            .get()
+            .unwrap()
+            .spawn_health_checks(HealthCheck {
+                interval: Duration::from_millis(10),
+                timeout: Duration::from_secs(1),
+                unhealthy_threshold: 3,
+                healthy_threshold: 2,
+            });
+
+        let observed = async {
+            while seen.lock().unwrap().len() <= STATUSES.len() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), observed)
+            .await
+            .unwrap();
+        checking.abort();
+        // Down after the third failed check, up after the second passing one
+        assert_eq!(
+            seen.lock().unwrap()[..=STATUSES.len()],
+            [true, true, true, true, true, false, false, true]
+        );
+    }
+}
@@ -8,20 +8,71 @@
 //! - Custom routing rules
 //!
 //! The main components are organized into the following modules:
+//! - `access_log`: Per-request access logging
+//! - `auth`: HTTP basic authentication middleware
+//! - `cache`: In-memory response caching
+//! - `canary`: Traffic splitting to a canary upstream group
+//! - `compression`: Response compression middleware
+//! - `connection_pool`: Reuse of idle upstream connections
 //! - `config`: Configuration structures for the proxy
+//! - `cors`: Cross-Origin Resource Sharing middleware
+//! - `error`: Error types returned by the public API
 //! - `filter`: Request and response filtering capabilities
+//! - `geoip`: Filtering of clients by country
+//! - `grpc`: Proxying of gRPC calls
+//! - `health`: Active health checking of upstream servers
 //! - `load_balancer`: Load balancing strategies
 //! - `logging`: Logging system initialization and configuration
+//! - `method_override`: Method overriding for clients limited to `GET` and `POST`
+//! - `metrics`: Hook observing every completed request
 //! - `middleware`: Request/response processing middleware
+//! - `mirror`: Traffic mirroring to a secondary upstream
+//! - `proxy_protocol`: PROXY protocol headers sent by TCP load balancers
+//! - `rate_limit`: Per-client rate limiting
+//! - `resolver`: Resolution of upstream host names
+//! - `response_headers`: Filtering of the headers of upstream responses
+//! - `retry_budget`: Limit on the share of retried requests
 //! - `server`: HTTP server implementation
 //! - `service`: Service definitions and processing logic
+//! - `tls`: Selection of the certificate presented to TLS clients
+//! - `trace_context`: W3C trace-context propagation (`trace-context` feature)
 //! - `upstream`: Upstream server configuration
+//!
+//! Optional cargo features:
+//! - `trace-context`: propagates W3C `traceparent`/`tracestate` headers to upstreams
+//! - `hickory`: resolves upstream host names with `hickory-resolver`, honoring DNS TTLs
+//! - `tower`: implements `tower_service::Service` for `ServiceBundle`, so it can be wrapped by `tower` layers
 
+pub mod access_log;
+pub mod auth;
+pub mod cache;
+pub mod canary;
+pub mod compression;
+pub mod config;
+pub mod connection_pool;
+pub mod cors;
+pub mod error;
 pub mod filter;
+pub mod geoip;
+pub mod grpc;
+pub mod health;
 pub mod load_balancer;
+pub mod method_override;
+pub mod metrics;
 pub mod middleware;
+pub mod mirror;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod resolver;
+pub mod response_headers;
+pub mod retry_budget;
 pub mod server;
 pub mod service;
+#[cfg(test)]
+mod test_support;
+pub mod tls;
+#[cfg(feature = "trace-context")]
+pub mod trace_context;
 pub mod upstream;
 pub mod utils;
 pub use hyper;
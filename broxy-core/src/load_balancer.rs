@@ -3,24 +3,82 @@
 //! This module will contain implementations of various load balancing algorithms
 //! such as round-robin, least connections, weighted distribution, etc.
 
-use crate::upstream::Upstream;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{
+    health::{self, HealthCheck},
+    upstream::{Upstream, UpstreamStatus},
+    utils::fnv1a,
+};
+use arc_swap::ArcSwap;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
 
-/// A round-robin load balancer that distributes requests evenly across upstream servers.
+/// Amount of points every server gets on the hash ring.
 ///
-/// This load balancer maintains an internal counter that increments for each request,
-/// and uses modulo arithmetic to cycle through the available servers in order.
-/// The set of servers is immutable once created.
+/// More points spread clients more evenly across servers.
+const RING_POINTS_PER_SERVER: usize = 160;
+
+/// Strategy used to pick a server for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    /// Cycle through the servers in order
+    RoundRobin,
+    /// Consistent hashing of the client IP
+    IpHash,
+    /// Less loaded of two randomly picked servers
+    PowerOfTwo,
+    /// Server with the fewest requests in flight relative to its weight
+    WeightedLeastConnections,
+}
+
+/// Snapshot of the servers, replaced as a whole whenever a server is added or removed.
+#[derive(Debug)]
+pub(crate) struct Pool {
+    pub(crate) servers: Vec<Arc<Upstream>>,
+    /// Hash ring of the IP hash strategy, sorted `(point, server index)` pairs
+    ring: Vec<(u64, usize)>,
+}
+
+impl Pool {
+    fn new(servers: Vec<Arc<Upstream>>, strategy: Strategy) -> Self {
+        let ring = match strategy {
+            Strategy::RoundRobin | Strategy::PowerOfTwo | Strategy::WeightedLeastConnections => {
+                Vec::new()
+            }
+            Strategy::IpHash => build_ring(&servers),
+        };
+        Self { servers, ring }
+    }
+}
+
+/// A load balancer that distributes requests across upstream servers.
+///
+/// By default it's round-robin: it maintains an internal counter that increments
+/// for each request, and uses modulo arithmetic to cycle through the available
+/// servers in order. See [`LoadBalancer::new_ip_hash`] for client-IP stickiness
+/// and [`LoadBalancer::new_p2c`] or [`LoadBalancer::new_weighted_least_connections`]
+/// for load-aware selection.
+/// Servers can be added and removed while requests are in flight.
 #[derive(Debug)]
 pub struct LoadBalancer {
-    /// The list of upstream servers to balance requests across
-    servers: Vec<Upstream>,
+    /// The upstream servers to balance requests across, shared with the health checks
+    pool: Arc<ArcSwap<Pool>>,
     /// The current index for round-robin selection (atomic for thread safety)
     current_index: AtomicUsize,
+    /// Zone the proxy runs in, servers in this zone are preferred
+    local_zone: Option<String>,
+    /// Strategy used to pick a server
+    strategy: Strategy,
 }
 
 impl LoadBalancer {
-    /// Creates a new load balancer with the given upstream servers.
+    /// Creates a new round-robin load balancer with the given upstream servers.
     ///
     /// # Arguments
     ///
@@ -30,26 +88,656 @@ impl LoadBalancer {
     ///
     /// A new `LoadBalancer` instance
     pub fn new(servers: Vec<Upstream>) -> Self {
+        Self::with_strategy(servers, Strategy::RoundRobin)
+    }
+
+    /// Creates a new load balancer sending every client IP to the same server.
+    ///
+    /// Client IPs are mapped to servers with consistent hashing, so adding or removing
+    /// a server only remaps the clients of that server. A client is only sent to
+    /// another server while its own is saturated.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - A vector of upstream servers to balance requests across
+    ///
+    /// # Returns
+    ///
+    /// A new `LoadBalancer` instance
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{load_balancer::LoadBalancer, upstream::Upstream};
+    ///
+    /// let load_balancer = LoadBalancer::new_ip_hash(vec![
+    ///     Upstream::new("10.0.0.1:80".parse().unwrap(), false),
+    ///     Upstream::new("10.0.0.2:80".parse().unwrap(), false),
+    ///     Upstream::new("10.0.0.3:80".parse().unwrap(), false),
+    /// ]);
+    ///
+    /// let client = "192.168.1.10:50000".parse().unwrap();
+    /// let first = load_balancer.get_upstream(&client).address;
+    /// for port in 50001..51000 {
+    ///     let client = std::net::SocketAddr::new(client.ip(), port);
+    ///     assert_eq!(load_balancer.get_upstream(&client).address, first);
+    /// }
+    /// ```
+    pub fn new_ip_hash(servers: Vec<Upstream>) -> Self {
+        Self::with_strategy(servers, Strategy::IpHash)
+    }
+
+    /// Creates a new load balancer using the power-of-two-choices algorithm.
+    ///
+    /// Every request picks two servers at random and is sent to the one with fewer
+    /// requests in flight. This balances uneven request durations almost as well as
+    /// scanning every server for the least loaded one, without herding every request
+    /// onto the same server.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - A vector of upstream servers to balance requests across
+    ///
+    /// # Returns
+    ///
+    /// A new `LoadBalancer` instance
+    pub fn new_p2c(servers: Vec<Upstream>) -> Self {
+        Self::with_strategy(servers, Strategy::PowerOfTwo)
+    }
+
+    /// Creates a new load balancer sending requests to the least loaded server,
+    /// relative to its capacity.
+    ///
+    /// Every request goes to the server with the lowest amount of requests in flight
+    /// divided by its weight, so a server of weight 2 takes twice the requests in
+    /// flight of a server of weight 1 before being deprioritized. Servers equally
+    /// loaded take turns.
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - A vector of upstream servers to balance requests across, see
+    ///   [`Upstream::with_weight`]
+    ///
+    /// # Returns
+    ///
+    /// A new `LoadBalancer` instance
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{load_balancer::LoadBalancer, upstream::Upstream};
+    ///
+    /// let load_balancer = LoadBalancer::new_weighted_least_connections(vec![
+    ///     Upstream::new("10.0.0.1:80".parse().unwrap(), false).with_weight(2),
+    ///     Upstream::new("10.0.0.2:80".parse().unwrap(), false),
+    /// ]);
+    /// ```
+    pub fn new_weighted_least_connections(servers: Vec<Upstream>) -> Self {
+        Self::with_strategy(servers, Strategy::WeightedLeastConnections)
+    }
+
+    fn with_strategy(servers: Vec<Upstream>, strategy: Strategy) -> Self {
         assert!(
             !servers.is_empty(),
             "Amount of servers should be greater than 0"
         );
+        let servers = servers.into_iter().map(Arc::new).collect();
         Self {
-            servers,
+            pool: Arc::new(ArcSwap::from_pointee(Pool::new(servers, strategy))),
             current_index: AtomicUsize::new(0),
+            local_zone: None,
+            strategy,
+        }
+    }
+
+    /// Adds an upstream server to the pool.
+    ///
+    /// Requests selecting a server after this call may be sent to the new server.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream` - The upstream server to add
+    pub fn add_upstream(&self, upstream: Upstream) {
+        info!("Adding upstream {}", upstream.address);
+        let upstream = Arc::new(upstream);
+        self.pool.rcu(|pool| {
+            let mut servers = pool.servers.clone();
+            servers.push(upstream.clone());
+            Pool::new(servers, self.strategy)
+        });
+    }
+
+    /// Removes an upstream server from the pool.
+    ///
+    /// Requests already sent to the server are completed. The last server
+    /// of the pool can't be removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Address of the upstream server to remove
+    ///
+    /// # Returns
+    ///
+    /// `true` if the server was removed, `false` if it's not in the pool or is the last one
+    pub fn remove_upstream(&self, address: SocketAddr) -> bool {
+        let mut removed = false;
+        self.pool.rcu(|pool| {
+            let servers: Vec<_> = pool
+                .servers
+                .iter()
+                .filter(|upstream| upstream.address != address)
+                .cloned()
+                .collect();
+            removed = servers.len() != pool.servers.len() && !servers.is_empty();
+            if removed {
+                Arc::new(Pool::new(servers, self.strategy))
+            } else {
+                pool.clone()
+            }
+        });
+        if removed {
+            info!("Removed upstream {}", address);
+        } else {
+            warn!("Upstream {} not removed", address);
         }
+        removed
+    }
+
+    /// Starts actively checking the health of the upstream servers.
+    ///
+    /// Only servers with a health check path are checked, see [`Upstream::with_health_check`].
+    /// Servers marked down are skipped when selecting a server. Must be called
+    /// from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Interval, timeout and thresholds of the checks
+    ///
+    /// # Returns
+    ///
+    /// The handle of the background task, abort it to stop the checks
+    pub fn spawn_health_checks(&self, config: HealthCheck) -> JoinHandle<()> {
+        info!("Starting health checks every {:?}", config.interval);
+        health::spawn(self.pool.clone(), config)
+    }
+
+    /// Sets the zone the proxy runs in.
+    ///
+    /// Servers tagged with this zone are preferred, servers in other zones
+    /// only receive requests when every local server is saturated.
+    ///
+    /// # Arguments
+    ///
+    /// * `zone` - Zone the proxy runs in, see [`Upstream::with_zone`]
+    pub fn with_local_zone(mut self, zone: impl Into<String>) -> Self {
+        self.local_zone = Some(zone.into());
+        self
     }
 
-    /// Selects the next upstream server using round-robin algorithm.
+    /// Selects the upstream server for a request.
+    ///
+    /// Servers in the local zone are tried first, if one is set. Unhealthy servers and
+    /// servers at their concurrency limit are skipped. If no server is available, the
+    /// server the strategy picked is returned anyway.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - Address of the client, used by the IP hash strategy
     ///
     /// # Returns
     ///
-    /// - `Some(Upstream)` if servers are available
-    /// - `None` if no servers are configured
-    pub fn get_upstream(&self) -> *const Upstream {
+    /// The selected `Upstream`, it stays valid even if removed from the pool meanwhile
+    pub fn get_upstream(&self, from: &SocketAddr) -> Arc<Upstream> {
+        let pool = self.pool.load();
+        let index = match self.strategy {
+            Strategy::RoundRobin => self.round_robin(&pool.servers),
+            Strategy::IpHash => self.ip_hash(&pool, from.ip()),
+            Strategy::PowerOfTwo => self.power_of_two(&pool.servers),
+            Strategy::WeightedLeastConnections => self.weighted_least_connections(&pool.servers),
+        };
+
+        pool.servers[index].clone()
+    }
+
+    fn round_robin(&self, servers: &[Arc<Upstream>]) -> usize {
+        let current = self.current_index.fetch_add(1, Ordering::Relaxed);
+        let len = servers.len();
+
+        let local = self.local_zone.as_ref().and_then(|zone| {
+            let is_local = |index: &usize| servers[*index].zone.as_ref() == Some(zone);
+            let count = (0..len).filter(is_local).count();
+            if count == 0 {
+                return None;
+            }
+            // The local servers from the `start`-th one, wrapping around
+            let start = current % count;
+            (0..len)
+                .filter(is_local)
+                .skip(start)
+                .chain((0..len).filter(is_local).take(start))
+                .find(|&index| servers[index].is_available())
+        });
+        local
+            .or_else(|| {
+                (0..len)
+                    .map(|offset| (current + offset) % len)
+                    .find(|&index| servers[index].is_available())
+            })
+            .unwrap_or(current % len)
+    }
+
+    fn power_of_two(&self, servers: &[Arc<Upstream>]) -> usize {
+        let len = servers.len();
+        let first = fastrand::usize(..len);
+        let second = if len > 1 {
+            (first + 1 + fastrand::usize(..len - 1)) % len
+        } else {
+            first
+        };
+
+        let is_remote = |index: usize| {
+            self.local_zone
+                .as_ref()
+                .is_some_and(|zone| servers[index].zone.as_ref() != Some(zone))
+        };
+        [first, second]
+            .into_iter()
+            .filter(|&index| servers[index].is_available())
+            .min_by_key(|&index| (is_remote(index), servers[index].in_flight()))
+            // Both picks are unavailable, look for any server that is
+            .unwrap_or_else(|| self.round_robin(servers))
+    }
+
+    fn weighted_least_connections(&self, servers: &[Arc<Upstream>]) -> usize {
         let current = self.current_index.fetch_add(1, Ordering::Relaxed);
-        let index = current % self.servers.len();
+        let len = servers.len();
+
+        let is_remote = |index: usize| {
+            self.local_zone
+                .as_ref()
+                .is_some_and(|zone| servers[index].zone.as_ref() != Some(zone))
+        };
+        // Compares in_flight / weight without dividing
+        let load = |index: usize| {
+            (
+                servers[index].in_flight() as u64,
+                servers[index].weight as u64,
+            )
+        };
+        // Starting the scan at the round-robin position spreads ties across servers
+        (0..len)
+            .map(|offset| (current + offset) % len)
+            .filter(|&index| servers[index].is_available())
+            .min_by(|&a, &b| {
+                let ((a_in_flight, a_weight), (b_in_flight, b_weight)) = (load(a), load(b));
+                is_remote(a)
+                    .cmp(&is_remote(b))
+                    .then((a_in_flight * b_weight).cmp(&(b_in_flight * a_weight)))
+            })
+            .unwrap_or(current % len)
+    }
+
+    fn ip_hash(&self, pool: &Pool, ip: IpAddr) -> usize {
+        let (servers, ring) = (&pool.servers, &pool.ring);
+        let key = match ip {
+            IpAddr::V4(ip) => mix(fnv1a(&ip.octets())),
+            IpAddr::V6(ip) => mix(fnv1a(&ip.octets())),
+        };
+        let start = ring.partition_point(|&(point, _)| point < key);
+        let walk = (0..ring.len()).map(|offset| ring[(start + offset) % ring.len()].1);
+
+        let is_local = |index: usize| {
+            self.local_zone
+                .as_ref()
+                .is_some_and(|zone| servers[index].zone.as_ref() == Some(zone))
+        };
+        walk.clone()
+            .find(|&index| is_local(index) && servers[index].is_available())
+            .or_else(|| walk.clone().find(|&index| servers[index].is_available()))
+            .unwrap_or(ring[start % ring.len()].1)
+    }
+
+    /// Finds an upstream server by its identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier returned by [`Upstream::id`]
+    ///
+    /// # Returns
+    ///
+    /// - `Some(Upstream)` if a server with this identifier is configured
+    /// - `None` otherwise
+    pub fn find_upstream(&self, id: u64) -> Option<Arc<Upstream>> {
+        self.pool
+            .load()
+            .servers
+            .iter()
+            .find(|upstream| upstream.id() == id)
+            .cloned()
+    }
+
+    /// Takes a snapshot of the runtime state of every upstream server.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `UpstreamStatus`, in the order the servers were configured
+    pub fn upstreams(&self) -> Vec<UpstreamStatus> {
+        self.pool
+            .load()
+            .servers
+            .iter()
+            .map(|upstream| upstream.status())
+            .collect()
+    }
+}
+
+/// Places every server on the hash ring, at points derived from its address.
+fn build_ring(servers: &[Arc<Upstream>]) -> Vec<(u64, usize)> {
+    let mut ring: Vec<(u64, usize)> = servers
+        .iter()
+        .enumerate()
+        .flat_map(|(index, upstream)| {
+            (0..RING_POINTS_PER_SERVER).map(move |point| {
+                let point = format!("{}#{}", upstream.authority(), point);
+                (mix(fnv1a(point.as_bytes())), index)
+            })
+        })
+        .collect();
+    ring.sort_unstable();
+    ring
+}
+
+/// Spreads the bits of a hash, so similar inputs land far apart on the ring.
+pub(crate) fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::{Service, ServiceBundle},
+        test_support::{echo_upstream, get, proxy, refused_address, send, upstream},
+    };
+    use hyper::Response;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    /// Sends requests to `load_balancer`, every fourth one never completing,
+    /// and returns the ratio of the highest to the mean amount of requests in flight.
+    fn skewed_load(load_balancer: &LoadBalancer) -> f64 {
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let mut long_requests = Vec::new();
+        for request in 0..400 {
+            let upstream = load_balancer.get_upstream(&client);
+            let guard = upstream.stats.try_begin_request().unwrap();
+            if request % 4 == 0 {
+                long_requests.push(guard);
+            }
+        }
+
+        let in_flight: Vec<usize> = load_balancer
+            .upstreams()
+            .iter()
+            .map(|status| status.in_flight)
+            .collect();
+        let mean = in_flight.iter().sum::<usize>() as f64 / in_flight.len() as f64;
+        *in_flight.iter().max().unwrap() as f64 / mean
+    }
+
+    fn servers() -> Vec<Upstream> {
+        (1..=4)
+            .map(|port| Upstream::new(SocketAddr::from(([10, 0, 0, 1], port)), false))
+            .collect()
+    }
+
+    #[test]
+    fn power_of_two_choices_balances_skewed_load() {
+        let round_robin = skewed_load(&LoadBalancer::new(servers()));
+        let p2c = skewed_load(&LoadBalancer::new_p2c(servers()));
+
+        // Round-robin sends every long request to the same server
+        assert!(round_robin > 3.0, "round-robin ratio {round_robin}");
+        assert!(p2c < 1.5, "power-of-two-choices ratio {p2c}");
+    }
+
+    #[test]
+    fn weighted_least_connections_follows_the_weights() {
+        let load_balancer = LoadBalancer::new_weighted_least_connections(
+            servers()
+                .into_iter()
+                .zip([1, 2, 3, 0])
+                .map(|(server, weight)| server.with_weight(weight))
+                .collect(),
+        );
+        let client = "127.0.0.1:50000".parse().unwrap();
+
+        // Requests never completing, sent from concurrent threads
+        let guards = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..175)
+                            .map(|_| {
+                                let upstream = load_balancer.get_upstream(&client);
+                                upstream.stats.try_begin_request().unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // A weight of 0 counts as 1, so 700 requests split 1:2:3:1
+        let upstreams = load_balancer.upstreams();
+        for (status, expected) in upstreams.iter().zip([100, 200, 300, 100]) {
+            assert!(
+                status.in_flight.abs_diff(expected) <= 8,
+                "{} requests in flight to weight {}, expected {expected}",
+                status.in_flight,
+                status.weight
+            );
+        }
+        assert_eq!(guards.len(), 700);
+    }
+
+    #[test]
+    fn round_robin_prefers_the_local_zone() {
+        let load_balancer = LoadBalancer::new(
+            servers()
+                .into_iter()
+                .zip(["a", "b", "a", "b"])
+                .map(|(server, zone)| server.with_zone(zone).with_max_concurrent(1))
+                .collect(),
+        )
+        .with_local_zone("a");
+        let client = "127.0.0.1:50000".parse().unwrap();
+        let port = || load_balancer.get_upstream(&client).address.port();
+
+        let ports: Vec<u16> = (0..4).map(|_| port()).collect();
+        assert_eq!(ports, [1, 3, 1, 3]);
+
+        // Saturated local servers are skipped, then remote ones take over
+        let first = load_balancer.get_upstream(&client);
+        assert_eq!(first.address.port(), 1);
+        let _first = first.stats.try_begin_request().unwrap();
+        assert_eq!(port(), 3);
+        assert_eq!(port(), 3);
+        let third = load_balancer.get_upstream(&client);
+        assert_eq!(third.address.port(), 3);
+        let _third = third.stats.try_begin_request().unwrap();
+        assert!([2, 4].contains(&port()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn servers_are_added_and_removed_while_requests_are_routed() {
+        let load_balancer = Arc::new(LoadBalancer::new(servers()));
+        let addresses: Vec<SocketAddr> = servers().iter().map(|server| server.address).collect();
+        let removed = addresses[3];
+        let client = "127.0.0.1:50000".parse().unwrap();
+
+        // Bursts of requests, each using the server it got after the pool changed
+        let requests: Vec<_> = (0..4)
+            .map(|_| {
+                let load_balancer = load_balancer.clone();
+                let addresses = addresses.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10_000 {
+                        let upstream = load_balancer.get_upstream(&client);
+                        let guard = upstream.stats.try_begin_request().unwrap();
+                        tokio::task::yield_now().await;
+                        assert!(addresses.contains(&upstream.address));
+                        assert!(upstream.status().in_flight > 0);
+                        drop(guard);
+                    }
+                })
+            })
+            .collect();
+        let churn = tokio::spawn({
+            let load_balancer = load_balancer.clone();
+            async move {
+                for _ in 0..1_000 {
+                    assert!(load_balancer.remove_upstream(removed));
+                    tokio::task::yield_now().await;
+                    load_balancer.add_upstream(Upstream::new(removed, false));
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        for request in requests {
+            request.await.unwrap();
+        }
+        churn.await.unwrap();
+
+        let upstreams = load_balancer.upstreams();
+        assert_eq!(upstreams.len(), 4);
+        assert!(upstreams.iter().all(|status| status.in_flight == 0));
+    }
+
+    #[tokio::test]
+    async fn failing_upstreams_are_ejected_until_their_cooldown_ends() {
+        let cooldown = Duration::from_millis(300);
+        let load_balancer = Arc::new(LoadBalancer::new(vec![
+            Upstream::new(refused_address(), false).with_passive_health_check(3, cooldown),
+            Upstream::new(echo_upstream().await, false),
+        ]));
+        let refused = || load_balancer.upstreams()[0].clone();
+        let service = Service::builder()
+            .load_balancer(load_balancer.clone())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let ok = async || get(address, "/").await.starts_with("HTTP/1.1 200 OK");
+
+        // Requests alternate between the servers until the third failure
+        for _ in 0..3 {
+            assert!(!ok().await);
+            assert!(ok().await);
+        }
+        assert!(!refused().healthy);
+        // The failing server is skipped while ejected
+        for _ in 0..10 {
+            assert!(ok().await);
+        }
+        assert_eq!(refused().total_requests, 3);
+        assert_eq!(refused().total_failures, 3);
+
+        // It's handed out again after the cooldown, a single failure ejecting it again
+        tokio::time::sleep(cooldown).await;
+        assert!(refused().healthy);
+        let (first, second) = (ok().await, ok().await);
+        assert!(first != second);
+        assert_eq!(refused().total_requests, 4);
+        assert!(!refused().healthy);
+        for _ in 0..10 {
+            assert!(ok().await);
+        }
+        assert_eq!(refused().total_requests, 4);
+    }
+
+    #[tokio::test]
+    async fn responses_of_the_middleware_dont_restore_ejected_upstreams() {
+        use crate::auth::BasicAuth;
+
+        let load_balancer = Arc::new(LoadBalancer::new(vec![
+            Upstream::new(refused_address(), false)
+                .with_passive_health_check(1, Duration::from_secs(60)),
+        ]));
+        let auth = BasicAuth::new("ejection", vec![("user".to_string(), "secret".to_string())]);
+        let service = Service::builder()
+            .load_balancer(load_balancer.clone())
+            .middleware(auth.into_middleware())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let healthy = || load_balancer.upstreams()[0].healthy;
+
+        // `user:secret`, forwarded to the refused server which gets ejected
+        let response = send(
+            address,
+            "GET / HTTP/1.1\r\nhost: localhost\r\nauthorization: Basic dXNlcjpzZWNyZXQ=\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(!healthy());
+
+        // The only server is still handed out, but the middleware answers without it
+        let response = get(address, "/").await;
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        assert!(!healthy());
+    }
+
+    #[tokio::test]
+    async fn saturated_upstreams_shed_requests() {
+        let release = Arc::new(Semaphore::new(0));
+        let slow = upstream({
+            let release = release.clone();
+            move |_| {
+                let release = release.clone();
+                async move {
+                    release.acquire().await.unwrap().forget();
+                    Response::new("ok".to_string())
+                }
+            }
+        })
+        .await;
+        let load_balancer = Arc::new(LoadBalancer::new(vec![
+            Upstream::new(slow, false).with_max_concurrent(2),
+        ]));
+        let in_flight = || load_balancer.upstreams()[0].in_flight;
+        let service = Service::builder()
+            .load_balancer(load_balancer.clone())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        let first = tokio::spawn(get(address, "/"));
+        let second = tokio::spawn(get(address, "/"));
+        for _ in 0..100 {
+            if in_flight() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(in_flight(), 2);
+
+        // The third concurrent request is shed while the others wait for the upstream
+        assert!(get(address, "/").await.starts_with("HTTP/1.1 503"));
+        release.add_permits(2);
+        assert!(first.await.unwrap().ends_with("ok"));
+        assert!(second.await.unwrap().ends_with("ok"));
 
-        (unsafe { self.servers.get_unchecked(index) }) as *const _
+        // Permits are given back once requests complete
+        assert_eq!(in_flight(), 0);
+        release.add_permits(1);
+        assert!(get(address, "/").await.ends_with("ok"));
     }
 }
@@ -0,0 +1,167 @@
+//! Method overriding for clients limited to `GET` and `POST`.
+//!
+//! [`MethodOverride`] reads the method a request stands for from a header, by default
+//! `X-HTTP-Method-Override`, and forwards the request with that method instead.
+//! Overrides to methods that aren't allowed are answered with `400 Bad Request`.
+//! It's installed as a middleware, see [`MethodOverride::into_middleware`].
+
+use std::{collections::HashSet, sync::Arc};
+
+use http::{HeaderName, Method, StatusCode, request};
+use tracing::debug;
+
+use crate::{
+    middleware::{Middleware, MiddlewareAction, MiddlewareIncomingFunction},
+    service::empty_response,
+};
+
+/// Header carrying the overriding method by default.
+pub const DEFAULT_METHOD_OVERRIDE_HEADER: &str = "x-http-method-override";
+
+/// Method override policy of a service.
+///
+/// The header is removed once applied, so the upstream only sees the overriding method.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{
+///     hyper::{Method, Request, StatusCode},
+///     method_override::MethodOverride,
+///     middleware::MiddlewareAction,
+/// };
+///
+/// let middleware = MethodOverride::new([Method::PUT, Method::DELETE]).into_middleware();
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "127.0.0.1:8080".parse().unwrap();
+///
+/// let (mut parts, _) = Request::post("/users/1")
+///     .header("x-http-method-override", "delete")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// let action = middleware.process_incoming(&from, &upstream, &mut parts, None).unwrap();
+/// assert!(matches!(action, MiddlewareAction::Continue));
+/// assert_eq!(parts.method, Method::DELETE);
+/// assert!(parts.headers.get("x-http-method-override").is_none());
+///
+/// let (mut parts, _) = Request::post("/users/1")
+///     .header("x-http-method-override", "PATCH")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// let MiddlewareAction::Respond(response) =
+///     middleware.process_incoming(&from, &upstream, &mut parts, None).unwrap()
+/// else {
+///     panic!("disallowed override accepted");
+/// };
+/// assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MethodOverride {
+    /// Header carrying the overriding method
+    header: HeaderName,
+    /// Methods requests may be overridden to
+    allowed: HashSet<Method>,
+}
+
+impl MethodOverride {
+    /// Creates a policy reading the method from `X-HTTP-Method-Override`.
+    ///
+    /// # Arguments
+    ///
+    /// * `allowed` - Methods requests may be overridden to
+    ///
+    /// # Returns
+    ///
+    /// A new `MethodOverride` instance
+    pub fn new(allowed: impl IntoIterator<Item = Method>) -> Self {
+        Self {
+            header: HeaderName::from_static(DEFAULT_METHOD_OVERRIDE_HEADER),
+            allowed: allowed.into_iter().collect(),
+        }
+    }
+
+    /// Reads the overriding method from another header, e.g. `x-method-override`.
+    pub fn with_header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Builds the middleware applying the policy.
+    ///
+    /// To combine it with other middleware, pass [`MiddlewareIncomingFunction::MethodOverride`]
+    /// to [`Middleware::new`].
+    pub fn into_middleware(self) -> Middleware {
+        Middleware::new(
+            vec![MiddlewareIncomingFunction::MethodOverride(Arc::new(self))],
+            vec![],
+        )
+    }
+
+    /// Replaces the method of requests carrying an allowed override, answers the others.
+    pub(crate) fn process_request(&self, parts: &mut request::Parts) -> MiddlewareAction {
+        let Some(value) = parts.headers.remove(&self.header) else {
+            return MiddlewareAction::Continue;
+        };
+        let method = value
+            .to_str()
+            .ok()
+            .and_then(|value| Method::from_bytes(value.trim().to_ascii_uppercase().as_bytes()).ok())
+            .filter(|method| self.allowed.contains(method));
+        match method {
+            Some(method) => {
+                debug!("Overriding method {} with {}", parts.method, method);
+                parts.method = method;
+                MiddlewareAction::Continue
+            }
+            None => {
+                debug!("Rejecting method override {:?}", value);
+                MiddlewareAction::Respond(empty_response(StatusCode::BAD_REQUEST))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::ServiceBundle,
+        test_support::{proxy, send, service_to, upstream},
+    };
+    use hyper::{Request, Response, body::Incoming};
+
+    #[tokio::test]
+    async fn overridden_methods_are_forwarded() {
+        // An upstream answering with the method and override header it received
+        let upstream = upstream(|request: Request<Incoming>| async move {
+            let header = request.headers().get(DEFAULT_METHOD_OVERRIDE_HEADER);
+            Response::new(format!("{} {:?}", request.method(), header))
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .middleware(MethodOverride::new([Method::PUT, Method::DELETE]).into_middleware())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let post = async |header: &str| {
+            let request = format!(
+                "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 0\r\n{header}connection: close\r\n\r\n"
+            );
+            send(address, request).await
+        };
+
+        let response = post("x-http-method-override: PUT\r\n").await;
+        assert!(response.ends_with("PUT None"), "{response}");
+
+        let response = post("x-http-method-override: CONNECT\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{response}"
+        );
+
+        let response = post("").await;
+        assert!(response.ends_with("POST None"), "{response}");
+    }
+}
@@ -0,0 +1,171 @@
+//! Hook observing every completed request.
+//!
+//! Broxy doesn't ship a metrics backend. A [`MetricsHook`] installed with
+//! [`crate::service::ServiceBundle::with_metrics_hook`] is handed a [`RequestSummary`]
+//! at the end of every request, to feed statsd, OpenTelemetry or in-process counters.
+//! The summary is built once the response body was sent to the client, so the byte
+//! count and duration are the final ones.
+
+use std::{
+    panic::{AssertUnwindSafe, catch_unwind},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use http::{Method, StatusCode};
+use http_body_util::{BodyExt as _, combinators::BoxBody};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::{error::BroxyError, service::ProxyResponse};
+
+/// Summary of a completed request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSummary {
+    /// Method of the request
+    pub method: Method,
+    /// Path of the request, without the query
+    pub path: String,
+    /// Index of the service that matched the request in its bundle, `None` if the
+    /// request was answered without one, e.g. when no service matched
+    pub service: Option<usize>,
+    /// Address of the upstream the request was forwarded to, if any
+    pub upstream: Option<String>,
+    /// Status of the response, `None` if the request failed without one
+    pub status: Option<StatusCode>,
+    /// Size of the request body as announced by the client, `None` if it wasn't, e.g.
+    /// for chunked bodies
+    pub request_bytes: Option<u64>,
+    /// Amount of response body bytes sent to the client
+    pub response_bytes: u64,
+    /// Time between the request headers being received and the end of the response
+    pub duration: Duration,
+}
+
+/// Callback invoked with the summary of every completed request.
+///
+/// Implemented by closures taking a `&RequestSummary`. Hooks are called on the
+/// request path, so they should be quick: hand the summary to a channel or update
+/// atomic counters rather than doing I/O. A panicking hook is logged and ignored.
+pub trait MetricsHook: Send + Sync {
+    /// Observes a completed request.
+    ///
+    /// # Arguments
+    ///
+    /// * `summary` - Summary of the request
+    fn on_request(&self, summary: &RequestSummary);
+}
+
+impl std::fmt::Debug for dyn MetricsHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MetricsHook")
+    }
+}
+
+impl<F> MetricsHook for F
+where
+    F: Fn(&RequestSummary) + Send + Sync,
+{
+    fn on_request(&self, summary: &RequestSummary) {
+        self(summary)
+    }
+}
+
+/// Summary of a request being processed, reported once it completes.
+pub(crate) struct PendingSummary {
+    pub(crate) hook: Arc<dyn MetricsHook>,
+    pub(crate) started: Instant,
+    pub(crate) summary: RequestSummary,
+}
+
+impl PendingSummary {
+    /// Completes the summary with the result of the request.
+    ///
+    /// A failed request is reported right away. A response is reported once its body
+    /// was sent to the end, or dropped.
+    pub(crate) fn finish(
+        mut self,
+        result: Result<ProxyResponse, BroxyError>,
+    ) -> Result<ProxyResponse, BroxyError> {
+        match result {
+            Ok(response) => {
+                self.summary.status = Some(response.status());
+                Ok(response.map(|body| {
+                    ObservedBody {
+                        inner: body,
+                        pending: Some(self),
+                    }
+                    .boxed()
+                }))
+            }
+            Err(e) => {
+                self.report();
+                Err(e)
+            }
+        }
+    }
+
+    /// Hands the summary to the hook, shielding the request path from its panics.
+    fn report(mut self) {
+        self.summary.duration = self.started.elapsed();
+        let hook = &self.hook;
+        let summary = &self.summary;
+        if catch_unwind(AssertUnwindSafe(|| hook.on_request(summary))).is_err() {
+            error!("Metrics hook panicked, ignoring it");
+        }
+    }
+}
+
+/// Response body counting the bytes sent, reporting the request once it ends.
+struct ObservedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    /// Taken once the summary is reported
+    pending: Option<PendingSummary>,
+}
+
+impl ObservedBody {
+    /// Reports the summary, unless it already was.
+    fn report(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.report();
+        }
+    }
+}
+
+impl Body for ObservedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(pending)) = (frame.data_ref(), &mut self.pending) {
+                    pending.summary.response_bytes += data.len() as u64;
+                }
+            }
+            Some(Err(_)) | None => self.report(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
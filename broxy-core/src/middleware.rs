@@ -1,6 +1,244 @@
-use std::net::SocketAddr;
+use std::{ffi::c_void, net::SocketAddr, sync::Arc};
 
-use http::{request, response};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, request, response};
+
+use crate::{
+    auth::BasicAuth, compression, cors::Cors, error::BroxyError, method_override::MethodOverride,
+    response_headers::ResponseHeaderFilter, service::ProxyResponse,
+};
+
+/// Name of the function called by incoming middleware loaded with [`ExternalMiddleware::load`].
+pub const INCOMING_SYMBOL: &[u8] = b"broxy_middleware_incoming";
+/// Name of the function called by outgoing middleware loaded with [`ExternalMiddleware::load`].
+pub const OUTGOING_SYMBOL: &[u8] = b"broxy_middleware_outgoing";
+
+/// Maximum amount of headers in a message written back by an external middleware.
+const MAX_HEADERS: usize = 256;
+
+/// Callback through which an external middleware replaces the message.
+///
+/// Receives the context passed to the middleware, a pointer to the new message and
+/// its length in bytes. The message is copied, the middleware keeps ownership of it.
+pub type MiddlewareWrite = unsafe extern "C" fn(*mut c_void, *const u8, u64);
+
+/// Type alias for external C middleware functions.
+///
+/// The function receives the request or response serialized as an HTTP/1.1 message
+/// (start line, headers, empty line, body), its length in bytes, a context and the
+/// [`MiddlewareWrite`] callback. To modify the message it calls the callback with the
+/// context and the complete new message, at most once. It returns `0` on success,
+/// anything else fails the request.
+///
+/// The message pointer and the context are only valid for the duration of the call.
+/// The function is called concurrently from several threads, so it must be thread-safe,
+/// and it must not unwind across the FFI boundary.
+pub type MiddlewareMessage =
+    unsafe extern "C" fn(*const u8, u64, *mut c_void, MiddlewareWrite) -> i32;
+
+/// Middleware function loaded from a dynamic library, see [`MiddlewareMessage`] for its contract.
+///
+/// The library stays loaded as long as any clone of the middleware exists. External
+/// middleware always has access to the body, so it forces the body to be buffered.
+#[derive(Debug, Clone)]
+pub struct ExternalMiddleware {
+    /// Keeps the library loaded while `function` can be called
+    _library: Arc<libloading::Library>,
+    function: MiddlewareMessage,
+}
+
+impl ExternalMiddleware {
+    /// Loads a middleware function from a dynamic library.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the dynamic library, e.g. `libmiddleware.so`
+    /// * `symbol` - Name of the exported function, usually [`INCOMING_SYMBOL`] or [`OUTGOING_SYMBOL`]
+    ///
+    /// # Returns
+    ///
+    /// Returns the loaded middleware, or `BroxyError::Library` if the library or the
+    /// symbol can't be loaded.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization routines, and the symbol must be
+    /// a function following the [`MiddlewareMessage`] contract.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use broxy_core::middleware::{
+    ///     ExternalMiddleware, INCOMING_SYMBOL, Middleware, MiddlewareIncomingFunction,
+    /// };
+    ///
+    /// let incoming = unsafe { ExternalMiddleware::load("./libplugin.so", INCOMING_SYMBOL) }.unwrap();
+    /// let middleware = Middleware::new(vec![MiddlewareIncomingFunction::External(incoming)], vec![]);
+    /// ```
+    pub unsafe fn load(
+        path: impl AsRef<std::ffi::OsStr>,
+        symbol: &[u8],
+    ) -> Result<Self, BroxyError> {
+        let library = unsafe { libloading::Library::new(path) }?;
+        let function = *unsafe { library.get::<MiddlewareMessage>(symbol) }?;
+        Ok(Self {
+            _library: Arc::new(library),
+            function,
+        })
+    }
+
+    /// Calls the loaded function with a serialized message.
+    ///
+    /// # Returns
+    ///
+    /// Returns the message written back by the middleware, `None` if it kept the message.
+    fn call(&self, message: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut written: Option<Vec<u8>> = None;
+        // SAFETY: the library is still loaded, the caller of `load` guaranteed that the
+        // function follows the `MiddlewareMessage` contract and `written` outlives the call
+        let code = unsafe {
+            (self.function)(
+                message.as_ptr(),
+                message.len() as u64,
+                &mut written as *mut Option<Vec<u8>> as *mut c_void,
+                write_message,
+            )
+        };
+        if code != 0 {
+            return Err(anyhow::anyhow!(
+                "External middleware failed with code {}",
+                code
+            ));
+        }
+        Ok(written)
+    }
+
+    /// Passes a request through the loaded function, applying the changes it made.
+    fn process_request(
+        &self,
+        parts: &mut request::Parts,
+        body: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut message = format!("{} {} HTTP/1.1\r\n", parts.method, parts.uri).into_bytes();
+        serialize_message(&mut message, &parts.headers, body);
+        let Some(written) = self.call(&message)? else {
+            return Ok(());
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let httparse::Status::Complete(offset) = request.parse(&written)? else {
+            return Err(anyhow::anyhow!(
+                "External middleware wrote an incomplete request"
+            ));
+        };
+        parts.method = Method::from_bytes(request.method.unwrap_or_default().as_bytes())?;
+        parts.uri = request.path.unwrap_or_default().parse()?;
+        parts.headers = parse_headers(request.headers)?;
+        *body = written[offset..].to_vec();
+        Ok(())
+    }
+
+    /// Passes a response through the loaded function, applying the changes it made.
+    fn process_response(
+        &self,
+        parts: &mut response::Parts,
+        body: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let mut message = format!(
+            "HTTP/1.1 {} {}\r\n",
+            parts.status.as_u16(),
+            parts.status.canonical_reason().unwrap_or_default()
+        )
+        .into_bytes();
+        serialize_message(&mut message, &parts.headers, body);
+        let Some(written) = self.call(&message)? else {
+            return Ok(());
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(offset) = response.parse(&written)? else {
+            return Err(anyhow::anyhow!(
+                "External middleware wrote an incomplete response"
+            ));
+        };
+        parts.status = StatusCode::from_u16(response.code.unwrap_or_default())?;
+        parts.headers = parse_headers(response.headers)?;
+        *body = written[offset..].to_vec();
+        Ok(())
+    }
+}
+
+/// Copies the message written by an external middleware, see [`MiddlewareWrite`].
+unsafe extern "C" fn write_message(context: *mut c_void, message: *const u8, len: u64) {
+    // SAFETY: the context is the `Option<Vec<u8>>` passed by `ExternalMiddleware::call`,
+    // and the middleware guarantees the message is `len` bytes long
+    unsafe {
+        let written = &mut *(context as *mut Option<Vec<u8>>);
+        *written = Some(std::slice::from_raw_parts(message, len as usize).to_vec());
+    }
+}
+
+/// Appends the headers and the body of a message after its start line.
+fn serialize_message(message: &mut Vec<u8>, headers: &HeaderMap, body: &[u8]) {
+    for (name, value) in headers {
+        message.extend_from_slice(name.as_str().as_bytes());
+        message.extend_from_slice(b": ");
+        message.extend_from_slice(value.as_bytes());
+        message.extend_from_slice(b"\r\n");
+    }
+    message.extend_from_slice(b"\r\n");
+    message.extend_from_slice(body);
+}
+
+/// Converts the headers parsed from a message written by an external middleware.
+fn parse_headers(headers: &[httparse::Header]) -> anyhow::Result<HeaderMap> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for header in headers {
+        map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    Ok(map)
+}
+
+/// Method and headers of the request a response answers.
+///
+/// Added to the extensions of the response before the outgoing middleware runs,
+/// so it can depend on the request, e.g. on its `Origin` or `Accept-Encoding`.
+#[derive(Debug, Clone)]
+pub struct OriginalRequest {
+    /// Method of the request
+    pub method: Method,
+    /// Headers of the request, after the incoming middleware ran
+    pub headers: HeaderMap,
+}
+
+impl OriginalRequest {
+    /// Copies the method and headers of a request.
+    pub fn new(parts: &request::Parts) -> Self {
+        Self {
+            method: parts.method.clone(),
+            headers: parts.headers.clone(),
+        }
+    }
+}
+
+/// What to do with a request after an incoming middleware processed it.
+#[derive(Debug)]
+pub enum MiddlewareAction {
+    /// Pass the request to the next middleware, then to the upstream
+    Continue,
+    /// Stop processing and send this response, without contacting the upstream
+    Respond(ProxyResponse),
+}
+
+impl From<()> for MiddlewareAction {
+    fn from((): ()) -> Self {
+        MiddlewareAction::Continue
+    }
+}
 
 /// Incoming request middleware function types.
 ///
@@ -8,12 +246,100 @@ use http::{request, response};
 /// and can modify request headers and bodies.
 #[derive(Debug, Clone)]
 pub enum MiddlewareIncomingFunction {
-    /// External middleware (not yet implemented)
-    External,
+    /// Middleware loaded from a dynamic library, processes both headers and body
+    External(ExternalMiddleware),
     /// Internal middleware that processes both headers and body
     InternalWithBody(fn(&SocketAddr, &mut request::Parts, &mut Vec<u8>) -> anyhow::Result<()>),
     /// Internal middleware that processes only headers
     Internal(fn(&SocketAddr, &mut request::Parts) -> anyhow::Result<()>),
+    /// Internal middleware that processes both headers and body, and may answer
+    /// the request itself
+    InternalWithBodyAction(
+        fn(&SocketAddr, &mut request::Parts, &mut Vec<u8>) -> anyhow::Result<MiddlewareAction>,
+    ),
+    /// Internal middleware that processes only headers, and may answer the request itself,
+    /// e.g. with `401 Unauthorized`
+    InternalAction(fn(&SocketAddr, &mut request::Parts) -> anyhow::Result<MiddlewareAction>),
+    /// Internal middleware that processes only headers, knowing the address of the
+    /// upstream the request is forwarded to, e.g. to set the credentials of a backend
+    ///
+    /// ```
+    /// use std::net::SocketAddr;
+    /// use broxy_core::middleware::{Middleware, MiddlewareIncomingFunction};
+    /// use http::request;
+    ///
+    /// // Every backend has its own token
+    /// fn authorize(_: &SocketAddr, upstream: &SocketAddr, parts: &mut request::Parts) -> anyhow::Result<()> {
+    ///     let token = format!("Bearer token-{}", upstream.port());
+    ///     parts.headers.insert("authorization", token.parse()?);
+    ///     Ok(())
+    /// }
+    ///
+    /// let middleware = Middleware::new(vec![MiddlewareIncomingFunction::InternalWithUpstream(authorize)], vec![]);
+    /// ```
+    InternalWithUpstream(fn(&SocketAddr, &SocketAddr, &mut request::Parts) -> anyhow::Result<()>),
+    /// Answers CORS preflight requests, see [`Cors`]
+    Cors(Arc<Cors>),
+    /// Answers requests without valid credentials with `401 Unauthorized`, see [`BasicAuth`]
+    BasicAuth(Arc<BasicAuth>),
+    /// Replaces the method of requests carrying an override header, answers disallowed
+    /// overrides with `400 Bad Request`, see [`MethodOverride`]
+    MethodOverride(Arc<MethodOverride>),
+    /// Inflates `gzip` and `deflate` encoded request bodies before they're forwarded,
+    /// bodies inflating to more than `max_size` bytes are rejected with `413 Payload Too Large`
+    ///
+    /// ```
+    /// use std::io::Write as _;
+    /// use broxy_core::{
+    ///     hyper::{Request, StatusCode},
+    ///     middleware::{Middleware, MiddlewareAction, MiddlewareIncomingFunction},
+    /// };
+    ///
+    /// let middleware = Middleware::new(
+    ///     vec![MiddlewareIncomingFunction::Decompress { max_size: 1024 * 1024 }],
+    ///     vec![],
+    /// );
+    /// let from = "127.0.0.1:50000".parse().unwrap();
+    /// let upstream = "127.0.0.1:8080".parse().unwrap();
+    /// let gzip = |data: &[u8]| {
+    ///     let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    ///     encoder.write_all(data).unwrap();
+    ///     encoder.finish().unwrap()
+    /// };
+    /// let request = |encoding: &str, body: &[u8]| {
+    ///     let (parts, _) = Request::post("/")
+    ///         .header("content-encoding", encoding)
+    ///         .header("content-length", body.len())
+    ///         .body(())
+    ///         .unwrap()
+    ///         .into_parts();
+    ///     (parts, body.to_vec())
+    /// };
+    ///
+    /// let (mut parts, mut body) = request("gzip", &gzip(b"hello"));
+    /// let action = middleware.process_incoming(&from, &upstream, &mut parts, Some(&mut body)).unwrap();
+    /// assert!(matches!(action, MiddlewareAction::Continue));
+    /// assert_eq!(body, b"hello");
+    /// assert!(parts.headers.get("content-encoding").is_none());
+    /// assert_eq!(parts.headers["content-length"], "5");
+    ///
+    /// let (mut parts, mut body) = request("br", b"not inflated");
+    /// middleware.process_incoming(&from, &upstream, &mut parts, Some(&mut body)).unwrap();
+    /// assert_eq!(body, b"not inflated");
+    /// assert_eq!(parts.headers["content-encoding"], "br");
+    ///
+    /// let (mut parts, mut body) = request("gzip", &gzip(&vec![0; 16 * 1024 * 1024]));
+    /// let MiddlewareAction::Respond(response) =
+    ///     middleware.process_incoming(&from, &upstream, &mut parts, Some(&mut body)).unwrap()
+    /// else {
+    ///     panic!("bomb not rejected");
+    /// };
+    /// assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    /// ```
+    Decompress {
+        /// Maximum size of an inflated body, in bytes
+        max_size: u64,
+    },
 }
 
 impl MiddlewareIncomingFunction {
@@ -21,29 +347,62 @@ impl MiddlewareIncomingFunction {
     ///
     /// # Arguments
     ///
+    /// * `from` - Address of the client
+    /// * `upstream_addr` - Address of the upstream selected for the request
     /// * `parts` - The HTTP request header parts to modify
     /// * `body` - Optional mutable reference to the request body
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` on success or an error if processing fails.
+    /// Returns the `MiddlewareAction` on success or an error if processing fails.
+    /// Middleware that can't answer requests always returns `MiddlewareAction::Continue`.
     #[inline]
     pub fn process(
         &self,
         from: &SocketAddr,
+        upstream_addr: &SocketAddr,
         parts: &mut request::Parts,
         body: &mut Option<&mut Vec<u8>>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<MiddlewareAction> {
         match self {
-            MiddlewareIncomingFunction::External => todo!(),
+            MiddlewareIncomingFunction::External(external) => {
+                if let Some(body) = body {
+                    external.process_request(parts, body).map(Into::into)
+                } else {
+                    Err(anyhow::anyhow!("No body provided"))
+                }
+            }
             MiddlewareIncomingFunction::InternalWithBody(func) => {
+                if let Some(body) = body {
+                    func(from, parts, body).map(Into::into)
+                } else {
+                    Err(anyhow::anyhow!("No body provided"))
+                }
+            }
+            MiddlewareIncomingFunction::Internal(func) => func(from, parts).map(Into::into),
+            MiddlewareIncomingFunction::InternalWithBodyAction(func) => {
                 if let Some(body) = body {
                     func(from, parts, body)
                 } else {
                     Err(anyhow::anyhow!("No body provided"))
                 }
             }
-            MiddlewareIncomingFunction::Internal(func) => func(from, parts),
+            MiddlewareIncomingFunction::InternalAction(func) => func(from, parts),
+            MiddlewareIncomingFunction::InternalWithUpstream(func) => {
+                func(from, upstream_addr, parts).map(Into::into)
+            }
+            MiddlewareIncomingFunction::Cors(cors) => Ok(cors.process_request(parts)),
+            MiddlewareIncomingFunction::BasicAuth(auth) => Ok(auth.process_request(parts)),
+            MiddlewareIncomingFunction::MethodOverride(method_override) => {
+                Ok(method_override.process_request(parts))
+            }
+            MiddlewareIncomingFunction::Decompress { max_size } => {
+                if let Some(body) = body {
+                    compression::decompress_request(parts, body, *max_size)
+                } else {
+                    Err(anyhow::anyhow!("No body provided"))
+                }
+            }
         }
     }
 
@@ -53,10 +412,13 @@ impl MiddlewareIncomingFunction {
     ///
     /// Returns `true` if the middleware needs the body, `false` otherwise.
     pub fn needs_body(&self) -> bool {
-        match self {
-            MiddlewareIncomingFunction::InternalWithBody(_) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            MiddlewareIncomingFunction::InternalWithBody(_)
+                | MiddlewareIncomingFunction::InternalWithBodyAction(_)
+                | MiddlewareIncomingFunction::External(_)
+                | MiddlewareIncomingFunction::Decompress { .. }
+        )
     }
 }
 
@@ -66,14 +428,18 @@ impl MiddlewareIncomingFunction {
 /// and can modify response headers and bodies.
 #[derive(Debug, Clone)]
 pub enum MiddlewareOutgoingFunction {
-    /// External middleware (not yet implemented)
-    External,
+    /// Middleware loaded from a dynamic library, processes both headers and body
+    External(ExternalMiddleware),
     /// Internal middleware that processes both headers and body
     InternalWithBody(
         fn(&SocketAddr, &SocketAddr, &mut response::Parts, &mut Vec<u8>) -> anyhow::Result<()>,
     ),
     /// Internal middleware that processes only headers
     Internal(fn(&SocketAddr, &SocketAddr, &mut response::Parts) -> anyhow::Result<()>),
+    /// Adds the CORS headers to responses, see [`Cors`]
+    Cors(Arc<Cors>),
+    /// Removes headers from responses, see [`ResponseHeaderFilter`]
+    ResponseHeaders(Arc<ResponseHeaderFilter>),
 }
 
 impl MiddlewareOutgoingFunction {
@@ -96,7 +462,13 @@ impl MiddlewareOutgoingFunction {
         body: &mut Option<&mut Vec<u8>>,
     ) -> anyhow::Result<()> {
         match self {
-            MiddlewareOutgoingFunction::External => todo!(),
+            MiddlewareOutgoingFunction::External(external) => {
+                if let Some(body) = body {
+                    external.process_response(parts, body)
+                } else {
+                    Err(anyhow::anyhow!("No body provided"))
+                }
+            }
             MiddlewareOutgoingFunction::InternalWithBody(func) => {
                 if let Some(body) = body {
                     func(from, upstream_addr, parts, body)
@@ -105,6 +477,14 @@ impl MiddlewareOutgoingFunction {
                 }
             }
             MiddlewareOutgoingFunction::Internal(func) => func(from, upstream_addr, parts),
+            MiddlewareOutgoingFunction::Cors(cors) => {
+                cors.process_response(parts);
+                Ok(())
+            }
+            MiddlewareOutgoingFunction::ResponseHeaders(filter) => {
+                filter.process_response(parts);
+                Ok(())
+            }
         }
     }
 
@@ -114,10 +494,7 @@ impl MiddlewareOutgoingFunction {
     ///
     /// Returns `true` if the middleware needs the body, `false` otherwise.
     pub fn needs_body(&self) -> bool {
-        match self {
-            Self::InternalWithBody(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::InternalWithBody(_) | Self::External(_))
     }
 }
 
@@ -125,6 +502,40 @@ impl MiddlewareOutgoingFunction {
 ///
 /// This struct contains collections of incoming and outgoing middleware functions
 /// that are applied to requests and responses respectively.
+///
+/// Middleware changing the length of a body doesn't need to fix its headers: once the
+/// chain ran, a body of a new length is sent with a matching `Content-Length`, and
+/// without `Transfer-Encoding`.
+///
+/// # Example
+///
+/// ```
+/// use std::net::SocketAddr;
+/// use broxy_core::middleware::{Middleware, MiddlewareIncomingFunction, MiddlewareOutgoingFunction};
+/// use http::{request, response};
+///
+/// fn rewrite_request(_: &SocketAddr, _: &mut request::Parts, body: &mut Vec<u8>) -> anyhow::Result<()> {
+///     *body = String::from_utf8(body.clone())?.replace("id", "identifier").into_bytes();
+///     Ok(())
+/// }
+///
+/// fn rewrite_response(
+///     _: &SocketAddr,
+///     _: &SocketAddr,
+///     _: &mut response::Parts,
+///     body: &mut Vec<u8>,
+/// ) -> anyhow::Result<()> {
+///     *body = String::from_utf8(body.clone())?
+///         .replace("internal.local", "api.example.com")
+///         .into_bytes();
+///     Ok(())
+/// }
+///
+/// let middleware = Middleware::new(
+///     vec![MiddlewareIncomingFunction::InternalWithBody(rewrite_request)],
+///     vec![MiddlewareOutgoingFunction::InternalWithBody(rewrite_response)],
+/// );
+/// ```
 #[derive(Debug, Clone)]
 pub struct Middleware {
     /// Collection of incoming request middleware functions
@@ -162,26 +573,57 @@ impl Middleware {
         }
     }
 
+    /// Checks if any incoming middleware is configured.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if requests have to go through the incoming middleware.
+    pub fn has_incoming(&self) -> bool {
+        !self.process_incoming.is_empty()
+    }
+
+    /// Checks if any outgoing middleware is configured.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if responses have to go through the outgoing middleware.
+    pub fn has_outgoing(&self) -> bool {
+        !self.process_out.is_empty()
+    }
+
     /// Processes incoming request headers and optionally the body through all middleware.
     ///
+    /// Requests retried or failed over to another upstream aren't processed again, the
+    /// middleware sees the first upstream selected.
+    ///
     /// # Arguments
     ///
+    /// * `from` - Address of the client
+    /// * `upstream_addr` - Address of the upstream selected for the request
     /// * `parts` - The HTTP request header parts to process
     /// * `body` - Optional mutable reference to the request body
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` on success or an error if any middleware fails.
+    /// Returns `MiddlewareAction::Respond` as soon as a middleware answers the request,
+    /// the remaining middleware is skipped. Otherwise returns `MiddlewareAction::Continue`,
+    /// or `BroxyError::Middleware` if any middleware fails.
     pub fn process_incoming(
         &self,
         from: &SocketAddr,
+        upstream_addr: &SocketAddr,
         parts: &mut request::Parts,
         mut body: Option<&mut Vec<u8>>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<MiddlewareAction, BroxyError> {
         for proc in &self.process_incoming {
-            proc.process(from, parts, &mut body)?;
+            if let MiddlewareAction::Respond(response) = proc
+                .process(from, upstream_addr, parts, &mut body)
+                .map_err(BroxyError::Middleware)?
+            {
+                return Ok(MiddlewareAction::Respond(response));
+            }
         }
-        Ok(())
+        Ok(MiddlewareAction::Continue)
     }
 
     /// Processes outgoing response headers and optionally the body through all middleware.
@@ -193,17 +635,202 @@ impl Middleware {
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` on success or an error if any middleware fails.
+    /// Returns `Ok(())` on success or `BroxyError::Middleware` if any middleware fails.
     pub fn process_outgoing(
         &self,
         from: &SocketAddr,
         upstream_addr: &SocketAddr,
         parts: &mut response::Parts,
         mut body: Option<&mut Vec<u8>>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), BroxyError> {
         for proc in &self.process_out {
-            proc.process(from, upstream_addr, parts, &mut body)?;
+            proc.process(from, upstream_addr, parts, &mut body)
+                .map_err(BroxyError::Middleware)?;
         }
         Ok(())
     }
+
+    /// Processes an outgoing response with the functions that don't need its body only.
+    pub(crate) fn process_outgoing_headers(
+        &self,
+        from: &SocketAddr,
+        upstream_addr: &SocketAddr,
+        parts: &mut response::Parts,
+    ) -> Result<(), BroxyError> {
+        for proc in self.process_out.iter().filter(|proc| !proc.needs_body()) {
+            proc.process(from, upstream_addr, parts, &mut None)
+                .map_err(BroxyError::Middleware)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::ServiceBundle,
+        test_support::{example_plugin, get, proxy, send, service_to, upstream},
+    };
+    use http_body_util::BodyExt as _;
+    use hyper::Response;
+
+    #[test]
+    fn external_middleware_round_trips_messages() {
+        let load = |symbol| unsafe { ExternalMiddleware::load(example_plugin(), symbol) };
+        let middleware = Middleware::new(
+            vec![MiddlewareIncomingFunction::External(
+                load(INCOMING_SYMBOL).unwrap(),
+            )],
+            vec![MiddlewareOutgoingFunction::External(
+                load(OUTGOING_SYMBOL).unwrap(),
+            )],
+        );
+        let from = "127.0.0.1:50000".parse().unwrap();
+        let upstream = "10.0.0.1:80".parse().unwrap();
+
+        let (mut request, _) = hyper::Request::post("/orders?page=2")
+            .header("content-type", "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let mut body = br#"{"item": 1}"#.to_vec();
+        let action = middleware
+            .process_incoming(&from, &upstream, &mut request, Some(&mut body))
+            .unwrap();
+        assert!(matches!(action, MiddlewareAction::Continue));
+        assert_eq!(request.method, Method::POST);
+        assert_eq!(request.uri, "/orders?page=2");
+        assert_eq!(request.headers["x-plugin"], "incoming");
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(body, br#"{"item": 1}"#);
+
+        let (mut response, _) = Response::builder()
+            .status(StatusCode::CREATED)
+            .header("location", "/orders/1")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let mut body = b"created\r\n\r\nwith blank lines".to_vec();
+        middleware
+            .process_outgoing(&from, &upstream, &mut response, Some(&mut body))
+            .unwrap();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.headers["x-plugin"], "outgoing");
+        assert_eq!(response.headers["location"], "/orders/1");
+        assert_eq!(body, b"created\r\n\r\nwith blank lines");
+
+        // A missing function is an error, not a panic
+        let error: anyhow::Error = load(b"broxy_middleware_missing").unwrap_err().into();
+        assert!(
+            error
+                .to_string()
+                .starts_with("failed to load external library"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rewritten_bodies_are_sent_with_their_new_length() {
+        fn rewrite_request(
+            _: &SocketAddr,
+            _: &mut request::Parts,
+            body: &mut Vec<u8>,
+        ) -> anyhow::Result<()> {
+            *body = String::from_utf8(body.clone())?
+                .replace("id", "identifier")
+                .into_bytes();
+            Ok(())
+        }
+        fn rewrite_response(
+            _: &SocketAddr,
+            _: &SocketAddr,
+            _: &mut response::Parts,
+            body: &mut Vec<u8>,
+        ) -> anyhow::Result<()> {
+            *body = String::from_utf8(body.clone())?
+                .replace("internal.local", "api.example.com")
+                .into_bytes();
+            Ok(())
+        }
+        // An upstream answering with its host, the request body and its announced length
+        let upstream = upstream(
+            |request: hyper::Request<hyper::body::Incoming>| async move {
+                let length = request.headers()["content-length"]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let body = request.into_body().collect().await.unwrap().to_bytes();
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                Response::new(format!(
+                    r#"{{"url":"http://internal.local/","body":{body},"length":{length}}}"#
+                ))
+            },
+        )
+        .await;
+        let service = service_to(&[upstream])
+            .middleware(Middleware::new(
+                vec![MiddlewareIncomingFunction::InternalWithBody(
+                    rewrite_request,
+                )],
+                vec![MiddlewareOutgoingFunction::InternalWithBody(
+                    rewrite_response,
+                )],
+            ))
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        let response = send(
+            address,
+            "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: 8\r\nconnection: close\r\n\r\n{\"id\":1}",
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        // The upstream received the whole rewritten request body, with its new length
+        assert_eq!(
+            body,
+            r#"{"url":"http://api.example.com/","body":{"identifier":1},"length":16}"#
+        );
+        assert!(head.contains(&format!("content-length: {}\r\n", body.len())));
+    }
+
+    #[tokio::test]
+    async fn upstream_aware_middleware_sees_the_chosen_upstream() {
+        // Every backend has its own token
+        fn authorize(
+            _: &SocketAddr,
+            upstream: &SocketAddr,
+            parts: &mut request::Parts,
+        ) -> anyhow::Result<()> {
+            let token = format!("Bearer token-{}", upstream.port());
+            parts.headers.insert("authorization", token.parse()?);
+            Ok(())
+        }
+        // An upstream answering with the credentials it received
+        let credentials_upstream = || {
+            upstream(
+                |request: hyper::Request<hyper::body::Incoming>| async move {
+                    let token = request.headers()["authorization"].to_str().unwrap();
+                    Response::new(token.to_string())
+                },
+            )
+        };
+        let upstreams = [credentials_upstream().await, credentials_upstream().await];
+        let service = service_to(&upstreams)
+            .middleware(Middleware::new(
+                vec![MiddlewareIncomingFunction::InternalWithUpstream(authorize)],
+                vec![],
+            ))
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        // Both upstreams get requests, each with its own token
+        for upstream in upstreams {
+            let response = get(address, "/").await;
+            let expected = format!("Bearer token-{}", upstream.port());
+            assert!(response.ends_with(&expected), "{response}");
+        }
+    }
 }
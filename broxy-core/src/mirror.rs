@@ -0,0 +1,217 @@
+//! Traffic mirroring (shadowing) to a secondary upstream.
+//!
+//! A mirror receives a copy of a sample of the requests handled by a service.
+//! Mirrored requests are fire-and-forget: their responses are discarded and
+//! their failures never affect the response sent to the client. A mirror that is
+//! slow or stops answering doesn't pile up requests either: each mirrored request
+//! is given up after a timeout, and requests aren't mirrored while too many are
+//! in flight.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use http::{Request, request::Parts};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use tracing::{debug, warn};
+
+use crate::{service::send_to_upstream, upstream::Upstream};
+
+/// Default time the mirror has to answer a mirrored request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default maximum number of mirrored requests in flight.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// A secondary upstream receiving a copy of a sample of the requests.
+#[derive(Debug)]
+pub struct Mirror {
+    /// The upstream server receiving the mirrored requests
+    upstream: Upstream,
+    /// Fraction of requests to mirror, between `0.0` and `1.0`
+    sample_rate: f64,
+    /// Number of requests seen so far, used for sampling
+    seen: AtomicU64,
+    /// Time after which a mirrored request is given up
+    timeout: Duration,
+    /// Maximum number of mirrored requests in flight
+    max_in_flight: usize,
+    /// Number of mirrored requests in flight, shared with their tasks
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Mirror {
+    /// Creates a new mirror.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream` - The upstream server receiving the mirrored requests
+    /// * `sample_rate` - Fraction of requests to mirror, clamped between `0.0` and `1.0`
+    ///
+    /// # Returns
+    ///
+    /// A new `Mirror` instance
+    pub fn new(upstream: Upstream, sample_rate: f64) -> Self {
+        Self {
+            upstream,
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            seen: AtomicU64::new(0),
+            timeout: DEFAULT_TIMEOUT,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sets the time after which a mirrored request is given up, [`DEFAULT_TIMEOUT`]
+    /// by default.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Time the mirror has to answer, the connection is closed past it
+    ///
+    /// # Returns
+    ///
+    /// The mirror with the timeout set
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of mirrored requests in flight, [`DEFAULT_MAX_IN_FLIGHT`]
+    /// by default. Sampled requests aren't mirrored while the limit is reached.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_in_flight` - Maximum number of mirrored requests in flight
+    ///
+    /// # Returns
+    ///
+    /// The mirror with the limit set
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Decides whether the current request should be mirrored.
+    ///
+    /// Sampling is deterministic: a request is mirrored every time the running
+    /// count multiplied by the sample rate crosses an integer, so exactly
+    /// `sample_rate` of the requests are mirrored over time.
+    fn should_mirror(&self) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((seen + 1.0) * self.sample_rate).floor() > (seen * self.sample_rate).floor()
+    }
+
+    /// Sends a copy of the request to the mirror, if it's sampled.
+    ///
+    /// The request is sent from a spawned task, so this never blocks
+    /// the processing of the original request. It's skipped if the maximum number
+    /// of mirrored requests are in flight already.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The request header parts, as they will be sent to the upstream
+    /// * `body` - The buffered request body
+    pub(crate) fn maybe_send(&self, header: &Parts, body: &[u8]) {
+        if !self.should_mirror() {
+            return;
+        }
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= self.max_in_flight {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            warn!(
+                "{} mirrored requests to {} in flight, not mirroring",
+                self.max_in_flight, self.upstream.address
+            );
+            return;
+        }
+
+        let mut request = Request::new(Full::<Bytes>::from(body.to_vec()));
+        *request.method_mut() = header.method.clone();
+        *request.uri_mut() = header.uri.clone();
+        *request.version_mut() = header.version;
+        *request.headers_mut() = header.headers.clone();
+
+        let upstream = self.upstream.clone();
+        let timeout = self.timeout;
+        let in_flight = self.in_flight.clone();
+        debug!("Mirroring request to {}", upstream.address);
+        tokio::spawn(async move {
+            // Dropping the request on timeout closes its connection
+            match tokio::time::timeout(timeout, send_to_upstream(&upstream, None, request)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!("Mirrored request to {} failed: {}", upstream.address, e),
+                Err(_) => warn!(
+                    "Mirrored request to {} timed out after {:?}",
+                    upstream.address, timeout
+                ),
+            }
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::ServiceBundle,
+        test_support::{echo_upstream, get, proxy, refused_address, service_to, upstream},
+    };
+    use hyper::Response;
+
+    #[test]
+    fn exactly_the_sample_rate_is_mirrored() {
+        let upstream = Upstream::new(refused_address(), false);
+        let mirrored = |sample_rate: f64| {
+            let mirror = Mirror::new(upstream.clone(), sample_rate);
+            (0..100).filter(|_| mirror.should_mirror()).count()
+        };
+
+        assert_eq!(mirrored(0.0), 0);
+        assert_eq!(mirrored(0.25), 25);
+        assert_eq!(mirrored(1.0), 100);
+        // Clamped between 0 and 1
+        assert_eq!(mirrored(-1.0), 0);
+        assert_eq!(mirrored(2.0), 100);
+    }
+
+    #[tokio::test]
+    async fn failing_mirrors_dont_affect_the_requests() {
+        let hanging = upstream(|_| std::future::pending::<Response<String>>()).await;
+        for mirror in [refused_address(), hanging] {
+            let service = service_to(&[echo_upstream().await])
+                .mirror(Upstream::new(mirror, false), 1.0)
+                .build()
+                .unwrap();
+            let address = proxy(ServiceBundle::new(vec![service])).await;
+            for _ in 0..3 {
+                let response = get(address, "/real").await;
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+                assert!(response.ends_with("/real"), "{response}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hanging_mirrored_requests_are_given_up() {
+        let hanging = upstream(|_| std::future::pending::<Response<String>>()).await;
+        let mirror = Mirror::new(Upstream::new(hanging, false), 1.0)
+            .with_timeout(Duration::from_millis(100))
+            .with_max_in_flight(2);
+        let header = hyper::Request::get("/").body(()).unwrap().into_parts().0;
+
+        // Requests beyond the limit aren't mirrored
+        for _ in 0..3 {
+            mirror.maybe_send(&header, b"");
+        }
+        assert_eq!(mirror.in_flight.load(Ordering::Relaxed), 2);
+        // The others are given up after the timeout
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(mirror.in_flight.load(Ordering::Relaxed), 0);
+    }
+}
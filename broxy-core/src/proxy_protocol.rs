@@ -0,0 +1,344 @@
+//! PROXY protocol, the address of the client behind a TCP load balancer.
+//!
+//! A load balancer forwarding TCP connections hides the client: the proxy sees
+//! connections coming from the load balancer. With the PROXY protocol, the load
+//! balancer sends a header with the address of the client before any data, in a text
+//! (v1) or binary (v2) format. Listeners enable it with
+//! [`crate::server::ServerOptions::proxy_protocol`], then every connection must start
+//! with the header, which becomes the client address of its requests.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+//!
+//! # Example
+//!
+//! ```
+//! use broxy_core::server::ServerOptions;
+//!
+//! let options = ServerOptions {
+//!     proxy_protocol: true,
+//!     ..ServerOptions::default()
+//! };
+//! ```
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+/// Time a connection has to send its header before being closed.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum length of a v1 header, line ending included.
+pub const V1_MAX_LENGTH: usize = 107;
+
+/// Signature starting a v2 header.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the fixed part of a v2 header, before the addresses.
+const V2_PREFIX_LENGTH: usize = 16;
+
+/// Length of the shortest header, `PROXY UNKNOWN\r\n`, read before telling the versions apart.
+const MIN_LENGTH: usize = 15;
+
+/// An invalid PROXY protocol header.
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    /// The connection failed or closed before the end of the header
+    #[error("failed to read PROXY protocol header: {0}")]
+    Io(#[from] io::Error),
+    /// The connection doesn't start with a header
+    #[error("missing PROXY protocol header")]
+    Missing,
+    /// The header doesn't follow the protocol
+    #[error("malformed PROXY protocol header: {0}")]
+    Malformed(&'static str),
+}
+
+/// Parses a v1 header.
+///
+/// # Arguments
+///
+/// * `line` - The header, from `PROXY` to the `\r\n` ending it
+///
+/// # Returns
+///
+/// Returns the address of the client, `None` for `UNKNOWN` connections, or an error
+/// if the header is malformed.
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::proxy_protocol::{ProxyHeaderError, parse_v1};
+///
+/// let client = parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n").unwrap();
+/// assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+///
+/// let client = parse_v1(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n").unwrap();
+/// assert_eq!(client, Some("[2001:db8::7]:51234".parse().unwrap()));
+///
+/// // Health checks of the load balancer itself
+/// assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+///
+/// for malformed in [
+///     &b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n"[..],
+///     b"PROXY TCP4 2001:db8::7 10.0.0.1 51234 443\r\n",
+///     b"PROXY TCP4 203.0.113.7 10.0.0.1 99999 443\r\n",
+///     b"PROXY UDP4 203.0.113.7 10.0.0.1 51234 443\r\n",
+///     b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\n",
+/// ] {
+///     assert!(matches!(parse_v1(malformed), Err(ProxyHeaderError::Malformed(_))));
+/// }
+/// ```
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or(ProxyHeaderError::Malformed("v1 header must end with CRLF"))?;
+    let line = std::str::from_utf8(line)
+        .map_err(|_| ProxyHeaderError::Malformed("v1 header must be ASCII"))?;
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(ProxyHeaderError::Missing);
+    }
+    let family = fields.next();
+    if family == Some("UNKNOWN") {
+        return Ok(None);
+    }
+    let [Some(source), Some(_), Some(port), Some(_), None] = [
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ] else {
+        return Err(ProxyHeaderError::Malformed("v1 header must have 6 fields"));
+    };
+    let ip = match family {
+        Some("TCP4") => source.parse::<Ipv4Addr>().map(IpAddr::V4),
+        Some("TCP6") => source.parse::<Ipv6Addr>().map(IpAddr::V6),
+        _ => return Err(ProxyHeaderError::Malformed("unknown v1 protocol")),
+    }
+    .map_err(|_| ProxyHeaderError::Malformed("invalid v1 source address"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid v1 source port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parses a v2 header.
+///
+/// # Arguments
+///
+/// * `header` - The whole header, from the signature to the end of its addresses
+///
+/// # Returns
+///
+/// Returns the address of the client, `None` for `LOCAL` connections and families
+/// other than IPv4 and IPv6, or an error if the header is malformed.
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::proxy_protocol::{ProxyHeaderError, V2_SIGNATURE, parse_v2};
+///
+/// // PROXY command over TCP/IPv4, from 203.0.113.7:51234 to 10.0.0.1:443
+/// let mut header = V2_SIGNATURE.to_vec();
+/// header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+/// header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+/// header.extend_from_slice(&51234u16.to_be_bytes());
+/// header.extend_from_slice(&443u16.to_be_bytes());
+/// assert_eq!(parse_v2(&header).unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+///
+/// // LOCAL command, sent by the load balancer for its own connections
+/// let mut local = V2_SIGNATURE.to_vec();
+/// local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+/// assert_eq!(parse_v2(&local).unwrap(), None);
+///
+/// // Addresses shorter than the family requires
+/// let mut truncated = V2_SIGNATURE.to_vec();
+/// truncated.extend_from_slice(&[0x21, 0x21, 0, 12]);
+/// truncated.extend_from_slice(&[0; 12]);
+/// assert!(matches!(parse_v2(&truncated), Err(ProxyHeaderError::Malformed(_))));
+///
+/// // Unsupported version
+/// header[12] = 0x31;
+/// assert!(matches!(parse_v2(&header), Err(ProxyHeaderError::Malformed(_))));
+/// ```
+pub fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    if header.len() < V2_PREFIX_LENGTH || header[..12] != V2_SIGNATURE {
+        return Err(ProxyHeaderError::Missing);
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let addresses = &header[V2_PREFIX_LENGTH..];
+    if addresses.len() != length {
+        return Err(ProxyHeaderError::Malformed("v2 header length mismatch"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(ProxyHeaderError::Malformed("unsupported v2 version"));
+    }
+    match header[12] & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(ProxyHeaderError::Malformed("unknown v2 command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match header[13] >> 4 {
+        // AF_INET
+        1 => {
+            if addresses.len() < 12 {
+                return Err(ProxyHeaderError::Malformed("v2 IPv4 addresses too short"));
+            }
+            let ip = <[u8; 4]>::try_from(&addresses[..4]).unwrap();
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // AF_INET6
+        2 => {
+            if addresses.len() < 36 {
+                return Err(ProxyHeaderError::Malformed("v2 IPv6 addresses too short"));
+            }
+            let ip = <[u8; 16]>::try_from(&addresses[..16]).unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable address
+        _ => Ok(None),
+    }
+}
+
+/// Reads the header starting a connection, v1 or v2.
+///
+/// Only the header is read, the data following it is left in the stream.
+///
+/// # Arguments
+///
+/// * `stream` - The accepted connection
+///
+/// # Returns
+///
+/// Returns the address of the client, `None` if the header carries none, or an
+/// error if the connection doesn't start with a valid header.
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::proxy_protocol::read_header;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let mut stream = &b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n"[..];
+/// let client = read_header(&mut stream).await.unwrap();
+/// assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+/// assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+/// # }
+/// ```
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut header = vec![0; MIN_LENGTH];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(b"PROXY ") {
+        // The line is read byte by byte, so the data following it stays in the stream
+        while !header.ends_with(b"\r\n") {
+            if header.len() == V1_MAX_LENGTH {
+                return Err(ProxyHeaderError::Malformed("v1 header too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+
+    if !header.starts_with(&V2_SIGNATURE) {
+        return Err(ProxyHeaderError::Missing);
+    }
+    header.resize(V2_PREFIX_LENGTH, 0);
+    stream.read_exact(&mut header[MIN_LENGTH..]).await?;
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    header.resize(V2_PREFIX_LENGTH + length, 0);
+    stream.read_exact(&mut header[V2_PREFIX_LENGTH..]).await?;
+    parse_v2(&header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{Server, ServerOptions},
+        service::ServiceBundle,
+        test_support::{send, serve, service_to, upstream},
+    };
+    use hyper::{Request, Response, body::Incoming};
+    use tokio::{io::AsyncWriteExt as _, net::TcpStream};
+
+    #[tokio::test]
+    async fn connections_must_start_with_a_valid_header() {
+        // A direct connection, without header
+        let mut stream = &b"GET / HTTP/1.1\r\nhost: example.com\r\n\r\n"[..];
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(ProxyHeaderError::Missing)
+        ));
+
+        // A v1 header without line ending
+        let mut stream = &[&b"PROXY TCP4 "[..], &[b'1'; 120]].concat()[..];
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(ProxyHeaderError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_address_of_the_requests_is_taken_from_the_header() {
+        // An upstream answering with the client address it was given
+        let upstream = upstream(|request: Request<Incoming>| async move {
+            let client = request.headers()["x-forwarded-for"].to_str().unwrap();
+            Response::new(client.to_string())
+        })
+        .await;
+        let server = Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(vec![
+                service_to(&[upstream]).build().unwrap(),
+            ]))
+            .options(ServerOptions {
+                proxy_protocol: true,
+                ..ServerOptions::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        let address = serve(server);
+        let request = |header: &[u8]| {
+            [
+                header,
+                b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+            ]
+            .concat()
+        };
+
+        let response = send(
+            address,
+            request(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n"),
+        )
+        .await;
+        assert!(response.ends_with("\r\n\r\n203.0.113.7"), "{response}");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 198, 51, 100, 1, 10, 0, 0, 1, 0xc8, 0x22, 0, 80,
+        ]);
+        let response = send(address, request(&header)).await;
+        assert!(response.ends_with("\r\n\r\n198.51.100.1"), "{response}");
+
+        // Connections without header are closed
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&request(b"")).await.unwrap();
+        let mut response = Vec::new();
+        // Closed connections may be reset, as the request was left unread
+        let _ = client.read_to_end(&mut response).await;
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+//! Per-client rate limiting.
+//!
+//! Every client IP gets a token bucket: it holds up to `burst` tokens, refills at
+//! `requests_per_second` and every request takes one token. Requests arriving while
+//! the bucket is empty are rejected. IPv6 clients are limited by /64 network, the
+//! block a single host usually picks its addresses from. Used by
+//! [`crate::filter::Filter::RateLimit`].
+//!
+//! Connections are limited with a sliding window instead, see [`SlidingWindowLimiter`],
+//! checked by the server before the TLS handshake.
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+/// Amount of checks between two sweeps of idle buckets.
+const SWEEP_EVERY: u64 = 4096;
+
+/// Tokens left in the bucket of a client.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of every client, keyed on the client IP.
+///
+/// The buckets live in a sharded map, so clients are checked concurrently
+/// without a global lock. At most `max_clients` buckets are kept, so a flood of
+/// distinct addresses can't exhaust memory: once full, refilled buckets are swept,
+/// and new clients are let through untracked while it stays full.
+///
+/// # Example
+///
+/// ```
+/// use std::{net::IpAddr, thread::sleep, time::Duration};
+/// use broxy_core::rate_limit::RateLimiter;
+///
+/// let limiter = RateLimiter::new(20.0, 2, 1024);
+/// let fast: IpAddr = "192.168.1.10".parse().unwrap();
+/// let slow: IpAddr = "192.168.1.11".parse().unwrap();
+///
+/// assert!(limiter.check(fast));
+/// assert!(limiter.check(fast));
+/// assert!(!limiter.check(fast));
+///
+/// for _ in 0..5 {
+///     assert!(limiter.check(slow));
+///     sleep(Duration::from_millis(60));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added to a bucket every second
+    requests_per_second: f64,
+    /// Capacity of a bucket
+    burst: f64,
+    /// Maximum amount of clients tracked at once
+    max_clients: usize,
+    buckets: DashMap<IpAddr, Bucket>,
+    /// Amount of checks so far, used to sweep idle buckets
+    checks: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests_per_second` - Sustained amount of requests a client may send per second
+    /// * `burst` - Amount of requests a client may send at once after being idle
+    /// * `max_clients` - Maximum amount of clients tracked at once
+    ///
+    /// # Returns
+    ///
+    /// A new `RateLimiter` instance
+    pub fn new(requests_per_second: f64, burst: u32, max_clients: usize) -> Self {
+        assert!(
+            requests_per_second > 0.0,
+            "Requests per second should be greater than 0"
+        );
+        assert!(burst > 0, "Burst should be greater than 0");
+        Self {
+            requests_per_second,
+            burst: burst as f64,
+            max_clients,
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token from the bucket of a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - IP address of the client
+    ///
+    /// # Returns
+    ///
+    /// `true` if the request is allowed, `false` if the client exceeded its rate
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let client = client_key(ip);
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.sweep(now);
+        }
+        if self.buckets.len() >= self.max_clients && !self.buckets.contains_key(&client) {
+            self.sweep(now);
+            if self.buckets.len() >= self.max_clients {
+                return true;
+            }
+        }
+
+        let mut bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops the buckets that refilled completely, they're recreated full on the next request.
+    fn sweep(&self, now: Instant) {
+        let refill = self.burst / self.requests_per_second;
+        self.buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.updated).as_secs_f64() < refill
+        });
+    }
+}
+
+/// Returns the key of the bucket of a client: its address, or its /64 network for IPv6.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !u128::from(u64::MAX))),
+        ip => ip,
+    }
+}
+
+/// Events counted for a client in the current and previous windows.
+#[derive(Debug)]
+struct Window {
+    /// Start of the current window
+    start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+/// Limits the amount of events per client IP over a sliding window.
+///
+/// The count of the previous fixed window is weighted by how much of it still
+/// overlaps the sliding window, so a client can't send twice the limit across a
+/// window boundary, while only two counters are kept per client. At most
+/// `max_clients` clients are tracked, so a flood of distinct addresses can't exhaust
+/// memory: once full, idle clients are swept, and new clients are let through
+/// untracked while it stays full.
+///
+/// # Example
+///
+/// ```
+/// use std::{net::IpAddr, time::Duration};
+/// use broxy_core::rate_limit::SlidingWindowLimiter;
+///
+/// let limiter = SlidingWindowLimiter::new(3, Duration::from_secs(60), 1024);
+/// let flood: IpAddr = "192.168.1.10".parse().unwrap();
+/// let other: IpAddr = "192.168.1.11".parse().unwrap();
+///
+/// for _ in 0..3 {
+///     assert!(limiter.check(flood));
+/// }
+/// assert!(!limiter.check(flood));
+/// assert!(limiter.check(other));
+/// ```
+#[derive(Debug)]
+pub struct SlidingWindowLimiter {
+    /// Events allowed per client over a window
+    max: u32,
+    window: Duration,
+    /// Maximum amount of clients tracked at once
+    max_clients: usize,
+    windows: DashMap<IpAddr, Window>,
+    /// Amount of checks so far, used to sweep idle clients
+    checks: AtomicU64,
+}
+
+impl SlidingWindowLimiter {
+    /// Creates a new sliding window limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - Amount of events a client may cause over `window`
+    /// * `window` - Length of the sliding window
+    /// * `max_clients` - Maximum amount of clients tracked at once
+    ///
+    /// # Returns
+    ///
+    /// A new `SlidingWindowLimiter` instance
+    pub fn new(max: u32, window: Duration, max_clients: usize) -> Self {
+        assert!(max > 0, "Maximum should be greater than 0");
+        assert!(!window.is_zero(), "Window should be longer than 0");
+        Self {
+            max,
+            window,
+            max_clients,
+            windows: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts an event of a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - IP address of the client
+    ///
+    /// # Returns
+    ///
+    /// `true` if the event is allowed, `false` if the client exceeded its rate,
+    /// rejected events aren't counted
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let ip = ip.to_canonical();
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.sweep(now);
+        }
+        if self.windows.len() >= self.max_clients && !self.windows.contains_key(&ip) {
+            self.sweep(now);
+            if self.windows.len() >= self.max_clients {
+                return true;
+            }
+        }
+
+        let mut window = self.windows.entry(ip).or_insert(Window {
+            start: now,
+            current: 0,
+            previous: 0,
+        });
+        let elapsed = now.saturating_duration_since(window.start);
+        if elapsed >= self.window * 2 {
+            *window = Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            };
+        } else if elapsed >= self.window {
+            window.start += self.window;
+            window.previous = std::mem::take(&mut window.current);
+        }
+
+        let overlap = 1.0
+            - now.saturating_duration_since(window.start).as_secs_f64() / self.window.as_secs_f64();
+        let count = window.previous as f64 * overlap + window.current as f64;
+        if count < self.max as f64 {
+            window.current += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drops the clients without events over the last two windows, they start over on the next one.
+    fn sweep(&self, now: Instant) {
+        self.windows
+            .retain(|_, window| now.saturating_duration_since(window.start) < self.window * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_clients_share_the_bucket_of_their_network() {
+        let limiter = RateLimiter::new(0.001, 2, 1024);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.check(ip("2001:db8:1:2::1")));
+        assert!(limiter.check(ip("2001:db8:1:2:ffff::2")));
+        assert!(!limiter.check(ip("2001:db8:1:2::3")));
+        // Other networks and IPv4 clients have their own
+        assert!(limiter.check(ip("2001:db8:1:3::1")));
+        assert!(limiter.check(ip("192.0.2.1")));
+        assert!(limiter.check(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn tracked_clients_are_bounded() {
+        let limiter = RateLimiter::new(0.001, 1, 16);
+        for i in 0..1000u32 {
+            limiter.check(IpAddr::from(i.to_be_bytes()));
+        }
+        assert_eq!(limiter.buckets.len(), 16);
+        // Tracked clients are still limited
+        assert!(!limiter.check(IpAddr::from(0u32.to_be_bytes())));
+    }
+}
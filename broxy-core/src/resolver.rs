@@ -0,0 +1,432 @@
+//! Resolution of upstream host names.
+//!
+//! Upstreams created with [`crate::upstream::Upstream::from_hostname`] are resolved
+//! right before connecting to them. The resolved addresses are cached for the TTL given
+//! by the resolver, or a refresh interval if it gives none, after which the host name is
+//! resolved again, so upstreams whose addresses change are followed. Addresses about to
+//! expire are refreshed in the background, and a host name without any address is only
+//! looked up again once its negative answer expires. Connections are spread across the
+//! resolved addresses in round-robin order.
+//!
+//! With the `hickory` feature, [`DnsResolver`] queries DNS servers directly and gives
+//! the TTL of the records, unlike [`SystemResolver`].
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+/// Default time the resolved addresses of a host name are used before resolving it again.
+pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default time a host name without any address is remembered as such, unless the
+/// resolver gives a TTL for the negative answer.
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+/// Share of the lifetime of cached addresses after which they're refreshed in the background.
+const REFRESH_AHEAD: f64 = 0.75;
+
+/// Future returned by [`Resolve::resolve`].
+pub type ResolveFuture = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send>>;
+
+/// Future returned by [`Resolve::resolve_with_ttl`].
+pub type ResolveWithTtlFuture = Pin<Box<dyn Future<Output = io::Result<Resolved>> + Send>>;
+
+/// Addresses of a host name, and how long they may be cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// Every address of the host, empty if it has none, e.g. on `NXDOMAIN`
+    pub addresses: Vec<SocketAddr>,
+    /// Time the addresses, or their absence, may be cached. `None` uses the refresh
+    /// interval of the upstream, or [`DEFAULT_NEGATIVE_TTL`] without addresses
+    pub ttl: Option<Duration>,
+}
+
+/// Looks up the addresses of host names.
+///
+/// Implement it to resolve upstreams from a service registry, or with a stub in tests.
+pub trait Resolve: Send + Sync {
+    /// Resolves a host name.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host name
+    /// * `port` - The port the upstream listens on, to set in the returned addresses
+    ///
+    /// # Returns
+    ///
+    /// Returns every address of the host, or an error if it can't be resolved.
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture;
+
+    /// Resolves a host name, along with how long its addresses may be cached.
+    ///
+    /// Defaults to [`Resolve::resolve`] without a TTL.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host name
+    /// * `port` - The port the upstream listens on, to set in the returned addresses
+    ///
+    /// # Returns
+    ///
+    /// Returns the addresses of the host, or an error if it can't be resolved.
+    fn resolve_with_ttl(&self, host: &str, port: u16) -> ResolveWithTtlFuture {
+        let addresses = self.resolve(host, port);
+        Box::pin(async move {
+            Ok(Resolved {
+                addresses: addresses.await?,
+                ttl: None,
+            })
+        })
+    }
+}
+
+/// Resolver asking the system, like `getaddrinfo` does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let host = host.to_string();
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Resolver querying DNS servers directly, giving the TTL of the records.
+///
+/// A single resolver can be shared by every upstream, see
+/// [`crate::upstream::Upstream::with_resolver`]. A host name that doesn't exist gets
+/// no address, cached for the negative TTL given by its zone.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::{sync::Arc, time::Duration};
+/// use broxy_core::{resolver::DnsResolver, upstream::Upstream};
+///
+/// let resolver = Arc::new(DnsResolver::from_system_conf().unwrap());
+/// let upstream = Upstream::from_hostname("api.internal:8080", false)
+///     .unwrap()
+///     .with_resolver(resolver, Duration::from_secs(30));
+/// ```
+#[cfg(feature = "hickory")]
+#[derive(Clone)]
+pub struct DnsResolver {
+    resolver: hickory_resolver::TokioAsyncResolver,
+}
+
+#[cfg(feature = "hickory")]
+impl DnsResolver {
+    /// Creates a resolver using the given hickory resolver.
+    pub fn new(resolver: hickory_resolver::TokioAsyncResolver) -> Self {
+        Self { resolver }
+    }
+
+    /// Creates a resolver with the system's configuration, e.g. `/etc/resolv.conf`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the resolver, or an error if the configuration can't be read.
+    pub fn from_system_conf() -> io::Result<Self> {
+        hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+            .map(Self::new)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsResolver").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "hickory")]
+impl Resolve for DnsResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let resolved = self.resolve_with_ttl(host, port);
+        Box::pin(async move { Ok(resolved.await?.addresses) })
+    }
+
+    fn resolve_with_ttl(&self, host: &str, port: u16) -> ResolveWithTtlFuture {
+        use hickory_resolver::error::ResolveErrorKind;
+
+        let resolver = self.resolver.clone();
+        let host = host.to_string();
+        Box::pin(async move {
+            match resolver.lookup_ip(host.as_str()).await {
+                Ok(lookup) => Ok(Resolved {
+                    addresses: lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+                    ttl: Some(
+                        lookup
+                            .valid_until()
+                            .saturating_duration_since(Instant::now()),
+                    ),
+                }),
+                Err(e) => match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => Ok(Resolved {
+                        addresses: Vec::new(),
+                        ttl: negative_ttl.map(|ttl| Duration::from_secs(ttl.into())),
+                    }),
+                    _ => Err(io::Error::other(e)),
+                },
+            }
+        })
+    }
+}
+
+/// Addresses of a resolution, and how long they're used.
+struct Cached {
+    resolved_at: Instant,
+    ttl: Duration,
+    addresses: Arc<[SocketAddr]>,
+}
+
+/// Host name of an upstream and its cached addresses.
+pub(crate) struct ResolvedHost {
+    host: String,
+    port: u16,
+    resolver: Arc<dyn Resolve>,
+    /// Time the resolved addresses are used before resolving the host again,
+    /// when the resolver gives no TTL
+    interval: Duration,
+    /// Addresses of the last resolution
+    cache: Mutex<Option<Cached>>,
+    /// Whether the addresses are being refreshed in the background
+    refreshing: AtomicBool,
+    /// Round-robin counter over the resolved addresses
+    next: AtomicUsize,
+}
+
+impl fmt::Debug for ResolvedHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResolvedHost")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvedHost {
+    /// Creates a host name that isn't resolved yet.
+    pub(crate) fn new(
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolve>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            resolver,
+            interval,
+            cache: Mutex::new(None),
+            refreshing: AtomicBool::new(false),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the host name.
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the port the upstream listens on.
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Picks the address to open the next connection to, resolving the host name
+    /// if it wasn't yet, or its addresses expired.
+    ///
+    /// Addresses close to expiring are still used while being refreshed in the background.
+    ///
+    /// # Returns
+    ///
+    /// Returns the address, or an error if the host name can't be resolved or has no address.
+    pub(crate) async fn address(self: &Arc<Self>) -> io::Result<SocketAddr> {
+        let cached = self.cache.lock().unwrap().as_ref().and_then(|cached| {
+            let age = cached.resolved_at.elapsed();
+            (age < cached.ttl).then(|| {
+                let refresh = age >= cached.ttl.mul_f64(REFRESH_AHEAD);
+                (cached.addresses.clone(), refresh)
+            })
+        });
+        let addresses = match cached {
+            Some((addresses, refresh)) => {
+                if refresh {
+                    self.refresh();
+                }
+                addresses
+            }
+            None => self.resolve().await?,
+        };
+        if addresses.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no address found"));
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % addresses.len();
+        Ok(addresses[index])
+    }
+
+    /// Resolves the host name and caches its addresses.
+    async fn resolve(&self) -> io::Result<Arc<[SocketAddr]>> {
+        debug!("Resolving upstream {}", self.host);
+        let resolved = self
+            .resolver
+            .resolve_with_ttl(&self.host, self.port)
+            .await?;
+        let addresses: Arc<[SocketAddr]> = resolved.addresses.into();
+        let ttl = match resolved.ttl {
+            Some(ttl) => ttl,
+            None if addresses.is_empty() => self.interval.min(DEFAULT_NEGATIVE_TTL),
+            None => self.interval,
+        };
+        debug!(
+            "Resolved upstream {} to {:?} for {:?}",
+            self.host, addresses, ttl
+        );
+        *self.cache.lock().unwrap() = Some(Cached {
+            resolved_at: Instant::now(),
+            ttl,
+            addresses: addresses.clone(),
+        });
+        Ok(addresses)
+    }
+
+    /// Resolves the host name in the background, unless it's already being resolved.
+    fn refresh(self: &Arc<Self>) {
+        if self.refreshing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let host = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = host.resolve().await {
+                warn!("Failed to refresh upstream {}: {}", host.host, e);
+            }
+            host.refreshing.store(false, Ordering::Relaxed);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A resolver answering with a new address on every lookup, valid for `ttl`.
+    struct Stub {
+        ttl: Duration,
+        /// Whether the host name has no address
+        missing: bool,
+        lookups: AtomicUsize,
+    }
+
+    impl Stub {
+        fn host(ttl: Duration, missing: bool) -> (Arc<Self>, Arc<ResolvedHost>) {
+            let stub = Arc::new(Self {
+                ttl,
+                missing,
+                lookups: AtomicUsize::new(0),
+            });
+            let host = ResolvedHost::new(
+                "api.internal".to_string(),
+                8080,
+                stub.clone(),
+                Duration::ZERO,
+            );
+            (stub, Arc::new(host))
+        }
+
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Resolve for Stub {
+        fn resolve(&self, _: &str, _: u16) -> ResolveFuture {
+            unreachable!("resolved with the TTL")
+        }
+
+        fn resolve_with_ttl(&self, _: &str, port: u16) -> ResolveWithTtlFuture {
+            let lookup = self.lookups.fetch_add(1, Ordering::SeqCst) as u8;
+            let addresses = match self.missing {
+                true => Vec::new(),
+                false => vec![SocketAddr::from(([10, 0, 0, lookup + 1], port))],
+            };
+            let ttl = Some(self.ttl);
+            Box::pin(async move { Ok(Resolved { addresses, ttl }) })
+        }
+    }
+
+    #[tokio::test]
+    async fn addresses_are_cached_for_their_ttl() {
+        let (stub, host) = Stub::host(Duration::from_secs(60), false);
+
+        // The TTL applies instead of the refresh interval
+        for _ in 0..3 {
+            assert_eq!(
+                host.address().await.unwrap(),
+                "10.0.0.1:8080".parse().unwrap()
+            );
+        }
+        assert_eq!(stub.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_addresses_are_resolved_again() {
+        let (stub, host) = Stub::host(Duration::from_millis(100), false);
+
+        assert_eq!(
+            host.address().await.unwrap(),
+            "10.0.0.1:8080".parse().unwrap()
+        );
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(
+            host.address().await.unwrap(),
+            "10.0.0.2:8080".parse().unwrap()
+        );
+        assert_eq!(stub.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn addresses_about_to_expire_are_refreshed_in_the_background() {
+        let (stub, host) = Stub::host(Duration::from_millis(400), false);
+
+        assert_eq!(
+            host.address().await.unwrap(),
+            "10.0.0.1:8080".parse().unwrap()
+        );
+        tokio::time::sleep(Duration::from_millis(320)).await;
+        // The cached address is used while the new one is looked up
+        assert_eq!(
+            host.address().await.unwrap(),
+            "10.0.0.1:8080".parse().unwrap()
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(stub.lookups(), 2);
+        assert_eq!(
+            host.address().await.unwrap(),
+            "10.0.0.2:8080".parse().unwrap()
+        );
+        assert_eq!(stub.lookups(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_host_names_are_cached() {
+        let (stub, host) = Stub::host(Duration::from_secs(60), true);
+
+        for _ in 0..3 {
+            let error = host.address().await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(stub.lookups(), 1);
+    }
+}
@@ -0,0 +1,186 @@
+//! Filtering of the headers of upstream responses.
+//!
+//! [`ResponseHeaderFilter`] removes headers revealing details of the upstreams, e.g.
+//! `Server` or `X-Powered-By`, or every header but an allowed list, before responses
+//! are returned to clients. It's installed as a middleware, see
+//! [`ResponseHeaderFilter::into_middleware`].
+
+use std::{collections::HashSet, sync::Arc};
+
+use http::{HeaderName, header, response};
+
+use crate::middleware::{Middleware, MiddlewareOutgoingFunction};
+
+/// Response headers kept in allow mode, as the body can't be delivered without them.
+const FRAMING_HEADERS: [HeaderName; 2] = [header::CONTENT_LENGTH, header::TRANSFER_ENCODING];
+
+/// Headers removed from, or kept in, the responses of a service.
+///
+/// Header names are matched case-insensitively. In allow mode, `Content-Length` and
+/// `Transfer-Encoding` are kept too, as they frame the body.
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{hyper::Response, response_headers::ResponseHeaderFilter};
+///
+/// let from = "127.0.0.1:50000".parse().unwrap();
+/// let upstream = "10.0.0.1:80".parse().unwrap();
+/// let filtered = |filter: ResponseHeaderFilter| {
+///     let (mut response, _) = Response::builder()
+///         .header("server", "nginx/1.25.3")
+///         .header("x-powered-by", "PHP/8.3")
+///         .header("content-type", "text/html")
+///         .body(())
+///         .unwrap()
+///         .into_parts();
+///     filter.into_middleware().process_outgoing(&from, &upstream, &mut response, None).unwrap();
+///     response.headers
+/// };
+///
+/// let headers = filtered(ResponseHeaderFilter::deny(["Server".parse().unwrap()]));
+/// assert!(headers.get("server").is_none());
+/// assert_eq!(headers["x-powered-by"], "PHP/8.3");
+///
+/// let headers = filtered(ResponseHeaderFilter::allow(["content-type".parse().unwrap()]));
+/// assert_eq!(headers.len(), 1);
+/// assert_eq!(headers["content-type"], "text/html");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ResponseHeaderFilter {
+    /// Headers kept, every header is if `None`
+    allowed: Option<HashSet<HeaderName>>,
+    /// Headers removed, even if allowed
+    denied: HashSet<HeaderName>,
+}
+
+impl ResponseHeaderFilter {
+    /// Creates a filter removing the given headers.
+    ///
+    /// # Arguments
+    ///
+    /// * `denied` - Names of the removed headers
+    ///
+    /// # Returns
+    ///
+    /// A new `ResponseHeaderFilter` instance
+    pub fn deny(denied: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            allowed: None,
+            denied: denied.into_iter().collect(),
+        }
+    }
+
+    /// Creates a filter removing every header but the given ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `allowed` - Names of the kept headers
+    ///
+    /// # Returns
+    ///
+    /// A new `ResponseHeaderFilter` instance
+    pub fn allow(allowed: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            allowed: Some(allowed.into_iter().chain(FRAMING_HEADERS).collect()),
+            denied: HashSet::new(),
+        }
+    }
+
+    /// Removes more headers, whether they're allowed or not.
+    pub fn with_denied(mut self, denied: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.denied.extend(denied);
+        self
+    }
+
+    /// Builds the middleware applying the filter.
+    ///
+    /// To combine it with other middleware, pass
+    /// [`MiddlewareOutgoingFunction::ResponseHeaders`] to [`Middleware::new`].
+    pub fn into_middleware(self) -> Middleware {
+        Middleware::new(
+            vec![],
+            vec![MiddlewareOutgoingFunction::ResponseHeaders(Arc::new(self))],
+        )
+    }
+
+    /// Removes the denied headers, and those not allowed, from a response.
+    pub(crate) fn process_response(&self, parts: &mut response::Parts) {
+        for name in &self.denied {
+            parts.headers.remove(name);
+        }
+        if let Some(allowed) = &self.allowed {
+            let removed: Vec<HeaderName> = parts
+                .headers
+                .keys()
+                .filter(|name| !allowed.contains(*name))
+                .cloned()
+                .collect();
+            for name in removed {
+                parts.headers.remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        service::ServiceBundle,
+        test_support::{get, proxy, service_to, upstream},
+    };
+    use hyper::Response;
+
+    /// Starts a proxy in front of an upstream revealing its software, filtering its
+    /// response headers with `filter`.
+    async fn proxy_filtering(filter: ResponseHeaderFilter) -> String {
+        let upstream = upstream(|_| async {
+            Response::builder()
+                .header("Server", "nginx/1.25.3")
+                .header("X-Powered-By", "PHP/8.3")
+                .header("Content-Type", "text/plain")
+                .header("X-Request-Id", "42")
+                .body("ok".to_string())
+                .unwrap()
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .middleware(filter.into_middleware())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        get(address, "/").await.to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn denied_headers_are_removed() {
+        let filter = ResponseHeaderFilter::deny([
+            HeaderName::from_static("server"),
+            "X-Powered-By".parse().unwrap(),
+        ]);
+        let response = proxy_filtering(filter).await;
+
+        assert!(!response.contains("server:"), "{response}");
+        assert!(!response.contains("x-powered-by:"), "{response}");
+        assert!(response.contains("content-type: text/plain"), "{response}");
+        assert!(response.contains("x-request-id: 42"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn only_allowed_headers_are_kept() {
+        let filter =
+            ResponseHeaderFilter::allow([header::CONTENT_TYPE, "X-Request-Id".parse().unwrap()])
+                .with_denied(["x-request-id".parse().unwrap()]);
+        let response = proxy_filtering(filter).await;
+
+        assert!(!response.contains("server:"), "{response}");
+        assert!(!response.contains("x-powered-by:"), "{response}");
+        assert!(!response.contains("x-request-id:"), "{response}");
+        assert!(response.contains("content-type: text/plain"), "{response}");
+        // The body is still framed
+        assert!(response.contains("content-length: 2"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok"), "{response}");
+    }
+}
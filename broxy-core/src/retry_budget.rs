@@ -0,0 +1,148 @@
+//! Limit on the share of requests a service may retry.
+//!
+//! Retrying failed requests helps with sporadic failures, but during an outage every
+//! request fails and retries multiply the load on upstreams that are already
+//! struggling. A [`RetryBudget`] only allows retries up to a fraction of the requests
+//! sent over a sliding window, past it requests fail fast. Install it with
+//! [`crate::service::ServiceBuilder::retry_budget`].
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Default amount of retries allowed per window, whatever the amount of requests.
+pub const DEFAULT_MIN_RETRIES: u32 = 10;
+
+/// Amount of slots the window is divided in, it slides one slot at a time.
+const SLOTS: usize = 10;
+
+/// Requests and retries counted during a slot of the window.
+#[derive(Debug, Default, Clone, Copy)]
+struct Slot {
+    /// Index of the slot since the budget was created
+    index: u64,
+    requests: u64,
+    retries: u64,
+}
+
+/// Budget of retries, shared by the requests of one or several services.
+///
+/// Retries are allowed while they amount to less than `ratio` of the requests sent
+/// during the last `window`, plus a minimum amount so that services with little
+/// traffic can still retry.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use broxy_core::retry_budget::RetryBudget;
+///
+/// let budget = RetryBudget::new(0.1, Duration::from_secs(10)).with_min_retries(0);
+/// for _ in 0..50 {
+///     budget.record_request();
+/// }
+///
+/// // 10% of 50 requests
+/// let retries = (0..50).filter(|_| budget.try_retry()).count();
+/// assert_eq!(retries, 5);
+/// ```
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// Fraction of the requests that may be retried
+    ratio: f64,
+    /// Retries allowed per window on top of the ratio
+    min_retries: u32,
+    /// Duration of a slot of the window
+    slot: Duration,
+    /// Creation time of the budget, slots are counted from it
+    created: Instant,
+    slots: Mutex<[Slot; SLOTS]>,
+}
+
+impl RetryBudget {
+    /// Creates a new retry budget.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - Fraction of the requests that may be retried, e.g. `0.2` for 20%
+    /// * `window` - Duration over which requests and retries are counted
+    ///
+    /// # Returns
+    ///
+    /// A new `RetryBudget` instance, allowing [`DEFAULT_MIN_RETRIES`] retries per window
+    /// on top of the ratio
+    pub fn new(ratio: f64, window: Duration) -> Self {
+        assert!(ratio >= 0.0, "Retry ratio should be positive");
+        assert!(!window.is_zero(), "Retry budget window should not be empty");
+        Self {
+            ratio,
+            min_retries: DEFAULT_MIN_RETRIES,
+            slot: window / SLOTS as u32,
+            created: Instant::now(),
+            slots: Mutex::new([Slot::default(); SLOTS]),
+        }
+    }
+
+    /// Sets the amount of retries allowed per window, whatever the amount of requests.
+    pub fn with_min_retries(mut self, min_retries: u32) -> Self {
+        self.min_retries = min_retries;
+        self
+    }
+
+    /// Returns the fraction of the requests that may be retried.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Counts a request sent to the upstreams, retries excluded.
+    pub fn record_request(&self) {
+        self.update(|slot| slot.requests += 1);
+    }
+
+    /// Withdraws a retry from the budget.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the retry is allowed, and counts it, `false` if the budget is
+    /// exhausted.
+    pub fn try_retry(&self) -> bool {
+        let index = self.current_index();
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        let (requests, retries) = slots
+            .iter()
+            .filter(|slot| slot.index + SLOTS as u64 > index)
+            .fold((0, 0), |(requests, retries), slot| {
+                (requests + slot.requests, retries + slot.retries)
+            });
+        let allowed = f64::from(self.min_retries) + requests as f64 * self.ratio;
+        if retries as f64 + 1.0 > allowed {
+            return false;
+        }
+        Self::slot(&mut slots, index).retries += 1;
+        true
+    }
+
+    fn current_index(&self) -> u64 {
+        (self.created.elapsed().as_nanos() / self.slot.as_nanos().max(1)) as u64
+    }
+
+    /// Updates the current slot of the window.
+    fn update(&self, update: impl FnOnce(&mut Slot)) {
+        let index = self.current_index();
+        let mut slots = self.slots.lock().unwrap_or_else(|e| e.into_inner());
+        update(Self::slot(&mut slots, index));
+    }
+
+    /// Returns the slot of `index`, reset if it was last used for an older one.
+    fn slot(slots: &mut [Slot; SLOTS], index: u64) -> &mut Slot {
+        let slot = &mut slots[(index % SLOTS as u64) as usize];
+        if slot.index != index {
+            *slot = Slot {
+                index,
+                ..Slot::default()
+            };
+        }
+        slot
+    }
+}
@@ -1,15 +1,44 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use http::HeaderName;
+use socket2::{Domain, Protocol, Socket, Type};
 
-use anyhow::Result;
 use hyper_util::{
-    rt::{TokioExecutor, TokioIo as HyperSocket},
-    server::conn::auto::Builder,
+    rt::{TokioExecutor, TokioIo as HyperSocket, TokioTimer},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, watch},
+    time::{Instant, Sleep},
 };
-use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::TlsAcceptor;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    error::{BroxyError, Result},
+    proxy_protocol,
+    rate_limit::SlidingWindowLimiter,
+    service::ServiceBundle,
+    tls::{ReloadableResolver, SniResolver},
+    utils,
+};
+
+/// Smallest read buffer hyper accepts for HTTP/1 connections.
+const MIN_HTTP1_BUFFER_SIZE: usize = 8192;
 
-use crate::service::ServiceBundle;
+/// Maximum amount of client addresses whose connection rate is tracked at once.
+const MAX_RATE_LIMITED_CLIENTS: usize = 100_000;
 
 /// HTTP server that accepts connections and routes requests to services.
 ///
@@ -18,12 +47,143 @@ use crate::service::ServiceBundle;
 pub struct Server {
     /// The TCP listener for accepting incoming connections
     connection: TcpListener,
-    /// The service bundle that handles request routing
-    services: ServiceBundle,
+    /// The service bundle that handles request routing, replaceable while running
+    services: ArcSwap<ServiceBundle>,
     tls_acceptor: Option<TlsAcceptor>,
+    /// Certificates of the TLS acceptor, when they can be reloaded
+    certificates: Option<Arc<ReloadableResolver>>,
+    /// Header the verified client certificate is forwarded in
+    client_cert_header: Option<HeaderName>,
+    /// Paths answering health probes before requests are routed
+    health: Option<Arc<HealthEndpoints>>,
+    /// Whether to disable Nagle's algorithm on accepted connections
+    tcp_nodelay: bool,
+    /// Checks and timeouts applied to accepted connections before serving them
+    limits: Arc<ConnectionLimits>,
+    /// HTTP connection settings, cloned for every accepted connection
+    http: Builder<TokioExecutor>,
+    /// Tracks the spawned connection tasks, so they can be drained on shutdown
+    graceful: GracefulShutdown,
+    /// Stage of the life of the server, followed by every accepted connection
+    lifecycle: watch::Sender<Lifecycle>,
     _accept: fn(&Server, ServiceBundle, TcpStream) -> (),
 }
 
+/// Paths the server answers itself, to be probed by an orchestrator.
+///
+/// Health probes are answered before requests are routed, so services can't shadow
+/// them, whichever bundle the server uses. Install them with
+/// [`ServerBuilder::health_endpoints`].
+#[derive(Debug, Clone)]
+pub struct HealthEndpoints {
+    /// Path answered with `200 OK` as long as the server accepts connections
+    pub live: String,
+    /// Path answered with `200 OK` once every service has a healthy upstream, in its
+    /// primary or canary group, and with `503 Service Unavailable` while one of them has none
+    pub ready: String,
+}
+
+impl Default for HealthEndpoints {
+    fn default() -> Self {
+        Self {
+            live: "/livez".to_string(),
+            ready: "/readyz".to_string(),
+        }
+    }
+}
+
+/// Amount of connections a client address may open over a sliding window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionRate {
+    /// Connections allowed over the window
+    pub connections: u32,
+    /// Length of the window
+    pub window: Duration,
+}
+
+/// Socket options applied to the listener and accepted connections.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use broxy_core::server::{Server, ServerOptions};
+///
+/// let builder = Server::builder().options(ServerOptions {
+///     tcp_nodelay: true,
+///     header_read_timeout: Some(Duration::from_secs(10)),
+///     max_connections: Some(10_000),
+///     ..ServerOptions::default()
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Sets `SO_REUSEADDR` on the listener, allowing fast restarts
+    pub reuse_address: bool,
+    /// Sets `SO_REUSEPORT` on the listener, allowing several processes to bind
+    /// the same address. Ignored on platforms that don't support it
+    pub reuse_port: bool,
+    /// Maximum length of the queue of pending connections
+    pub backlog: u32,
+    /// Sets `TCP_NODELAY` on accepted connections, disabling Nagle's algorithm
+    pub tcp_nodelay: bool,
+    /// Whether HTTP/1 connections are kept alive between requests
+    pub keep_alive: bool,
+    /// How long a connection may go without sending or receiving data, e.g. a client
+    /// stalling in the middle of a request body. `None` waits forever
+    pub idle_timeout: Option<Duration>,
+    /// How long a connection has to complete the TLS handshake, then to send the
+    /// headers of each request, counted from the end of the previous response, so
+    /// idle HTTP/1 keep-alive connections are closed once it elapses. Protects against
+    /// clients sending their headers slowly to hold connections open. `None` waits forever
+    pub header_read_timeout: Option<Duration>,
+    /// Maximum amount of connections open at once from the same client address,
+    /// further connections are closed right away. `None` doesn't limit them
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum rate at which the same client address may open connections, further
+    /// connections are closed right after being accepted, before the TLS handshake.
+    /// Applies to the peer address, not the one of a PROXY protocol header.
+    /// `None` doesn't limit it
+    pub max_connection_rate_per_ip: Option<ConnectionRate>,
+    /// Maximum amount of connections open at once, further connections are closed
+    /// right away, so a flood of connections can't exhaust memory or file descriptors.
+    /// `None` doesn't limit them
+    pub max_connections: Option<usize>,
+    /// Maximum size of the headers of a request, in bytes, larger ones are answered
+    /// with `431 Request Header Fields Too Large`. HTTP/1 connections can't buffer less
+    /// than 8 KiB, so smaller limits are raised to it. `None` keeps hyper's defaults
+    pub max_header_bytes: Option<usize>,
+    /// Interval of the pings sent on idle HTTP/2 connections, `None` disables them
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// How long to wait for a ping acknowledgement before closing an HTTP/2 connection
+    pub http2_keep_alive_timeout: Duration,
+    /// Reads a PROXY protocol header at the start of every connection, and uses the
+    /// client address it carries, see [`crate::proxy_protocol`]. Only enable it
+    /// behind a load balancer sending it, connections without the header are closed
+    pub proxy_protocol: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            tcp_nodelay: false,
+            keep_alive: true,
+            idle_timeout: None,
+            header_read_timeout: Some(Duration::from_secs(30)),
+            max_connections_per_ip: None,
+            max_connection_rate_per_ip: None,
+            max_connections: None,
+            max_header_bytes: None,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            proxy_protocol: false,
+        }
+    }
+}
+
 impl Server {
     /// Creates a new server instance bound to the specified address.
     ///
@@ -35,12 +195,36 @@ impl Server {
     ///
     /// # Returns
     ///
-    /// Returns a `Result<Server>` containing the new server instance or an error.
+    /// Returns a `Result<Server>` containing the new server instance, or `BroxyError::Io`
+    /// if binding fails.
     pub async fn new(
         addr: SocketAddr,
         services: ServiceBundle,
         tls_acceptor: Option<TlsAcceptor>,
     ) -> Result<Self> {
+        Self::with_options(addr, services, tls_acceptor, ServerOptions::default()).await
+    }
+
+    /// Creates a new server instance bound to the specified address with custom socket options.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The network address to bind to
+    /// * `services` - The service bundle for handling requests
+    /// * `tls_acceptor` - Optional TLS acceptor for secure connections
+    /// * `options` - Socket options for the listener and accepted connections
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result<Server>` containing the new server instance, or `BroxyError::Io`
+    /// if binding fails.
+    pub async fn with_options(
+        addr: SocketAddr,
+        mut services: ServiceBundle,
+        tls_acceptor: Option<TlsAcceptor>,
+        options: ServerOptions,
+    ) -> Result<Self> {
+        services.tls = tls_acceptor.is_some();
         Ok(Self {
             _accept: if tls_acceptor.is_some() {
                 debug!("Setting up tls acceptor");
@@ -49,47 +233,160 @@ impl Server {
                 debug!("Setting up non-tls acceptor");
                 Self::_non_tls_acceptor
             },
-            connection: TcpListener::bind(&addr).await?,
+            connection: Self::bind(addr, &options)?,
             tls_acceptor,
-            services,
+            certificates: None,
+            client_cert_header: None,
+            health: None,
+            tcp_nodelay: options.tcp_nodelay,
+            limits: Arc::new(ConnectionLimits::new(&options)),
+            http: Self::http_builder(&options),
+            graceful: GracefulShutdown::new(),
+            lifecycle: watch::Sender::new(Lifecycle::Serving),
+            services: ArcSwap::from_pointee(services),
         })
     }
 
-    fn _non_tls_acceptor(_: &Self, bundle: ServiceBundle, conn: TcpStream) {
-        let io = HyperSocket::new(conn);
+    fn http_builder(options: &ServerOptions) -> Builder<TokioExecutor> {
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(options.keep_alive)
+            .header_read_timeout(options.header_read_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(options.http2_keep_alive_interval)
+            .keep_alive_timeout(options.http2_keep_alive_timeout);
+        if let Some(max_header_bytes) = options.max_header_bytes {
+            builder
+                .http1()
+                .max_buf_size(max_header_bytes.max(MIN_HTTP1_BUFFER_SIZE));
+            builder
+                .http2()
+                .max_header_list_size(u32::try_from(max_header_bytes).unwrap_or(u32::MAX));
+        }
+        builder
+    }
+
+    fn bind(addr: SocketAddr, options: &ServerOptions) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(options.reuse_address)?;
+        #[cfg(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+        ))]
+        socket.set_reuse_port(options.reuse_port)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(i32::try_from(options.backlog).unwrap_or(i32::MAX))?;
+        debug!("Listening on {} with {:?}", addr, options);
+
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    /// Creates a builder for configuring a new server.
+    ///
+    /// # Returns
+    ///
+    /// Returns an empty `ServerBuilder`.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    fn _non_tls_acceptor(server: &Self, mut bundle: ServiceBundle, conn: TcpStream) {
+        let Some(permit) = server.limits.reserve(&bundle) else {
+            return;
+        };
+        let limits = server.limits.clone();
+        let http = server.http.clone();
+        let watcher = server.graceful.watcher();
+        let lifecycle = bundle.lifecycle.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = Builder::new(TokioExecutor::new())
-                .serve_connection(io, bundle)
-                .await
-            {
+            let _permit = permit;
+            let Some((conn, _guard)) = limits.admit(conn, &mut bundle).await else {
+                return;
+            };
+            let io = HyperSocket::new(conn);
+            let serving = watcher.watch(http.serve_connection(io, bundle));
+            if let Some(Err(e)) = until_closed(serving, lifecycle).await {
                 error!("Error serving non tls connection: {:?}", e);
             }
         });
     }
 
-    fn _tls_acceptor(server: &Self, bundle: ServiceBundle, conn: TcpStream) {
+    fn _tls_acceptor(server: &Self, mut bundle: ServiceBundle, conn: TcpStream) {
+        let Some(permit) = server.limits.reserve(&bundle) else {
+            return;
+        };
         // TODO: remove clone
         let acceptor = unsafe { server.tls_acceptor.as_ref().unwrap_unchecked() }.clone();
+        let client_cert_header = server.client_cert_header.clone();
+        let limits = server.limits.clone();
+        let http = server.http.clone();
+        // Taken before the handshake, so connections still handshaking are waited for too
+        let watcher = server.graceful.watcher();
+        let lifecycle = bundle.lifecycle.clone();
 
         tokio::spawn(async move {
-            let tls_stream = match acceptor.accept(conn).await {
+            let _permit = permit;
+            // The PROXY protocol header comes before the TLS handshake
+            let Some((conn, _guard)) = limits.admit(conn, &mut bundle).await else {
+                return;
+            };
+            let handshake = acceptor.accept(conn);
+            let tls_stream = match limits.header_read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, handshake).await.map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::TimedOut, "tls handshake timed out")
+                }),
+                None => Ok(handshake.await),
+            };
+            let tls_stream = match tls_stream.and_then(|tls_stream| tls_stream) {
                 Ok(tls_stream) => tls_stream,
                 Err(err) => {
                     error!("failed to perform tls handshake: {err:#}");
                     return;
                 }
             };
+            if let Some(header) = client_cert_header {
+                let certificate = tls_stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certificates| certificates.first());
+                debug!("Client certificate presented: {}", certificate.is_some());
+                bundle.client_cert = Some((
+                    header,
+                    certificate.and_then(|certificate| utils::describe_certificate(certificate)),
+                ));
+            }
+            // Serves the protocol negotiated with ALPN, guessed from the first bytes without it
+            let http = match tls_stream.get_ref().1.alpn_protocol() {
+                Some(b"h2") => http.http2_only(),
+                Some(b"http/1.1") => http.http1_only(),
+                _ => http,
+            };
             let io = HyperSocket::new(tls_stream);
-            if let Err(e) = Builder::new(TokioExecutor::new())
-                .serve_connection(io, bundle)
-                .await
-            {
+            let serving = watcher.watch(http.serve_connection(io, bundle));
+            if let Some(Err(e)) = until_closed(serving, lifecycle).await {
                 error!("Error serving tls connection: {:?}", e);
             }
         });
     }
 
+    /// Returns the address the server is listening on.
+    ///
+    /// Useful to discover the port picked by the OS when binding to port 0.
+    ///
+    /// # Returns
+    ///
+    /// Returns the local address of the listener, or an error if it can't be retrieved.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.connection.local_addr()
+    }
+
     /// Accepts a new connection and spawns a task to handle it.
     ///
     /// This method accepts a TCP connection and spawns an asynchronous task
@@ -98,14 +395,1393 @@ impl Server {
     /// # Returns
     ///
     /// Returns `Ok(())` when a connection is successfully accepted and handled,
-    /// or an error if the connection fails.
+    /// or `BroxyError::Io` if accepting the connection fails.
     pub async fn accept(&self) -> Result<()> {
         let (conn, address) = self.connection.accept().await?;
+        if !self.limits.check_rate(address.ip()) {
+            warn!("Closing connection from {}: connecting too often", address);
+            return Ok(());
+        }
+        if self.tcp_nodelay {
+            conn.set_nodelay(true)?;
+        }
 
-        let mut bundle = self.services.clone();
+        let mut bundle = ServiceBundle::clone(&self.services.load());
         bundle.from = address;
+        bundle.health = self.health.clone();
+        bundle.lifecycle = Some(self.lifecycle.subscribe());
 
         (self._accept)(self, bundle, conn);
         Ok(())
     }
+
+    /// Replaces the service bundle handling the requests.
+    ///
+    /// Connections accepted from now on use the new bundle, while the accepted ones
+    /// keep the bundle they started with until they're closed, so requests in flight
+    /// finish with the services that received them.
+    ///
+    /// # Arguments
+    ///
+    /// * `services` - The new service bundle, see [`ServiceBundle::new`]
+    pub fn replace_services(&self, mut services: ServiceBundle) {
+        services.tls = self.tls_acceptor.is_some();
+        self.services.store(Arc::new(services));
+        info!("Service bundle replaced");
+    }
+
+    /// Returns whether the server terminates TLS.
+    pub fn is_tls(&self) -> bool {
+        self.tls_acceptor.is_some()
+    }
+
+    /// Replaces the certificates presented to TLS clients, e.g. once renewed.
+    ///
+    /// Handshakes started from now on present the new certificates, while the
+    /// established connections keep their session.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The new certificates, see [`crate::config::Ssl::sni_resolver`]
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` once replaced, or `BroxyError::Config` if the server wasn't
+    /// built with reloadable certificates, see [`ServerBuilder::certificates`].
+    pub fn reload_certificates(&self, resolver: SniResolver) -> Result<()> {
+        let certificates = self.certificates.as_ref().ok_or_else(|| {
+            BroxyError::Config("server certificates can't be reloaded".to_string())
+        })?;
+        certificates.reload(resolver);
+        info!("Certificates replaced");
+        Ok(())
+    }
+
+    /// Stops accepting connections and waits for the accepted ones to finish.
+    ///
+    /// The listener is closed right away. Connections are told to shut down
+    /// gracefully: in-flight requests complete, then the connections are closed.
+    /// Connections still open when `timeout` elapses are left running in the background.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long to wait for the outstanding connections
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if every connection finished within the timeout, `false` otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use broxy_core::server::Server;
+    ///
+    /// # async fn stop(server: Server) {
+    /// // Lets the in-flight requests finish for up to 30 seconds
+    /// if !server.shutdown(Duration::from_secs(30)).await {
+    ///     eprintln!("Some connections were still open");
+    /// }
+    /// # }
+    /// ```
+    pub async fn shutdown(self, timeout: Duration) -> bool {
+        let Self {
+            connection,
+            graceful,
+            ..
+        } = self;
+        drop(connection);
+
+        info!(
+            "Shutting down, waiting for {} connections to finish",
+            graceful.count()
+        );
+        match tokio::time::timeout(timeout, graceful.shutdown()).await {
+            Ok(()) => {
+                info!("All connections finished");
+                true
+            }
+            Err(_) => {
+                warn!(
+                    "Shutdown timed out after {:?}, dropping remaining connections",
+                    timeout
+                );
+                false
+            }
+        }
+    }
+
+    /// Stops accepting connections and lets clients move off the open ones.
+    ///
+    /// The listener is closed right away, so new connections are refused. The open
+    /// connections keep being served during `window`, but every HTTP/1 response asks
+    /// the client to close its connection with `Connection: close`, so clients open
+    /// their next ones elsewhere. Connections still open once `window` elapses are
+    /// closed, whatever they're doing.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - How long the open connections keep being served
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if every connection was closed by its client within the window,
+    /// `false` if some had to be closed by the server.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use broxy_core::server::Server;
+    ///
+    /// # async fn stop(server: Server) {
+    /// // Gives the clients 10 seconds to move their connections to the other instances
+    /// if !server.drain(Duration::from_secs(10)).await {
+    ///     eprintln!("Some connections had to be closed");
+    /// }
+    /// # }
+    /// ```
+    pub async fn drain(self, window: Duration) -> bool {
+        let Self {
+            connection,
+            lifecycle,
+            ..
+        } = self;
+        drop(connection);
+        lifecycle.send_replace(Lifecycle::Draining);
+
+        info!(
+            "Draining {} connections for {:?}",
+            lifecycle.receiver_count(),
+            window
+        );
+        // Every connection holds a receiver until it's closed
+        match tokio::time::timeout(window, lifecycle.closed()).await {
+            Ok(()) => {
+                info!("All connections were closed by their clients");
+                true
+            }
+            Err(_) => {
+                warn!(
+                    "Drain window of {:?} elapsed, closing remaining connections",
+                    window
+                );
+                lifecycle.send_replace(Lifecycle::Closing);
+                false
+            }
+        }
+    }
+}
+
+/// Servers listening on several addresses in a single process, keyed by name.
+///
+/// Each server has its own listener, TLS settings and service bundle, e.g. a plaintext
+/// one redirecting to HTTPS and a TLS one routing to the upstreams. Built from the
+/// entry points of a configuration with [`crate::config::Config::build`].
+///
+/// # Example
+///
+/// ```
+/// use broxy_core::{
+///     error::BroxyError,
+///     server::{Server, ServerGroup},
+///     service::ServiceBundle,
+/// };
+///
+/// # async fn run(services: ServiceBundle) -> Result<(), BroxyError> {
+/// let server = async |address: &str, bundle: ServiceBundle| {
+///     Server::builder()
+///         .address(address.parse().unwrap())
+///         .services(bundle)
+///         .build()
+///         .await
+/// };
+/// let group = ServerGroup::new()
+///     .with_server("http", server("0.0.0.0:80", ServiceBundle::https_redirect(443)).await?)
+///     .with_server("https", server("0.0.0.0:443", services).await?);
+/// group.serve().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ServerGroup {
+    servers: HashMap<String, Server>,
+}
+
+impl From<HashMap<String, Server>> for ServerGroup {
+    fn from(servers: HashMap<String, Server>) -> Self {
+        Self { servers }
+    }
+}
+
+impl ServerGroup {
+    /// Creates a group without servers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a server to the group.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the server, e.g. its entry point, replacing the server
+    ///   previously added with it
+    /// * `server` - The server
+    pub fn with_server(mut self, name: impl Into<String>, server: Server) -> Self {
+        self.servers.insert(name.into(), server);
+        self
+    }
+
+    /// Returns the server of a name.
+    pub fn get(&self, name: &str) -> Option<&Server> {
+        self.servers.get(name)
+    }
+
+    /// Returns the servers keyed by name, e.g. to reload them with
+    /// [`crate::config::Config::reload`].
+    pub fn servers(&self) -> &HashMap<String, Server> {
+        &self.servers
+    }
+
+    /// Accepts connections on every server concurrently.
+    ///
+    /// Failures to accept a connection are logged and the server keeps accepting, so
+    /// this only returns for a group without servers. Drop the future to stop
+    /// accepting, then shut the servers down with [`ServerGroup::shutdown`].
+    pub async fn serve(&self) {
+        futures::future::join_all(self.servers.iter().map(|(name, server)| async move {
+            loop {
+                match server.accept().await {
+                    Ok(()) => debug!("Server {} accepted a connection", name),
+                    Err(e) => error!("Server {} failed to accept a connection: {}", name, e),
+                }
+            }
+        }))
+        .await;
+    }
+
+    /// Shuts every server down concurrently, see [`Server::shutdown`].
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait for the connections of each server
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the connections of every server finished in time.
+    pub async fn shutdown(self, timeout: Duration) -> bool {
+        futures::future::join_all(
+            self.servers
+                .into_values()
+                .map(|server| server.shutdown(timeout)),
+        )
+        .await
+        .into_iter()
+        .all(|finished| finished)
+    }
+}
+
+/// Stage of the life of a server, followed by its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lifecycle {
+    Serving,
+    /// No new connections are accepted, responses ask clients to close theirs
+    Draining,
+    /// The remaining connections are closed
+    Closing,
+}
+
+/// Serves a connection until it ends, or until the server closes it at the end of
+/// a drain, see [`Server::drain`].
+///
+/// # Returns
+///
+/// Returns the output of `serving`, or `None` if the connection was closed.
+async fn until_closed<T>(
+    serving: impl Future<Output = T>,
+    lifecycle: Option<watch::Receiver<Lifecycle>>,
+) -> Option<T> {
+    let Some(mut lifecycle) = lifecycle else {
+        return Some(serving.await);
+    };
+    let closing = async move {
+        // A server dropped without draining leaves its connections alone
+        if lifecycle
+            .wait_for(|stage| *stage == Lifecycle::Closing)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        output = serving => Some(output),
+        () = closing => {
+            debug!("Closing connection still open at the end of the drain");
+            None
+        }
+    }
+}
+
+/// Reads the PROXY protocol header of a connection, its client becoming the one
+/// requests come from.
+///
+/// # Returns
+///
+/// Returns `false` if the connection has no valid header and must be closed.
+async fn read_proxy_header(conn: &mut TcpStream, bundle: &mut ServiceBundle) -> bool {
+    let header = tokio::time::timeout(
+        proxy_protocol::HEADER_TIMEOUT,
+        proxy_protocol::read_header(conn),
+    );
+    match header.await {
+        Ok(Ok(Some(client))) => {
+            debug!("Connection from {} proxied for {}", bundle.from, client);
+            bundle.from = client;
+            true
+        }
+        Ok(Ok(None)) => true,
+        Ok(Err(e)) => {
+            warn!("Closing connection from {}: {}", bundle.from, e);
+            false
+        }
+        Err(_) => {
+            warn!(
+                "Closing connection from {}: no PROXY protocol header within {:?}",
+                bundle.from,
+                proxy_protocol::HEADER_TIMEOUT
+            );
+            false
+        }
+    }
+}
+
+/// Checks and timeouts applied to accepted connections before serving them.
+struct ConnectionLimits {
+    /// Whether connections start with a PROXY protocol header
+    proxy_protocol: bool,
+    idle_timeout: Option<Duration>,
+    header_read_timeout: Option<Duration>,
+    max_connections_per_ip: Option<usize>,
+    /// Amount of open connections by client address, those without any left removed
+    connections_per_ip: Arc<DashMap<IpAddr, usize>>,
+    /// Connections recently opened by client address
+    connection_rate: Option<SlidingWindowLimiter>,
+    /// A permit per connection that may be open at once
+    connections: Arc<Semaphore>,
+}
+
+impl ConnectionLimits {
+    fn new(options: &ServerOptions) -> Self {
+        Self {
+            proxy_protocol: options.proxy_protocol,
+            idle_timeout: options.idle_timeout,
+            header_read_timeout: options.header_read_timeout,
+            max_connections_per_ip: options.max_connections_per_ip,
+            connections_per_ip: Arc::new(DashMap::new()),
+            connection_rate: options.max_connection_rate_per_ip.map(|rate| {
+                SlidingWindowLimiter::new(rate.connections, rate.window, MAX_RATE_LIMITED_CLIENTS)
+            }),
+            connections: Arc::new(Semaphore::new(
+                options
+                    .max_connections
+                    .unwrap_or(Semaphore::MAX_PERMITS)
+                    .min(Semaphore::MAX_PERMITS),
+            )),
+        }
+    }
+
+    /// Counts a connection opened by a client, right after accepting it.
+    ///
+    /// # Returns
+    ///
+    /// Returns `false` if the client opens connections too often, the connection
+    /// then being closed.
+    fn check_rate(&self, ip: IpAddr) -> bool {
+        self.connection_rate
+            .as_ref()
+            .is_none_or(|limiter| limiter.check(ip))
+    }
+
+    /// Reserves a connection slot, before spawning the task serving the connection.
+    ///
+    /// # Returns
+    ///
+    /// Returns the permit to hold until the connection is closed, dropped with the
+    /// task even if it panics, or `None` if too many connections are open, the
+    /// connection then being closed.
+    fn reserve(&self, bundle: &ServiceBundle) -> Option<OwnedSemaphorePermit> {
+        let permit = self.connections.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            warn!(
+                "Closing connection from {}: too many connections open",
+                bundle.from
+            );
+        }
+        permit
+    }
+
+    /// Prepares an accepted connection to be served.
+    ///
+    /// Reads its PROXY protocol header if enabled, so the limit per client applies
+    /// to the real client, then counts it as open for its client.
+    ///
+    /// # Returns
+    ///
+    /// Returns the connection closing once idle, and the guard counting it until
+    /// it's dropped, or `None` if the connection must be closed.
+    async fn admit(
+        &self,
+        mut conn: TcpStream,
+        bundle: &mut ServiceBundle,
+    ) -> Option<(IdleTimeout<TcpStream>, Option<ConnectionGuard>)> {
+        if self.proxy_protocol && !read_proxy_header(&mut conn, bundle).await {
+            return None;
+        }
+        let guard = match self.max_connections_per_ip {
+            Some(max) => Some(self.open(bundle.from.ip(), max)?),
+            None => None,
+        };
+        Some((IdleTimeout::new(conn, self.idle_timeout), guard))
+    }
+
+    /// Counts a connection as open for its client.
+    ///
+    /// # Returns
+    ///
+    /// Returns the guard closing it when dropped, or `None` if the client already
+    /// has `max` connections open.
+    fn open(&self, ip: IpAddr, max: usize) -> Option<ConnectionGuard> {
+        let mut open = self.connections_per_ip.entry(ip).or_insert(0);
+        if *open >= max {
+            warn!(
+                "Closing connection from {}: {} connections already open",
+                ip, *open
+            );
+            return None;
+        }
+        *open += 1;
+        Some(ConnectionGuard {
+            ip,
+            connections_per_ip: self.connections_per_ip.clone(),
+        })
+    }
+}
+
+/// Counts a connection as open for its client until it's dropped, even when
+/// the connection task panics.
+struct ConnectionGuard {
+    ip: IpAddr,
+    connections_per_ip: Arc<DashMap<IpAddr, usize>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections_per_ip.remove_if_mut(&self.ip, |_, open| {
+            *open -= 1;
+            *open == 0
+        });
+    }
+}
+
+/// Connection failing with `TimedOut` once no data was sent nor received for a while.
+struct IdleTimeout<T> {
+    inner: T,
+    /// Time without traffic after which the connection fails, and when it happens
+    timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<T> IdleTimeout<T> {
+    fn new(inner: T, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout: timeout.map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout)))),
+        }
+    }
+
+    /// Pushes the deadline back after some traffic.
+    fn reset(&mut self) {
+        if let Some((timeout, sleep)) = &mut self.timeout {
+            sleep.as_mut().reset(Instant::now() + *timeout);
+        }
+    }
+
+    /// Checks if the deadline passed while waiting for the connection.
+    fn poll_elapsed<R>(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<R>> {
+        if let Some((_, sleep)) = &mut self.timeout
+            && sleep.as_mut().poll(cx).is_ready()
+        {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connection idle",
+            )));
+        }
+        Poll::Pending
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_elapsed(cx),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            // A client not reading its responses is idle too
+            Poll::Pending => this.poll_elapsed(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                this.reset();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_elapsed(cx),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Builder for [`Server`].
+///
+/// Allows configuring a server option by option instead of passing
+/// every option positionally to [`Server::new`].
+#[derive(Default)]
+pub struct ServerBuilder {
+    address: Option<SocketAddr>,
+    services: Option<ServiceBundle>,
+    tls_acceptor: Option<TlsAcceptor>,
+    certificates: Option<Arc<ReloadableResolver>>,
+    client_cert_header: Option<HeaderName>,
+    health: Option<HealthEndpoints>,
+    options: ServerOptions,
+}
+
+impl ServerBuilder {
+    /// Sets the network address to bind to.
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the service bundle used for handling requests.
+    pub fn services(mut self, services: ServiceBundle) -> Self {
+        self.services = Some(services);
+        self
+    }
+
+    /// Sets the TLS acceptor, enabling TLS on accepted connections.
+    pub fn tls_acceptor(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
+    /// Sets the certificates of the TLS acceptor, so they can be replaced with
+    /// [`Server::reload_certificates`].
+    ///
+    /// The acceptor must present the certificates of this resolver, see
+    /// [`crate::config::Ssl::reloadable_tls_acceptor`].
+    pub fn certificates(mut self, certificates: Arc<ReloadableResolver>) -> Self {
+        self.certificates = Some(certificates);
+        self
+    }
+
+    /// Forwards the client certificate of TLS connections in a header.
+    ///
+    /// The certificate is described with [`utils::describe_certificate`]. The header
+    /// is removed from requests of clients without a certificate, so it can't be
+    /// spoofed. Only useful with a TLS acceptor verifying client certificates.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{
+    ///     config::Ssl, error::BroxyError, hyper::header::HeaderName, server::Server,
+    ///     service::ServiceBundle,
+    /// };
+    ///
+    /// # async fn build(ssl: Ssl, services: ServiceBundle) -> Result<Server, BroxyError> {
+    /// let server = Server::builder()
+    ///     .address("127.0.0.1:8443".parse().unwrap())
+    ///     .services(services)
+    ///     .tls_acceptor(ssl.tls_acceptor()?)
+    ///     .client_cert_header(HeaderName::from_static("x-client-cert"))
+    ///     .build()
+    ///     .await?;
+    /// # Ok(server)
+    /// # }
+    /// ```
+    pub fn client_cert_header(mut self, header: HeaderName) -> Self {
+        self.client_cert_header = Some(header);
+        self
+    }
+
+    /// Answers health probes on the paths of `health`, see [`HealthEndpoints`].
+    ///
+    /// Readiness follows the health of the upstreams, as tracked by active health
+    /// checks, see [`crate::load_balancer::LoadBalancer::spawn_health_checks`], and
+    /// passive ones, see [`crate::upstream::Upstream::with_passive_health_check`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{
+    ///     error::BroxyError,
+    ///     server::{HealthEndpoints, Server},
+    ///     service::ServiceBundle,
+    /// };
+    ///
+    /// # async fn build(services: ServiceBundle) -> Result<Server, BroxyError> {
+    /// // Answers `/livez` and `/readyz` before routing to the services
+    /// let server = Server::builder()
+    ///     .address("127.0.0.1:8080".parse().unwrap())
+    ///     .services(services)
+    ///     .health_endpoints(HealthEndpoints::default())
+    ///     .build()
+    ///     .await?;
+    /// # Ok(server)
+    /// # }
+    /// ```
+    pub fn health_endpoints(mut self, health: HealthEndpoints) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Sets the socket options for the listener and accepted connections.
+    pub fn options(mut self, options: ServerOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Binds the listener and builds the server.
+    ///
+    /// # Returns
+    ///
+    /// Returns the configured `Server`, `BroxyError::Config` if the address or
+    /// services were not set, or `BroxyError::Io` if binding fails.
+    pub async fn build(self) -> Result<Server> {
+        let address = self
+            .address
+            .ok_or_else(|| BroxyError::Config("server requires an address".to_string()))?;
+        let services = self
+            .services
+            .ok_or_else(|| BroxyError::Config("server requires a service bundle".to_string()))?;
+
+        let mut server =
+            Server::with_options(address, services, self.tls_acceptor, self.options).await?;
+        server.certificates = self.certificates;
+        server.client_cert_header = self.client_cert_header;
+        server.health = self.health.map(Arc::new);
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        canary::Canary,
+        health::HealthCheck,
+        load_balancer::LoadBalancer,
+        service::{Service, empty_response},
+        test_support::{echo_upstream, fixture, get, serve, service_to, upstream},
+        upstream::Upstream,
+    };
+    use http::StatusCode;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+    use tokio::task::JoinHandle;
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    const REQUEST: &[u8] = b"GET /ok HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n";
+
+    /// Starts a server with `options` in front of an upstream echoing request paths.
+    ///
+    /// # Returns
+    ///
+    /// The address the server listens on.
+    async fn proxy_with(address: SocketAddr, options: ServerOptions) -> Result<SocketAddr> {
+        let upstream = echo_upstream().await;
+        let server = Server::builder()
+            .address(address)
+            .services(ServiceBundle::new(vec![
+                service_to(&[upstream]).build().unwrap(),
+            ]))
+            .options(options)
+            .build()
+            .await?;
+        Ok(serve(server))
+    }
+
+    /// Reads the response of a connection the server may close or reset.
+    async fn read(mut client: TcpStream) -> String {
+        let mut response = Vec::new();
+        // Closed connections may be reset, as their request was left unread
+        let _ = client.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// Sends [`REQUEST`] on `client` and reads the response.
+    async fn request(mut client: TcpStream) -> String {
+        client.write_all(REQUEST).await.unwrap();
+        read(client).await
+    }
+
+    #[tokio::test]
+    async fn reuse_port_lets_servers_share_an_address() {
+        let options = |reuse_port| ServerOptions {
+            reuse_port,
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options(true))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            proxy_with(address, options(false)).await,
+            Err(BroxyError::Io(_))
+        ));
+        proxy_with(address, options(true)).await.unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        assert!(request(client).await.ends_with("/ok"));
+    }
+
+    #[tokio::test]
+    async fn clients_sending_their_headers_slowly_are_dropped() {
+        let options = ServerOptions {
+            header_read_timeout: Some(Duration::from_millis(200)),
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+
+        let mut slow = TcpStream::connect(address).await.unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\nhost: local")
+            .await
+            .unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        assert!(request(client).await.ends_with("/ok"));
+        let response = tokio::time::timeout(Duration::from_secs(5), read(slow))
+            .await
+            .unwrap();
+        assert!(!response.contains("200 OK"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited_per_client() {
+        let options = ServerOptions {
+            max_connections_per_ip: Some(2),
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+
+        let first = TcpStream::connect(address).await.unwrap();
+        let _second = TcpStream::connect(address).await.unwrap();
+        let third = TcpStream::connect(address).await.unwrap();
+        assert_eq!(request(third).await, "");
+
+        // Closing a connection makes room for a new one
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let fourth = TcpStream::connect(address).await.unwrap();
+        assert!(request(fourth).await.ends_with("/ok"));
+    }
+
+    #[tokio::test]
+    async fn clients_connecting_too_often_are_throttled() {
+        let options = ServerOptions {
+            max_connection_rate_per_ip: Some(ConnectionRate {
+                connections: 3,
+                window: Duration::from_secs(60),
+            }),
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            let client = TcpStream::connect(address).await.unwrap();
+            assert!(request(client).await.ends_with("/ok"));
+        }
+        let burst = TcpStream::connect(address).await.unwrap();
+        assert_eq!(request(burst).await, "");
+
+        // Other clients still connect
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let other = socket.connect(address).await.unwrap();
+        assert!(request(other).await.ends_with("/ok"));
+    }
+
+    #[tokio::test]
+    async fn connections_are_limited_whatever_their_client() {
+        let options = ServerOptions {
+            max_connections: Some(2),
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+
+        let first = TcpStream::connect(address).await.unwrap();
+        let second = TcpStream::connect(address).await.unwrap();
+        let third = TcpStream::connect(address).await.unwrap();
+        assert_eq!(request(third).await, "");
+        // The first ones are still served
+        assert!(request(first).await.ends_with("/ok"));
+        assert!(request(second).await.ends_with("/ok"));
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_refused() {
+        let options = ServerOptions {
+            max_header_bytes: Some(8 * 1024),
+            ..ServerOptions::default()
+        };
+        let address = proxy_with("127.0.0.1:0".parse().unwrap(), options)
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let cookie = "a".repeat(16 * 1024);
+        let oversized = format!("GET / HTTP/1.1\r\nhost: localhost\r\ncookie: {cookie}\r\n\r\n");
+        client.write_all(oversized.as_bytes()).await.unwrap();
+        let response = read(client).await;
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"),
+            "{response}"
+        );
+        let client = TcpStream::connect(address).await.unwrap();
+        assert!(request(client).await.ends_with("/ok"));
+    }
+
+    /// Binds a server in front of an upstream answering `slow` after 200 milliseconds,
+    /// leaving it to the test to accept connections.
+    async fn slow_proxy() -> Server {
+        let upstream = upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            hyper::Response::new("slow".to_string())
+        })
+        .await;
+        let services = vec![service_to(&[upstream]).build().unwrap()];
+        Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(services))
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_in_flight_requests_finish() {
+        let server = slow_proxy().await;
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        server.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The request is in flight when the shutdown starts, and still gets its response
+        assert!(server.shutdown(Duration::from_secs(5)).await);
+        let response = read(client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("slow"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn drain_asks_clients_to_leave_then_closes_their_connections() {
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n";
+        let server = slow_proxy().await;
+        let address = server.local_addr().unwrap();
+
+        // A keep-alive connection left idle after its first response
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        idle.write_all(REQUEST).await.unwrap();
+        server.accept().await.unwrap();
+        let mut response = [0; 1024];
+        let read = idle.read(&mut response).await.unwrap();
+        assert!(!String::from_utf8_lossy(&response[..read]).contains("connection: close"));
+
+        // A request in flight when the drain starts
+        let mut busy = TcpStream::connect(address).await.unwrap();
+        busy.write_all(REQUEST).await.unwrap();
+        server.accept().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let drain = tokio::spawn(server.drain(Duration::from_secs(1)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(address).await.is_err());
+
+        // The request completes, and its client is asked to close the connection
+        let mut response = String::new();
+        busy.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("connection: close"), "{response}");
+
+        // The idle connection is closed once the window elapses
+        assert!(!drain.await.unwrap());
+        assert_eq!(idle.read(&mut [0; 1024]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn every_server_of_a_group_routes_with_its_own_bundle() {
+        let server = async |bundle: ServiceBundle| {
+            Server::builder()
+                .address("127.0.0.1:0".parse().unwrap())
+                .services(bundle)
+                .build()
+                .await
+                .unwrap()
+        };
+        let group = ServerGroup::from(HashMap::from([
+            (
+                "http".to_string(),
+                server(ServiceBundle::https_redirect(8443)).await,
+            ),
+            (
+                "https".to_string(),
+                server(ServiceBundle::new(Vec::new())).await,
+            ),
+        ]));
+        let http = group.get("http").unwrap().local_addr().unwrap();
+        let https = group.get("https").unwrap().local_addr().unwrap();
+        tokio::spawn(async move { group.serve().await });
+
+        let redirected = get(http, "/login").await;
+        assert!(
+            redirected.starts_with("HTTP/1.1 308 Permanent Redirect"),
+            "{redirected}"
+        );
+        assert!(
+            redirected.contains("location: https://localhost:8443/login"),
+            "{redirected}"
+        );
+        assert!(
+            get(https, "/login")
+                .await
+                .starts_with("HTTP/1.1 404 Not Found")
+        );
+    }
+
+    #[tokio::test]
+    async fn verified_client_certificates_are_forwarded_in_a_header() {
+        use crate::config::{ClientAuth, Ssl};
+
+        // An upstream answering with the client certificate header it received
+        let upstream = upstream(
+            |request: hyper::Request<hyper::body::Incoming>| async move {
+                let client_cert = request
+                    .headers()
+                    .get("x-client-cert")
+                    .map_or("none".to_string(), |value| {
+                        value.to_str().unwrap().to_string()
+                    });
+                hyper::Response::new(client_cert)
+            },
+        )
+        .await;
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let ssl = Ssl {
+            certificate: format!("{fixtures}/upstream.pem"),
+            private_key: format!("{fixtures}/upstream.key"),
+            certificates: Vec::new(),
+            client_ca: Some(format!("{fixtures}/ca.pem")),
+            client_auth: ClientAuth::Require,
+            client_cert_header: None,
+            alpn_protocols: None,
+        };
+        let server = Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(vec![
+                service_to(&[upstream]).build().unwrap(),
+            ]))
+            .tls_acceptor(ssl.tls_acceptor().unwrap())
+            .client_cert_header(HeaderName::from_static("x-client-cert"))
+            .build()
+            .await
+            .unwrap();
+        let address = serve(server);
+
+        // Connects with a client certificate, returning the response or the handshake error
+        let get = async |client_cert: Option<&str>| -> std::io::Result<String> {
+            let mut roots = RootCertStore::empty();
+            for certificate in rustls_pemfile::certs(&mut &fixture("ca.pem")[..]) {
+                roots.add(certificate.unwrap()).unwrap();
+            }
+            let config = ClientConfig::builder().with_root_certificates(roots);
+            let config = match client_cert {
+                Some(name) => {
+                    let certificates =
+                        rustls_pemfile::certs(&mut &fixture(&format!("{name}.pem"))[..])
+                            .collect::<std::result::Result<Vec<_>, _>>()
+                            .unwrap();
+                    let key =
+                        rustls_pemfile::private_key(&mut &fixture(&format!("{name}.key"))[..])
+                            .unwrap()
+                            .unwrap();
+                    config.with_client_auth_cert(certificates, key).unwrap()
+                }
+                None => config.with_no_client_auth(),
+            };
+            let stream = TcpStream::connect(address).await?;
+            let server_name = ServerName::try_from("upstream.test").unwrap();
+            let mut stream = TlsConnector::from(Arc::new(config))
+                .connect(server_name, stream)
+                .await?;
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nx-client-cert: spoofed\r\nconnection: close\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok(response)
+        };
+
+        // The verified certificate replaces the header sent by the client
+        let response = get(Some("client")).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(
+            response.ends_with(r#"Subject="CN=client.test";DNS=client.test"#),
+            "{response}"
+        );
+        // Certificates not signed by the CA, and missing ones, are rejected
+        assert!(get(Some("untrusted")).await.is_err());
+        assert!(get(None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http2_is_negotiated_with_alpn() {
+        use crate::config::{ClientAuth, Ssl};
+        use http_body_util::{BodyExt as _, Empty};
+
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let ssl = Ssl {
+            certificate: format!("{fixtures}/upstream.pem"),
+            private_key: format!("{fixtures}/upstream.key"),
+            certificates: Vec::new(),
+            client_ca: None,
+            client_auth: ClientAuth::None,
+            client_cert_header: None,
+            alpn_protocols: None,
+        };
+        let server = Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(vec![
+                service_to(&[echo_upstream().await]).build().unwrap(),
+            ]))
+            .tls_acceptor(ssl.tls_acceptor().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let address = serve(server);
+
+        // Connects offering `protocols`
+        let connect = async |protocols: &[&[u8]]| {
+            let mut roots = RootCertStore::empty();
+            for certificate in rustls_pemfile::certs(&mut &fixture("ca.pem")[..]) {
+                roots.add(certificate.unwrap()).unwrap();
+            }
+            let mut config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+            let stream = TcpStream::connect(address).await.unwrap();
+            let server_name = ServerName::try_from("upstream.test").unwrap();
+            TlsConnector::from(Arc::new(config))
+                .connect(server_name, stream)
+                .await
+                .unwrap()
+        };
+
+        let stream = connect(&[b"h2", b"http/1.1"]).await;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), HyperSocket::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = hyper::Request::get("https://upstream.test/ok")
+            .body(Empty::<hyper::body::Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"/ok");
+
+        // Clients only speaking HTTP/1.1 fall back to it
+        let mut stream = connect(&[b"http/1.1"]).await;
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+        stream.write_all(REQUEST).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("/ok"), "{response}");
+
+        // Only the protocols the server speaks can be offered
+        let ssl = Ssl {
+            alpn_protocols: Some(vec!["h3".to_string()]),
+            ..ssl
+        };
+        assert!(matches!(ssl.tls_acceptor(), Err(BroxyError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn tls_connections_are_terminated() {
+        use crate::config::{ClientAuth, Ssl};
+        use std::sync::Mutex;
+        use tracing_subscriber::fmt::MakeWriter;
+
+        // A writer collecting the lines logged by the server
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for Logs {
+            type Writer = Self;
+
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let logs = Logs::default();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_ansi(false)
+                .finish(),
+        );
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let ssl = Ssl {
+            certificate: format!("{fixtures}/self-signed.pem"),
+            private_key: format!("{fixtures}/self-signed.key"),
+            certificates: Vec::new(),
+            client_ca: None,
+            client_auth: ClientAuth::None,
+            client_cert_header: None,
+            alpn_protocols: None,
+        };
+        let server = Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(vec![
+                service_to(&[echo_upstream().await]).build().unwrap(),
+            ]))
+            .tls_acceptor(ssl.tls_acceptor().unwrap())
+            .build()
+            .await
+            .unwrap();
+        let address = serve(server);
+
+        // Sends [`REQUEST`] trusting `root`, returning the response or the handshake error
+        let get = async |root: &str| -> std::io::Result<String> {
+            let mut roots = RootCertStore::empty();
+            for certificate in rustls_pemfile::certs(&mut &fixture(root)[..]) {
+                roots.add(certificate.unwrap()).unwrap();
+            }
+            let config = ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let stream = TcpStream::connect(address).await?;
+            let server_name = ServerName::try_from("self-signed.test").unwrap();
+            let mut stream = TlsConnector::from(Arc::new(config))
+                .connect(server_name, stream)
+                .await?;
+            stream.write_all(REQUEST).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok(response)
+        };
+
+        let response = get("self-signed.pem").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("/ok"), "{response}");
+
+        // A client not trusting the certificate aborts the handshake, which is logged
+        assert!(get("ca.pem").await.is_err());
+        let logged = async || {
+            for _ in 0..100 {
+                let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+                if logs.contains("failed to perform tls handshake") {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            false
+        };
+        assert!(logged().await);
+
+        // A plaintext client fails the handshake too
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(REQUEST).await.unwrap();
+        assert!(!read(client).await.contains("200 OK"));
+
+        // The listener keeps accepting connections
+        let response = get("self-signed.pem").await.unwrap();
+        assert!(response.ends_with("/ok"), "{response}");
+    }
+
+    /// Starts an upstream failing its health checks until the returned flag is set.
+    async fn flapping_upstream() -> (SocketAddr, Arc<AtomicBool>) {
+        let started = Arc::new(AtomicBool::new(false));
+        let upstream_started = started.clone();
+        let upstream = upstream(move |_| {
+            let status = match upstream_started.load(Ordering::Relaxed) {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            async move { empty_response(status) }
+        })
+        .await;
+        (upstream, started)
+    }
+
+    /// Balances requests across `upstreams`, checking their health every 20 milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// The load balancer and the task checking the upstreams.
+    fn health_checked(upstreams: &[SocketAddr]) -> (Arc<LoadBalancer>, JoinHandle<()>) {
+        let upstreams = upstreams
+            .iter()
+            .map(|address| Upstream::new(*address, false).with_health_check("/health"))
+            .collect();
+        let load_balancer = LoadBalancer::new(upstreams);
+        let checks = load_balancer.spawn_health_checks(HealthCheck {
+            interval: Duration::from_millis(20),
+            unhealthy_threshold: 1,
+            healthy_threshold: 1,
+            ..HealthCheck::default()
+        });
+        (Arc::new(load_balancer), checks)
+    }
+
+    /// Starts a server answering health probes in front of `service`.
+    ///
+    /// # Returns
+    ///
+    /// A function waiting until probing `path` answers `status`, panicking after 5 seconds.
+    async fn probed(service: Service) -> impl AsyncFn(&str, &str) {
+        let server = Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(ServiceBundle::new(vec![service]))
+            .health_endpoints(HealthEndpoints::default())
+            .build()
+            .await
+            .unwrap();
+        let address = serve(server);
+        async move |path: &str, status: &str| {
+            let wait = async {
+                while &get(address, path).await[9..12] != status {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), wait)
+                .await
+                .unwrap_or_else(|_| panic!("{path} never answered {status}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_health_of_the_upstreams() {
+        let (upstream, started) = flapping_upstream().await;
+        let (load_balancer, _checks) = health_checked(&[upstream]);
+        // A catch-all service, which would otherwise receive the probes
+        let service = Service::builder()
+            .load_balancer(load_balancer)
+            .build()
+            .unwrap();
+        let wait_for = probed(service).await;
+
+        // The upstream is marked down: the proxy is alive, but not ready
+        wait_for("/readyz", "503").await;
+        wait_for("/livez", "200").await;
+
+        // Ready once the upstream passes its health checks
+        started.store(true, Ordering::Relaxed);
+        wait_for("/readyz", "200").await;
+        wait_for("/livez", "200").await;
+    }
+
+    #[tokio::test]
+    async fn readiness_counts_the_canary_upstreams() {
+        let (stable, stable_started) = flapping_upstream().await;
+        let (canary, canary_started) = flapping_upstream().await;
+        let (stable, _stable_checks) = health_checked(&[stable]);
+        let (canary, _canary_checks) = health_checked(&[canary]);
+        let service = Service::builder()
+            .load_balancer(stable)
+            .canary(Canary::new(canary, 0.1))
+            .build()
+            .unwrap();
+        let wait_for = probed(service).await;
+
+        wait_for("/readyz", "503").await;
+        // Either group serves requests once it's up
+        canary_started.store(true, Ordering::Relaxed);
+        wait_for("/readyz", "200").await;
+        canary_started.store(false, Ordering::Relaxed);
+        wait_for("/readyz", "503").await;
+        stable_started.store(true, Ordering::Relaxed);
+        wait_for("/readyz", "200").await;
+    }
 }
@@ -4,47 +4,147 @@
 //! filtering, middleware application, and upstream forwarding. It provides both individual
 //! service instances and service bundles for routing requests.
 
-use std::{net::SocketAddr, pin::Pin, str::FromStr as _};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    iter::Peekable,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr as _,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use http::{Request, Response, StatusCode, request::Parts};
-use http_body_util::{BodyExt as _, Empty, Full, combinators::BoxBody};
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+    request::Parts,
+    uri::{Authority, PathAndQuery, Scheme},
+};
+use http_body_util::{BodyExt as _, Empty, Full, Limited, StreamBody, combinators::BoxBody};
 use hyper::{
-    body::{Body as _, Bytes, Incoming},
-    client::conn::http1::Builder,
+    body::{Body as _, Bytes, Frame, Incoming, SizeHint},
+    client::conn::{http1::Builder, http2},
     service::Service as HyperService,
 };
-use hyper_util::rt::TokioIo as HyperSocket;
+use hyper_util::rt::{TokioExecutor, TokioIo as HyperSocket, TokioTimer};
 use rayon::iter::{IntoParallelRefIterator as _, ParallelIterator as _};
-use tokio::net::TcpStream;
-use tracing::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+    net::TcpStream,
+    sync::watch,
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{Instrument as _, Span, debug, error, field, info, info_span, warn};
 
 use crate::{
-    filter::{BodyFilter, BodyFilters, Filter},
+    access_log::AccessLog,
+    cache::{Flight, ResponseCache},
+    canary::Canary,
+    connection_pool::{ConnectionPool, RequestBody, Sender},
+    error::BroxyError,
+    filter::{self, BodyFilter, Filter, FilterOutcome},
+    grpc,
     load_balancer::LoadBalancer,
-    middleware::Middleware,
-    upstream::Upstream,
+    metrics::{MetricsHook, PendingSummary, RequestSummary},
+    middleware::{Middleware, MiddlewareAction, OriginalRequest},
+    mirror::Mirror,
+    retry_budget::RetryBudget,
+    server::{HealthEndpoints, Lifecycle},
+    upstream::{HttpVersion, RequestGuard, Upstream},
+    utils,
 };
 
+/// Response type produced by services and sent back to the client.
+pub type ProxyResponse = Response<BoxBody<Bytes, hyper::Error>>;
+
+/// Boxed future resolving to the response of a processed request.
+pub type ProcessFuture = Pin<Box<dyn Future<Output = Result<ProxyResponse, BroxyError>> + Send>>;
+
 /// Function type for processing HTTP requests.
 ///
 /// This type alias defines the signature for request processing functions
 /// that take a service reference, upstream configuration, request parts,
-/// and incoming body, returning a future that resolves to a response.
-type ProcessFunction = fn(
-    &Service,
-    Upstream,
-    &SocketAddr,
-    http::request::Parts,
-    Incoming,
-) -> Pin<
-    Box<dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>> + Send>,
->;
+/// incoming body and maximum body size, returning a future that resolves to a response.
+type ProcessFunction =
+    fn(&Service, Arc<Upstream>, &SocketAddr, http::request::Parts, Incoming, u64) -> ProcessFuture;
+
+/// Default maximum size of a request body accepted by a service, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 64;
+
+/// Default name of the sticky session cookie.
+pub const DEFAULT_STICKY_COOKIE: &str = "BROXY_UPSTREAM";
+
+/// Default maximum size of a buffered upstream response body, in bytes.
+pub const DEFAULT_MAX_RESPONSE_BODY_SIZE: u64 = 1024 * 1024 * 16;
+
+/// Default amount of header filters above which they're evaluated in parallel, see
+/// [`ServiceBuilder::parallel_filter_threshold`].
+pub const DEFAULT_PARALLEL_FILTER_THRESHOLD: usize = 5;
+
+/// Methods of the requests that may be sent again by default, see
+/// [`ServiceBuilder::retry_methods`].
+pub const DEFAULT_RETRY_METHODS: [Method; 5] = [
+    Method::GET,
+    Method::HEAD,
+    Method::PUT,
+    Method::DELETE,
+    Method::OPTIONS,
+];
+
+/// Header marking a request as safe to send again, whatever its method.
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Action taken when a buffered upstream response body exceeds the configured maximum size.
+///
+/// # Example
+///
+/// Streaming responses too large to be compressed:
+///
+/// ```
+/// use std::sync::Arc;
+/// use broxy_core::{
+///     compression,
+///     load_balancer::LoadBalancer,
+///     middleware::{Middleware, MiddlewareOutgoingFunction},
+///     service::{ResponseBodyOverflow, Service},
+///     upstream::Upstream,
+/// };
+///
+/// let middleware = Middleware::new(
+///     vec![],
+///     vec![MiddlewareOutgoingFunction::InternalWithBody(compression::gzip)],
+/// );
+/// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+/// let service = Service::builder()
+///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+///     .middleware(middleware)
+///     .max_response_body_size(64 * 1024)
+///     .response_body_overflow(ResponseBodyOverflow::Stream)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseBodyOverflow {
+    /// Abort and return `502 Bad Gateway` to the client
+    #[default]
+    Reject,
+    /// Forward only the first bytes of the body, up to the maximum size
+    Truncate,
+    /// Skip the middleware needing the body, and stream the body through unchanged
+    ///
+    /// The response is still held until the maximum size is reached or the body ends,
+    /// so large bodies keep flowing with a bounded memory use, at the cost of not being
+    /// processed. Lower maximum sizes get the first bytes to the client sooner.
+    Stream,
+}
 
 /// Function type for generating "not found" responses.
 ///
 /// This type alias defines the signature for functions that generate
 /// custom response bodies when no matching service is found.
-type BodyNotFoundFunction = fn(&SocketAddr, &[u8]) -> Response<BoxBody<Bytes, hyper::Error>>;
+pub type BodyNotFoundFunction = fn(&SocketAddr, &[u8]) -> ProxyResponse;
 
 /// A service that handles HTTP requests with filtering, middleware, and upstream forwarding.
 ///
@@ -55,18 +155,57 @@ type BodyNotFoundFunction = fn(&SocketAddr, &[u8]) -> Response<BoxBody<Bytes, hy
 pub struct Service {
     /// Request header filters for matching requests
     filters: Vec<Filter>,
-    /// Request body filters for content-based filtering
-    body_filters: Vec<BodyFilter>,
+    /// Request body filters for content-based filtering, shared with the requests
+    /// being processed
+    body_filters: Arc<[BodyFilter]>,
     /// Optional middleware for request/response processing
     middleware: Option<Middleware>,
-    /// Upstream server configuration
-    load_balancer: *const LoadBalancer,
+    /// Load balancer selecting the upstream server, shared with other services
+    load_balancer: Arc<LoadBalancer>,
+    /// Optional canary upstream group receiving a share of the requests
+    canary: Option<Arc<Canary>>,
     /// Optional custom "not found" response generator, when a body filtered out
     not_found_body_response: Option<BodyNotFoundFunction>,
+    /// Maximum size of a request body, larger requests are rejected with PAYLOAD_TOO_LARGE,
+    /// the bundle-wide default applies when not set
+    max_body_size: Option<u64>,
+    /// Size above which a buffered request body is written to a temporary file,
+    /// bodies are kept in memory when not set
+    spill_threshold: Option<u64>,
+    /// Maximum size of a buffered upstream response body
+    max_response_body_size: u64,
+    /// What to do with buffered upstream responses larger than `max_response_body_size`
+    response_body_overflow: ResponseBodyOverflow,
+    /// Optional mirror receiving a copy of a sample of the requests
+    mirror: Option<Arc<Mirror>>,
+    /// Optional cache of upstream responses
+    cache: Option<Arc<ResponseCache>>,
+    /// Name of the cookie pinning clients to an upstream, when sticky sessions are enabled
+    sticky_cookie: Option<String>,
+    /// Whether the `X-Forwarded-*` headers are added to requests
+    forwarded_headers: bool,
+    /// Whether failed gRPC calls are answered with a gRPC status
+    grpc: bool,
+    /// Pool of idle upstream connections, a new connection is opened per request when not set
+    connection_pool: Option<Arc<ConnectionPool>>,
+    /// Amount of times a request failing to connect is retried on another upstream
+    retries: u32,
+    /// Delay before the first retry, doubled for every following one
+    retry_backoff: Duration,
+    /// Upstream response statuses retried on another upstream
+    retry_on_status: Arc<HashSet<StatusCode>>,
+    /// Methods of the requests that may be sent again
+    retry_methods: Arc<HashSet<Method>>,
+    /// Optional limit on the share of requests retried
+    retry_budget: Option<Arc<RetryBudget>>,
+    /// Time the upstream has to answer a request, unlimited when not set
+    upstream_timeout: Option<Duration>,
+    /// Optional access log of the processed requests
+    access_log: Option<Arc<AccessLog>>,
     /// Function pointer to the appropriate processing method
     _process: ProcessFunction,
     /// Function pointer to the appropriate filtering method
-    _filter: fn(&Service, &SocketAddr, header: &Parts) -> anyhow::Result<bool>,
+    _filter: fn(&Service, &SocketAddr, header: &Parts) -> Result<bool, BroxyError>,
 }
 
 impl Service {
@@ -81,7 +220,7 @@ impl Service {
     /// * `filters` - Request header filters for matching requests
     /// * `body_filters` - Request body filters for content-based filtering
     /// * `middleware` - Optional middleware for request/response processing
-    /// * `upstream` - Upstream server configuration
+    /// * `load_balancer` - Load balancer selecting the upstream server
     /// * `not_found_body_response` - Optional custom "not found" response generator
     ///
     /// # Returns
@@ -91,39 +230,121 @@ impl Service {
         filters: Vec<Filter>,
         body_filters: Vec<BodyFilter>,
         middleware: Option<Middleware>,
-        load_balancer: *const LoadBalancer,
+        load_balancer: Arc<LoadBalancer>,
         not_found_body_response: Option<BodyNotFoundFunction>,
     ) -> Self {
+        Self::from_builder(
+            ServiceBuilder {
+                filters,
+                body_filters,
+                middleware,
+                not_found_body_response,
+                ..Default::default()
+            },
+            load_balancer,
+        )
+    }
+
+    /// Creates a service from the options collected by a `ServiceBuilder`.
+    fn from_builder(builder: ServiceBuilder, load_balancer: Arc<LoadBalancer>) -> Self {
+        let ServiceBuilder {
+            filters,
+            body_filters,
+            middleware,
+            not_found_body_response,
+            max_body_size,
+            spill_threshold,
+            max_response_body_size,
+            response_body_overflow,
+            mirror,
+            cache,
+            canary,
+            sticky_cookie,
+            skip_forwarded_headers,
+            grpc,
+            connection_pool,
+            retries,
+            retry_backoff,
+            retry_on_status,
+            retry_methods,
+            retry_budget,
+            upstream_timeout,
+            access_log,
+            parallel_filter_threshold,
+            ..
+        } = builder;
+
+        // Cache hits are served before the incoming middleware runs, so they would
+        // skip the middleware refusing or rewriting requests, e.g. basic authentication
+        let cache = match cache {
+            Some(_) if middleware.as_ref().is_some_and(Middleware::has_incoming) => {
+                warn!("Response caching disabled, the service has incoming middleware");
+                None
+            }
+            cache => cache,
+        };
+
         let amount_of_filters = filters.len();
         let has_body_filters = !body_filters.is_empty();
         let has_middleware = middleware.is_some();
         let needs_body = has_body_filters
-            || (has_middleware && middleware.as_ref().unwrap().incoming_needs_body);
+            || mirror.is_some()
+            || spill_threshold.is_some()
+            || middleware.as_ref().is_some_and(|middleware| {
+                middleware.incoming_needs_body || middleware.out_needs_body
+            });
 
         debug!(
-            "Creating service with {} filters, {} body filters, middleware: {}, needs_body: {}",
+            "Creating service with {} filters, {} body filters, middleware: {}, mirror: {}, needs_body: {}",
             amount_of_filters,
             body_filters.len(),
             has_middleware,
+            mirror.is_some(),
             needs_body
         );
 
         Self {
             filters,
             load_balancer,
-            _process: if has_middleware {
-                if needs_body {
-                    Service::process_with_body
-                } else {
-                    Service::process_without_body_with_middleware
-                }
+            _process: if needs_body {
+                Service::process_with_body
+            } else if has_middleware {
+                Service::process_without_body_with_middleware
             } else {
                 Self::process_without_body_without_middleware
             },
             middleware,
-            body_filters,
+            body_filters: body_filters.into(),
             not_found_body_response,
-            _filter: if amount_of_filters > 5 {
+            max_body_size,
+            spill_threshold,
+            max_response_body_size: max_response_body_size
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BODY_SIZE),
+            response_body_overflow,
+            mirror: mirror.map(|mirror| {
+                Arc::new(match upstream_timeout {
+                    Some(upstream_timeout) => mirror.with_timeout(upstream_timeout),
+                    None => mirror,
+                })
+            }),
+            cache: cache.map(Arc::new),
+            canary: canary.map(Arc::new),
+            sticky_cookie,
+            forwarded_headers: !skip_forwarded_headers,
+            grpc,
+            connection_pool,
+            retries,
+            retry_backoff,
+            retry_on_status: Arc::new(retry_on_status),
+            retry_methods: Arc::new(
+                retry_methods.unwrap_or_else(|| HashSet::from(DEFAULT_RETRY_METHODS)),
+            ),
+            retry_budget,
+            upstream_timeout,
+            access_log,
+            _filter: if amount_of_filters
+                > parallel_filter_threshold.unwrap_or(DEFAULT_PARALLEL_FILTER_THRESHOLD)
+            {
                 Service::filter_parallel_header
             } else {
                 Service::filter_sequential_header
@@ -131,20 +352,78 @@ impl Service {
         }
     }
 
+    /// Creates a builder for configuring a new service.
+    ///
+    /// # Returns
+    ///
+    /// Returns an empty `ServiceBuilder`.
+    pub fn builder() -> ServiceBuilder {
+        ServiceBuilder::default()
+    }
+
     /// Returns a reference to the upstream configuration for this service.
     ///
+    /// # Arguments
+    ///
+    /// * `from` - Address of the client
+    ///
+    /// # Returns
+    ///
+    /// Returns the selected `Upstream` configuration, from the canary upstream group
+    /// if the request is split to it.
+    pub fn get_upstream(&self, from: &SocketAddr) -> Arc<Upstream> {
+        match &self.canary {
+            Some(canary) if canary.selects(from) => {
+                debug!("Request split to the canary upstream group");
+                canary.load_balancer().get_upstream(from)
+            }
+            _ => self.load_balancer.get_upstream(from),
+        }
+    }
+
+    /// Selects the upstream server for a request.
+    ///
+    /// When sticky sessions are enabled and the request carries the sticky cookie
+    /// pointing at a known upstream, that upstream is used. Otherwise the load
+    /// balancer strategy picks one, see [`Service::get_upstream`].
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - Address of the client
+    /// * `header` - The HTTP request header parts
+    ///
     /// # Returns
     ///
-    /// Returns a reference to the `Upstream` configuration.
-    pub fn get_upstream(&self) -> &Upstream {
-        unsafe { &*(*self.load_balancer).get_upstream() }
+    /// Returns the selected `Upstream`.
+    pub fn select_upstream(&self, from: &SocketAddr, header: &Parts) -> Arc<Upstream> {
+        let pinned = self
+            .sticky_cookie
+            .as_ref()
+            .and_then(|name| utils::find_cookie(&header.headers, name))
+            .and_then(|value| u64::from_str_radix(value, 16).ok())
+            .and_then(|id| {
+                self.load_balancer.find_upstream(id).or_else(|| {
+                    self.canary
+                        .as_ref()
+                        .and_then(|canary| canary.load_balancer().find_upstream(id))
+                })
+            });
+
+        match pinned {
+            Some(upstream) if upstream.is_available() => {
+                debug!("Request pinned to upstream by sticky cookie");
+                upstream
+            }
+            _ => self.get_upstream(from),
+        }
     }
 
     /// Filters a request by its header information.
     ///
     /// This method applies all configured header filters to determine if the request
     /// should be processed by this service. The filtering strategy (sequential vs parallel)
-    /// is automatically selected based on the number of filters.
+    /// is selected based on the number of filters, see
+    /// [`ServiceBuilder::parallel_filter_threshold`].
     ///
     /// # Arguments
     ///
@@ -159,7 +438,7 @@ impl Service {
         &self,
         from: &SocketAddr,
         header: &Parts,
-    ) -> anyhow::Result<bool> {
+    ) -> Result<bool, BroxyError> {
         let result = (self._filter)(self, from, header);
         match &result {
             Ok(matched) => debug!("Header filter result: {}", matched),
@@ -168,26 +447,33 @@ impl Service {
         result
     }
 
-    /// Creates a raw body filters structure for FFI integration.
-    ///
-    /// This method creates a `BodyFilters` struct that can be safely passed to external code.
-    ///
-    /// # Returns
-    ///
-    /// Returns a `BodyFilters` struct containing raw pointers to the body filters.
-    ///
-    /// # Panics
-    ///
-    /// Panics if called on a service with no body filters.
-    fn get_body_filters_raw(&self) -> BodyFilters {
-        BodyFilters {
-            filters: self
-                .body_filters
-                .first()
-                .expect("`get_body_filters_raw` was called with no body filters")
-                as *const _,
-            len: self.body_filters.len(),
-        }
+    /// Checks whether the service has an upstream to send requests to, in its primary
+    /// or canary upstream group, ignoring their concurrency limits.
+    fn has_healthy_upstream(&self) -> bool {
+        let healthy = |load_balancer: &LoadBalancer| {
+            load_balancer
+                .upstreams()
+                .iter()
+                .any(|upstream| upstream.healthy)
+        };
+        healthy(&self.load_balancer)
+            || self
+                .canary
+                .as_ref()
+                .is_some_and(|canary| healthy(canary.load_balancer()))
+    }
+
+    /// Returns the length of the longest [`Filter::PathPrefix`] of the service, `0`
+    /// without one.
+    fn path_prefix_len(&self) -> usize {
+        self.filters
+            .iter()
+            .filter_map(|filter| match filter {
+                Filter::PathPrefix(prefix) => Some(prefix.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
     }
 
     /// Filters a request body using the provided body filters.
@@ -198,19 +484,19 @@ impl Service {
     /// # Arguments
     ///
     /// * `body_filters` - The body filters to apply
-    /// * `body` - The request body as bytes
+    /// * `body` - The request body, rewriting filters modify it in place
     ///
     /// # Returns
     ///
-    /// Returns `Ok(true)` if the body passes all filters, `Ok(false)` if it's rejected,
-    /// or an error if filtering fails.
+    /// Returns `FilterOutcome::Pass` if the body passes all filters, the outcome of the
+    /// first filter rejecting it otherwise, or an error if filtering fails.
     #[inline]
     // TODO: for now we assume that `BodyFilter::InternalIncoming` never used
     pub fn filter_request_by_body(
         body_filters: &[BodyFilter],
         from: &SocketAddr,
-        body: &[u8],
-    ) -> anyhow::Result<bool> {
+        body: &mut Vec<u8>,
+    ) -> Result<FilterOutcome, BroxyError> {
         debug!(
             "Filtering request body with {} filters, body size: {} bytes",
             body_filters.len(),
@@ -218,12 +504,13 @@ impl Service {
         );
 
         for (i, filter) in body_filters.iter().enumerate() {
-            match filter.filter(from, body) {
-                Ok(passed) => {
-                    debug!("Body filter {} result: {}", i, passed);
-                    if !passed {
-                        return Ok(false);
-                    }
+            match filter.apply(from, body) {
+                Ok(FilterOutcome::Pass) => {
+                    debug!("Body filter {} passed", i);
+                }
+                Ok(outcome) => {
+                    debug!("Body filter {} rejected request", i);
+                    return Ok(outcome);
                 }
                 Err(e) => {
                     error!("Body filter {} error: {}", i, e);
@@ -232,13 +519,13 @@ impl Service {
             }
         }
         debug!("All body filters passed");
-        Ok(true)
+        Ok(FilterOutcome::Pass)
     }
 
     /// Filters requests sequentially using all configured header filters.
     ///
     /// This method processes filters one by one, stopping at the first filter that
-    /// doesn't match. It's used when there are few filters (≤5) for better performance.
+    /// doesn't match. It's used when there are few filters (≤5 by default) for better performance.
     ///
     /// # Arguments
     ///
@@ -253,7 +540,7 @@ impl Service {
         service: &Service,
         from: &SocketAddr,
         header: &Parts,
-    ) -> anyhow::Result<bool> {
+    ) -> Result<bool, BroxyError> {
         debug!(
             "Running sequential header filtering with {} filters",
             service.filters.len()
@@ -280,7 +567,8 @@ impl Service {
     /// Filters requests in parallel using all configured header filters.
     ///
     /// This method processes filters in parallel using rayon, which is more efficient
-    /// when there are many filters (>5). It stops at the first filter that doesn't match.
+    /// when there are many filters (>5 by default). The result is the one of the first filter, in
+    /// order, that doesn't match or fails, as with sequential filtering.
     ///
     /// # Arguments
     ///
@@ -295,7 +583,7 @@ impl Service {
         service: &Service,
         from: &SocketAddr,
         header: &Parts,
-    ) -> anyhow::Result<bool> {
+    ) -> Result<bool, BroxyError> {
         debug!(
             "Running parallel header filtering with {} filters",
             service.filters.len()
@@ -304,20 +592,15 @@ impl Service {
         let result = service
             .filters
             .par_iter()
-            .find_map_any(|filter| match filter.filter(from, header) {
-                Ok(f) => {
-                    if f {
-                        None
-                    } else {
-                        Some(())
-                    }
-                }
-                Err(_) => Some(()),
+            .find_map_first(|filter| match filter.filter(from, header) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok(false)),
+                Err(e) => Some(Err(e)),
             })
-            .is_none();
+            .unwrap_or(Ok(true));
 
-        debug!("Parallel filter result: {}", result);
-        Ok(result)
+        debug!("Parallel filter result: {:?}", result);
+        result
     }
 
     /// Processes an HTTP request through this service.
@@ -331,58 +614,241 @@ impl Service {
     /// * `upstream` - The upstream server configuration to forward requests to
     /// * `header` - The HTTP request header parts
     /// * `body` - The incoming HTTP body stream
+    /// * `max_body_size` - Maximum size of the request body, enforced while it's read
     ///
     /// # Returns
     ///
-    /// Returns a future that resolves to the HTTP response from the upstream server.
+    /// Returns a future that resolves to the HTTP response from the upstream server,
+    /// `502 Bad Gateway` if the upstream couldn't be reached or failed to answer,
+    /// `503 Service Unavailable` if the upstream is at its concurrency limit, or
+    /// `504 Gateway Timeout` if it didn't answer within the upstream timeout, or
+    /// the matching gRPC status for gRPC calls when enabled, see [`ServiceBuilder::grpc`].
+    /// Errors are left for failures of the proxy itself, e.g. a failing middleware.
+    /// The upstream's runtime counters are updated once the future completes,
+    /// and the request is written to the access log once its response was sent.
     #[inline]
     pub fn process(
         &self,
-        upstream: Upstream,
+        upstream: Arc<Upstream>,
         from: &SocketAddr,
         header: http::request::Parts,
         body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
-        (self._process)(self, upstream, from, header, body)
+        max_body_size: u64,
+    ) -> ProcessFuture {
+        let grpc = self.grpc && grpc::is_grpc(&header.headers);
+        let entry = self
+            .access_log
+            .as_ref()
+            .map(|access_log| access_log.start(from, &header, upstream.authority()));
+        let mut future = self.process_upstream(upstream, from, header, body, max_body_size);
+        if grpc {
+            future = grpc::answer_failures(future);
+        }
+        match entry {
+            Some(entry) => Box::pin(async move { entry.finish(future.await) }),
+            None => future,
+        }
+    }
+
+    /// Processes an HTTP request through this service, see [`Service::process`].
+    fn process_upstream(
+        &self,
+        upstream: Arc<Upstream>,
+        from: &SocketAddr,
+        mut header: http::request::Parts,
+        body: Incoming,
+        max_body_size: u64,
+    ) -> ProcessFuture {
+        let mut cache = self.cache.clone().zip(ResponseCache::request(&header));
+        let flight = cache
+            .as_ref()
+            .and_then(|(cache, request)| cache.join_flight(request));
+        // Requests waiting for an identical one only reach the upstream if its
+        // response couldn't be cached
+        let (flight, leader) = match flight {
+            Some(Flight::Leader(flight)) => (Some(flight), None),
+            Some(Flight::Follower(leader)) => (None, Some(leader)),
+            None => (None, None),
+        };
+        let guard = match leader {
+            Some(_) => None,
+            None => match upstream.stats.try_begin_request() {
+                Some(guard) => Some(guard),
+                None => return Self::unavailable(&upstream),
+            },
+        };
+        let set_cookie = self.sticky_cookie.as_ref().and_then(|name| {
+            let id = format!("{:016x}", upstream.id());
+            (utils::find_cookie(&header.headers, name) != Some(id.as_str()))
+                .then(|| format!("{name}={id}; Path=/; HttpOnly"))
+                .and_then(|cookie| HeaderValue::from_str(&cookie).ok())
+        });
+        if let Some((cache, request)) = &mut cache {
+            cache.revalidate(request, &mut header);
+        }
+        let target = upstream.clone();
+        let future = (self._process)(self, upstream, from, header, body, max_body_size);
+        Box::pin(async move {
+            // Dropped once the response is stored, letting the identical requests through
+            let _flight = flight;
+            let mut guard = match (guard, leader) {
+                (Some(guard), _) => guard,
+                (None, leader) => {
+                    if let Some(leader) = leader {
+                        leader.finished().await;
+                    }
+                    if let Some((cache, request)) = &cache
+                        && let Some(response) = cache.lookup(request)
+                    {
+                        debug!("Request coalesced with an identical one");
+                        return Ok(response);
+                    }
+                    match target.stats.try_begin_request() {
+                        Some(guard) => guard,
+                        None => return Self::unavailable(&target).await,
+                    }
+                }
+            };
+            let result = future.await;
+            match &result {
+                // The first upstream failed, the response comes from a retry
+                Ok(response) if let Some(retried) = response.extensions().get::<Retried>() => {
+                    if retried.first_connect_failed {
+                        guard.connect_failed(&target);
+                    } else {
+                        guard.connected(&target);
+                        guard.fail();
+                    }
+                }
+                // Answered by the first upstream, rather than by middleware or the proxy
+                Ok(response) if response.extensions().get::<Forwarded>().is_some() => {
+                    guard.connected(&target)
+                }
+                Ok(_) => {}
+                Err(BroxyError::UpstreamConnect { .. } | BroxyError::Handshake(_)) => {
+                    guard.connect_failed(&target)
+                }
+                Err(_) => {}
+            }
+            match &result {
+                Ok(response) if !response.status().is_server_error() => {}
+                _ => guard.fail(),
+            }
+            let result = match result {
+                Err(BroxyError::UpstreamTimeout { address }) => {
+                    warn!("Upstream {} timed out, returning GATEWAY_TIMEOUT", address);
+                    return Ok(empty_response(StatusCode::GATEWAY_TIMEOUT));
+                }
+                Err(
+                    e @ (BroxyError::UpstreamConnect { .. }
+                    | BroxyError::Handshake(_)
+                    | BroxyError::Upstream(_)),
+                ) => {
+                    warn!("{}, returning BAD_GATEWAY", e);
+                    return Ok(empty_response(StatusCode::BAD_GATEWAY));
+                }
+                result => result,
+            };
+            let mut result = match (result, cache) {
+                (Ok(response), Some((cache, request))) => cache
+                    .store(request, response)
+                    .await
+                    .map_err(BroxyError::Body),
+                (result, _) => result,
+            };
+            if let (Ok(response), Some(set_cookie)) = (&mut result, set_cookie) {
+                response
+                    .headers_mut()
+                    .append(http::header::SET_COOKIE, set_cookie);
+            }
+            result
+        })
+    }
+
+    /// Answers a request to an upstream at its concurrency limit.
+    fn unavailable(upstream: &Upstream) -> ProcessFuture {
+        warn!(
+            "Upstream {} is at its concurrency limit, returning SERVICE_UNAVAILABLE",
+            upstream.address
+        );
+        Box::pin(async { Ok(empty_response(StatusCode::SERVICE_UNAVAILABLE)) })
+    }
+
+    /// Looks up a cached response for the request, if caching is enabled.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The HTTP request header parts
+    ///
+    /// # Returns
+    ///
+    /// Returns the cached response on a hit, or `None` if the upstream has to be contacted.
+    pub fn cached_response(&self, header: &Parts) -> Option<ProxyResponse> {
+        let cache = self.cache.as_ref()?;
+        cache.lookup(&ResponseCache::request(header)?)
+    }
+
+    /// Returns the retry count and the delay before the first retry.
+    pub fn retries(&self) -> (u32, Duration) {
+        (self.retries, self.retry_backoff)
+    }
+
+    /// Returns the budget limiting the share of requests retried, if any.
+    pub fn retry_budget(&self) -> Option<&Arc<RetryBudget>> {
+        self.retry_budget.as_ref()
+    }
+
+    /// Creates the forwarder sending the requests of a client to the upstreams.
+    ///
+    /// Retries stay within the upstream group of the selected upstream.
+    fn forwarder(&self, from: &SocketAddr, upstream: &Upstream) -> Forwarder {
+        let load_balancer = match &self.canary {
+            Some(canary) if canary.contains(upstream) => canary.load_balancer(),
+            _ => &self.load_balancer,
+        };
+        Forwarder {
+            load_balancer: load_balancer.clone(),
+            from: *from,
+            connection_pool: self.connection_pool.clone(),
+            retries: self.retries,
+            retry_backoff: self.retry_backoff,
+            retry_on_status: self.retry_on_status.clone(),
+            retry_methods: self.retry_methods.clone(),
+            retry_budget: self.retry_budget.clone(),
+            upstream_timeout: self.upstream_timeout,
+        }
     }
 
     fn process_without_body_without_middleware(
-        _: &Service,
-        upstream: Upstream,
-        _: &SocketAddr,
+        service: &Service,
+        upstream: Arc<Upstream>,
+        from: &SocketAddr,
         header: http::request::Parts,
         body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
+        max_body_size: u64,
+    ) -> ProcessFuture {
         debug!(
             "Processing request without body and without middleware to upstream: {:?}",
             upstream
         );
 
-        Self::process_without_body_internal(upstream, header, body)
+        Self::process_without_body_internal(
+            service.forwarder(from, &upstream),
+            upstream,
+            header,
+            body,
+            max_body_size,
+        )
     }
 
     fn process_without_body_with_middleware(
         service: &Service,
-        upstream: Upstream,
+        upstream: Arc<Upstream>,
         from: &SocketAddr,
         mut header: http::request::Parts,
         body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
+        max_body_size: u64,
+    ) -> ProcessFuture {
         debug!(
             "Processing request without body and without middleware to upstream: {:?}",
             upstream
@@ -390,87 +856,47 @@ impl Service {
 
         let middleware = unsafe { service.middleware.clone().unwrap_unchecked() };
         debug!("Applying middleware to request");
-        if let Err(e) = middleware.process_incoming(from, &mut header, None) {
-            error!("Middleware processing error: {}", e);
-            return Box::pin(async {
-                let mut response = Response::new(
-                    Empty::<Bytes>::new()
-                        .map_err(|never| match never {})
-                        .boxed(),
-                );
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                Ok(response)
-            });
+        match middleware.process_incoming(from, &upstream.address, &mut header, None) {
+            Ok(MiddlewareAction::Continue) => {}
+            Ok(MiddlewareAction::Respond(response)) => {
+                debug!("Middleware answered the request");
+                return Box::pin(async { Ok(response) });
+            }
+            Err(e) => {
+                error!("Middleware processing error: {}", e);
+                return Box::pin(async { Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR)) });
+            }
         }
         debug!("Middleware processing completed successfully");
 
         Self::process_without_body_with_middleware_internal(
-            middleware, upstream, from, header, body,
+            middleware,
+            service.forwarder(from, &upstream),
+            upstream,
+            from,
+            header,
+            body,
+            max_body_size,
         )
     }
 
     #[inline(always)]
     fn process_without_body_internal(
-        upstream: Upstream,
+        forwarder: Forwarder,
+        upstream: Arc<Upstream>,
         header: http::request::Parts,
         body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
+        max_body_size: u64,
+    ) -> ProcessFuture {
         Box::pin(async move {
-            debug!("Connecting to upstream: {}", upstream.address);
-            let stream = match TcpStream::connect(upstream.address).await {
-                Ok(stream) => {
-                    debug!("Successfully connected to upstream");
-                    stream
-                }
-                Err(e) => {
-                    error!("Failed to connect to upstream {}: {}", upstream.address, e);
-                    return Err(e.into());
-                }
-            };
-
-            let io = HyperSocket::new(stream);
-
-            debug!("Performing HTTP handshake");
-            let (mut sender, conn) = match Builder::new()
-                .preserve_header_case(true)
-                .title_case_headers(true)
-                .handshake(io)
-                .await
-            {
-                Ok(result) => {
-                    debug!("HTTP handshake successful");
-                    result
-                }
-                Err(e) => {
-                    error!("HTTP handshake failed: {}", e);
-                    return Err(e.into());
-                }
-            };
-
-            tokio::task::spawn(async move {
-                if let Err(err) = conn.await {
-                    error!("Connection error: {}", err);
-                }
-            });
-
-            let request = Request::from_parts(header, body);
-            debug!("Sending request to upstream");
-
-            let (header, body) = match sender.send_request(request).await {
-                Ok(response) => {
-                    debug!("Request sent successfully, received response");
-                    response.into_parts()
-                }
-                Err(e) => {
-                    error!("Failed to send request: {}", e);
-                    return Err(e.into());
-                }
-            };
+            let replay = (forwarder.can_replay(&header.method, &header.headers)
+                && body.is_end_stream())
+            .then(Replay::default);
+            let request = Request::from_parts(header, limited_body(body, max_body_size));
+            let (header, body) = forwarder
+                .send(&upstream, request, replay, forwarder.deadline())
+                .await?
+                .into_parts();
 
             let response = Response::from_parts(header, body.boxed());
             debug!("Response created successfully");
@@ -481,80 +907,35 @@ impl Service {
     #[inline(always)]
     fn process_without_body_with_middleware_internal(
         middleware: Middleware,
-        upstream: Upstream,
+        forwarder: Forwarder,
+        upstream: Arc<Upstream>,
         from: &SocketAddr,
         header: http::request::Parts,
         body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
+        max_body_size: u64,
+    ) -> ProcessFuture {
         let from = *from;
+        let original = middleware
+            .has_outgoing()
+            .then(|| OriginalRequest::new(&header));
         Box::pin(async move {
-            debug!("Connecting to upstream: {}", upstream.address);
-            let stream = match TcpStream::connect(upstream.address).await {
-                Ok(stream) => {
-                    debug!("Successfully connected to upstream");
-                    stream
-                }
-                Err(e) => {
-                    error!("Failed to connect to upstream {}: {}", upstream.address, e);
-                    return Err(e.into());
-                }
-            };
-
-            let io = HyperSocket::new(stream);
-
-            debug!("Performing HTTP handshake");
-            let (mut sender, conn) = match Builder::new()
-                .preserve_header_case(true)
-                .title_case_headers(true)
-                .handshake(io)
-                .await
-            {
-                Ok(result) => {
-                    debug!("HTTP handshake successful");
-                    result
-                }
-                Err(e) => {
-                    error!("HTTP handshake failed: {}", e);
-                    return Err(e.into());
-                }
-            };
-
-            tokio::task::spawn(async move {
-                if let Err(err) = conn.await {
-                    error!("Connection error: {}", err);
-                }
-            });
-
-            let request = Request::from_parts(header, body);
-            debug!("Sending request to upstream");
-
-            let (mut header, body) = match sender.send_request(request).await {
-                Ok(response) => {
-                    debug!("Request sent successfully, received response");
-                    response.into_parts()
-                }
-                Err(e) => {
-                    error!("Failed to send request: {}", e);
-                    return Err(e.into());
-                }
-            };
+            let replay = (forwarder.can_replay(&header.method, &header.headers)
+                && body.is_end_stream())
+            .then(Replay::default);
+            let request = Request::from_parts(header, limited_body(body, max_body_size));
+            let (mut header, body) = forwarder
+                .send(&upstream, request, replay, forwarder.deadline())
+                .await?
+                .into_parts();
+            if let Some(original) = original {
+                header.extensions.insert(original);
+            }
 
             debug!("Applying middleware to response");
             if let Err(e) = middleware.process_outgoing(&from, &upstream.address, &mut header, None)
             {
                 error!("Middleware processing error: {}", e);
-                let mut response = Response::new(
-                    Empty::<Bytes>::new()
-                        .map_err(|never| match never {})
-                        .boxed(),
-                );
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return Ok(response);
+                return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
             }
             debug!("Middleware processing completed successfully");
 
@@ -566,138 +947,223 @@ impl Service {
 
     fn process_with_body(
         service: &Service,
-        upstream: Upstream,
+        upstream: Arc<Upstream>,
         from: &SocketAddr,
         mut header: http::request::Parts,
-        body: Incoming,
-    ) -> Pin<
-        Box<
-            dyn Future<Output = Result<Response<BoxBody<Bytes, hyper::Error>>, anyhow::Error>>
-                + Send,
-        >,
-    > {
+        mut body: Incoming,
+        max_body_size: u64,
+    ) -> ProcessFuture {
         debug!("Processing request with body to upstream: {:?}", upstream);
 
         let middleware = service.middleware.clone();
-        let body_filters = service.get_body_filters_raw();
+        let body_filters = service.body_filters.clone();
         let not_found_body_response = service.not_found_body_response;
+        let mirror = service.mirror.clone();
+        let spill_threshold = service.spill_threshold;
+        let max_response_body_size = service.max_response_body_size;
+        let response_body_overflow = service.response_body_overflow;
+        let forwarder = service.forwarder(from, &upstream);
         let from = *from;
         Box::pin(async move {
-            let middleware = unsafe { middleware.unwrap_unchecked() };
-            let body_filters = body_filters;
-            let body_filters =
-                unsafe { std::slice::from_raw_parts(body_filters.filters, body_filters.len) };
-
             debug!("Collecting request body");
-            // NOTE: we won't be always recieving full body here
-            let mut entire_body = match body.collect().await {
-                Ok(collected) => {
-                    let bytes = collected.to_bytes().to_vec();
-                    debug!("Collected body of {} bytes", bytes.len());
-                    bytes
+            let collected = match spill_threshold {
+                Some(threshold) => collect_spilling(&mut body, max_body_size, threshold).await,
+                None => collect_limited(&mut body, max_body_size).await.map(
+                    |(bytes, trailers, exceeded)| {
+                        (CollectedBody::Memory(bytes), trailers, exceeded)
+                    },
+                ),
+            };
+            let (entire_body, trailers) = match collected {
+                Ok((_, _, true)) => {
+                    warn!(
+                        "Request body exceeds {} bytes, returning PAYLOAD_TOO_LARGE",
+                        max_body_size
+                    );
+                    return Ok(empty_response(StatusCode::PAYLOAD_TOO_LARGE));
                 }
+                Ok((collected, trailers, false)) => (collected, trailers),
                 Err(e) => {
                     error!("Failed to collect request body: {}", e);
-                    return Err(e.into());
+                    return Err(e);
+                }
+            };
+            let (mut entire_body, spilled) = match entire_body {
+                CollectedBody::Memory(bytes) => {
+                    debug!("Collected body of {} bytes", bytes.len());
+                    (bytes, None)
                 }
+                // Nothing reads the bytes of spilled bodies, see `ServiceBuilder::build`
+                CollectedBody::Spilled(file) => (Vec::new(), Some(file)),
             };
 
             debug!("Applying body filters");
-            if !Service::filter_request_by_body(body_filters, &from, &entire_body)? {
-                if let Some(not_found_body_response) = not_found_body_response {
-                    warn!("Request body not filtered, returning specified response");
-                    return Ok(not_found_body_response(&from, &entire_body));
-                } else {
-                    warn!("Request body not filtered, returning FORBIDDEN");
-                    let mut response = Response::new(
-                        Empty::<Bytes>::new()
-                            .map_err(|never| match never {})
-                            .boxed(),
-                    );
-                    *response.status_mut() = StatusCode::FORBIDDEN;
+            let original_len = entire_body.len();
+            match Service::filter_request_by_body(&body_filters, &from, &mut entire_body)? {
+                FilterOutcome::Pass => {}
+                FilterOutcome::RejectWith(response) => {
+                    warn!("Request body not filtered, returning filter response");
                     return Ok(response);
                 }
+                FilterOutcome::Reject => {
+                    if let Some(not_found_body_response) = not_found_body_response {
+                        warn!("Request body not filtered, returning specified response");
+                        return Ok(not_found_body_response(&from, &entire_body));
+                    } else {
+                        warn!("Request body not filtered, returning FORBIDDEN");
+                        return Ok(empty_response(StatusCode::FORBIDDEN));
+                    }
+                }
             }
+            set_content_length(
+                &mut header.headers,
+                original_len,
+                entire_body.len(),
+                trailers.is_some(),
+            );
 
-            debug!("Connecting to upstream: {}", upstream.address);
-            let stream = match TcpStream::connect(upstream.address).await {
-                Ok(stream) => {
-                    debug!("Successfully connected to upstream");
-                    stream
-                }
-                Err(e) => {
-                    error!("Failed to connect to upstream {}: {}", upstream.address, e);
-                    return Err(e.into());
-                }
-            };
+            if let Some(middleware) = &middleware {
+                debug!("Applying middleware to request with body");
+                let original_len = entire_body.len();
+                match middleware.process_incoming(
+                    &from,
+                    &upstream.address,
+                    &mut header,
+                    Some(&mut entire_body),
+                ) {
+                    Ok(MiddlewareAction::Continue) => {}
+                    Ok(MiddlewareAction::Respond(response)) => {
+                        debug!("Middleware answered the request");
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        error!("Middleware processing error: {}", e);
+                        return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+                    }
+                };
+                set_content_length(
+                    &mut header.headers,
+                    original_len,
+                    entire_body.len(),
+                    trailers.is_some(),
+                );
+                debug!("Middleware processing completed successfully");
+            }
 
-            let io = HyperSocket::new(stream);
+            if let Some(mirror) = mirror {
+                mirror.maybe_send(&header, &entire_body);
+            }
 
-            debug!("Performing HTTP handshake");
-            let (mut sender, conn) = match Builder::new()
-                .preserve_header_case(true)
-                .title_case_headers(true)
-                .handshake(io)
-                .await
-            {
-                Ok(result) => {
-                    debug!("HTTP handshake successful");
-                    result
+            let original = middleware
+                .as_ref()
+                .filter(|middleware| middleware.has_outgoing())
+                .map(|_| OriginalRequest::new(&header));
+            let (request, replay) = match spilled {
+                Some(file) => {
+                    let body = spilled_body(file, trailers).await?;
+                    (Request::from_parts(header, body), None)
                 }
-                Err(e) => {
-                    error!("HTTP handshake failed: {}", e);
-                    return Err(e.into());
+                None => {
+                    // The collected body can be sent again, unlike a streamed one
+                    let entire_body = Bytes::from(entire_body);
+                    let replay = forwarder
+                        .can_replay(&header.method, &header.headers)
+                        .then(|| Replay {
+                            body: entire_body.clone(),
+                            trailers: trailers.clone(),
+                        });
+                    let body = buffered_body(entire_body, trailers);
+                    let body: RequestBody = body.map_err(Into::into).boxed_unsync();
+                    (Request::from_parts(header, body), replay)
                 }
             };
+            let deadline = forwarder.deadline();
+            let (mut header, mut body) = forwarder
+                .send(&upstream, request, replay, deadline)
+                .await?
+                .into_parts();
+            if let Some(original) = original {
+                header.extensions.insert(original);
+            }
 
-            tokio::task::spawn(async move {
-                if let Err(err) = conn.await {
-                    error!("Connection error: {}", err);
-                }
-            });
-
-            debug!("Applying middleware to request with body");
-            if let Err(e) = middleware.process_incoming(&from, &mut header, Some(&mut entire_body))
-            {
-                error!("Middleware processing error: {}", e);
-                let mut response = Response::new(
-                    Empty::<Bytes>::new()
-                        .map_err(|never| match never {})
-                        .boxed(),
-                );
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            let Some(middleware) = middleware else {
+                let response = Response::from_parts(header, body.boxed());
+                debug!("Response created successfully");
                 return Ok(response);
             };
-            debug!("Middleware processing completed successfully");
-
-            let request = Request::from_parts(header, Full::<Bytes>::from(entire_body));
-            debug!("Sending request with body to upstream");
-
-            let (mut header, body) = match sender.send_request(request).await {
-                Ok(response) => {
-                    debug!("Request sent successfully, received response");
-                    response.into_parts()
-                }
-                Err(e) => {
-                    error!("Failed to send request: {}", e);
-                    return Err(e.into());
+            if !middleware.out_needs_body {
+                debug!("Applying middleware to streamed response");
+                if let Err(e) =
+                    middleware.process_outgoing(&from, &upstream.address, &mut header, None)
+                {
+                    error!("Middleware processing error: {}", e);
+                    return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
                 }
-            };
+                let response = Response::from_parts(header, body.boxed());
+                debug!("Response created successfully");
+                return Ok(response);
+            }
 
-            // NOTE: we won't be always recieving full body here
-            let mut entire_body = match body.collect().await {
-                Ok(collected) => {
-                    let bytes = collected.to_bytes().to_vec();
-                    debug!("Collected body of {} bytes", bytes.len());
-                    bytes
-                }
-                Err(e) => {
-                    error!("Failed to collect response body: {}", e);
-                    return Err(e.into());
+            let collected = collect_limited(&mut body, max_response_body_size);
+            let (mut entire_body, mut trailers, exceeded) =
+                match before_deadline(deadline, upstream.address, collected).await {
+                    Ok(collected) => collected,
+                    Err(e) => {
+                        error!("Failed to collect response body: {}", e);
+                        return Err(e);
+                    }
+                };
+            debug!("Collected body of {} bytes", entire_body.len());
+
+            if exceeded {
+                match response_body_overflow {
+                    ResponseBodyOverflow::Reject => {
+                        error!(
+                            "Response body exceeds {} bytes, returning BAD_GATEWAY",
+                            max_response_body_size
+                        );
+                        return Ok(empty_response(StatusCode::BAD_GATEWAY));
+                    }
+                    ResponseBodyOverflow::Truncate => {
+                        warn!(
+                            "Response body exceeds {} bytes, truncating",
+                            max_response_body_size
+                        );
+                        entire_body.truncate(
+                            usize::try_from(max_response_body_size).unwrap_or(usize::MAX),
+                        );
+                        header.headers.remove(http::header::TRANSFER_ENCODING);
+                        header
+                            .headers
+                            .insert(http::header::CONTENT_LENGTH, entire_body.len().into());
+                        // Trailers, e.g. a checksum, don't describe the truncated body
+                        header.headers.remove(http::header::TRAILER);
+                        trailers = None;
+                    }
+                    ResponseBodyOverflow::Stream => {
+                        warn!(
+                            "Response body exceeds {} bytes, streaming it unprocessed",
+                            max_response_body_size
+                        );
+                        if let Err(e) = middleware.process_outgoing_headers(
+                            &from,
+                            &upstream.address,
+                            &mut header,
+                        ) {
+                            error!("Middleware processing error: {}", e);
+                            return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+                        }
+                        let body = PrefixedBody {
+                            prefix: Some(Bytes::from(entire_body)),
+                            inner: body,
+                        };
+                        return Ok(Response::from_parts(header, body.boxed()));
+                    }
                 }
-            };
+            }
 
             debug!("Applying middleware to response with body");
+            let original_len = entire_body.len();
             if let Err(e) = middleware.process_outgoing(
                 &from,
                 &upstream.address,
@@ -705,155 +1171,3340 @@ impl Service {
                 Some(&mut entire_body),
             ) {
                 error!("Middleware processing error: {}", e);
-                let mut response = Response::new(
-                    Empty::<Bytes>::new()
-                        .map_err(|never| match never {})
-                        .boxed(),
-                );
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return Ok(response);
+                return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
             };
+            set_content_length(
+                &mut header.headers,
+                original_len,
+                entire_body.len(),
+                trailers.is_some(),
+            );
             debug!("Middleware processing completed successfully");
 
-            let response = Response::from_parts(
-                header,
-                Full::<Bytes>::from(entire_body)
-                    .map_err(|never| match never {})
-                    .boxed(),
-            );
+            let response = Response::from_parts(header, buffered_body(entire_body, trailers));
             debug!("Response created successfully");
             Ok(response)
         })
     }
 }
 
-/// A collection of services that can be used to route HTTP requests.
+/// Builder for [`Service`].
 ///
-/// Service bundles are used by the HTTP server to determine which service
-/// should handle an incoming request. They iterate through all services
-/// and use the first one that matches the request criteria.
-#[derive(Debug, Clone)]
-pub struct ServiceBundle {
-    /// Raw pointer to the array of services for FFI safety
-    services: *const [Service],
-
-    pub from: SocketAddr,
-}
-
-// SAFETY: This is safe because Service is Send and Sync
-unsafe impl Sync for ServiceBundle {}
-// SAFETY: This is safe because Service is Send and Sync
-unsafe impl Send for ServiceBundle {}
-
-impl ServiceBundle {
-    /// Creates a new service bundle from an array of services.
+/// Allows configuring a service option by option instead of passing
+/// every option positionally to [`Service::new`].
+///
+/// # Example
+///
+/// ```
+/// use std::{net::SocketAddr, sync::Arc};
+/// use broxy_core::{
+///     filter::{BodyFilter, Filter},
+///     hyper::{Method, Response, StatusCode},
+///     load_balancer::LoadBalancer,
+///     service::{ProxyResponse, Service},
+///     upstream::Upstream,
+/// };
+/// use http_body_util::{BodyExt as _, Full};
+///
+/// // Accepts JSON objects only
+/// fn is_object(_: &SocketAddr, body: &[u8]) -> anyhow::Result<bool> {
+///     Ok(body.starts_with(b"{"))
+/// }
+///
+/// fn rejected(_: &SocketAddr, _: &[u8]) -> ProxyResponse {
+///     let body = Full::from("expected a JSON object").map_err(|never| match never {}).boxed();
+///     let mut response = Response::new(body);
+///     *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+///     response
+/// }
+///
+/// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+/// let service = Service::builder()
+///     .filter(Filter::Method(Method::POST))
+///     .filter(Filter::PathPrefix("/orders".to_string()))
+///     .body_filter(BodyFilter::InternalFullBody(is_object))
+///     .not_found_response(rejected)
+///     .max_body_size(1024)
+///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct ServiceBuilder {
+    filters: Vec<Filter>,
+    body_filters: Vec<BodyFilter>,
+    middleware: Option<Middleware>,
+    load_balancer: Option<Arc<LoadBalancer>>,
+    not_found_body_response: Option<BodyNotFoundFunction>,
+    max_body_size: Option<u64>,
+    spill_threshold: Option<u64>,
+    max_response_body_size: Option<u64>,
+    response_body_overflow: ResponseBodyOverflow,
+    mirror: Option<Mirror>,
+    cache: Option<ResponseCache>,
+    canary: Option<Canary>,
+    sticky_cookie: Option<String>,
+    skip_forwarded_headers: bool,
+    grpc: bool,
+    connection_pool: Option<Arc<ConnectionPool>>,
+    retries: u32,
+    retry_backoff: Duration,
+    retry_on_status: HashSet<StatusCode>,
+    retry_methods: Option<HashSet<Method>>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    upstream_timeout: Option<Duration>,
+    access_log: Option<Arc<AccessLog>>,
+    parallel_filter_threshold: Option<usize>,
+}
+
+impl ServiceBuilder {
+    /// Adds a request header filter.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Adds a request body filter.
+    pub fn body_filter(mut self, body_filter: BodyFilter) -> Self {
+        self.body_filters.push(body_filter);
+        self
+    }
+
+    /// Sets the middleware used for request/response processing.
+    pub fn middleware(mut self, middleware: Middleware) -> Self {
+        self.middleware = Some(middleware);
+        self
+    }
+
+    /// Sets the load balancer used to select upstream servers.
+    pub fn load_balancer(mut self, load_balancer: Arc<LoadBalancer>) -> Self {
+        self.load_balancer = Some(load_balancer);
+        self
+    }
+
+    /// Sets the response generator used when a request body is filtered out.
+    pub fn not_found_response(mut self, not_found_body_response: BodyNotFoundFunction) -> Self {
+        self.not_found_body_response = Some(not_found_body_response);
+        self
+    }
+
+    /// Sets the maximum accepted request body size, in bytes.
     ///
-    /// This method initializes a new `ServiceBundle` that can be used to route
-    /// requests to multiple services. It logs the number of services being bundled.
+    /// Defaults to the maximum size of the bundle, see [`ServiceBundle::with_max_body_size`].
+    pub fn max_body_size(mut self, max_body_size: u64) -> Self {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+
+    /// Buffers request bodies larger than `threshold` bytes in a temporary file
+    /// instead of in memory.
+    ///
+    /// Large uploads can be accepted, by raising [`ServiceBuilder::max_body_size`],
+    /// without holding them in memory: the body is read to the end, then streamed from
+    /// the file to the upstream. Body filters, body-reading middleware and mirrors need
+    /// the whole body in memory, so services using them can't spill it, see
+    /// [`ServiceBuilder::build`]. Spilled bodies aren't kept for retries. The file is removed as soon as the request is
+    /// done with, whatever its outcome.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{load_balancer::LoadBalancer, service::Service, upstream::Upstream};
+    ///
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+    /// // Uploads up to 64 MiB, kept in memory up to 64 KiB
+    /// let service = Service::builder()
+    ///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///     .max_body_size(64 * 1024 * 1024)
+    ///     .spill_threshold(64 * 1024)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn spill_threshold(mut self, threshold: u64) -> Self {
+        self.spill_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets the maximum size of an upstream response body buffered by the service, in bytes.
+    ///
+    /// Only applies when the response has to be buffered, e.g. for body middleware.
+    /// Defaults to [`DEFAULT_MAX_RESPONSE_BODY_SIZE`].
+    pub fn max_response_body_size(mut self, max_response_body_size: u64) -> Self {
+        self.max_response_body_size = Some(max_response_body_size);
+        self
+    }
+
+    /// Sets what to do with buffered upstream responses larger than the maximum size.
+    ///
+    /// Defaults to [`ResponseBodyOverflow::Reject`].
+    pub fn response_body_overflow(mut self, response_body_overflow: ResponseBodyOverflow) -> Self {
+        self.response_body_overflow = response_body_overflow;
+        self
+    }
+
+    /// Mirrors a sample of the requests to a secondary upstream.
+    ///
+    /// Mirrored requests are fired in a background task, their responses are discarded
+    /// and their failures never affect the real request. They're given up after the
+    /// [`ServiceBuilder::upstream_timeout`], [`crate::mirror::DEFAULT_TIMEOUT`] if unset,
+    /// and at most [`crate::mirror::DEFAULT_MAX_IN_FLIGHT`] are in flight. Since the
+    /// request has to be replayed, enabling a mirror forces the request body to be buffered.
     ///
     /// # Arguments
     ///
-    /// * `services` - An array of `Service` instances to bundle
+    /// * `upstream` - The upstream server receiving the mirrored requests
+    /// * `sample_rate` - Fraction of requests to mirror, between `0.0` and `1.0`
+    pub fn mirror(mut self, upstream: Upstream, sample_rate: f64) -> Self {
+        self.mirror = Some(Mirror::new(upstream, sample_rate));
+        self
+    }
+
+    /// Splits the matched requests between the load balancer and a canary upstream group.
     ///
-    /// # Returns
+    /// Requests selected by the canary are sent to its load balancer, the others to the
+    /// one set with [`ServiceBuilder::load_balancer`]. Retries stay within the group of
+    /// the first upstream, and sticky cookies keep pointing at upstreams of either group.
     ///
-    /// Returns a new `ServiceBundle` instance.
-    pub fn new(services: &[Service]) -> Self {
-        info!("Creating service bundle with {} services", services.len());
-        Self {
-            services: services as *const _,
-            from: unsafe { SocketAddr::from_str("0.0.0.0:1").unwrap_unchecked() },
-        }
+    /// # Example
+    ///
+    /// ```
+    /// use std::{net::SocketAddr, sync::Arc};
+    /// use broxy_core::{
+    ///     canary::Canary,
+    ///     load_balancer::LoadBalancer,
+    ///     service::Service,
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// let stable: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    /// let canary: SocketAddr = "10.0.0.2:80".parse().unwrap();
+    /// let service = |sticky: bool| {
+    ///     Service::builder()
+    ///         .load_balancer(Arc::new(LoadBalancer::new(vec![Upstream::new(stable, false)])))
+    ///         .canary(
+    ///             Canary::new(Arc::new(LoadBalancer::new(vec![Upstream::new(canary, false)])), 0.05)
+    ///                 .with_sticky(sticky),
+    ///         )
+    ///         .build()
+    ///         .unwrap()
+    /// };
+    ///
+    /// // 5% of the requests go to the canary
+    /// let split = service(false);
+    /// let from: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+    /// let to_canary = (0..10_000)
+    ///     .filter(|_| split.get_upstream(&from).address == canary)
+    ///     .count();
+    /// assert_eq!(to_canary, 500);
+    ///
+    /// // 5% of the clients go to the canary, each one always to the same group
+    /// let sticky = service(true);
+    /// let mut to_canary = 0;
+    /// for client in 0..10_000u32 {
+    ///     let from = SocketAddr::from(((client | 0x0a00_0000).to_be_bytes(), 40000));
+    ///     let address = sticky.get_upstream(&from).address;
+    ///     assert!((0..5).all(|_| sticky.get_upstream(&from).address == address));
+    ///     to_canary += usize::from(address == canary);
+    /// }
+    /// assert!((400..600).contains(&to_canary), "{to_canary} clients sent to the canary");
+    /// ```
+    pub fn canary(mut self, canary: Canary) -> Self {
+        self.canary = Some(canary);
+        self
     }
-}
 
-impl HyperService<hyper::Request<Incoming>> for ServiceBundle {
-    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    /// Caches cacheable upstream responses, serving hits without contacting the upstream.
+    ///
+    /// Only `GET`/`HEAD` requests are cached, their responses get an
+    /// `X-Cache: HIT` or `X-Cache: MISS` header. Ignored if the service has incoming
+    /// middleware, as cache hits would not go through it.
+    pub fn cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Writes every request processed by the service to an access log.
+    ///
+    /// The same access log can be passed to several services.
+    pub fn access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Enables cookie-based sticky sessions.
+    ///
+    /// The first response to a client sets a cookie identifying the chosen upstream,
+    /// subsequent requests carrying it are routed to the same upstream. Requests without
+    /// the cookie, or with one pointing at an unknown upstream, use the normal strategy.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the cookie, e.g. [`DEFAULT_STICKY_COOKIE`]
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{
+    ///     load_balancer::LoadBalancer,
+    ///     service::{DEFAULT_STICKY_COOKIE, Service},
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// let load_balancer = Arc::new(LoadBalancer::new(vec![
+    ///     Upstream::new("127.0.0.1:8081".parse().unwrap(), false),
+    ///     Upstream::new("127.0.0.1:8082".parse().unwrap(), false),
+    /// ]));
+    /// let service = Service::builder()
+    ///     .load_balancer(load_balancer)
+    ///     .sticky_cookie(DEFAULT_STICKY_COOKIE)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn sticky_cookie(mut self, name: impl Into<String>) -> Self {
+        self.sticky_cookie = Some(name.into());
+        self
+    }
 
-    type Error = anyhow::Error;
+    /// Sets whether the `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// headers are added to requests, see [`utils::append_forwarded`].
+    ///
+    /// Enabled by default.
+    pub fn forwarded_headers(mut self, enabled: bool) -> Self {
+        self.skip_forwarded_headers = !enabled;
+        self
+    }
 
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    /// Sets whether failed gRPC calls are answered with a gRPC status, see [`crate::grpc`].
+    ///
+    /// gRPC calls are requests with an `application/grpc` content type. When enabled,
+    /// those failing to reach the upstream, or answered with an HTTP error, get a
+    /// `200 OK` trailers-only response carrying the matching `grpc-status` instead.
+    /// Disabled by default.
+    pub fn grpc(mut self, enabled: bool) -> Self {
+        self.grpc = enabled;
+        self
+    }
 
-    /// Calls the service bundle to process an incoming HTTP request.
+    /// Reuses idle upstream connections from the pool instead of opening one per request.
     ///
-    /// This method iterates through all configured services and attempts to find
-    /// the first service that matches the request. It filters the request by header,
-    /// checks for large payloads, and then forwards the request to the selected service.
+    /// The same pool can be passed to several services.
+    pub fn connection_pool(mut self, connection_pool: Arc<ConnectionPool>) -> Self {
+        self.connection_pool = Some(connection_pool);
+        self
+    }
+
+    /// Retries requests that fail to connect to their upstream.
+    ///
+    /// Every retry is sent to an upstream freshly selected by the load balancer.
+    /// Connection failures are always retried: the request wasn't sent at all, so even
+    /// non-idempotent requests can't be submitted twice. Requests failing once sent are
+    /// only retried if they may be sent again, see [`ServiceBuilder::retry_methods`].
+    /// Disabled by default.
     ///
     /// # Arguments
     ///
-    /// * `req` - The incoming HTTP request
+    /// * `retries` - Maximum amount of retries of a request
+    /// * `backoff` - Delay before the first retry, doubled for every following one. Every
+    ///   delay is picked at random in its upper half, so that requests failing together
+    ///   aren't retried together
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use broxy_core::{load_balancer::LoadBalancer, service::Service, upstream::Upstream};
+    ///
+    /// let load_balancer = Arc::new(LoadBalancer::new(vec![
+    ///     Upstream::new("127.0.0.1:8081".parse().unwrap(), false),
+    ///     Upstream::new("127.0.0.1:8082".parse().unwrap(), false),
+    /// ]));
+    /// // Up to 2 retries, the first one within 100 milliseconds, the second within 200
+    /// let service = Service::builder()
+    ///     .load_balancer(load_balancer)
+    ///     .retries(2, Duration::from_millis(100))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    /// Retries requests answered with one of these statuses on another upstream,
+    /// e.g. `502 Bad Gateway`, `503 Service Unavailable` and `504 Gateway Timeout`.
+    ///
+    /// Only requests that may be sent again are retried, see [`ServiceBuilder::retry_methods`],
+    /// and only if their body was buffered or is empty. The amount of retries and their
+    /// delay are set with [`ServiceBuilder::retries`], once they're exhausted the last
+    /// response is returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{collections::HashSet, sync::Arc, time::Duration};
+    /// use broxy_core::{
+    ///     hyper::StatusCode,
+    ///     load_balancer::LoadBalancer,
+    ///     service::Service,
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// let load_balancer = Arc::new(LoadBalancer::new(vec![
+    ///     Upstream::new("127.0.0.1:8081".parse().unwrap(), false),
+    ///     Upstream::new("127.0.0.1:8082".parse().unwrap(), false),
+    /// ]));
+    /// let service = Service::builder()
+    ///     .load_balancer(load_balancer)
+    ///     .retries(1, Duration::ZERO)
+    ///     .retry_on_status(HashSet::from([
+    ///         StatusCode::BAD_GATEWAY,
+    ///         StatusCode::SERVICE_UNAVAILABLE,
+    ///         StatusCode::GATEWAY_TIMEOUT,
+    ///     ]))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn retry_on_status(mut self, statuses: HashSet<StatusCode>) -> Self {
+        self.retry_on_status = statuses;
+        self
+    }
+
+    /// Sets the methods of the requests that may be sent to the upstreams again.
+    ///
+    /// A request that reached an upstream is only retried, after failing or being
+    /// answered with a status of [`ServiceBuilder::retry_on_status`], if its method is
+    /// one of these or if it carries an [`IDEMPOTENCY_KEY`] header, so that e.g. a
+    /// payment isn't submitted twice. Defaults to [`DEFAULT_RETRY_METHODS`], widen them
+    /// for upstreams whose `POST` requests are idempotent.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{collections::HashSet, sync::Arc, time::Duration};
+    /// use broxy_core::{
+    ///     hyper::Method,
+    ///     load_balancer::LoadBalancer,
+    ///     service::{DEFAULT_RETRY_METHODS, Service},
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// // The `POST` requests of this upstream are idempotent too
+    /// let mut methods = HashSet::from(DEFAULT_RETRY_METHODS);
+    /// methods.insert(Method::POST);
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+    /// let service = Service::builder()
+    ///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///     .retries(2, Duration::from_millis(50))
+    ///     .retry_methods(methods)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn retry_methods(mut self, methods: HashSet<Method>) -> Self {
+        self.retry_methods = Some(methods);
+        self
+    }
+
+    /// Limits the share of requests retried, see [`RetryBudget`].
+    ///
+    /// Once the budget is exhausted failed requests aren't retried anymore, their error
+    /// or last response is returned right away. The same budget can be passed to several
+    /// services, capping their retries together.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use broxy_core::{
+    ///     load_balancer::LoadBalancer, retry_budget::RetryBudget, service::Service, upstream::Upstream,
+    /// };
+    ///
+    /// // Shared by the services, retrying at most 20% of their requests together
+    /// let budget = Arc::new(RetryBudget::new(0.2, Duration::from_secs(10)));
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+    /// let service = Service::builder()
+    ///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///     .retries(3, Duration::from_millis(50))
+    ///     .retry_budget(budget.clone())
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn retry_budget(mut self, retry_budget: Arc<RetryBudget>) -> Self {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+
+    /// Limits the time the upstream has to answer a request.
+    ///
+    /// The timeout covers connecting to the upstream, sending the request and receiving
+    /// the response headers, retries included, as well as reading the response body when
+    /// it's buffered for middleware. Streamed response bodies aren't limited, so long
+    /// downloads aren't cut off. When the timeout fires the request is answered with
+    /// `504 Gateway Timeout` and its upstream connection is closed. Unlimited by default.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use broxy_core::{load_balancer::LoadBalancer, service::Service, upstream::Upstream};
+    ///
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+    /// let service = Service::builder()
+    ///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///     .upstream_timeout(Duration::from_secs(30))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn upstream_timeout(mut self, upstream_timeout: Duration) -> Self {
+        self.upstream_timeout = Some(upstream_timeout);
+        self
+    }
+
+    /// Sets the amount of header filters above which they're evaluated in parallel.
+    ///
+    /// Defaults to [`DEFAULT_PARALLEL_FILTER_THRESHOLD`]. Parallel evaluation runs on
+    /// the rayon thread pool, which only pays off for expensive filters. Either way the
+    /// result is the one of the first filter, in order, that doesn't match or fails.
+    pub fn parallel_filter_threshold(mut self, threshold: usize) -> Self {
+        self.parallel_filter_threshold = Some(threshold);
+        self
+    }
+
+    /// Evaluates the header filters one after the other, in order, however many there are.
+    ///
+    /// The rayon thread pool is then never started by this service.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{
+    ///     filter::Filter, hyper::Method, load_balancer::LoadBalancer, service::Service,
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false);
+    /// let mut builder = Service::builder()
+    ///     .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///     .sequential_filters();
+    /// for path in ["/a", "/b", "/c", "/d", "/e", "/f", "/g"] {
+    ///     builder = builder.filter(Filter::PathPrefix(path.to_string()));
+    /// }
+    /// let service = builder.build().unwrap();
+    /// ```
+    pub fn sequential_filters(self) -> Self {
+        self.parallel_filter_threshold(usize::MAX)
+    }
+
+    /// Builds the service.
     ///
     /// # Returns
     ///
-    /// Returns a future that resolves to the HTTP response from the selected service.
-    fn call(&self, req: hyper::Request<Incoming>) -> Self::Future {
-        let (header, body) = req.into_parts();
-        let uri = header.uri.clone();
-        let method = header.method.clone();
+    /// Returns the configured `Service`, or `BroxyError::Config` if no load balancer was set
+    /// or if request bodies are spilled to disk while body filters, middleware or a mirror
+    /// need them in memory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{error::BroxyError, filter::Filter, hyper::Method, service::Service};
+    ///
+    /// let service = Service::builder().filter(Filter::Method(Method::GET)).build();
+    /// assert!(matches!(service, Err(BroxyError::Config(_))));
+    /// ```
+    pub fn build(mut self) -> Result<Service, BroxyError> {
+        let load_balancer = self
+            .load_balancer
+            .take()
+            .ok_or_else(|| BroxyError::Config("service requires a load balancer".to_string()))?;
+        let needs_bytes = !self.body_filters.is_empty()
+            || self.mirror.is_some()
+            || self
+                .middleware
+                .as_ref()
+                .is_some_and(|middleware| middleware.incoming_needs_body);
+        if self.spill_threshold.is_some() && needs_bytes {
+            return Err(BroxyError::Config(
+                "request bodies read by body filters, middleware or mirrors can't be spilled to disk"
+                    .to_string(),
+            ));
+        }
 
-        debug!("Processing request: {} {}", method, uri);
+        Ok(Service::from_builder(self, load_balancer))
+    }
+}
 
-        for (i, service) in unsafe { &*self.services }.iter().enumerate() {
-            debug!("Trying service {} for request", i);
+/// Marks the responses generated by the proxy itself, rather than by an upstream.
+#[derive(Debug, Clone, Copy)]
+struct Generated;
 
-            match service.filter_request_by_header(&self.from, &header) {
-                Ok(found) => {
-                    if !found {
-                        debug!("Service {} did not match request", i);
-                        continue;
+/// Builds a response with an empty body and the given status.
+///
+/// The response is marked as generated by the proxy, so the bundle can replace
+/// it with a custom one, see [`ServiceBundle::with_response`].
+pub(crate) fn empty_response(status: StatusCode) -> ProxyResponse {
+    let mut response = Response::new(
+        Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response.extensions_mut().insert(Generated);
+    response
+}
+
+/// Buffers a body, reading at most `limit` bytes.
+///
+/// Reading stops as soon as the limit is exceeded, so a body streaming
+/// indefinitely can't exhaust the memory. The chunk exceeding the limit is kept
+/// whole, the rest of the body can still be read from `body`.
+///
+/// # Returns
+///
+/// Returns the bytes read, the trailers and whether the body was longer than `limit`,
+/// or `BroxyError::Body` if reading fails.
+async fn collect_limited<B>(
+    body: &mut B,
+    limit: u64,
+) -> Result<(Vec<u8>, Option<HeaderMap>, bool), BroxyError>
+where
+    B: hyper::body::Body<Data = Bytes, Error = hyper::Error> + Unpin,
+{
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    let mut collected = Vec::new();
+    let mut trailers: Option<HeaderMap> = None;
+    while let Some(frame) = body.frame().await {
+        let data = match frame.map_err(BroxyError::Body)?.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                if let Ok(frame_trailers) = frame.into_trailers() {
+                    trailers.get_or_insert_default().extend(frame_trailers);
+                }
+                continue;
+            }
+        };
+        collected.extend_from_slice(&data);
+        if collected.len() > limit {
+            return Ok((collected, trailers, true));
+        }
+    }
+    Ok((collected, trailers, false))
+}
+
+/// Request body buffered by a service.
+enum CollectedBody {
+    Memory(Vec<u8>),
+    /// Written to an anonymous temporary file, removed once it's closed
+    Spilled(tokio::fs::File),
+}
+
+/// Size of the chunks a spilled body is streamed in.
+const SPILLED_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Buffers a body like [`collect_limited`], moving it to a temporary file once it's
+/// larger than `threshold` bytes.
+///
+/// The file is created already unlinked, so it's removed as soon as it's closed,
+/// whether the body is sent, rejected or the request is cancelled.
+async fn collect_spilling<B>(
+    body: &mut B,
+    limit: u64,
+    threshold: u64,
+) -> Result<(CollectedBody, Option<HeaderMap>, bool), BroxyError>
+where
+    B: hyper::body::Body<Data = Bytes, Error = hyper::Error> + Unpin,
+{
+    let threshold = usize::try_from(threshold).unwrap_or(usize::MAX);
+    let mut collected = Vec::new();
+    let mut file: Option<tokio::fs::File> = None;
+    let mut length = 0u64;
+    let mut trailers: Option<HeaderMap> = None;
+    while let Some(frame) = body.frame().await {
+        let data = match frame.map_err(BroxyError::Body)?.into_data() {
+            Ok(data) => data,
+            Err(frame) => {
+                if let Ok(frame_trailers) = frame.into_trailers() {
+                    trailers.get_or_insert_default().extend(frame_trailers);
+                }
+                continue;
+            }
+        };
+        length += data.len() as u64;
+        if length > limit {
+            return Ok((CollectedBody::Memory(collected), trailers, true));
+        }
+        match &mut file {
+            Some(file) => file.write_all(&data).await?,
+            None if collected.len() + data.len() > threshold => {
+                debug!(
+                    "Request body exceeds {} bytes, spilling it to disk",
+                    threshold
+                );
+                let mut spilled = tokio::fs::File::from_std(tempfile::tempfile()?);
+                spilled.write_all(&collected).await?;
+                spilled.write_all(&data).await?;
+                collected = Vec::new();
+                file = Some(spilled);
+            }
+            None => collected.extend_from_slice(&data),
+        }
+    }
+    let collected = match file {
+        Some(mut file) => {
+            file.flush().await?;
+            debug!("Spilled body of {} bytes", length);
+            CollectedBody::Spilled(file)
+        }
+        None => CollectedBody::Memory(collected),
+    };
+    Ok((collected, trailers, false))
+}
+
+/// Builds a request body streamed from a spilled body, followed by its trailers.
+///
+/// The file is closed, and so removed, once the body is dropped.
+async fn spilled_body(
+    mut file: tokio::fs::File,
+    trailers: Option<HeaderMap>,
+) -> Result<RequestBody, BroxyError> {
+    file.rewind().await?;
+    let chunks = futures::stream::try_unfold(file, |mut file| async move {
+        let mut chunk = vec![0; SPILLED_CHUNK_SIZE];
+        let read = file.read(&mut chunk).await?;
+        chunk.truncate(read);
+        Ok::<_, std::io::Error>((read > 0).then(|| (Frame::data(Bytes::from(chunk)), file)))
+    });
+    let trailers = futures::stream::iter(trailers.map(|trailers| Ok(Frame::trailers(trailers))));
+    Ok(StreamBody::new(futures::StreamExt::chain(chunks, trailers))
+        .map_err(Into::into)
+        .boxed_unsync())
+}
+
+/// Body made of already read bytes, followed by the rest of a streamed body.
+struct PrefixedBody<B> {
+    prefix: Option<Bytes>,
+    inner: B,
+}
+
+impl<B> hyper::body::Body for PrefixedBody<B>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+
+    type Error = B::Error;
+
+    #[inline]
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.inner.is_end_stream()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        let prefix = self.prefix.as_ref().map_or(0, |prefix| prefix.len() as u64);
+        let inner = self.inner.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(inner.lower() + prefix);
+        if let Some(upper) = inner.upper() {
+            hint.set_upper(upper + prefix);
+        }
+        hint
+    }
+}
+
+/// Wraps a streamed request body, so reading it fails once it exceeds `limit` bytes.
+///
+/// The upstream then sees the request aborted, instead of receiving an unbounded body.
+fn limited_body(body: Incoming, limit: u64) -> Limited<Incoming> {
+    Limited::new(body, usize::try_from(limit).unwrap_or(usize::MAX))
+}
+
+/// Makes the headers of a buffered body describe it again, once a body filter or a
+/// middleware changed its length.
+///
+/// The stale `Content-Length` would make the message malformed. Bodies are sent with
+/// their new length, or chunked when trailers follow them, see [`buffered_body`].
+fn set_content_length(headers: &mut HeaderMap, original_len: usize, len: usize, trailers: bool) {
+    if len == original_len {
+        return;
+    }
+    debug!("Body rewritten from {} to {} bytes", original_len, len);
+    if trailers {
+        headers.remove(http::header::CONTENT_LENGTH);
+    } else {
+        headers.remove(http::header::TRANSFER_ENCODING);
+        headers.insert(http::header::CONTENT_LENGTH, len.into());
+    }
+}
+
+/// Builds the body of a buffered request or response.
+///
+/// Bodies with trailers are sent chunked, since trailers can't follow
+/// a body with a `Content-Length`.
+///
+/// # Arguments
+///
+/// * `body` - The complete body
+/// * `trailers` - The trailers to send after the body, if any
+fn buffered_body(
+    body: impl Into<Bytes>,
+    trailers: Option<HeaderMap>,
+) -> BoxBody<Bytes, hyper::Error> {
+    match trailers {
+        None => Full::<Bytes>::new(body.into())
+            .map_err(|never| match never {})
+            .boxed(),
+        Some(trailers) => StreamBody::new(futures::stream::iter([
+            Ok(Frame::data(body.into())),
+            Ok(Frame::trailers(trailers)),
+        ]))
+        .boxed(),
+    }
+}
+
+/// Sends requests to the upstream servers of a service, retrying failed attempts.
+pub(crate) struct Forwarder {
+    /// Load balancer selecting the upstream of every retry
+    load_balancer: Arc<LoadBalancer>,
+    /// Address of the client, used by the load balancer
+    from: SocketAddr,
+    connection_pool: Option<Arc<ConnectionPool>>,
+    retries: u32,
+    retry_backoff: Duration,
+    retry_on_status: Arc<HashSet<StatusCode>>,
+    retry_methods: Arc<HashSet<Method>>,
+    retry_budget: Option<Arc<RetryBudget>>,
+    upstream_timeout: Option<Duration>,
+}
+
+/// Marks responses received from an upstream, rather than answered by the proxy or
+/// its middleware without contacting it.
+#[derive(Debug, Clone, Copy)]
+struct Forwarded;
+
+/// Marks responses received after retrying the request on another upstream.
+#[derive(Debug, Clone, Copy)]
+struct Retried {
+    /// Whether the first attempt failed to connect, rather than failed once connected
+    first_connect_failed: bool,
+}
+
+/// Copy of a request body, so the request can be sent again.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+    body: Bytes,
+    trailers: Option<HeaderMap>,
+}
+
+impl Replay {
+    /// Builds a copy of the request, extensions excluded.
+    fn request(&self, request: &Request<RequestBody>) -> Request<RequestBody> {
+        let body = buffered_body(self.body.clone(), self.trailers.clone());
+        let mut copy = Request::new(body.map_err(Into::into).boxed_unsync());
+        *copy.method_mut() = request.method().clone();
+        *copy.uri_mut() = request.uri().clone();
+        *copy.version_mut() = request.version();
+        *copy.headers_mut() = request.headers().clone();
+        copy
+    }
+}
+
+impl Forwarder {
+    /// Checks whether a request may be sent again, and so is worth keeping a copy of.
+    ///
+    /// Every retry of a request that was sent goes through this check: its method has
+    /// to be one of the retry methods, or it has to carry an [`IDEMPOTENCY_KEY`].
+    pub(crate) fn can_replay(&self, method: &Method, headers: &HeaderMap) -> bool {
+        self.retries > 0
+            && (self.retry_methods.contains(method) || headers.contains_key(IDEMPOTENCY_KEY))
+    }
+
+    /// Returns the time by which the upstream has to answer a request sent now, if limited.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.upstream_timeout
+            .map(|upstream_timeout| Instant::now() + upstream_timeout)
+    }
+
+    /// Sends the request to the upstream server, see [`Forwarder::send_with_retries`].
+    ///
+    /// # Returns
+    ///
+    /// Returns `BroxyError::UpstreamTimeout` if no response was received by `deadline`,
+    /// retries included. The attempt in progress is then cancelled and its connection closed.
+    pub(crate) async fn send<B>(
+        &self,
+        upstream: &Upstream,
+        request: Request<B>,
+        replay: Option<Replay>,
+        deadline: Option<Instant>,
+    ) -> Result<Response<UpstreamBody>, BroxyError>
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let attempts = self.send_with_retries(upstream, request, replay);
+        before_deadline(deadline, upstream.address, attempts).await
+    }
+
+    /// Sends the request to the upstream server.
+    ///
+    /// Failed attempts are retried up to `retries` times, each time on an upstream
+    /// freshly selected by the load balancer:
+    /// - When connecting to the upstream fails, the request wasn't sent, so it's
+    ///   retried whatever its method.
+    /// - When the request fails once sent, it's only retried if it can be replayed,
+    ///   see [`Forwarder::can_replay`].
+    /// - When the upstream answers with a status of `retry_on_status`, the request is
+    ///   only retried if it can be replayed. The last response is returned once the
+    ///   retries are exhausted.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream` - The upstream server selected for the first attempt
+    /// * `request` - The request to forward
+    /// * `replay` - Copy of the request body, `None` if it's streamed
+    ///
+    /// # Returns
+    ///
+    /// Returns the upstream response, or the error of the last attempt,
+    /// see [`send_to_upstream`].
+    async fn send_with_retries<B>(
+        &self,
+        upstream: &Upstream,
+        request: Request<B>,
+        replay: Option<Replay>,
+    ) -> Result<Response<UpstreamBody>, BroxyError>
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut request = request.map(|body| body.map_err(Into::into).boxed_unsync());
+        let replay = replay.filter(|_| self.can_replay(request.method(), request.headers()));
+        // Every upstream prepends its own root path and may override the host
+        let uri = request.uri().clone();
+        let host = request.headers().get(http::header::HOST).cloned();
+        // Upstream of the current retry, the first attempt is accounted for by `Service::process`
+        let mut target: Option<(Arc<Upstream>, RequestGuard)> = None;
+        let mut first_connect_failed = false;
+        let mut attempt = 0;
+        if let Some(retry_budget) = &self.retry_budget {
+            retry_budget.record_request();
+        }
+        loop {
+            let (upstream, guard) = match &mut target {
+                Some((upstream, guard)) => (&**upstream, Some(guard)),
+                None => (upstream, None),
+            };
+            *request.uri_mut() = upstream.rewrite_uri(&uri);
+            upstream.rewrite_host(request.headers_mut(), host.as_ref());
+            let copy = replay
+                .as_ref()
+                .filter(|_| attempt < self.retries)
+                .map(|replay| replay.request(&request));
+            let (result, next_request) = match try_send_to_upstream(
+                upstream,
+                self.connection_pool.as_ref(),
+                request,
+            )
+            .await
+            {
+                Ok(response) => {
+                    if let Some(guard) = guard {
+                        guard.connected(upstream);
+                        if response.status().is_server_error() {
+                            guard.fail();
+                        }
                     }
-                    debug!("Service {} matched request", i);
+                    let failover = self.retry_on_status.contains(&response.status());
+                    (Ok(response), copy.filter(|_| failover))
                 }
-                Err(e) => {
-                    error!("Service {} header filter error: {}", i, e);
-                    return Box::pin(async {
-                        let mut response = Response::new(
-                            Empty::<Bytes>::new()
-                                .map_err(|never| match never {})
-                                .boxed(),
-                        );
-                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                        Ok(response)
-                    });
+                Err((error, unsent)) => {
+                    let connect_failed = matches!(
+                        error,
+                        BroxyError::UpstreamConnect { .. } | BroxyError::Handshake(_)
+                    );
+                    if attempt == 0 {
+                        first_connect_failed = connect_failed;
+                    }
+                    if let Some(guard) = guard {
+                        if connect_failed {
+                            guard.connect_failed(upstream);
+                        } else {
+                            guard.fail();
+                        }
+                    }
+                    (Err(error), unsent.or(copy))
                 }
             };
 
-            let max = body.size_hint().upper().unwrap_or(u64::MAX);
-            debug!("Request body size hint: {} bytes", max);
-
-            if max > 1024 * 64 {
+            let retried = (attempt > 0).then_some(Retried {
+                first_connect_failed,
+            });
+            let finish = |mut result: Result<Response<UpstreamBody>, BroxyError>| {
+                if let Ok(response) = &mut result {
+                    response.extensions_mut().insert(Forwarded);
+                    if let Some(retried) = retried {
+                        response.extensions_mut().insert(retried);
+                    }
+                }
+                result
+            };
+            let Some(next_request) = next_request.filter(|_| attempt < self.retries) else {
+                return finish(result);
+            };
+            if let Some(retry_budget) = &self.retry_budget
+                && !retry_budget.try_retry()
+            {
+                warn!("Retry budget exhausted, not retrying");
+                return finish(result);
+            }
+            let next = self.load_balancer.get_upstream(&self.from);
+            let Some(next_guard) = next.stats.try_begin_request() else {
                 warn!(
-                    "Request body too large ({} bytes), returning PAYLOAD_TOO_LARGE",
-                    max
+                    "Upstream {} is at its concurrency limit, not retrying",
+                    next.address
                 );
-                return Box::pin(async {
-                    let mut response = Response::new(
-                        Empty::<Bytes>::new()
-                            .map_err(|never| match never {})
-                            .boxed(),
-                    );
-                    *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
-                    Ok(response)
-                });
-            }
-
-            let upstream = service.get_upstream();
-            debug!("Selected service {} with upstream: {:?}", i, upstream);
+                return finish(result);
+            };
 
-            // TODO: REMOVE CLONE
-            return service.process(upstream.clone(), &self.from, header, body);
+            attempt += 1;
+            let backoff = retry_delay(self.retry_backoff, attempt);
+            match &result {
+                Ok(response) => warn!(
+                    "Upstream {} answered {}, retrying request on upstream {} in {:?} ({}/{})",
+                    upstream.address,
+                    response.status(),
+                    next.address,
+                    backoff,
+                    attempt,
+                    self.retries
+                ),
+                Err(e) => warn!(
+                    "Retrying request on upstream {} in {:?} ({}/{}): {}",
+                    next.address, backoff, attempt, self.retries, e
+                ),
+            }
+            drop(result);
+            tokio::time::sleep(backoff).await;
+            target = Some((next, next_guard));
+            request = next_request;
         }
+    }
+}
 
-        warn!("No matching service found for request: {} {}", method, uri);
-        Box::pin(async {
-            let mut response = Response::new(
-                Empty::<Bytes>::new()
-                    .map_err(|never| match never {})
+/// Computes the delay before a retry.
+///
+/// The delay doubles with every retry, starting from `base`, and is picked at random
+/// in its upper half so that requests failing together spread their retries out.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base.saturating_mul(1 << (attempt - 1).min(16));
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+}
+
+/// Runs an upstream operation, failing with `BroxyError::UpstreamTimeout` once `deadline`
+/// has passed.
+///
+/// The operation is dropped when it times out, which aborts the task driving a dedicated
+/// upstream connection, and makes hyper close a pooled HTTP/1.1 one.
+async fn before_deadline<T>(
+    deadline: Option<Instant>,
+    address: SocketAddr,
+    operation: impl Future<Output = Result<T, BroxyError>>,
+) -> Result<T, BroxyError> {
+    let Some(deadline) = deadline else {
+        return operation.await;
+    };
+    tokio::time::timeout_at(deadline, operation)
+        .await
+        .unwrap_or_else(|_| {
+            warn!("Upstream {} didn't answer in time", address);
+            Err(BroxyError::UpstreamTimeout { address })
+        })
+}
+
+/// Sends the request to the upstream server over HTTP/1.
+///
+/// Without a connection pool a fresh connection is opened. With one, an idle connection
+/// is reused if there is any, falling back to a new connection if the idle ones were
+/// closed by the upstream before the request could be sent.
+///
+/// # Arguments
+///
+/// * `upstream` - The upstream server to connect to
+/// * `connection_pool` - Pool of idle connections to the upstream, if any
+/// * `request` - The request to forward
+///
+/// # Returns
+///
+/// Returns the upstream response, or `BroxyError::UpstreamConnect`, `BroxyError::Handshake`
+/// or `BroxyError::Upstream` depending on which step failed. A dedicated connection is
+/// closed once the response body is dropped, or right away if this future is cancelled,
+/// while a pooled one is released to the pool once the response body was read to the end.
+pub(crate) async fn send_to_upstream<B>(
+    upstream: &Upstream,
+    connection_pool: Option<Arc<ConnectionPool>>,
+    request: Request<B>,
+) -> Result<Response<UpstreamBody>, BroxyError>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut request = request.map(|body| body.map_err(Into::into).boxed_unsync());
+    let host = request.headers().get(http::header::HOST).cloned();
+    upstream.rewrite_host(request.headers_mut(), host.as_ref());
+    try_send_to_upstream(upstream, connection_pool.as_ref(), request)
+        .await
+        .map_err(|(error, _)| error)
+}
+
+/// Sends the request to the upstream server, see [`send_to_upstream`].
+///
+/// # Returns
+///
+/// On failure, returns the error along with the request if it wasn't sent at all.
+async fn try_send_to_upstream(
+    upstream: &Upstream,
+    connection_pool: Option<&Arc<ConnectionPool>>,
+    mut request: Request<RequestBody>,
+) -> Result<Response<UpstreamBody>, (BroxyError, Option<Request<RequestBody>>)> {
+    if upstream.http_version == HttpVersion::Http2 {
+        set_absolute_uri(upstream, &mut request);
+    }
+
+    let address = match upstream.resolve().await {
+        Ok(address) => address,
+        Err(e) => return Err((e, Some(request))),
+    };
+    let Some(connection_pool) = connection_pool else {
+        let (mut sender, connection) = match connect(upstream, address).await {
+            Ok(connected) => connected,
+            Err(e) => return Err((e, Some(request))),
+        };
+        let connection = UpstreamConnection::Dedicated {
+            _task: AbortOnDrop(tokio::task::spawn(connection)),
+        };
+        debug!("Sending request to upstream");
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| (request_failed(e), None))?;
+        debug!("Request sent successfully, received response");
+        return Ok(UpstreamBody::wrap(response, connection));
+    };
+
+    // How long the client keeps its connection open doesn't concern the pooled one
+    let headers = request.headers_mut();
+    let persistence_only = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .all(|value| {
+            value.to_str().is_ok_and(|value| {
+                value.split(',').all(|option| {
+                    let option = option.trim();
+                    option.eq_ignore_ascii_case("close")
+                        || option.eq_ignore_ascii_case("keep-alive")
+                })
+            })
+        });
+    if persistence_only {
+        headers.remove(http::header::CONNECTION);
+        headers.remove("keep-alive");
+    }
+
+    let pooled =
+        |sender| UpstreamConnection::Pooled(Some((connection_pool.clone(), address, sender)));
+    while let Some(mut sender) = connection_pool.checkout(address).await {
+        debug!("Sending request to upstream over a pooled connection");
+        match sender.try_send_request(request).await {
+            Ok(response) => {
+                debug!("Request sent successfully, received response");
+                return Ok(UpstreamBody::wrap(response, pooled(sender)));
+            }
+            Err(mut e) => match e.take_message() {
+                Some(message) => {
+                    debug!("Pooled connection closed before sending, trying another one");
+                    request = message;
+                }
+                None => return Err((request_failed(e.into_error()), None)),
+            },
+        }
+    }
+
+    let (mut sender, connection) = match connect(upstream, address).await {
+        Ok(connected) => connected,
+        Err(e) => return Err((e, Some(request))),
+    };
+    // The connection outlives the response while it's idle in the pool,
+    // it ends by itself once closed or dropped from the pool
+    tokio::task::spawn(connection);
+    if let Some(shared) = sender.share() {
+        connection_pool.insert(address, shared);
+    }
+    debug!("Sending request to upstream");
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| (request_failed(e), None))?;
+    debug!("Request sent successfully, received response");
+    Ok(UpstreamBody::wrap(response, pooled(sender)))
+}
+
+/// Opens a new connection to the upstream server.
+///
+/// # Arguments
+///
+/// * `upstream` - The upstream server to connect to
+/// * `address` - The address to connect to, resolved from the upstream's host name if any
+///
+/// # Returns
+///
+/// Returns the sending half of the connection and the future driving it, which has
+/// to be spawned, or `BroxyError::UpstreamConnect` or `BroxyError::Handshake`.
+async fn connect(
+    upstream: &Upstream,
+    address: SocketAddr,
+) -> Result<(Sender, Pin<Box<dyn Future<Output = ()> + Send>>), BroxyError> {
+    debug!("Connecting to upstream: {}", address);
+    let started = Instant::now();
+    let connection = connect_and_handshake(upstream, address).await;
+    if connection.is_ok() {
+        let elapsed = started.elapsed().as_secs_f64() * 1000.0;
+        Span::current().record("connect_ms", elapsed);
+    }
+    connection
+}
+
+/// Opens a new connection to the upstream server, see [`connect`].
+async fn connect_and_handshake(
+    upstream: &Upstream,
+    address: SocketAddr,
+) -> Result<(Sender, Pin<Box<dyn Future<Output = ()> + Send>>), BroxyError> {
+    let stream = match TcpStream::connect(address).await {
+        Ok(stream) => {
+            debug!("Successfully connected to upstream");
+            if upstream.tcp_nodelay
+                && let Err(e) = stream.set_nodelay(true)
+            {
+                warn!("Failed to set TCP_NODELAY on upstream connection: {}", e);
+            }
+            stream
+        }
+        Err(e) => {
+            error!("Failed to connect to upstream {}: {}", address, e);
+            return Err(BroxyError::UpstreamConnect { address, source: e });
+        }
+    };
+
+    if upstream.use_ssl {
+        let (connector, servername) = upstream.tls_connector()?;
+        debug!("Performing TLS handshake with {:?}", servername);
+        let stream = connector.connect(servername, stream).await.map_err(|e| {
+            error!("TLS handshake with upstream {} failed: {}", address, e);
+            BroxyError::UpstreamConnect { address, source: e }
+        })?;
+        handshake(upstream, HyperSocket::new(stream)).await
+    } else {
+        handshake(upstream, HyperSocket::new(stream)).await
+    }
+}
+
+/// Performs the HTTP handshake on a connection to an upstream.
+async fn handshake<I>(
+    upstream: &Upstream,
+    io: I,
+) -> Result<(Sender, Pin<Box<dyn Future<Output = ()> + Send>>), BroxyError>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    debug!("Performing {:?} handshake", upstream.http_version);
+    let handshake = match upstream.http_version {
+        HttpVersion::Http1 => Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .handshake(io)
+            .await
+            .map(|(sender, conn)| (Sender::Http1(sender), drive(conn))),
+        HttpVersion::Http2 => http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .handshake(io)
+            .await
+            .map(|(sender, conn)| (Sender::Http2(sender), drive(conn))),
+    };
+    match handshake {
+        Ok(result) => {
+            debug!("HTTP handshake successful");
+            Ok(result)
+        }
+        Err(e) => {
+            error!("HTTP handshake failed: {}", e);
+            Err(BroxyError::Handshake(e))
+        }
+    }
+}
+
+/// Drives an upstream connection until it's closed.
+fn drive(
+    conn: impl Future<Output = hyper::Result<()>> + Send + 'static,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        if let Err(err) = conn.await {
+            error!("Connection error: {}", err);
+        }
+    })
+}
+
+/// Makes the URI of a request absolute, HTTP/2 sends the scheme and authority with the path.
+///
+/// The authority is taken from the `Host` header, or the upstream authority without one.
+fn set_absolute_uri(upstream: &Upstream, request: &mut Request<RequestBody>) {
+    let authority = request
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| Authority::from_str(host).ok())
+        .or_else(|| request.uri().authority().cloned())
+        .or_else(|| Authority::from_str(&upstream.authority()).ok());
+    let mut parts = request.uri().clone().into_parts();
+    parts.scheme = Some(if upstream.use_ssl {
+        Scheme::HTTPS
+    } else {
+        Scheme::HTTP
+    });
+    parts.authority = authority;
+    parts
+        .path_and_query
+        .get_or_insert(PathAndQuery::from_static("/"));
+    match Uri::from_parts(parts) {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(e) => warn!(
+            "Failed to build absolute URI for {}: {}",
+            upstream.address, e
+        ),
+    }
+    *request.version_mut() = Version::HTTP_2;
+}
+
+fn request_failed(e: hyper::Error) -> BroxyError {
+    error!("Failed to send request: {}", e);
+    BroxyError::Upstream(e)
+}
+
+/// Aborts the wrapped task when dropped.
+#[derive(Debug)]
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Connection an upstream response is read from.
+#[derive(Debug)]
+enum UpstreamConnection {
+    /// Connection opened for this request only
+    Dedicated { _task: AbortOnDrop },
+    /// Connection released to the pool once the response was read to the end
+    Pooled(Option<(Arc<ConnectionPool>, SocketAddr, Sender)>),
+}
+
+/// Body of an upstream response, keeping the connection driving it alive.
+///
+/// Dropping the body, e.g. when the client disconnects, aborts a dedicated connection
+/// task, or closes a pooled connection unless the body was read to the end.
+#[derive(Debug)]
+pub(crate) struct UpstreamBody {
+    inner: Incoming,
+    connection: UpstreamConnection,
+    /// Whether the last frame was read
+    finished: bool,
+}
+
+impl UpstreamBody {
+    fn wrap(response: Response<Incoming>, connection: UpstreamConnection) -> Response<Self> {
+        response.map(|inner| Self {
+            inner,
+            connection,
+            finished: false,
+        })
+    }
+}
+
+impl hyper::body::Body for UpstreamBody {
+    type Data = Bytes;
+
+    type Error = hyper::Error;
+
+    #[inline]
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(None) = frame {
+            self.finished = true;
+        }
+        frame
+    }
+
+    #[inline]
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for UpstreamBody {
+    fn drop(&mut self) {
+        if let UpstreamConnection::Pooled(pooled) = &mut self.connection
+            && (self.finished || self.inner.is_end_stream())
+            && let Some((connection_pool, address, sender)) = pooled.take()
+        {
+            connection_pool.release(address, sender);
+        }
+    }
+}
+
+/// A collection of services that can be used to route HTTP requests.
+///
+/// Service bundles are used by the HTTP server to determine which service
+/// should handle an incoming request. They use the first service, in order, that
+/// matches the request criteria.
+///
+/// Services with a [`Filter::HostExact`] filter are indexed by their host, a request
+/// only tries the services of its host and the ones without such a filter, e.g.
+/// matching hosts with a pattern. Routing many domains doesn't go through every
+/// service, and picks the same service as trying them all in order.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use broxy_core::{
+///     filter::Filter,
+///     load_balancer::LoadBalancer,
+///     service::{Service, ServiceBundle},
+///     upstream::Upstream,
+/// };
+///
+/// let service = |address: &str| {
+///     let upstream = Upstream::new(address.parse().unwrap(), false);
+///     Service::builder().load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+/// };
+/// let bundle = ServiceBundle::new(vec![
+///     service("127.0.0.1:8081")
+///         .filter(Filter::HostExact("api.example.com".to_string()))
+///         .build()
+///         .unwrap(),
+///     // Every other host
+///     service("127.0.0.1:8080").build().unwrap(),
+/// ]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServiceBundle {
+    /// Services tried in order, shared by every clone of the bundle
+    services: Arc<[Service]>,
+    /// Order the services are tried in, by host
+    routes: Arc<Routes>,
+
+    pub from: SocketAddr,
+    /// Whether the client connected over TLS
+    pub(crate) tls: bool,
+    /// Header the client certificate is forwarded in, and the certificate if the
+    /// client presented one
+    pub(crate) client_cert: Option<(HeaderName, Option<HeaderValue>)>,
+
+    /// Build the responses replacing the empty ones generated by the proxy, by status
+    responses: Arc<HashMap<StatusCode, BundleResponseFunction>>,
+    /// Builds the response sent when a request fails to be processed
+    error_response: Option<BundleResponseFunction>,
+    /// Maximum size of a request body, for services without their own maximum
+    max_body_size: u64,
+    /// Port every request is redirected to over HTTPS, instead of being routed
+    https_redirect: Option<u16>,
+    /// Paths answering health probes, set by the server
+    pub(crate) health: Option<Arc<HealthEndpoints>>,
+    /// Stage of the life of the server the connection was accepted by
+    pub(crate) lifecycle: Option<watch::Receiver<Lifecycle>>,
+    /// Observes every completed request
+    metrics_hook: Option<Arc<dyn MetricsHook>>,
+}
+
+/// Function building a bundle-level response, e.g. a branded 404 page or a JSON error.
+pub type BundleResponseFunction = fn() -> ProxyResponse;
+
+/// Host compared and hashed ignoring ASCII case, so a request host can be looked
+/// up without lowercasing it first.
+#[derive(Debug)]
+#[repr(transparent)]
+struct HostKey(str);
+
+impl HostKey {
+    fn new(host: &str) -> &Self {
+        // SAFETY: `HostKey` is a transparent wrapper around `str`
+        unsafe { &*(host as *const str as *const Self) }
+    }
+
+    fn boxed(host: &str) -> Box<Self> {
+        let host: Box<str> = host.into();
+        // SAFETY: `HostKey` is a transparent wrapper around `str`
+        unsafe { Box::from_raw(Box::into_raw(host) as *mut Self) }
+    }
+}
+
+impl PartialEq for HostKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for HostKey {}
+
+impl Hash for HostKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in self.0.bytes() {
+            state.write_u8(byte.to_ascii_lowercase());
+        }
+        // Like `str`, so that hashing a sequence of keys is prefix-free
+        state.write_u8(0xff);
+    }
+}
+
+/// Order in which the services of a bundle are tried, indexed by the host they
+/// match exactly.
+#[derive(Debug)]
+struct Routes {
+    /// Services with a [`Filter::HostExact`] filter, by host, in order
+    hosts: HashMap<Box<HostKey>, Vec<usize>>,
+    /// Services that may match any host, in order
+    fallback: Vec<usize>,
+    /// Every service, in order
+    order: Vec<usize>,
+    /// Position of every service in `order`
+    positions: Vec<usize>,
+}
+
+impl Routes {
+    /// Indexes services, tried in the bundle order, or by decreasing length of their
+    /// [`Filter::PathPrefix`] when `longest_prefix` is set.
+    fn new(services: &[Service], longest_prefix: bool) -> Self {
+        let mut order: Vec<usize> = (0..services.len()).collect();
+        if longest_prefix {
+            // Stable, services with prefixes of the same length keep the bundle order
+            order.sort_by_key(|&i| std::cmp::Reverse(services[i].path_prefix_len()));
+        }
+        let mut positions = vec![0; services.len()];
+        let mut hosts: HashMap<Box<HostKey>, Vec<usize>> = HashMap::new();
+        let mut fallback = Vec::new();
+        for (position, &i) in order.iter().enumerate() {
+            positions[i] = position;
+            let host = services[i].filters.iter().find_map(|filter| match filter {
+                Filter::HostExact(host) => Some(HostKey::boxed(host)),
+                _ => None,
+            });
+            match host {
+                Some(host) => hosts.entry(host).or_default().push(i),
+                None => fallback.push(i),
+            }
+        }
+        debug!(
+            "Indexed services of {} hosts, {} services match any host",
+            hosts.len(),
+            fallback.len()
+        );
+        Self {
+            hosts,
+            fallback,
+            order,
+            positions,
+        }
+    }
+
+    /// Returns the services that may match a request, in the order they're tried.
+    ///
+    /// Requests without a host try every service, so that filters failing on them
+    /// fail the same way whether they're indexed or not.
+    fn candidates(&self, host: Option<&str>) -> Candidates<'_> {
+        let (indexed, fallback) = match host {
+            Some(host) => (
+                self.hosts
+                    .get(HostKey::new(host))
+                    .map_or(&[][..], Vec::as_slice),
+                self.fallback.as_slice(),
+            ),
+            None => (&[][..], self.order.as_slice()),
+        };
+        Candidates {
+            indexed: indexed.iter().peekable(),
+            fallback: fallback.iter().peekable(),
+            positions: &self.positions,
+        }
+    }
+}
+
+/// Services that may match a request, see [`Routes::candidates`].
+struct Candidates<'a> {
+    indexed: Peekable<std::slice::Iter<'a, usize>>,
+    fallback: Peekable<std::slice::Iter<'a, usize>>,
+    positions: &'a [usize],
+}
+
+impl Iterator for Candidates<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        // Both lists are in order, merging them keeps the first matching service first
+        let next = match (self.indexed.peek(), self.fallback.peek()) {
+            (Some(&&i), Some(&&f)) if self.positions[i] < self.positions[f] => self.indexed.next(),
+            (_, Some(_)) => self.fallback.next(),
+            (Some(_), None) => self.indexed.next(),
+            (None, None) => None,
+        };
+        next.copied()
+    }
+}
+
+impl ServiceBundle {
+    /// Creates a new service bundle from an array of services.
+    ///
+    /// This method initializes a new `ServiceBundle` that can be used to route
+    /// requests to multiple services. It logs the number of services being bundled.
+    ///
+    /// The bundle owns its services, they're dropped with its last clone. Connections
+    /// keep a clone of the bundle, so a bundle replaced with
+    /// [`crate::server::Server::replace_services`] keeps serving the requests in flight.
+    ///
+    /// # Arguments
+    ///
+    /// * `services` - The `Service` instances to bundle, e.g. a `Vec` or an `Arc<[Service]>`
+    ///   shared with other bundles
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `ServiceBundle` instance.
+    pub fn new(services: impl Into<Arc<[Service]>>) -> Self {
+        let services = services.into();
+        info!("Creating service bundle with {} services", services.len());
+        Self {
+            routes: Arc::new(Routes::new(&services, false)),
+            services,
+            from: SocketAddr::from(([0, 0, 0, 0], 1)),
+            tls: false,
+            client_cert: None,
+            responses: Arc::default(),
+            error_response: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            https_redirect: None,
+            health: None,
+            lifecycle: None,
+            metrics_hook: None,
+        }
+    }
+
+    /// Creates a bundle redirecting every request to the same URL over HTTPS.
+    ///
+    /// Meant for a plaintext listener running alongside a TLS one, no upstream is
+    /// involved. Requests are answered with `308 Permanent Redirect`, so clients
+    /// repeat them with the same method and body, see [`utils::https_location`].
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - Port of the TLS listener
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `ServiceBundle` instance.
+    pub fn https_redirect(port: u16) -> Self {
+        info!("Creating service bundle redirecting to HTTPS port {}", port);
+        Self {
+            https_redirect: Some(port),
+            ..Self::new(Vec::new())
+        }
+    }
+
+    /// Sets the response returned when no service matches the request.
+    ///
+    /// By default an empty `404 Not Found` response is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - Function building the response
+    pub fn with_not_found_response(self, response: BundleResponseFunction) -> Self {
+        self.with_response(StatusCode::NOT_FOUND, response)
+    }
+
+    /// Sets the response replacing the empty ones the proxy generates with a status.
+    ///
+    /// Applies to every response built by the proxy itself rather than received from an
+    /// upstream, e.g. `404 Not Found` when no service matches, `413 Payload Too Large`,
+    /// `403 Forbidden` when a body filter rejects a request, `500 Internal Server Error`
+    /// when a middleware fails, `502 Bad Gateway`, `503 Service Unavailable` or
+    /// `504 Gateway Timeout`. Responses of the other statuses stay empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The status of the responses to replace
+    /// * `response` - Function building the response, e.g. a branded error page
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{
+    ///     hyper::{Response, StatusCode, header},
+    ///     server::Server,
+    ///     service::{ProxyResponse, ServiceBundle},
+    /// };
+    /// use http_body_util::{BodyExt as _, Full};
+    /// use tokio::{
+    ///     io::{AsyncReadExt as _, AsyncWriteExt as _},
+    ///     net::TcpStream,
+    /// };
+    ///
+    /// fn not_found() -> ProxyResponse {
+    ///     let body = Full::from(r#"{"error":"not found"}"#)
+    ///         .map_err(|never| match never {})
+    ///         .boxed();
+    ///     Response::builder()
+    ///         .status(StatusCode::NOT_FOUND)
+    ///         .header(header::CONTENT_TYPE, "application/json")
+    ///         .body(body)
+    ///         .unwrap()
+    /// }
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// // No service matches any request
+    /// let bundle = ServiceBundle::new(Vec::new()).with_response(StatusCode::NOT_FOUND, not_found);
+    /// let server = Server::builder()
+    ///     .address("127.0.0.1:0".parse().unwrap())
+    ///     .services(bundle)
+    ///     .build()
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// let mut client = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+    /// client
+    ///     .write_all(b"GET /missing HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+    ///     .await
+    ///     .unwrap();
+    /// server.accept().await.unwrap();
+    /// let mut response = String::new();
+    /// client.read_to_string(&mut response).await.unwrap();
+    /// assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    /// assert!(response.contains("content-type: application/json"));
+    /// assert!(response.ends_with(r#"{"error":"not found"}"#));
+    /// # }
+    /// ```
+    pub fn with_response(mut self, status: StatusCode, response: BundleResponseFunction) -> Self {
+        Arc::make_mut(&mut self.responses).insert(status, response);
+        self
+    }
+
+    /// Sets the response returned when a request fails to be processed.
+    ///
+    /// It replaces the empty `500 Internal Server Error` sent when a header filter
+    /// or a middleware fails, unless one is set for this status with
+    /// [`ServiceBundle::with_response`], and is sent instead of closing the connection when a service fails
+    /// to produce a response, e.g. because a middleware failed. Unreachable upstreams
+    /// are answered with `502 Bad Gateway` instead, see [`ServiceBundle::with_response`].
+    ///
+    /// # Arguments
+    ///
+    /// * `response` - Function building the response
+    pub fn with_error_response(mut self, response: BundleResponseFunction) -> Self {
+        self.error_response = Some(response);
+        self
+    }
+
+    /// Routes requests to the service with the longest matching path prefix.
+    ///
+    /// By default the first matching service is used, so `/api` placed before `/api/v2`
+    /// shadows it. Once enabled, services are tried by decreasing length of their
+    /// [`Filter::PathPrefix`], the longest one if they have several, then in the bundle
+    /// order for prefixes of the same length. Services without a prefix, including the
+    /// ones only filtering paths with [`Filter::Path`] patterns, are tried last.
+    ///
+    /// Prefixes only decide the order: a service is used if all its filters match, so
+    /// one whose host or method filter doesn't match falls through to the services
+    /// with shorter prefixes, and [`Filter::HostExact`] services of another host are
+    /// never tried.
+    ///
+    /// # Arguments
+    ///
+    /// * `enabled` - Whether to route by longest prefix instead of by first match
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{
+    ///     filter::Filter,
+    ///     load_balancer::LoadBalancer,
+    ///     service::{Service, ServiceBundle},
+    ///     upstream::Upstream,
+    /// };
+    ///
+    /// let service = |prefix: &str, address: &str| {
+    ///     let upstream = Upstream::new(address.parse().unwrap(), false);
+    ///     Service::builder()
+    ///         .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+    ///         .filter(Filter::PathPrefix(prefix.to_string()))
+    ///         .build()
+    ///         .unwrap()
+    /// };
+    /// // `/api/v2/users` goes to the second service, although the first one matches too
+    /// let bundle = ServiceBundle::new(vec![
+    ///     service("/api", "127.0.0.1:8080"),
+    ///     service("/api/v2", "127.0.0.1:8081"),
+    /// ])
+    /// .with_longest_prefix(true);
+    /// ```
+    pub fn with_longest_prefix(mut self, enabled: bool) -> Self {
+        self.routes = Arc::new(Routes::new(&self.services, enabled));
+        self
+    }
+
+    /// Sets the maximum request body size of the services that don't set their own.
+    ///
+    /// Defaults to [`DEFAULT_MAX_BODY_SIZE`].
+    ///
+    /// # Arguments
+    ///
+    /// * `max_body_size` - Maximum size of a request body, in bytes
+    pub fn with_max_body_size(mut self, max_body_size: u64) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets the hook observing every completed request, see [`crate::metrics`].
+    ///
+    /// Every request reaching the bundle is reported, including the ones answered
+    /// without an upstream, e.g. when no service matches or a health probe.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook` - The hook, e.g. a closure taking a [`RequestSummary`]
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{metrics::RequestSummary, service::ServiceBundle};
+    ///
+    /// let bundle = ServiceBundle::new(vec![]).with_metrics_hook(Arc::new(|summary: &RequestSummary| {
+    ///     println!("{} {} {:?} in {:?}", summary.method, summary.path, summary.status, summary.duration);
+    /// }));
+    /// ```
+    pub fn with_metrics_hook(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.metrics_hook = Some(hook);
+        self
+    }
+
+    /// Answers a health probe, see [`HealthEndpoints`].
+    ///
+    /// # Returns
+    ///
+    /// Returns the response if the request is a probe, `None` otherwise.
+    fn health_response(&self, header: &Parts) -> Option<ProxyResponse> {
+        let health = self.health.as_ref()?;
+        let path = header.uri.path();
+        let (status, body) = if path == health.live {
+            (StatusCode::OK, "ok")
+        } else if path != health.ready {
+            return None;
+        } else if self.services.iter().all(Service::has_healthy_upstream) {
+            (StatusCode::OK, "ready")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+        };
+        let mut response = Response::new(buffered_body(body, None));
+        *response.status_mut() = status;
+        Some(response)
+    }
+
+    /// Builds the response returned when a request fails to be processed.
+    fn internal_error(&self) -> ProxyResponse {
+        match self.error_response {
+            Some(response) => response(),
+            None => empty_response(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    /// Routes an incoming HTTP request to the first matching service.
+    ///
+    /// This method iterates through all configured services and attempts to find
+    /// the first service that matches the request. It filters the request by header,
+    /// checks for large payloads, and then forwards the request to the selected service.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The incoming HTTP request
+    /// * `summary` - Summary the matched service and chosen upstream are recorded in,
+    ///   when a metrics hook is installed
+    ///
+    /// # Returns
+    ///
+    /// Returns a future that resolves to the HTTP response from the selected service.
+    fn route(
+        &self,
+        req: hyper::Request<Incoming>,
+        summary: Option<&mut RequestSummary>,
+    ) -> ProcessFuture {
+        let (mut header, body) = req.into_parts();
+        let uri = header.uri.clone();
+        let method = header.method.clone();
+
+        debug!("Processing request: {} {}", method, uri);
+
+        if let Some(response) = self.health_response(&header) {
+            debug!("Answered health probe with {}", response.status());
+            return Box::pin(async { Ok(response) });
+        }
+
+        if let Some(port) = self.https_redirect {
+            let response = match utils::https_location(&header, port) {
+                Some(location) => {
+                    debug!("Redirecting request to {:?}", location);
+                    let mut response = empty_response(StatusCode::PERMANENT_REDIRECT);
+                    response
+                        .headers_mut()
+                        .insert(http::header::LOCATION, location);
+                    response
+                }
+                None => {
+                    warn!("Request without host can't be redirected, returning BAD_REQUEST");
+                    empty_response(StatusCode::BAD_REQUEST)
+                }
+            };
+            return Box::pin(async { Ok(response) });
+        }
+
+        if let Some((name, certificate)) = &self.client_cert {
+            header.headers.remove(name);
+            if let Some(certificate) = certificate {
+                header.headers.insert(name.clone(), certificate.clone());
+            }
+        }
+
+        let host = filter::request_host(&header).ok();
+        for i in self.routes.candidates(host.as_deref()) {
+            let service = &self.services[i];
+            debug!("Trying service {} for request", i);
+
+            match service.filter_request_by_header(&self.from, &header) {
+                Ok(found) => {
+                    if !found {
+                        debug!("Service {} did not match request", i);
+                        continue;
+                    }
+                    debug!("Service {} matched request", i);
+                }
+                Err(e) => {
+                    error!("Service {} header filter error: {}", i, e);
+                    let response = self.internal_error();
+                    return Box::pin(async { Ok(response) });
+                }
+            };
+
+            let max_body_size = service.max_body_size.unwrap_or(self.max_body_size);
+            // Bodies without a known size, e.g. chunked ones, are checked while they're read
+            let hint = body.size_hint().upper();
+            debug!("Request body size hint: {:?} bytes", hint);
+
+            if let Some(max) = hint
+                && max > max_body_size
+            {
+                warn!(
+                    "Request body too large ({} bytes), returning PAYLOAD_TOO_LARGE",
+                    max
+                );
+                return Box::pin(async { Ok(empty_response(StatusCode::PAYLOAD_TOO_LARGE)) });
+            }
+
+            if let Some(response) = service.cached_response(&header) {
+                debug!("Service {} served request from cache", i);
+                return Box::pin(async { Ok(response) });
+            }
+
+            if service.forwarded_headers {
+                utils::append_forwarded(&mut header, &self.from, self.tls);
+            }
+
+            let upstream = service.select_upstream(&self.from, &header);
+            debug!("Selected service {} with upstream: {:?}", i, upstream);
+            Span::current()
+                .record("service", i)
+                .record("upstream", field::display(upstream.authority()));
+            if let Some(summary) = summary {
+                summary.service = Some(i);
+                summary.upstream = Some(upstream.authority());
+            }
+
+            #[cfg(feature = "trace-context")]
+            let header = {
+                let mut header = header;
+                let trace_parent = crate::trace_context::propagate(&mut header.headers);
+                Span::current().record(
+                    "trace_id",
+                    field::display(format_args!("{:032x}", trace_parent.trace_id)),
+                );
+                header
+            };
+
+            let future = service.process(upstream, &self.from, header, body, max_body_size);
+            let Some(error_response) = self.error_response else {
+                return future;
+            };
+            return Box::pin(async move {
+                match future.await {
+                    Ok(response) => Ok(response),
+                    Err(e) => {
+                        error!("Failed to process request: {}", e);
+                        Ok(error_response())
+                    }
+                }
+            });
+        }
+
+        warn!("No matching service found for request: {} {}", method, uri);
+        Box::pin(async { Ok(empty_response(StatusCode::NOT_FOUND)) })
+    }
+}
+
+impl HyperService<hyper::Request<Incoming>> for ServiceBundle {
+    type Response = ProxyResponse;
+
+    type Error = BroxyError;
+
+    type Future = ProcessFuture;
+
+    /// Calls the service bundle to process an incoming HTTP request.
+    ///
+    /// The request is processed within a `request` span carrying the method, path,
+    /// client IP, index of the matched service, chosen upstream and response status,
+    /// see [`ServiceBundle::route`]. The span also records how long connecting to the
+    /// upstream took as `connect_ms`, when a new connection was opened, and how long
+    /// the response headers took as `duration_ms`.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The incoming HTTP request
+    ///
+    /// # Returns
+    ///
+    /// Returns a future that resolves to the HTTP response from the selected service.
+    fn call(&self, req: hyper::Request<Incoming>) -> Self::Future {
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            client = %self.from.ip(),
+            service = field::Empty,
+            upstream = field::Empty,
+            status = field::Empty,
+            connect_ms = field::Empty,
+            duration_ms = field::Empty,
+            trace_id = field::Empty,
+        );
+        let started = Instant::now();
+        // HTTP/2 has no connection header, clients are told to leave with a GOAWAY
+        let lifecycle = self
+            .lifecycle
+            .clone()
+            .filter(|_| req.version() < Version::HTTP_2);
+        let mut pending = self.metrics_hook.clone().map(|hook| PendingSummary {
+            hook,
+            started,
+            summary: RequestSummary {
+                method: req.method().clone(),
+                path: req.uri().path().to_string(),
+                service: None,
+                upstream: None,
+                status: None,
+                request_bytes: req.body().size_hint().exact(),
+                response_bytes: 0,
+                duration: Duration::ZERO,
+            },
+        });
+        let summary = pending.as_mut().map(|pending| &mut pending.summary);
+        let future = span.in_scope(|| self.route(req, summary));
+        let responses = self.responses.clone();
+        let error_response = self.error_response;
+        let future = async move {
+            let result = future.await.map(|response| {
+                let custom = response.extensions().get::<Generated>().and_then(|_| {
+                    responses.get(&response.status()).copied().or(error_response
+                        .filter(|_| response.status() == StatusCode::INTERNAL_SERVER_ERROR))
+                });
+                let mut response = match custom {
+                    Some(custom) => custom(),
+                    None => response,
+                };
+                if lifecycle.is_some_and(|lifecycle| *lifecycle.borrow() != Lifecycle::Serving) {
+                    debug!("Server draining, asking the client to close the connection");
+                    response
+                        .headers_mut()
+                        .insert(http::header::CONNECTION, HeaderValue::from_static("close"));
+                }
+                response
+            });
+            let span = Span::current();
+            if let Ok(response) = &result {
+                span.record("status", response.status().as_u16());
+            }
+            span.record("duration_ms", started.elapsed().as_secs_f64() * 1000.0);
+            match pending {
+                Some(pending) => pending.finish(result),
+                None => result,
+            }
+        };
+        Box::pin(future.instrument(span))
+    }
+}
+
+/// `tower` compatibility, so a `ServiceBundle` can be wrapped by `tower::Layer`s.
+///
+/// The bundle is always ready to accept requests. To serve a layered bundle with
+/// hyper, convert it back with `hyper_util::service::TowerToHyperService`.
+#[cfg(feature = "tower")]
+impl tower_service::Service<hyper::Request<Incoming>> for ServiceBundle {
+    type Response = ProxyResponse;
+
+    type Error = BroxyError;
+
+    type Future = ProcessFuture;
+
+    #[inline]
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&mut self, req: hyper::Request<Incoming>) -> Self::Future {
+        HyperService::call(self, req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        middleware::MiddlewareOutgoingFunction,
+        test_support::{echo_upstream, get, proxy, refused_address, send, service_to, upstream},
+    };
+    use std::convert::Infallible;
+
+    /// Middleware uppercasing response bodies, so it needs them buffered.
+    fn shouting() -> Middleware {
+        fn shout(
+            _: &SocketAddr,
+            _: &SocketAddr,
+            _: &mut http::response::Parts,
+            body: &mut Vec<u8>,
+        ) -> anyhow::Result<()> {
+            *body = body.to_ascii_uppercase();
+            Ok(())
+        }
+        Middleware::new(
+            vec![],
+            vec![MiddlewareOutgoingFunction::InternalWithBody(shout)],
+        )
+    }
+
+    /// Starts an upstream answering with the `content-length` and the body of the request,
+    /// `chunked` standing for the length of requests without one.
+    async fn body_echo_upstream() -> SocketAddr {
+        upstream(|request: Request<Incoming>| async move {
+            let length = match request.headers().get(http::header::CONTENT_LENGTH) {
+                Some(length) => length.to_str().unwrap().to_string(),
+                None => "chunked".to_string(),
+            };
+            let body = request.into_body().collect().await.unwrap().to_bytes();
+            let mut echo = format!("{length}:").into_bytes();
+            echo.extend_from_slice(&body);
+            Response::new(Full::new(Bytes::from(echo)))
+        })
+        .await
+    }
+
+    /// Sends a `POST` request carrying `body` on a new connection.
+    async fn post(address: SocketAddr, body: &str) -> String {
+        let request = format!(
+            "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        send(address, request).await
+    }
+
+    fn failing_filter(_: &SocketAddr, _: &Parts) -> anyhow::Result<bool> {
+        anyhow::bail!("filter failed")
+    }
+
+    /// Builds a service with `amount` passing filters, the one at `position` replaced by `filter`.
+    fn service_with_filters(amount: usize, position: usize, filter: Filter) -> Service {
+        let mut builder = service_to(&[refused_address()]);
+        for index in 0..amount {
+            builder = builder.filter(match index == position {
+                true => filter.clone(),
+                false => Filter::Method(http::Method::GET),
+            });
+        }
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn filter_errors_propagate_whatever_the_amount_of_filters() {
+        let from = "127.0.0.1:50000".parse().unwrap();
+        let (header, _) = Request::get("/").body(()).unwrap().into_parts();
+        let rejecting = Filter::Method(http::Method::POST);
+
+        // Sequential with 5 filters, parallel with 6
+        for amount in [5, 6] {
+            for position in [0, amount - 1] {
+                let service =
+                    service_with_filters(amount, position, Filter::CustomFunction(failing_filter));
+                assert!(matches!(
+                    service.filter_request_by_header(&from, &header),
+                    Err(BroxyError::Filter(_))
+                ));
+            }
+
+            // The first filter not passing decides, whatever the others do
+            let mut builder = service_to(&[refused_address()]).filter(rejecting.clone());
+            for _ in 1..amount {
+                builder = builder.filter(Filter::CustomFunction(failing_filter));
+            }
+            let service = builder.build().unwrap();
+            assert!(!service.filter_request_by_header(&from, &header).unwrap());
+
+            let service = service_with_filters(amount, amount - 1, rejecting.clone());
+            assert!(!service.filter_request_by_header(&from, &header).unwrap());
+            let service = service_with_filters(amount, 0, Filter::Method(http::Method::GET));
+            assert!(service.filter_request_by_header(&from, &header).unwrap());
+        }
+    }
+
+    thread_local! {
+        /// Filters evaluated on the current thread
+        static EVALUATED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn counted_filter(_: &SocketAddr, _: &Parts) -> anyhow::Result<bool> {
+        EVALUATED.set(EVALUATED.get() + 1);
+        Ok(true)
+    }
+
+    #[test]
+    fn sequential_filters_are_evaluated_in_order_on_the_calling_thread() {
+        let from = "127.0.0.1:50000".parse().unwrap();
+        let (header, _) = Request::get("/").body(()).unwrap().into_parts();
+        let mut builder = service_to(&[refused_address()]).sequential_filters();
+        for index in 0..10 {
+            builder = builder.filter(match index {
+                4 => Filter::Method(http::Method::POST),
+                _ => Filter::CustomFunction(counted_filter),
+            });
+        }
+        let service = builder.build().unwrap();
+
+        // Filters after the rejecting one aren't evaluated
+        assert!(!service.filter_request_by_header(&from, &header).unwrap());
+        assert_eq!(EVALUATED.get(), 4);
+    }
+
+    #[tokio::test]
+    async fn requests_failing_to_connect_are_retried_on_another_upstream() {
+        let service = service_to(&[refused_address(), body_echo_upstream().await])
+            .retries(2, Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        // Even non-idempotent requests, as they weren't sent
+        let response = post(address, "data").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("4:data"), "{response}");
+    }
+
+    /// Starts an upstream answering every request with `status`.
+    async fn status_upstream(status: StatusCode) -> SocketAddr {
+        upstream(move |_| async move {
+            Response::builder()
+                .status(status)
+                .body(status.as_str().to_string())
+                .unwrap()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn responses_with_a_retried_status_are_retried_on_another_upstream() {
+        let overloaded = status_upstream(StatusCode::SERVICE_UNAVAILABLE).await;
+        let proxy_to = async |upstreams: &[SocketAddr]| {
+            let service = service_to(upstreams)
+                .retries(1, Duration::ZERO)
+                .retry_on_status(HashSet::from([StatusCode::SERVICE_UNAVAILABLE]))
+                .build()
+                .unwrap();
+            proxy(ServiceBundle::new(vec![service])).await
+        };
+
+        let address = proxy_to(&[overloaded, status_upstream(StatusCode::OK).await]).await;
+        assert!(get(address, "/").await.starts_with("HTTP/1.1 200 OK"));
+
+        // Once the retries are exhausted the last response is returned
+        let address = proxy_to(&[overloaded, overloaded]).await;
+        assert!(get(address, "/").await.starts_with("HTTP/1.1 503"));
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_streamed_unprocessed() {
+        use futures::StreamExt as _;
+        use tokio::{
+            io::{AsyncReadExt as _, AsyncWriteExt as _},
+            sync::Notify,
+        };
+
+        const MIB: usize = 1024 * 1024;
+        // Sends the second half of a 4 MiB body only once the client received the first one
+        let first_half_received = Arc::new(Notify::new());
+        let notified = first_half_received.clone();
+        let upstream = upstream(move |_| {
+            let notified = notified.clone();
+            let first = futures::stream::iter([Bytes::from(vec![b'a'; 2 * MIB])]);
+            let second = futures::stream::once(async move {
+                notified.notified().await;
+                Bytes::from(vec![b'a'; 2 * MIB])
+            });
+            let frames = first
+                .chain(second)
+                .map(|data| Ok::<_, Infallible>(Frame::data(data)));
+            async move {
+                Response::builder()
+                    .header(http::header::CONTENT_LENGTH, 4 * MIB)
+                    .body(StreamBody::new(frames))
+                    .unwrap()
+            }
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .middleware(shouting())
+            .max_response_body_size(64 * 1024)
+            .response_body_overflow(ResponseBodyOverflow::Stream)
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut response = Vec::new();
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let read = client.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    break response;
+                }
+                response.extend_from_slice(&buffer[..read]);
+                if response.len() >= 2 * MIB {
+                    first_half_received.notify_one();
+                }
+            }
+        })
+        .await
+        .expect("the response was buffered");
+
+        // The middleware was skipped
+        let response = String::from_utf8(received).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert_eq!(body.len(), 4 * MIB);
+        assert!(body.bytes().all(|byte| byte == b'a'));
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_rejected_or_truncated() {
+        // Sends a chunked body followed by its checksum in a trailer
+        let upstream = upstream(|_| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("0d3e5fb1"));
+            let frames = futures::stream::iter([
+                Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"abcdefgh"))),
+                Ok(Frame::trailers(trailers)),
+            ]);
+            Response::builder()
+                .header(http::header::TRAILER, "x-checksum")
+                .body(StreamBody::new(frames))
+                .unwrap()
+        })
+        .await;
+        let get_with = async |overflow: ResponseBodyOverflow| {
+            let service = service_to(&[upstream])
+                .middleware(shouting())
+                .max_response_body_size(4)
+                .response_body_overflow(overflow)
+                .build()
+                .unwrap();
+            let address = proxy(ServiceBundle::new(vec![service])).await;
+            send(
+                address,
+                "GET / HTTP/1.1\r\nhost: localhost\r\nte: trailers\r\nconnection: close\r\n\r\n",
+            )
+            .await
+        };
+
+        let response = get_with(ResponseBodyOverflow::Reject).await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway"),
+            "{response}"
+        );
+
+        // Cut to the limit and processed, its trailers dropped
+        let response = get_with(ResponseBodyOverflow::Truncate).await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(head.contains("content-length: 4"), "{head}");
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        assert_eq!(body, "ABCD");
+        assert!(!response.contains("x-checksum"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn request_bodies_are_limited_per_service() {
+        // Accepts every body, so it's buffered
+        fn any(_: &SocketAddr, _: &[u8]) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+        let upstream = body_echo_upstream().await;
+        let bundle = ServiceBundle::new(vec![
+            service_to(&[upstream])
+                .filter(Filter::PathPrefix("/upload".to_string()))
+                .body_filter(BodyFilter::InternalFullBody(any))
+                .max_body_size(32)
+                .build()
+                .unwrap(),
+            service_to(&[upstream]).build().unwrap(),
+        ])
+        .with_max_body_size(16);
+        let address = proxy(bundle).await;
+        let post = async |path: &str, framing: &str, body: &str| {
+            send(
+                address,
+                format!("POST {path} HTTP/1.1\r\nhost: localhost\r\n{framing}\r\nconnection: close\r\n\r\n{body}"),
+            )
+            .await
+        };
+        let sized = async |path: &str, body: &str| {
+            post(path, &format!("content-length: {}", body.len()), body).await
+        };
+        let chunked = async |path: &str, chunks: &[&str]| {
+            let mut body: String = chunks
+                .iter()
+                .map(|chunk| format!("{:x}\r\n{chunk}\r\n", chunk.len()))
+                .collect();
+            body.push_str("0\r\n\r\n");
+            post(path, "transfer-encoding: chunked", &body).await
+        };
+        let too_large = |response: String| response.starts_with("HTTP/1.1 413 Payload Too Large");
+
+        // The service limit applies to the announced length, even above the bundle's
+        let response = sized("/upload", &"a".repeat(24)).await;
+        assert!(
+            response.ends_with(&format!("24:{}", "a".repeat(24))),
+            "{response}"
+        );
+        assert!(too_large(sized("/upload", &"a".repeat(40)).await));
+        // Chunked bodies are checked while they're read
+        let response = chunked(
+            "/upload",
+            &["a".repeat(12).as_str(), "b".repeat(12).as_str()],
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with(&format!("{}{}", "a".repeat(12), "b".repeat(12))));
+        let response = chunked(
+            "/upload",
+            &["a".repeat(20).as_str(), "b".repeat(20).as_str()],
+        )
+        .await;
+        assert!(too_large(response));
+
+        // Services without a limit use the bundle's
+        assert!(
+            sized("/other", "0123456789")
+                .await
+                .ends_with("10:0123456789")
+        );
+        assert!(too_large(sized("/other", &"a".repeat(20)).await));
+    }
+
+    #[tokio::test]
+    async fn trailers_of_buffered_responses_are_forwarded() {
+        // Sends a chunked body followed by its checksum in a trailer
+        let upstream = upstream(|_| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-checksum", HeaderValue::from_static("5d41402a"));
+            let frames = futures::stream::iter([
+                Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"hello"))),
+                Ok(Frame::trailers(trailers)),
+            ]);
+            Response::builder()
+                .header(http::header::TRAILER, "x-checksum")
+                .body(StreamBody::new(frames))
+                .unwrap()
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .middleware(shouting())
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        let response = send(
+            address,
+            "GET / HTTP/1.1\r\nhost: localhost\r\nte: trailers\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("transfer-encoding: chunked"), "{head}");
+        // The body went through the middleware, and kept its trailer
+        assert_eq!(body, "5\r\nHELLO\r\n0\r\nx-checksum: 5d41402a\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn stalling_upstreams_are_timed_out_and_hung_up_on() {
+        use tokio::{
+            io::{AsyncReadExt as _, AsyncWriteExt as _},
+            net::TcpListener,
+            sync::oneshot,
+        };
+
+        // Sends the start of a response, then stalls until the proxy hangs up
+        async fn stalling_upstream(start: &'static [u8]) -> (SocketAddr, oneshot::Receiver<()>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let (closed, closed_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let _ = conn.read(&mut [0; 1024]).await.unwrap();
+                conn.write_all(start).await.unwrap();
+                while conn.read(&mut [0; 1024]).await.is_ok_and(|read| read > 0) {}
+                closed.send(()).unwrap();
+            });
+            (address, closed_rx)
+        }
+        // Serves a single request, so no task is left behind
+        async fn request(upstream: SocketAddr, middleware: Option<Middleware>) -> String {
+            let mut service = service_to(&[upstream]).upstream_timeout(Duration::from_millis(100));
+            if let Some(middleware) = middleware {
+                service = service.middleware(middleware);
+            }
+            let server = crate::server::Server::builder()
+                .address("127.0.0.1:0".parse().unwrap())
+                .services(ServiceBundle::new(vec![service.build().unwrap()]))
+                .build()
+                .await
+                .unwrap();
+            let mut client = TcpStream::connect(server.local_addr().unwrap())
+                .await
+                .unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            server.accept().await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        }
+        let timed_out = |response: String| response.starts_with("HTTP/1.1 504 Gateway Timeout");
+
+        // No response headers
+        let (upstream, closed) = stalling_upstream(b"").await;
+        assert!(timed_out(request(upstream, None).await));
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Incomplete body, buffered for the middleware
+        let (upstream, closed) =
+            stalling_upstream(b"HTTP/1.1 200 OK\r\ncontent-length: 2048\r\n\r\nstart").await;
+        assert!(timed_out(request(upstream, Some(shouting())).await));
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_requests_do_not_leak_upstream_tasks() {
+        use tokio::{
+            io::{AsyncReadExt as _, AsyncWriteExt as _},
+            net::TcpListener,
+        };
+
+        // Fails every response once the upstream answered
+        fn failing(
+            _: &SocketAddr,
+            _: &SocketAddr,
+            _: &mut http::response::Parts,
+        ) -> anyhow::Result<()> {
+            anyhow::bail!("rejected response")
+        }
+        // Accepts JSON objects only
+        fn is_object(_: &SocketAddr, body: &[u8]) -> anyhow::Result<bool> {
+            Ok(body.starts_with(b"{"))
+        }
+        // An upstream sending the start of its responses, then stalling
+        async fn stalling_upstream(start: &'static [u8]) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                loop {
+                    let (mut conn, _) = listener.accept().await.unwrap();
+                    tokio::spawn(async move {
+                        let _ = conn.read(&mut [0; 1024]).await;
+                        let _ = conn.write_all(start).await;
+                        while conn.read(&mut [0; 1024]).await.is_ok_and(|read| read > 0) {}
+                    });
+                }
+            });
+            address
+        }
+        let echo = echo_upstream().await;
+        let routed = |path: &str, upstream: SocketAddr| {
+            service_to(&[upstream]).filter(Filter::PathPrefix(path.to_string()))
+        };
+        let bundle = ServiceBundle::new(vec![
+            routed("/refused", refused_address()).build().unwrap(),
+            routed("/stalling", stalling_upstream(b"").await)
+                .upstream_timeout(Duration::from_millis(50))
+                .build()
+                .unwrap(),
+            routed("/rejected", echo)
+                .body_filter(BodyFilter::InternalFullBody(is_object))
+                .build()
+                .unwrap(),
+            routed(
+                "/failing",
+                stalling_upstream(b"HTTP/1.1 200 OK\r\ncontent-length: 2048\r\n\r\nstart").await,
+            )
+            .middleware(Middleware::new(
+                vec![],
+                vec![MiddlewareOutgoingFunction::Internal(failing)],
+            ))
+            .build()
+            .unwrap(),
+        ]);
+        let address = proxy(bundle).await;
+        let fail_every_way = async || {
+            let response = get(address, "/refused").await;
+            assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+            let response = get(address, "/stalling").await;
+            assert!(response.starts_with("HTTP/1.1 504"), "{response}");
+            let response = send(
+                address,
+                "POST /rejected HTTP/1.1\r\nhost: localhost\r\ncontent-length: 2\r\nconnection: close\r\n\r\n[]",
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+            let response = get(address, "/failing").await;
+            assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        };
+
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let alive = async || {
+            // Lets the tasks of closed connections end
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            metrics.num_alive_tasks()
+        };
+        fail_every_way().await;
+        let before = alive().await;
+        for _ in 0..20 {
+            fail_every_way().await;
+        }
+        let settled = async { while alive().await > before {} };
+        tokio::time::timeout(Duration::from_secs(5), settled)
+            .await
+            .expect("tasks of failed requests are left behind");
+    }
+
+    #[tokio::test]
+    async fn requests_are_traced_with_their_timing() {
+        use std::{collections::HashMap, sync::Mutex};
+        use tracing::{
+            field::{Field, Visit},
+            span::{Attributes, Id, Record},
+        };
+        use tracing_subscriber::{
+            layer::{Context, Layer, SubscriberExt as _},
+            registry::Registry,
+        };
+
+        // A layer collecting the fields recorded on `request` spans
+        #[derive(Clone, Default)]
+        struct Fields(Arc<Mutex<HashMap<String, String>>>);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                let value = format!("{value:?}");
+                self.0
+                    .lock()
+                    .unwrap()
+                    .insert(field.name().to_string(), value);
+            }
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for Fields {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+                if attrs.metadata().name() == "request" {
+                    attrs.record(&mut self.clone());
+                }
+            }
+
+            fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+                values.record(&mut self.clone());
+            }
+        }
+
+        let fields = Fields::default();
+        let _subscriber =
+            tracing::subscriber::set_default(Registry::default().with(fields.clone()));
+        let upstream = echo_upstream().await;
+        let service = service_to(&[upstream]).build().unwrap();
+        get(proxy(ServiceBundle::new(vec![service])).await, "/slow").await;
+
+        let fields = fields.0.lock().unwrap();
+        assert_eq!(fields["method"], "GET");
+        assert_eq!(fields["path"], "/slow");
+        assert_eq!(fields["service"], "0");
+        assert_eq!(fields["upstream"], upstream.to_string());
+        assert_eq!(fields["status"], "200");
+        assert!(fields["connect_ms"].parse::<f64>().unwrap() >= 0.0);
+        assert!(fields["duration_ms"].parse::<f64>().unwrap() >= 0.0);
+    }
+
+    #[tokio::test]
+    async fn sticky_cookie_pins_clients_to_their_upstream() {
+        let named =
+            |name: &'static str| upstream(move |_| async move { Response::new(name.to_string()) });
+        let (a, b) = (named("a").await, named("b").await);
+        let load_balancer = Arc::new(LoadBalancer::new(vec![
+            Upstream::new(a, false),
+            Upstream::new(b, false),
+        ]));
+        let service = Service::builder()
+            .load_balancer(load_balancer.clone())
+            .sticky_cookie(DEFAULT_STICKY_COOKIE)
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let get_with = async |cookie: Option<&str>| {
+            let cookie = cookie
+                .map(|cookie| format!("cookie: {cookie}\r\n"))
+                .unwrap_or_default();
+            let request =
+                format!("GET / HTTP/1.1\r\nhost: localhost\r\n{cookie}connection: close\r\n\r\n");
+            let response = send(address, request).await;
+            let set_cookie = response
+                .lines()
+                .find_map(|line| line.strip_prefix("set-cookie: "))
+                .and_then(|cookie| cookie.split(';').next())
+                .map(str::to_string);
+            (response.chars().last().unwrap(), set_cookie)
+        };
+
+        // The first response pins the client to its upstream
+        let (first, cookie) = get_with(None).await;
+        let cookie = cookie.unwrap();
+        assert!(cookie.starts_with("BROXY_UPSTREAM="), "{cookie}");
+        for _ in 0..4 {
+            assert_eq!(get_with(Some(&cookie)).await, (first, None));
+        }
+
+        // Once its upstream is gone the client is balanced again, and pinned to the other one
+        load_balancer.remove_upstream(if first == 'a' { a } else { b });
+        let (other, repinned) = get_with(Some(&cookie)).await;
+        assert_ne!(other, first);
+        assert_ne!(repinned.unwrap(), cookie);
+    }
+
+    #[tokio::test]
+    async fn retries_back_off_and_are_limited_by_the_budget() {
+        use crate::retry_budget::RetryBudget;
+        use std::{sync::Mutex, time::Instant};
+
+        // An upstream in the middle of an outage, recording when it's contacted
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let recorded = attempts.clone();
+        let upstream = upstream(move |_| {
+            recorded.lock().unwrap().push(Instant::now());
+            async { empty_response(StatusCode::SERVICE_UNAVAILABLE) }
+        })
+        .await;
+        let proxy_with = async |budget: Option<RetryBudget>| {
+            let service = service_to(&[upstream])
+                .retries(3, Duration::from_millis(40))
+                .retry_on_status(HashSet::from([StatusCode::SERVICE_UNAVAILABLE]));
+            let service = match budget {
+                Some(budget) => service.retry_budget(Arc::new(budget)),
+                None => service,
+            };
+            proxy(ServiceBundle::new(vec![service.build().unwrap()])).await
+        };
+
+        // Without a budget every retry is sent, each one waiting longer than the previous one
+        let address = proxy_with(None).await;
+        assert!(get(address, "/").await.starts_with("HTTP/1.1 503"));
+        let delays = attempts
+            .lock()
+            .unwrap()
+            .windows(2)
+            .map(|attempts| attempts[1] - attempts[0])
+            .collect::<Vec<_>>();
+        assert_eq!(delays.len(), 3);
+        assert!(delays[1] >= Duration::from_millis(40));
+        assert!(delays[2] >= Duration::from_millis(80));
+        assert!(delays[2] > delays[0]);
+
+        // With a budget of 10% of the requests, 20 failing requests are retried twice overall
+        attempts.lock().unwrap().clear();
+        let budget = RetryBudget::new(0.1, Duration::from_secs(60)).with_min_retries(0);
+        let address = proxy_with(Some(budget)).await;
+        for _ in 0..20 {
+            assert!(get(address, "/").await.starts_with("HTTP/1.1 503"));
+        }
+        assert_eq!(attempts.lock().unwrap().len(), 22);
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_spilled_and_forwarded_whole() {
+        // An upstream answering with the size of the body it received
+        let upstream = upstream(|request: Request<Incoming>| async move {
+            let body = request.into_body().collect().await.unwrap().to_bytes();
+            Response::new(body.len().to_string())
+        })
+        .await;
+        let service = service_to(&[upstream])
+            .max_body_size(4 * 1024 * 1024)
+            .spill_threshold(64 * 1024)
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+
+        // A 1 MiB upload, well above the threshold
+        let response = post(address, &"x".repeat(1024 * 1024)).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("\r\n\r\n1048576"), "{response}");
+        // Small bodies stay in memory
+        assert!(post(address, "small").await.ends_with("\r\n\r\n5"));
+    }
+
+    #[tokio::test]
+    async fn spilled_bodies_are_never_held_in_memory() {
+        // 4 MiB sent in 1 MiB frames, with a threshold well below a single frame
+        let frames = futures::stream::iter(
+            (0..4).map(|_| Ok::<_, hyper::Error>(Frame::data(Bytes::from(vec![b'x'; 1 << 20])))),
+        );
+        let mut body = StreamBody::new(frames);
+        let (collected, trailers, too_large) =
+            collect_spilling(&mut body, u64::MAX, 1024).await.unwrap();
+        assert!(!too_large);
+        let CollectedBody::Spilled(file) = collected else {
+            panic!("the body wasn't spilled");
+        };
+
+        // It's streamed back from the file in bounded chunks
+        let mut body = spilled_body(file, trailers).await.unwrap();
+        let mut length = 0;
+        while let Some(frame) = body.frame().await {
+            let data = frame.unwrap().into_data().unwrap();
+            assert!(
+                data.len() <= SPILLED_CHUNK_SIZE,
+                "{} bytes read",
+                data.len()
+            );
+            length += data.len();
+        }
+        assert_eq!(length, 4 << 20);
+    }
+
+    #[test]
+    fn spilling_bodies_needed_in_memory_is_refused() {
+        let upstream: SocketAddr = ([127, 0, 0, 1], 80).into();
+        let service = service_to(&[upstream])
+            .spill_threshold(1024)
+            .body_filter(BodyFilter::InternalFullBody(|_, _| Ok(true)));
+        assert!(matches!(service.build(), Err(BroxyError::Config(_))));
+        let service = service_to(&[upstream])
+            .spill_threshold(1024)
+            .mirror(Upstream::new(upstream, false), 1.0);
+        assert!(matches!(service.build(), Err(BroxyError::Config(_))));
+        // Without them the body is only forwarded, so it can be spilled
+        assert!(
+            service_to(&[upstream])
+                .spill_threshold(1024)
+                .build()
+                .is_ok()
+        );
+    }
+
+    /// Builds a service forwarding to `/<name>` on `upstream`, so that an upstream
+    /// echoing paths tells which service was used.
+    fn named_service(upstream: SocketAddr, name: &str, filters: Vec<Filter>) -> Service {
+        let upstream =
+            Upstream::new(upstream, false).with_root_path(format!("/{name}").parse().unwrap());
+        filters
+            .into_iter()
+            .fold(
+                Service::builder().load_balancer(Arc::new(LoadBalancer::new(vec![upstream]))),
+                ServiceBuilder::filter,
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn indexed_hosts_are_routed_like_scanned_ones() {
+        use regex::Regex;
+
+        let upstream = echo_upstream().await;
+        let service = |name: &str, filter: Option<Filter>| {
+            named_service(upstream, name, filter.into_iter().collect())
+        };
+        // Hundreds of domains, matched exactly or with a pattern each
+        let services = |indexed: bool| {
+            let host = |host: &str| match indexed {
+                true => Filter::HostExact(host.to_string()),
+                false => Filter::HostInsensitive(
+                    Regex::new(&format!("^{}$", regex::escape(host))).unwrap(),
+                ),
+            };
+            let wildcard = Filter::HostInsensitive(Regex::new(r"\.wild\.test$").unwrap());
+            let mut services = vec![service("wild", Some(wildcard))];
+            services.extend(
+                (0..300)
+                    .map(|i| service(&format!("host{i}"), Some(host(&format!("host{i}.test"))))),
+            );
+            // Shadowed by the first service of the host
+            services.push(service("shadowed", Some(host("host7.test"))));
+            services.push(service("default", None));
+            ServiceBundle::new(services)
+        };
+        let get = async |address: SocketAddr, host: &str| {
+            let request = format!("GET / HTTP/1.1\r\nhost: {host}\r\nconnection: close\r\n\r\n");
+            let response = send(address, request).await;
+            response.rsplit("\r\n").next().unwrap().to_string()
+        };
+
+        let indexed = proxy(services(true)).await;
+        let scanned = proxy(services(false)).await;
+        for (host, expected) in [
+            ("host7.test", "/host7/"),
+            ("HOST250.test:8080", "/host250/"),
+            ("host299.test", "/host299/"),
+            ("host1.wild.test", "/wild/"),
+            ("unknown.test", "/default/"),
+        ] {
+            assert_eq!(get(indexed, host).await, expected);
+            assert_eq!(get(scanned, host).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn longest_path_prefix_wins_when_enabled() {
+        let upstream = echo_upstream().await;
+        let service = |name: &str, filters: Vec<Filter>| named_service(upstream, name, filters);
+        let prefix = |prefix: &str| Filter::PathPrefix(prefix.to_string());
+        let services = vec![
+            service("default", vec![]),
+            service("api", vec![prefix("/api")]),
+            service("v2", vec![prefix("/api/v2")]),
+            service(
+                "v3-writes",
+                vec![prefix("/api/v3"), Filter::Method(Method::POST)],
+            ),
+        ];
+        let address = proxy(ServiceBundle::new(services).with_longest_prefix(true)).await;
+        let send = async |method: &str, path: &str| {
+            let request = format!(
+                "{method} {path} HTTP/1.1\r\nhost: localhost\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+            );
+            let response = send(address, request).await;
+            response.rsplit("\r\n").next().unwrap().to_string()
+        };
+
+        assert_eq!(send("GET", "/api/v2/users").await, "/v2/api/v2/users");
+        assert_eq!(send("GET", "/api/v1/users").await, "/api/api/v1/users");
+        // Prefixes match whole path segments
+        assert_eq!(send("GET", "/api/v20").await, "/api/api/v20");
+        assert_eq!(send("GET", "/apis").await, "/default/apis");
+        // Services whose other filters don't match fall through to shorter prefixes
+        assert_eq!(
+            send("POST", "/api/v3/users").await,
+            "/v3-writes/api/v3/users"
+        );
+        assert_eq!(send("GET", "/api/v3/users").await, "/api/api/v3/users");
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstreams_get_bad_gateway() {
+        fn bad_gateway() -> ProxyResponse {
+            let mut response = Response::new(
+                Full::from("upstream unreachable")
+                    .map_err(|never| match never {})
                     .boxed(),
             );
-            *response.status_mut() = StatusCode::NOT_FOUND;
-            Ok(response)
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            response
+        }
+        let service = service_to(&[refused_address()]).build().unwrap();
+
+        let address = proxy(ServiceBundle::new(vec![service.clone()])).await;
+        let response = get(address, "/").await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway"),
+            "{response}"
+        );
+        let address = proxy(
+            ServiceBundle::new(vec![service]).with_response(StatusCode::BAD_GATEWAY, bad_gateway),
+        )
+        .await;
+        assert!(get(address, "/").await.ends_with("upstream unreachable"));
+    }
+
+    #[tokio::test]
+    async fn custom_responses_replace_the_generated_ones() {
+        fn failing(
+            _: &SocketAddr,
+            _: &SocketAddr,
+            _: &mut http::response::Parts,
+        ) -> anyhow::Result<()> {
+            anyhow::bail!("rejected response")
+        }
+        fn custom(status: StatusCode, body: &'static str) -> ProxyResponse {
+            let mut response =
+                Response::new(Full::from(body).map_err(|never| match never {}).boxed());
+            *response.status_mut() = status;
+            response
+        }
+        let service = |path: &str, upstream: SocketAddr| {
+            service_to(&[upstream]).filter(Filter::PathPrefix(path.to_string()))
+        };
+        let bundle = ServiceBundle::new(vec![
+            service("/missing", status_upstream(StatusCode::NOT_FOUND).await)
+                .build()
+                .unwrap(),
+            service("/failing", echo_upstream().await)
+                .middleware(Middleware::new(
+                    vec![],
+                    vec![MiddlewareOutgoingFunction::Internal(failing)],
+                ))
+                .build()
+                .unwrap(),
+            service("/large", echo_upstream().await)
+                .middleware(shouting())
+                .max_response_body_size(4)
+                .build()
+                .unwrap(),
+        ])
+        .with_response(StatusCode::NOT_FOUND, || {
+            custom(StatusCode::NOT_FOUND, "no such page")
         })
+        .with_response(StatusCode::INTERNAL_SERVER_ERROR, || {
+            custom(StatusCode::INTERNAL_SERVER_ERROR, "something went wrong")
+        })
+        .with_response(StatusCode::BAD_GATEWAY, || {
+            custom(StatusCode::BAD_GATEWAY, "upstream misbehaved")
+        });
+        let address = proxy(bundle).await;
+
+        // No service matches
+        let response = get(address, "/unknown").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        assert!(response.ends_with("no such page"), "{response}");
+        // Responses of upstreams are kept, whatever their status
+        let response = get(address, "/missing").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        assert!(response.ends_with("\r\n\r\n404"), "{response}");
+        // A middleware fails
+        let response = get(address, "/failing").await;
+        assert!(response.starts_with("HTTP/1.1 500"), "{response}");
+        assert!(response.ends_with("something went wrong"), "{response}");
+        // The response body is too large to be buffered
+        let response = get(address, "/large/path").await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(response.ends_with("upstream misbehaved"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn middleware_errors_get_the_error_response() {
+        fn failing(
+            _: &SocketAddr,
+            _: &SocketAddr,
+            _: &mut http::response::Parts,
+        ) -> anyhow::Result<()> {
+            anyhow::bail!("rejected response")
+        }
+        fn error() -> ProxyResponse {
+            let mut response = Response::new(
+                Full::from("something went wrong")
+                    .map_err(|never| match never {})
+                    .boxed(),
+            );
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+        let service = service_to(&[echo_upstream().await])
+            .middleware(Middleware::new(
+                vec![],
+                vec![MiddlewareOutgoingFunction::Internal(failing)],
+            ))
+            .build()
+            .unwrap();
+
+        let address = proxy(ServiceBundle::new(vec![service.clone()])).await;
+        let response = get(address, "/").await;
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error"),
+            "{response}"
+        );
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+        let address = proxy(ServiceBundle::new(vec![service]).with_error_response(error)).await;
+        let response = get(address, "/").await;
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error"),
+            "{response}"
+        );
+        assert!(response.ends_with("something went wrong"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn body_filter_rejections_get_the_not_found_response() {
+        // Accepts JSON objects only
+        fn is_object(_: &SocketAddr, body: &[u8]) -> anyhow::Result<bool> {
+            Ok(body.starts_with(b"{"))
+        }
+        fn rejected(_: &SocketAddr, _: &[u8]) -> ProxyResponse {
+            let mut response = Response::new(
+                Full::from("expected a JSON object")
+                    .map_err(|never| match never {})
+                    .boxed(),
+            );
+            *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            response
+        }
+        let service = service_to(&[status_upstream(StatusCode::CREATED).await])
+            .filter(Filter::Method(Method::POST))
+            .filter(Filter::PathPrefix("/orders".to_string()))
+            .body_filter(BodyFilter::InternalFullBody(is_object))
+            .not_found_response(rejected)
+            .max_body_size(1024)
+            .build()
+            .unwrap();
+        let address = proxy(ServiceBundle::new(vec![service])).await;
+        let post = async |body: &str| {
+            let request = format!(
+                "POST /orders HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            send(address, request).await
+        };
+
+        let response = post("[]").await;
+        assert!(
+            response.starts_with("HTTP/1.1 422 Unprocessable Entity"),
+            "{response}"
+        );
+        assert!(response.ends_with("expected a JSON object"), "{response}");
+        assert!(
+            post(r#"{"item":1}"#)
+                .await
+                .starts_with("HTTP/1.1 201 Created")
+        );
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_requests_are_retried() {
+        use tokio::{io::AsyncReadExt as _, net::TcpListener};
+
+        // An upstream hanging up on every request it receives, and a healthy one
+        let failing = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failing_address = failing.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = failing.accept().await.unwrap();
+                let _ = conn.read(&mut [0; 1024]).await;
+            }
+        });
+        let healthy = status_upstream(StatusCode::OK).await;
+        // Sends a POST to the failing upstream first, then to the healthy one if retried
+        let post = async |methods: Option<HashSet<Method>>, headers: &str| {
+            let service = service_to(&[failing_address, healthy])
+                .retries(1, Duration::ZERO)
+                // Buffers the body, so it can be replayed
+                .spill_threshold(1024);
+            let service = match methods {
+                Some(methods) => service.retry_methods(methods),
+                None => service,
+            };
+            let address = proxy(ServiceBundle::new(vec![service.build().unwrap()])).await;
+            let request = format!(
+                "POST /payments HTTP/1.1\r\nhost: localhost\r\n{headers}content-length: 4\r\nconnection: close\r\n\r\ndata"
+            );
+            send(address, request).await
+        };
+
+        // Not retried by default
+        let response = post(None, "").await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway"),
+            "{response}"
+        );
+        // Retried when it carries an idempotency key
+        let response = post(None, "idempotency-key: 8e03978e\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        // Retried when its method is declared idempotent
+        let mut methods = HashSet::from(DEFAULT_RETRY_METHODS);
+        methods.insert(Method::POST);
+        let response = post(Some(methods), "").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn plaintext_requests_are_redirected_to_https() {
+        let address = proxy(ServiceBundle::https_redirect(8443)).await;
+        let location = |response: &str| {
+            response
+                .lines()
+                .find_map(|line| line.strip_prefix("location: "))
+                .map(str::to_string)
+        };
+
+        let response = send(
+            address,
+            "GET /users?page=2 HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 308 Permanent Redirect"),
+            "{response}"
+        );
+        assert_eq!(
+            location(&response).as_deref(),
+            Some("https://example.com:8443/users?page=2")
+        );
+        // Other methods are redirected alike, the client repeating them with their body
+        let response = send(
+            address,
+            "POST /submit HTTP/1.1\r\nhost: example.com:80\r\ncontent-length: 2\r\nconnection: close\r\n\r\nhi",
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 308 Permanent Redirect"),
+            "{response}"
+        );
+        assert_eq!(
+            location(&response).as_deref(),
+            Some("https://example.com:8443/submit")
+        );
+        // There's nowhere to redirect requests without a host to
+        let response = send(address, "GET / HTTP/1.0\r\n\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.0 400 Bad Request"),
+            "{response}"
+        );
+        assert_eq!(location(&response), None);
+    }
+
+    #[tokio::test]
+    async fn metrics_hook_observes_every_request() {
+        use crate::metrics::RequestSummary;
+        use std::sync::Mutex;
+
+        let upstream = upstream(|_| async { Response::new("created".to_string()) }).await;
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = summaries.clone();
+        let service = service_to(&[upstream])
+            .filter(Filter::PathPrefix("/api".to_string()))
+            .build()
+            .unwrap();
+        let bundle = ServiceBundle::new(vec![service]).with_metrics_hook(Arc::new(
+            move |summary: &RequestSummary| recorded.lock().unwrap().push(summary.clone()),
+        ));
+        let address = proxy(bundle).await;
+
+        send(
+            address,
+            "POST /api/users?page=2 HTTP/1.1\r\nhost: localhost\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbob!",
+        )
+        .await;
+        get(address, "/missing").await;
+
+        let summaries = summaries.lock().unwrap();
+        let proxied = &summaries[0];
+        assert_eq!(proxied.method, Method::POST);
+        assert_eq!(proxied.path, "/api/users");
+        assert_eq!(proxied.service, Some(0));
+        assert_eq!(proxied.upstream, Some(upstream.to_string()));
+        assert_eq!(proxied.status, Some(StatusCode::OK));
+        assert_eq!(proxied.request_bytes, Some(4));
+        assert_eq!(proxied.response_bytes, 7);
+        assert!(!proxied.duration.is_zero());
+
+        let unmatched = &summaries[1];
+        assert_eq!(unmatched.service, None);
+        assert_eq!(unmatched.upstream, None);
+        assert_eq!(unmatched.status, Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn panicking_metrics_hooks_dont_fail_requests() {
+        use crate::metrics::RequestSummary;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let upstream = echo_upstream().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let bundle = ServiceBundle::new(vec![service_to(&[upstream]).build().unwrap()])
+            .with_metrics_hook(Arc::new(move |_: &RequestSummary| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                panic!("metrics backend unavailable");
+            }));
+        let address = proxy(bundle).await;
+
+        // Both requests are answered whole, the connection task surviving the first panic
+        for path in ["/first", "/second"] {
+            let response = get(address, path).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+            assert!(response.ends_with(path), "{response}");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
     }
 }
@@ -0,0 +1,175 @@
+//! Helpers shared by the unit tests: fake upstreams, a proxy listening in front of
+//! them, a raw HTTP/1.1 client, the fixture files and the example plugin.
+
+use crate::{
+    load_balancer::LoadBalancer,
+    server::Server,
+    service::{Service, ServiceBuilder, ServiceBundle},
+    upstream::Upstream,
+};
+use hyper::{Request, Response, body::Incoming, service::service_fn};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    path::PathBuf,
+    process::Command,
+    sync::{Arc, OnceLock},
+};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+
+/// Starts an upstream answering every request with `handler`, over HTTP/1.1 or HTTP/2.
+///
+/// # Returns
+///
+/// The address the upstream listens on.
+pub(crate) async fn upstream<F, Fut, B>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<B>> + Send + 'static,
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((conn, _)) = listener.accept().await else {
+                continue;
+            };
+            let handler = handler.clone();
+            let service = service_fn(move |request| {
+                let response = handler(request);
+                async move { Ok::<_, Infallible>(response.await) }
+            });
+            tokio::spawn(async move {
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(conn), service)
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+/// Reads a file of the `fixtures` directory.
+pub(crate) fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}/fixtures/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap()
+}
+
+/// Builds the example plugin of `plugins/example`, once per test run.
+///
+/// # Returns
+///
+/// The path of its dynamic library.
+pub(crate) fn example_plugin() -> PathBuf {
+    static LIBRARY: OnceLock<PathBuf> = OnceLock::new();
+    LIBRARY
+        .get_or_init(|| {
+            let root = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/.."));
+            let target = root.join("target/test-plugins");
+            let status = Command::new(env!("CARGO"))
+                .current_dir(&root)
+                .args([
+                    "build",
+                    "--quiet",
+                    "-p",
+                    "broxy-example-plugin",
+                    "--target-dir",
+                ])
+                .arg(&target)
+                .status()
+                .unwrap();
+            assert!(status.success(), "failed to build the example plugin");
+            target.join(format!(
+                "debug/{}broxy_example_plugin{}",
+                std::env::consts::DLL_PREFIX,
+                std::env::consts::DLL_SUFFIX
+            ))
+        })
+        .clone()
+}
+
+/// Starts an upstream answering every request with `200 OK` and the request path.
+pub(crate) async fn echo_upstream() -> SocketAddr {
+    upstream(
+        |request: Request<Incoming>| async move { Response::new(request.uri().path().to_string()) },
+    )
+    .await
+}
+
+/// Returns an address nothing listens on, so connecting to it is refused.
+pub(crate) fn refused_address() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Starts building a service balancing requests round-robin across `upstreams`.
+pub(crate) fn service_to(upstreams: &[SocketAddr]) -> ServiceBuilder {
+    let upstreams = upstreams
+        .iter()
+        .map(|address| Upstream::new(*address, false))
+        .collect();
+    Service::builder().load_balancer(Arc::new(LoadBalancer::new(upstreams)))
+}
+
+/// Starts a server in front of `bundle`, accepting connections until the test ends.
+///
+/// # Returns
+///
+/// The address the server listens on.
+pub(crate) async fn proxy(bundle: ServiceBundle) -> SocketAddr {
+    serve(
+        Server::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .services(bundle)
+            .build()
+            .await
+            .unwrap(),
+    )
+}
+
+/// Accepts connections on `server` until the test ends.
+///
+/// # Returns
+///
+/// The address the server listens on.
+pub(crate) fn serve(server: Server) -> SocketAddr {
+    let address = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let _ = server.accept().await;
+        }
+    });
+    address
+}
+
+/// Sends a raw request on a new connection and reads until the server closes it.
+///
+/// # Returns
+///
+/// The raw response, status line, headers and body.
+pub(crate) async fn send(address: SocketAddr, request: impl AsRef<[u8]>) -> String {
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client.write_all(request.as_ref()).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Sends a `GET` request for `path` on a new connection, see [`send`].
+pub(crate) async fn get(address: SocketAddr, path: &str) -> String {
+    send(
+        address,
+        format!("GET {path} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n"),
+    )
+    .await
+}
@@ -0,0 +1,177 @@
+//! Selection of the certificate presented to TLS clients.
+//!
+//! A listener terminating several domains holds a certificate per domain. The
+//! [`SniResolver`] picks the one matching the server name the client sent in its
+//! hello (SNI), falling back to a default certificate for clients that sent none
+//! or asked for an unknown name. Entry points configure it with
+//! [`crate::config::Ssl::certificates`].
+//!
+//! Certificates are renewed while the proxy runs, a [`ReloadableResolver`] lets the
+//! ones of a listener be replaced without restarting it.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use arc_swap::ArcSwap;
+
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tracing::debug;
+
+/// Resolves the certificate of a TLS connection from its server name.
+///
+/// Names are matched without case. A wildcard name, e.g. `*.example.com`, matches
+/// a single label in place of the `*`: `api.example.com`, but neither `example.com`
+/// nor `v1.api.example.com`. Names added explicitly take precedence over wildcards.
+#[derive(Default)]
+pub struct SniResolver {
+    /// Certificate of the connections whose server name matches none
+    default: Option<Arc<CertifiedKey>>,
+    /// Certificates by lowercase name, wildcards included as `*.example.com`
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("default", &self.default.is_some())
+            .field("names", &self.names.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl SniResolver {
+    /// Creates a resolver without certificates.
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `SniResolver`, refusing every handshake until certificates are added.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the certificate of connections without a server name, or with an unknown one.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The certificate chain and its private key
+    pub fn with_default(mut self, key: Arc<CertifiedKey>) -> Self {
+        self.default = Some(key);
+        self
+    }
+
+    /// Adds the certificate of a server name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The host name, or a wildcard like `*.example.com`
+    /// * `key` - The certificate chain and its private key, replacing the one
+    ///   previously added for the name
+    pub fn add(&mut self, name: &str, key: Arc<CertifiedKey>) {
+        self.names.insert(name.to_ascii_lowercase(), key);
+    }
+
+    /// Finds the certificate of a server name.
+    ///
+    /// # Arguments
+    ///
+    /// * `server_name` - The name sent by the client, if any
+    ///
+    /// # Returns
+    ///
+    /// Returns the certificate of the name, of the wildcard matching it, or the default one.
+    pub fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let found = server_name.and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            self.names.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.names.get(&format!("*.{parent}"))
+            })
+        });
+        if found.is_none() {
+            debug!("No certificate for server name {:?}", server_name);
+        }
+        found.or(self.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+/// Certificates of a listener, replaceable while it's running.
+///
+/// Handshakes started after [`ReloadableResolver::reload`] present the new
+/// certificates, while established connections keep their session. Built by
+/// [`crate::config::Ssl::reloadable_tls_acceptor`], and reloaded with
+/// [`crate::server::Server::reload_certificates`].
+#[derive(Debug)]
+pub struct ReloadableResolver {
+    current: ArcSwap<SniResolver>,
+}
+
+impl ReloadableResolver {
+    /// Creates a resolver starting with certificates.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The current certificates
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `ReloadableResolver`.
+    pub fn new(resolver: SniResolver) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(resolver),
+        }
+    }
+
+    /// Replaces the certificates presented by the next handshakes.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The new certificates
+    pub fn reload(&self, resolver: SniResolver) {
+        self.current.store(Arc::new(resolver));
+        debug!("Certificates reloaded");
+    }
+}
+
+impl ResolvesServerCert for ReloadableResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.load().lookup(client_hello.server_name())
+    }
+}
+
+/// Lists the DNS names a certificate is valid for, from its subject alternative names.
+///
+/// # Arguments
+///
+/// * `certificate` - The DER encoded certificate
+///
+/// # Returns
+///
+/// Returns the names, wildcards included, or an empty list if the certificate
+/// can't be parsed.
+pub(crate) fn dns_names(certificate: &[u8]) -> Vec<String> {
+    use x509_parser::prelude::{GeneralName, parse_x509_certificate};
+
+    let Ok((_, certificate)) = parse_x509_certificate(certificate) else {
+        return Vec::new();
+    };
+    let Ok(Some(names)) = certificate.subject_alternative_name() else {
+        return Vec::new();
+    };
+    names
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            _ => None,
+        })
+        .collect()
+}
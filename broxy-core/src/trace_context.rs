@@ -0,0 +1,229 @@
+//! W3C trace-context propagation.
+//!
+//! Parses the `traceparent` header of incoming requests and injects a new one,
+//! with the same trace id and a freshly generated span id, into the request
+//! forwarded to the upstream. The `tracestate` header is forwarded untouched
+//! when the incoming `traceparent` is valid, and dropped otherwise, as the
+//! specification requires. Requests without a valid `traceparent` start a new trace.
+
+use std::fmt;
+
+use http::{HeaderMap, HeaderValue};
+
+/// Name of the header carrying the trace and parent span ids.
+pub const TRACEPARENT: &str = "traceparent";
+/// Name of the header carrying vendor-specific trace information.
+pub const TRACESTATE: &str = "tracestate";
+
+/// Flag marking the trace as sampled.
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// A parsed `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    /// Id of the whole trace
+    pub trace_id: u128,
+    /// Id of the span that sent the request
+    pub parent_id: u64,
+    /// Trace flags, e.g. sampled
+    pub flags: u8,
+}
+
+impl TraceParent {
+    /// Parses a `traceparent` header value (`00-<trace-id>-<parent-id>-<flags>`).
+    ///
+    /// # Returns
+    ///
+    /// `None` if the value is malformed or contains all-zero ids.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if version.len() != 2 || version == "ff" || u8::from_str_radix(version, 16).is_err() {
+            return None;
+        }
+        // Version 00 has exactly four fields, later versions may append more
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if trace_id.len() != 32 || parent_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        if [version, trace_id, parent_id, flags]
+            .iter()
+            .any(|part| part.bytes().any(|b| b.is_ascii_uppercase()))
+        {
+            return None;
+        }
+
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        if trace_id == 0 || parent_id == 0 {
+            return None;
+        }
+
+        Some(Self {
+            trace_id,
+            parent_id,
+            flags,
+        })
+    }
+
+    /// Starts a new, sampled trace.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: random_non_zero_u128(),
+            parent_id: random_non_zero_u64(),
+            flags: FLAG_SAMPLED,
+        }
+    }
+
+    /// Creates the context of a new span within the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            parent_id: random_non_zero_u64(),
+            ..*self
+        }
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+/// Injects the trace context for the upstream request into the headers.
+///
+/// # Arguments
+///
+/// * `headers` - The request headers, as they will be sent to the upstream
+///
+/// # Returns
+///
+/// The trace context that was injected.
+pub(crate) fn propagate(headers: &mut HeaderMap) -> TraceParent {
+    let incoming = headers
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(TraceParent::parse);
+
+    let trace_parent = match incoming {
+        Some(incoming) => incoming.child(),
+        None => {
+            headers.remove(TRACESTATE);
+            TraceParent::new_root()
+        }
+    };
+
+    headers.insert(
+        TRACEPARENT,
+        HeaderValue::from_str(&trace_parent.to_string())
+            .expect("traceparent is always a valid header value"),
+    );
+    trace_parent
+}
+
+fn random_non_zero_u64() -> u64 {
+    loop {
+        let value = fastrand::u64(..);
+        if value != 0 {
+            return value;
+        }
+    }
+}
+
+fn random_non_zero_u128() -> u128 {
+    loop {
+        let value = fastrand::u128(..);
+        if value != 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn valid_trace_parents_are_parsed() {
+        assert_eq!(
+            TraceParent::parse(VALID),
+            Some(TraceParent {
+                trace_id: 0x4bf92f3577b34da6a3ce929d0e0e4736,
+                parent_id: 0x00f067aa0ba902b7,
+                flags: FLAG_SAMPLED,
+            })
+        );
+        // Formatted back as received
+        assert_eq!(TraceParent::parse(VALID).unwrap().to_string(), VALID);
+        // Later versions may append fields
+        assert!(TraceParent::parse(&format!("01{}-extra", &VALID[2..])).is_some());
+    }
+
+    #[test]
+    fn malformed_trace_parents_are_rejected() {
+        for value in [
+            // Uppercase hex
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00F067AA0BA902B7-01",
+            // All-zero ids
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            // Invalid version, or extra fields on version 00
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "FF-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            // Wrong lengths, missing fields and non-hex digits
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902bz-01",
+            "",
+        ] {
+            assert_eq!(TraceParent::parse(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn upstreams_get_a_new_span_of_the_same_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(VALID));
+        headers.insert(TRACESTATE, HeaderValue::from_static("vendor=value"));
+
+        let propagated = propagate(&mut headers);
+        let incoming = TraceParent::parse(VALID).unwrap();
+        assert_eq!(propagated.trace_id, incoming.trace_id);
+        assert_eq!(propagated.flags, incoming.flags);
+        assert_ne!(propagated.parent_id, incoming.parent_id);
+        assert_eq!(headers[TRACEPARENT], propagated.to_string());
+        assert_eq!(headers[TRACESTATE], "vendor=value");
+    }
+
+    #[test]
+    fn invalid_trace_parents_start_a_new_trace() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            TRACEPARENT,
+            HeaderValue::from_static("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+        );
+        headers.insert(TRACESTATE, HeaderValue::from_static("vendor=value"));
+
+        let propagated = propagate(&mut headers);
+        assert_ne!(propagated.trace_id, 0);
+        assert_eq!(propagated.flags, FLAG_SAMPLED);
+        assert_eq!(headers[TRACEPARENT], propagated.to_string());
+        // The state belongs to the discarded trace
+        assert!(!headers.contains_key(TRACESTATE));
+    }
+}
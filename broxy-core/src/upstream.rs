@@ -1,6 +1,84 @@
-use std::net::SocketAddr;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr as _,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
-use http::Uri;
+use http::{HeaderMap, HeaderValue, Uri, header, uri::Authority};
+use hyper_rustls::ConfigBuilderExt as _;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, pki_types::ServerName},
+};
+use tracing::{info, warn};
+
+use crate::{
+    error::BroxyError,
+    health::HealthCheck,
+    resolver::{DEFAULT_RESOLVE_INTERVAL, Resolve, ResolvedHost, SystemResolver},
+    utils::{self, fnv1a},
+};
+
+/// Default amount of consecutive connection failures after which an upstream is ejected.
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+
+/// Default time an ejected upstream is skipped by the load balancer.
+pub const DEFAULT_FAILURE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Reference point of the timestamps stored in atomics.
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// TLS configurations trusting the system's root certificates, offering HTTP/1.1
+/// and HTTP/2 with ALPN, `None` if no root certificate could be loaded.
+static NATIVE_TLS_CONFIGS: LazyLock<Option<[Arc<ClientConfig>; 2]>> = LazyLock::new(|| {
+    let config = ClientConfig::builder()
+        .with_native_roots()
+        .inspect_err(|e| warn!("Failed to load the system's root certificates: {}", e))
+        .ok()?
+        .with_no_client_auth();
+    let with_alpn = |protocol: &[u8]| {
+        let mut config = config.clone();
+        config.alpn_protocols = vec![protocol.to_vec()];
+        Arc::new(config)
+    };
+    Some([with_alpn(b"http/1.1"), with_alpn(b"h2")])
+});
+
+/// Milliseconds elapsed since [`EPOCH`], never 0.
+fn now_millis() -> u64 {
+    EPOCH.elapsed().as_millis() as u64 + 1
+}
+
+/// HTTP version spoken to an upstream server.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+/// use broxy_core::{
+///     load_balancer::LoadBalancer,
+///     upstream::{HttpVersion, Upstream},
+/// };
+///
+/// // A gRPC backend speaking HTTP/2 without TLS
+/// let upstream = Upstream::new("127.0.0.1:50051".parse().unwrap(), false)
+///     .with_http_version(HttpVersion::Http2);
+/// let load_balancer = Arc::new(LoadBalancer::new(vec![upstream]));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1
+    #[default]
+    Http1,
+    /// HTTP/2, spoken right away without upgrade, so the upstream has to support it
+    Http2,
+}
 
 /// Configuration for an upstream server that the proxy forwards requests to.
 ///
@@ -8,8 +86,892 @@ use http::Uri;
 /// a backend server that handles the actual request processing.
 #[derive(Debug, Clone)]
 pub struct Upstream {
-    /// The network address (IP and port) of the upstream server
+    /// The network address (IP and port) of the upstream server, for upstreams
+    /// created from a host name the unspecified address with their port
     pub address: SocketAddr,
+    /// Host name resolved before connecting, see [`Upstream::from_hostname`]
+    pub(crate) hostname: Option<Arc<ResolvedHost>>,
     /// Whether to use SSL/TLS when connecting to the upstream server
     pub use_ssl: bool,
+    /// Server name sent with SNI and expected in the certificate, the IP address if `None`
+    pub servername: Option<String>,
+    /// TLS configuration, trusting the system's root certificates if `None`
+    pub tls_config: Option<Arc<ClientConfig>>,
+    /// HTTP version spoken to the upstream server
+    pub http_version: HttpVersion,
+    /// Whether to disable Nagle's algorithm on connections to the upstream server
+    pub tcp_nodelay: bool,
+    /// Availability zone or region the upstream server runs in
+    pub zone: Option<String>,
+    /// Capacity of the upstream server relative to the others, at least 1
+    pub weight: u32,
+    /// Amount of consecutive connection failures after which the upstream is ejected,
+    /// 0 disables ejection
+    pub max_failures: u32,
+    /// Time an ejected upstream is skipped by the load balancer before being tried again
+    pub failure_cooldown: Duration,
+    /// Path requested by active health checks, `None` disables them for this upstream
+    pub health_check_path: Option<String>,
+    /// Path the upstream server serves the proxied requests under, prepended to their path
+    pub root_path: Uri,
+    /// `Host` header sent to the upstream server instead of the client's, if set
+    pub host_header: Option<String>,
+    /// Runtime counters, shared by every clone of this upstream
+    pub(crate) stats: Arc<UpstreamStats>,
+}
+
+impl Upstream {
+    /// Creates a new upstream server definition.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - The network address of the upstream server
+    /// * `use_ssl` - Whether to use SSL/TLS when connecting to the upstream server
+    ///
+    /// # Returns
+    ///
+    /// A new `Upstream` instance with zeroed runtime counters
+    pub fn new(address: SocketAddr, use_ssl: bool) -> Self {
+        Self {
+            address,
+            hostname: None,
+            use_ssl,
+            servername: None,
+            tls_config: None,
+            http_version: HttpVersion::default(),
+            tcp_nodelay: false,
+            zone: None,
+            weight: 1,
+            max_failures: DEFAULT_MAX_FAILURES,
+            failure_cooldown: DEFAULT_FAILURE_COOLDOWN,
+            health_check_path: None,
+            root_path: Uri::from_static("/"),
+            host_header: None,
+            stats: Arc::new(UpstreamStats::default()),
+        }
+    }
+
+    /// Creates an upstream server definition from a host name and port, e.g. `api.internal:8080`.
+    ///
+    /// The host name is resolved with the system's resolver right before connecting,
+    /// and resolved again once its addresses are older than [`DEFAULT_RESOLVE_INTERVAL`],
+    /// see [`Upstream::with_resolver`]. Connections are opened to each resolved address
+    /// in turn. Failing to resolve the host name counts as failing to connect to the
+    /// upstream: the request is retried, and the upstream ejected after too many failures.
+    ///
+    /// Without a [`Upstream::with_servername`], the host name is also the TLS server name.
+    ///
+    /// # Arguments
+    ///
+    /// * `authority` - The host name or IP address of the upstream server, and its port
+    /// * `use_ssl` - Whether to use SSL/TLS when connecting to the upstream server
+    ///
+    /// # Returns
+    ///
+    /// Returns a new `Upstream` instance, or `BroxyError::Config` if the authority isn't
+    /// valid or has no port. An IP address is used as is, without resolution.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::upstream::Upstream;
+    ///
+    /// let upstream = Upstream::from_hostname("api.internal:8080", false).unwrap();
+    /// let local = Upstream::from_hostname("127.0.0.1:8080", false).unwrap();
+    /// // The port is required
+    /// assert!(Upstream::from_hostname("api.internal", false).is_err());
+    /// ```
+    pub fn from_hostname(authority: &str, use_ssl: bool) -> Result<Self, BroxyError> {
+        let invalid = |reason: &str| {
+            BroxyError::Config(format!(
+                "invalid upstream authority {authority:?}: {reason}"
+            ))
+        };
+        let parsed = Authority::from_str(authority).map_err(|e| invalid(&e.to_string()))?;
+        let port = parsed.port_u16().ok_or_else(|| invalid("missing port"))?;
+        let host = parsed.host();
+        if let Ok(ip) = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            return Ok(Self::new(SocketAddr::new(ip, port), use_ssl));
+        }
+
+        let mut upstream = Self::new(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port), use_ssl);
+        upstream.hostname = Some(Arc::new(ResolvedHost::new(
+            host.to_string(),
+            port,
+            Arc::new(SystemResolver),
+            DEFAULT_RESOLVE_INTERVAL,
+        )));
+        Ok(upstream)
+    }
+
+    /// Sets how the host name of an upstream created with [`Upstream::from_hostname`]
+    /// is resolved, it has no effect on other upstreams.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - Resolver looking up the addresses of the host name
+    /// * `interval` - Time the resolved addresses are used before resolving the host name again,
+    ///   when the resolver gives no TTL, see [`Resolve::resolve_with_ttl`]
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolve>, interval: Duration) -> Self {
+        if let Some(hostname) = &self.hostname {
+            self.hostname = Some(Arc::new(ResolvedHost::new(
+                hostname.host().to_string(),
+                hostname.port(),
+                resolver,
+                interval,
+            )));
+        }
+        self
+    }
+
+    /// Returns the host name of an upstream created with [`Upstream::from_hostname`].
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref().map(ResolvedHost::host)
+    }
+
+    /// Returns the host name and port of the upstream, or its address.
+    pub(crate) fn authority(&self) -> String {
+        match &self.hostname {
+            Some(hostname) => format!("{}:{}", hostname.host(), hostname.port()),
+            None => self.address.to_string(),
+        }
+    }
+
+    /// Returns the address to open the next connection to, resolving the host name if any.
+    ///
+    /// # Returns
+    ///
+    /// Returns the address, or `BroxyError::UpstreamConnect` if the host name can't be resolved.
+    pub(crate) async fn resolve(&self) -> Result<SocketAddr, BroxyError> {
+        let Some(hostname) = &self.hostname else {
+            return Ok(self.address);
+        };
+        hostname.address().await.map_err(|e| {
+            warn!("Failed to resolve upstream {}: {}", hostname.host(), e);
+            BroxyError::UpstreamConnect {
+                address: self.address,
+                source: io::Error::new(
+                    e.kind(),
+                    format!("failed to resolve {}: {e}", hostname.host()),
+                ),
+            }
+        })
+    }
+
+    /// Sets the HTTP version spoken to the upstream server.
+    ///
+    /// HTTP/2 multiplexes the requests over a single connection, shared by
+    /// every request once a [`crate::connection_pool::ConnectionPool`] is used.
+    pub fn with_http_version(mut self, http_version: HttpVersion) -> Self {
+        self.http_version = http_version;
+        self
+    }
+
+    /// Sets the server name of the upstream server, when it differs from its address.
+    ///
+    /// The name is sent with SNI and the certificate of the upstream has to be valid
+    /// for it. Without one, the certificate has to be valid for the host name of the
+    /// upstream, or its IP address.
+    pub fn with_servername(mut self, servername: impl Into<String>) -> Self {
+        self.servername = Some(servername.into());
+        self
+    }
+
+    /// Sets the TLS configuration used when `use_ssl` is set.
+    ///
+    /// By default the certificates of upstream servers are verified against the system's
+    /// root certificates, and ALPN offers the configured [`HttpVersion`]. A custom
+    /// configuration is used as is, set its `alpn_protocols` if the upstream needs them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::upstream::Upstream;
+    /// use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    ///
+    /// // Trusting the private CA which signed the certificate of the upstream
+    /// let mut roots = RootCertStore::empty();
+    /// for ca in rustls_pemfile::certs(&mut &include_bytes!("../fixtures/ca.pem")[..]) {
+    ///     roots.add(ca.unwrap()).unwrap();
+    /// }
+    /// let tls_config = ClientConfig::builder()
+    ///     .with_root_certificates(roots)
+    ///     .with_no_client_auth();
+    /// let upstream = Upstream::new("10.0.0.1:443".parse().unwrap(), true)
+    ///     .with_servername("upstream.test")
+    ///     .with_tls_config(Arc::new(tls_config));
+    /// ```
+    pub fn with_tls_config(mut self, tls_config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Builds the TLS connector of the upstream and the server name it connects to.
+    pub(crate) fn tls_connector(&self) -> Result<(TlsConnector, ServerName<'static>), BroxyError> {
+        let servername = match &self.servername {
+            Some(servername) => ServerName::try_from(servername.clone()).map_err(|e| {
+                BroxyError::Config(format!("invalid server name {servername:?}: {e}"))
+            })?,
+            None => match self.hostname() {
+                Some(hostname) => ServerName::try_from(hostname.to_string()).map_err(|e| {
+                    BroxyError::Config(format!("invalid server name {hostname:?}: {e}"))
+                })?,
+                None => ServerName::from(self.address.ip()),
+            },
+        };
+        let config = match &self.tls_config {
+            Some(config) => config.clone(),
+            None => {
+                let configs = NATIVE_TLS_CONFIGS.as_ref().ok_or_else(|| {
+                    BroxyError::Config("no root certificate to verify upstreams".to_string())
+                })?;
+                configs[(self.http_version == HttpVersion::Http2) as usize].clone()
+            }
+        };
+        Ok((TlsConnector::from(config), servername))
+    }
+
+    /// Sets `TCP_NODELAY` on connections to the upstream server.
+    ///
+    /// Disabling Nagle's algorithm lowers the latency of small requests.
+    pub fn with_tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Tags the upstream server with the availability zone or region it runs in.
+    ///
+    /// See [`crate::load_balancer::LoadBalancer::with_local_zone`].
+    pub fn with_zone(mut self, zone: impl Into<String>) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Sets the capacity of the upstream server relative to the others of its pool.
+    ///
+    /// See [`crate::load_balancer::LoadBalancer::new_weighted_least_connections`].
+    ///
+    /// # Arguments
+    ///
+    /// * `weight` - Relative capacity, e.g. 2 for a server handling twice the load of
+    ///   a server of weight 1. A weight of 0 is raised to 1.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight.max(1);
+        self
+    }
+
+    /// Configures passive health checking.
+    ///
+    /// After `max_failures` consecutive failures to connect to the upstream, it's
+    /// ejected: the load balancer skips it for `cooldown`, then lets requests through
+    /// again. A successful request resets the failure count, while another failure
+    /// ejects the upstream right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_failures` - Amount of consecutive failures before ejection, 0 disables it
+    /// * `cooldown` - Time the upstream is skipped once ejected
+    pub fn with_passive_health_check(mut self, max_failures: u32, cooldown: Duration) -> Self {
+        self.max_failures = max_failures;
+        self.failure_cooldown = cooldown;
+        self
+    }
+
+    /// Enables active health checks of the upstream.
+    ///
+    /// See [`crate::load_balancer::LoadBalancer::spawn_health_checks`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path requested with `GET`, a 2xx response means the upstream is healthy
+    pub fn with_health_check(mut self, path: impl Into<String>) -> Self {
+        self.health_check_path = Some(path.into());
+        self
+    }
+
+    /// Sets the path the upstream server serves the proxied requests under.
+    ///
+    /// The path of every request is appended to it, e.g. with a root path of `/api`,
+    /// `/users?page=1` is forwarded as `/api/users?page=1`. Defaults to `/`, forwarding
+    /// requests unchanged.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::{hyper::Uri, upstream::Upstream};
+    ///
+    /// // `/users?page=1` is forwarded as `/api/users?page=1`
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false)
+    ///     .with_root_path(Uri::from_static("/api"));
+    /// ```
+    pub fn with_root_path(mut self, root_path: Uri) -> Self {
+        self.root_path = root_path;
+        self
+    }
+
+    /// Builds the URI a request is forwarded to, under the root path.
+    pub(crate) fn rewrite_uri(&self, uri: &Uri) -> Uri {
+        if self.root_path == "/" {
+            return uri.clone();
+        }
+        utils::combine_uris(&self.root_path, uri).unwrap_or_else(|e| {
+            warn!("Failed to prepend root path to {}: {}", uri, e);
+            uri.clone()
+        })
+    }
+
+    /// Sets the `Host` header sent to the upstream server, replacing the client's.
+    ///
+    /// Useful when the upstream routes requests by virtual host, but is reached by
+    /// its address. Without one, the `Host` header of the client is forwarded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use broxy_core::upstream::Upstream;
+    ///
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false)
+    ///     .with_host_header("backend.internal");
+    /// ```
+    pub fn with_host_header(mut self, host_header: impl Into<String>) -> Self {
+        self.host_header = Some(host_header.into());
+        self
+    }
+
+    /// Sets the `Host` header of a request forwarded to the upstream.
+    ///
+    /// # Arguments
+    ///
+    /// * `headers` - The headers of the forwarded request
+    /// * `host` - The `Host` header of the client, restored if the upstream doesn't
+    ///   override it, as a previous attempt may have
+    pub(crate) fn rewrite_host(&self, headers: &mut HeaderMap, host: Option<&HeaderValue>) {
+        let host_header = self.host_header.as_deref().and_then(|host_header| {
+            HeaderValue::from_str(host_header)
+                .inspect_err(|e| warn!("Invalid host header {:?}: {}", host_header, e))
+                .ok()
+        });
+        match host_header.or_else(|| host.cloned()) {
+            Some(host) => headers.insert(header::HOST, host),
+            None => headers.remove(header::HOST),
+        };
+    }
+
+    /// Limits the amount of requests processed by this upstream at the same time.
+    ///
+    /// Once the limit is reached, the load balancer skips this upstream, and requests
+    /// that can't be sent anywhere else are answered with `503 Service Unavailable`.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_concurrent` - Maximum amount of in-flight requests
+    ///
+    /// # Returns
+    ///
+    /// The upstream with the limit applied and zeroed runtime counters
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use broxy_core::{load_balancer::LoadBalancer, upstream::Upstream};
+    ///
+    /// let upstream = Upstream::new("127.0.0.1:8080".parse().unwrap(), false).with_max_concurrent(64);
+    /// let load_balancer = Arc::new(LoadBalancer::new(vec![upstream]));
+    /// ```
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.stats = Arc::new(UpstreamStats {
+            permits: Some((max_concurrent, Arc::new(Semaphore::new(max_concurrent)))),
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Returns the maximum amount of in-flight requests, if limited.
+    pub fn max_concurrent(&self) -> Option<usize> {
+        self.stats.permits.as_ref().map(|(max, _)| *max)
+    }
+
+    /// Checks whether the upstream can accept another request.
+    pub fn has_capacity(&self) -> bool {
+        self.stats
+            .permits
+            .as_ref()
+            .is_none_or(|(_, permits)| permits.available_permits() > 0)
+    }
+
+    /// Returns the number of requests currently being processed by the upstream.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.stats.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Checks whether the upstream is considered healthy, i.e. neither ejected
+    /// nor marked down by active health checks.
+    pub fn is_healthy(&self) -> bool {
+        if self.stats.down.load(Ordering::Relaxed) {
+            return false;
+        }
+        let ejected_until = self.stats.ejected_until.load(Ordering::Relaxed);
+        ejected_until == 0 || now_millis() >= ejected_until
+    }
+
+    /// Checks whether the load balancer may send a request to the upstream.
+    pub fn is_available(&self) -> bool {
+        self.is_healthy() && self.has_capacity()
+    }
+
+    /// Returns a stable identifier of this upstream, derived from its address or host name.
+    ///
+    /// Used to refer to the upstream from outside the proxy, e.g. in sticky session
+    /// cookies, without exposing its address.
+    pub fn id(&self) -> u64 {
+        fnv1a(self.authority().as_bytes())
+    }
+
+    /// Takes a snapshot of the runtime state of this upstream.
+    ///
+    /// # Returns
+    ///
+    /// An `UpstreamStatus` with the current counter values
+    pub fn status(&self) -> UpstreamStatus {
+        UpstreamStatus {
+            address: self.address,
+            hostname: self.hostname().map(str::to_string),
+            use_ssl: self.use_ssl,
+            zone: self.zone.clone(),
+            weight: self.weight,
+            max_concurrent: self.max_concurrent(),
+            healthy: self.is_healthy(),
+            in_flight: self.stats.in_flight.load(Ordering::Relaxed),
+            total_requests: self.stats.total_requests.load(Ordering::Relaxed),
+            total_failures: self.stats.total_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Runtime counters tracked for every upstream server.
+#[derive(Debug, Default)]
+pub(crate) struct UpstreamStats {
+    /// Number of requests currently being processed by the upstream
+    in_flight: AtomicUsize,
+    /// Number of requests forwarded to the upstream since startup
+    total_requests: AtomicU64,
+    /// Number of forwarded requests that failed or got a 5xx response
+    total_failures: AtomicU64,
+    /// Concurrency limit and the semaphore enforcing it
+    permits: Option<(usize, Arc<Semaphore>)>,
+    /// Number of connection failures since the last successful request
+    consecutive_failures: AtomicU32,
+    /// Time until which the upstream is ejected, in milliseconds since `EPOCH`, 0 if it's not
+    ejected_until: AtomicU64,
+    /// Whether active health checks marked the upstream down
+    down: AtomicBool,
+    /// Number of consecutive passed active health checks
+    checks_passed: AtomicU32,
+    /// Number of consecutive failed active health checks
+    checks_failed: AtomicU32,
+}
+
+impl UpstreamStats {
+    /// Registers the start of a request to the upstream.
+    ///
+    /// # Returns
+    ///
+    /// A guard that marks the request as finished when dropped, or `None`
+    /// if the upstream is at its concurrency limit
+    pub(crate) fn try_begin_request(self: &Arc<Self>) -> Option<RequestGuard> {
+        let permit = match &self.permits {
+            Some((_, permits)) => Some(permits.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        Some(RequestGuard {
+            stats: self.clone(),
+            failed: false,
+            _permit: permit,
+        })
+    }
+}
+
+impl UpstreamStats {
+    /// Records the result of an active health check, marking the upstream
+    /// down or up once the thresholds are reached.
+    pub(crate) fn record_check(&self, upstream: &Upstream, passed: bool, config: &HealthCheck) {
+        if passed {
+            self.checks_failed.store(0, Ordering::Relaxed);
+            let passes = self.checks_passed.fetch_add(1, Ordering::Relaxed) + 1;
+            if passes >= config.healthy_threshold && self.down.swap(false, Ordering::Relaxed) {
+                info!(
+                    "Upstream {} passed {} health checks, marking up",
+                    upstream.address, passes
+                );
+            }
+        } else {
+            self.checks_passed.store(0, Ordering::Relaxed);
+            let failures = self.checks_failed.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= config.unhealthy_threshold && !self.down.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Upstream {} failed {} health checks, marking down",
+                    upstream.address, failures
+                );
+            }
+        }
+    }
+}
+
+/// Guard tracking a single in-flight request to an upstream.
+///
+/// The in-flight counter is decremented on drop, so requests that are
+/// cancelled mid-way are accounted for as well.
+pub(crate) struct RequestGuard {
+    stats: Arc<UpstreamStats>,
+    failed: bool,
+    /// Concurrency permit, released on drop
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl RequestGuard {
+    /// Marks the request as failed, it will be counted on drop.
+    pub(crate) fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// Marks the request as failed to connect, ejecting the upstream once
+    /// `max_failures` consecutive connections failed.
+    pub(crate) fn connect_failed(&mut self, upstream: &Upstream) {
+        self.fail();
+        let failures = self
+            .stats
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if upstream.max_failures > 0 && failures >= upstream.max_failures {
+            warn!(
+                "Ejecting upstream {} for {:?} after {} consecutive failures",
+                upstream.address, upstream.failure_cooldown, failures
+            );
+            self.stats.ejected_until.store(
+                now_millis() + upstream.failure_cooldown.as_millis() as u64,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Marks the upstream as reachable, resetting its failure count.
+    pub(crate) fn connected(&self, upstream: &Upstream) {
+        self.stats.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.stats.ejected_until.swap(0, Ordering::Relaxed) != 0 {
+            info!("Upstream {} is healthy again", upstream.address);
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+        if self.failed {
+            self.stats.total_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Point-in-time snapshot of an upstream server's runtime state.
+///
+/// Returned by [`crate::load_balancer::LoadBalancer::upstreams`] for building
+/// dashboards or admin interfaces in-process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    /// The network address of the upstream server
+    pub address: SocketAddr,
+    /// The host name of the upstream server, if created from one
+    pub hostname: Option<String>,
+    /// Whether SSL/TLS is used when connecting to the upstream server
+    pub use_ssl: bool,
+    /// Availability zone or region the upstream server runs in
+    pub zone: Option<String>,
+    /// Capacity of the upstream server relative to the others
+    pub weight: u32,
+    /// Maximum amount of in-flight requests, if limited
+    pub max_concurrent: Option<usize>,
+    /// Whether the upstream is healthy, i.e. neither ejected nor marked down
+    pub healthy: bool,
+    /// Number of requests currently being processed by the upstream
+    pub in_flight: usize,
+    /// Number of requests forwarded to the upstream since startup
+    pub total_requests: u64,
+    /// Number of forwarded requests that failed or got a 5xx response
+    pub total_failures: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_balancer::LoadBalancer,
+        resolver::ResolveFuture,
+        service::{Service, ServiceBundle},
+        test_support::{fixture, get, proxy, send, upstream},
+    };
+    use hyper::{Request, Response, body::Incoming};
+
+    /// Builds a service forwarding every request to `upstream`.
+    fn service_to(upstream: Upstream) -> Service {
+        Service::builder()
+            .load_balancer(Arc::new(LoadBalancer::new(vec![upstream])))
+            .build()
+            .unwrap()
+    }
+
+    /// Starts an upstream answering with the version, URI and host of the requests.
+    async fn request_echo_upstream() -> SocketAddr {
+        upstream(|request: Request<Incoming>| async move {
+            let host = request.headers().get(header::HOST).cloned();
+            Response::new(format!(
+                "{:?} {} {:?}",
+                request.version(),
+                request.uri(),
+                host
+            ))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_with_the_version_of_the_upstream() {
+        let address = request_echo_upstream().await;
+        let proxy_to = async |version| {
+            let upstream = Upstream::new(address, false).with_http_version(version);
+            proxy(ServiceBundle::new(vec![service_to(upstream)])).await
+        };
+        let request =
+            "GET /hello?name=broxy HTTP/1.1\r\nhost: example.com\r\nconnection: close\r\n\r\n";
+
+        let response = send(proxy_to(HttpVersion::Http1).await, request).await;
+        assert!(
+            response.ends_with("HTTP/1.1 /hello?name=broxy Some(\"example.com\")"),
+            "{response}"
+        );
+        // HTTP/2 requests carry the authority in their URI
+        let response = send(proxy_to(HttpVersion::Http2).await, request).await;
+        assert!(
+            response.contains("HTTP/2.0 http://example.com/hello?name=broxy"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn tls_upstreams_are_verified_with_the_custom_configuration() {
+        use tokio_rustls::{
+            TlsAcceptor,
+            rustls::{RootCertStore, ServerConfig},
+        };
+
+        // An upstream only accepting TLS, with a certificate for `upstream.test`
+        let certs = rustls_pemfile::certs(&mut &fixture("upstream.pem")[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut &fixture("upstream.key")[..])
+            .unwrap()
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap(),
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (conn, _) = listener.accept().await.unwrap();
+                let Ok(conn) = acceptor.accept(conn).await else {
+                    continue;
+                };
+                let service = hyper::service::service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(Response::new("secure".to_string()))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(hyper_util::rt::TokioIo::new(conn), service),
+                );
+            }
+        });
+
+        // Trusting the CA which signed the upstream certificate
+        let mut roots = RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut &fixture("ca.pem")[..]) {
+            roots.add(ca.unwrap()).unwrap();
+        }
+        let tls_config = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let get_through = async |servername: &str| {
+            let upstream = Upstream::new(address, true)
+                .with_servername(servername)
+                .with_tls_config(tls_config.clone());
+            get(
+                proxy(ServiceBundle::new(vec![service_to(upstream)])).await,
+                "/",
+            )
+            .await
+        };
+
+        let response = get_through("upstream.test").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("secure"), "{response}");
+        // The certificate isn't valid for another name, the request fails
+        let response = get_through("other.test").await;
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn request_paths_are_appended_to_the_root_path() {
+        let forwarded = |root_path: &'static str, path: &'static str| {
+            Upstream::new("127.0.0.1:8080".parse().unwrap(), false)
+                .with_root_path(Uri::from_static(root_path))
+                .rewrite_uri(&Uri::from_static(path))
+                .to_string()
+        };
+
+        assert_eq!(forwarded("/", "/users?page=1"), "/users?page=1");
+        assert_eq!(forwarded("/api", "/users?page=1"), "/api/users?page=1");
+        // Slashes between the root path and the request path aren't doubled
+        assert_eq!(forwarded("/api/", "/users"), "/api/users");
+        assert_eq!(forwarded("/api/", "/"), "/api/");
+    }
+
+    /// A resolver answering with the addresses it's given, counting the lookups.
+    struct Stub {
+        addresses: Vec<SocketAddr>,
+        lookups: AtomicUsize,
+    }
+
+    impl Stub {
+        fn new(addresses: Vec<SocketAddr>) -> Arc<Self> {
+            Arc::new(Self {
+                addresses,
+                lookups: AtomicUsize::new(0),
+            })
+        }
+
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Resolve for Stub {
+        fn resolve(&self, host: &str, _: u16) -> ResolveFuture {
+            assert_eq!(host, "api.internal");
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            let addresses = self.addresses.clone();
+            Box::pin(async move {
+                if addresses.is_empty() {
+                    Err(std::io::Error::other("NXDOMAIN"))
+                } else {
+                    Ok(addresses)
+                }
+            })
+        }
+    }
+
+    /// Starts a proxy in front of `api.internal:8080`, resolved by `stub` every `interval`.
+    async fn proxy_resolving_with(
+        stub: Arc<Stub>,
+        interval: Duration,
+    ) -> (SocketAddr, Arc<LoadBalancer>) {
+        let upstream = Upstream::from_hostname("api.internal:8080", false)
+            .unwrap()
+            .with_resolver(stub, interval)
+            .with_passive_health_check(3, Duration::from_secs(60));
+        let load_balancer = Arc::new(LoadBalancer::new(vec![upstream]));
+        let service = Service::builder()
+            .load_balancer(load_balancer.clone())
+            .build()
+            .unwrap();
+        (
+            proxy(ServiceBundle::new(vec![service])).await,
+            load_balancer,
+        )
+    }
+
+    /// Starts an upstream answering with its name.
+    async fn named_upstream(name: &'static str) -> SocketAddr {
+        upstream(move |_| async move { Response::new(name.to_string()) }).await
+    }
+
+    #[tokio::test]
+    async fn resolved_addresses_are_used_in_turn() {
+        let (a, b) = (named_upstream("a").await, named_upstream("b").await);
+
+        // Requests go to every resolved address in turn, resolved once
+        let stub = Stub::new(vec![a, b]);
+        let (address, _) = proxy_resolving_with(stub.clone(), Duration::from_secs(60)).await;
+        for expected in ["a", "b", "a", "b"] {
+            assert!(get(address, "/").await.ends_with(expected));
+        }
+        assert_eq!(stub.lookups(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_addresses_are_resolved_again() {
+        let stub = Stub::new(vec![named_upstream("a").await]);
+        let (address, _) = proxy_resolving_with(stub.clone(), Duration::ZERO).await;
+        for _ in 0..3 {
+            assert!(get(address, "/").await.ends_with("a"));
+        }
+        assert_eq!(stub.lookups(), 3);
+    }
+
+    #[tokio::test]
+    async fn unresolved_host_names_fail_requests_and_eject_the_upstream() {
+        let stub = Stub::new(vec![]);
+        let (address, load_balancer) =
+            proxy_resolving_with(stub.clone(), Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            assert!(
+                get(address, "/")
+                    .await
+                    .starts_with("HTTP/1.1 502 Bad Gateway")
+            );
+        }
+        let status = &load_balancer.upstreams()[0];
+        assert_eq!(status.hostname.as_deref(), Some("api.internal"));
+        assert!(!status.healthy);
+    }
+
+    #[tokio::test]
+    async fn host_header_replaces_the_one_of_the_client() {
+        let address = request_echo_upstream().await;
+        let forwarded = async |upstream: Upstream| {
+            let proxy = proxy(ServiceBundle::new(vec![service_to(upstream)])).await;
+            send(
+                proxy,
+                "GET / HTTP/1.1\r\nhost: proxy.example.com\r\nconnection: close\r\n\r\n",
+            )
+            .await
+        };
+
+        let upstream = Upstream::new(address, false).with_host_header("backend.internal");
+        let response = forwarded(upstream).await;
+        assert!(
+            response.ends_with("Some(\"backend.internal\")"),
+            "{response}"
+        );
+        let response = forwarded(Upstream::new(address, false)).await;
+        assert!(
+            response.ends_with("Some(\"proxy.example.com\")"),
+            "{response}"
+        );
+    }
 }
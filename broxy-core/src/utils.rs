@@ -1,10 +1,26 @@
-use http::Uri;
+use std::net::SocketAddr;
+
+use http::{HeaderName, HeaderValue, Uri};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
 
 /// Combines a base URI with an append URI to create a full URI.
 ///
 /// This function takes a base URI and an append URI, then combines them
 /// by properly handling path concatenation and query parameters.
 ///
+/// The combined path is normalized: repeated slashes are collapsed, and `.` and `..`
+/// segments are resolved, percent-encoded ones included. A `..` never goes above the
+/// base path, so a request can't reach paths of the upstream outside of it. The path
+/// ends with a slash only if the append path does.
+///
+/// The query parameters of both URIs are kept, those of the base first. When both
+/// have a parameter with the same name, the append one wins and every base parameter
+/// with that name is dropped. Parameters are compared and copied as written, so
+/// percent-encoded `&` and `=` in names and values are preserved.
+///
 /// # Arguments
 ///
 /// * `base` - The base URI that provides the scheme, authority, and base path
@@ -19,27 +35,60 @@ use http::Uri;
 ///
 /// ```
 /// use http::Uri;
-/// use broxy::utils::combine_uris;
+/// use broxy_core::utils::combine_uris;
 ///
 /// let base = "https://example.com/api".parse::<Uri>().unwrap();
 /// let append = "/users?page=1".parse::<Uri>().unwrap();
 /// let combined = combine_uris(&base, &append).unwrap();
 /// assert_eq!(combined.to_string(), "https://example.com/api/users?page=1");
+///
+/// let combine = |base: &str, append: &str| {
+///     combine_uris(&base.parse().unwrap(), &append.parse().unwrap())
+///         .unwrap()
+///         .to_string()
+/// };
+/// // Base query only
+/// assert_eq!(combine("https://example.com/api?token=x", "/users"), "https://example.com/api/users?token=x");
+/// // Append query only
+/// assert_eq!(combine("/api", "/users?page=1"), "/api/users?page=1");
+/// // Both, the append parameter wins on conflict
+/// assert_eq!(
+///     combine("/api?token=x&page=0&page=9", "/users?page=1&q=a%26b%3Dc"),
+///     "/api/users?token=x&page=1&q=a%26b%3Dc"
+/// );
+///
+/// // Repeated slashes are collapsed
+/// assert_eq!(combine("/api/", "//users//1"), "/api/users/1");
+/// // Dot segments are resolved
+/// assert_eq!(combine("/api", "/./users/./1"), "/api/users/1");
+/// assert_eq!(combine("/api", "/users/../orders/"), "/api/orders/");
+/// // `..` can't escape the base path
+/// assert_eq!(combine("/api/v1", "/../../admin"), "/api/v1/admin");
+/// assert_eq!(combine("/api", "/%2e%2e/%2E%2E/etc/passwd"), "/api/etc/passwd");
 /// ```
-#[allow(unused)]
 pub fn combine_uris(base: &Uri, append: &Uri) -> Result<Uri, http::Error> {
     let base_path = base.path();
     let append_path = append.path();
     let append_query = append.query().unwrap_or("");
+    let query = merge_queries(base.query().unwrap_or(""), append_query);
 
-    let base_path_trimmed = base_path.trim_end_matches('/');
-    let append_path_trimmed = append_path.trim_start_matches('/');
+    let mut segments = Vec::new();
+    push_segments(&mut segments, base_path, 0);
+    let base_len = segments.len();
+    push_segments(&mut segments, append_path, base_len);
 
-    let mut full_path = format!("{}/{}", base_path_trimmed, append_path_trimmed);
+    let mut full_path = format!("/{}", segments.join("/"));
+    let trailing_slash = append_path
+        .rsplit('/')
+        .next()
+        .is_some_and(|last| last.is_empty() || dot_segment(last).is_some());
+    if trailing_slash && !segments.is_empty() {
+        full_path.push('/');
+    }
 
-    if !append_query.is_empty() {
+    if !query.is_empty() {
         full_path.push('?');
-        full_path.push_str(append_query);
+        full_path.push_str(&query);
     }
 
     if let Some(scheme) = base.scheme_str() {
@@ -49,3 +98,276 @@ pub fn combine_uris(base: &Uri, append: &Uri) -> Result<Uri, http::Error> {
     }
     Ok(full_path.parse::<Uri>()?)
 }
+
+/// Normalized form of a `.` or `..` segment, percent-encoded or not.
+fn dot_segment(segment: &str) -> Option<&'static str> {
+    match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+        "." => Some("."),
+        ".." => Some(".."),
+        _ => None,
+    }
+}
+
+/// Adds the segments of a path, resolving `.` and `..` ones and skipping empty ones.
+///
+/// # Arguments
+///
+/// * `segments` - The segments so far
+/// * `path` - The path to add
+/// * `floor` - Amount of segments a `..` can't remove
+fn push_segments<'a>(segments: &mut Vec<&'a str>, path: &'a str, floor: usize) {
+    for segment in path.split('/') {
+        match dot_segment(segment) {
+            Some("..") => {
+                if segments.len() > floor {
+                    segments.pop();
+                }
+            }
+            Some(_) => {}
+            None if segment.is_empty() => {}
+            None => segments.push(segment),
+        }
+    }
+}
+
+/// Merges two query strings, the parameters of `append` replacing those of `base`
+/// with the same name, see [`combine_uris`].
+fn merge_queries(base: &str, append: &str) -> String {
+    fn name(parameter: &str) -> &str {
+        parameter
+            .split_once('=')
+            .map_or(parameter, |(name, _)| name)
+    }
+
+    let appended = append
+        .split('&')
+        .filter(|parameter| !parameter.is_empty())
+        .collect::<Vec<_>>();
+    base.split('&')
+        .filter(|parameter| {
+            !parameter.is_empty()
+                && !appended
+                    .iter()
+                    .any(|appended| name(appended) == name(parameter))
+        })
+        .chain(appended.iter().copied())
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Hashes bytes with the 64-bit FNV-1a algorithm.
+///
+/// Unlike `std`'s default hasher, the result is stable across Rust versions and
+/// processes, so it can be used for values that are persisted or shared, like
+/// sticky session cookies and hash rings.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to hash
+///
+/// # Returns
+///
+/// Returns the 64-bit hash of the bytes.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Finds the value of a cookie in the request headers.
+///
+/// # Arguments
+///
+/// * `headers` - The request headers
+/// * `name` - The name of the cookie
+///
+/// # Returns
+///
+/// Returns the value of the first cookie with the given name, or `None` if it's not present.
+pub fn find_cookie<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Adds the `X-Forwarded-*` headers describing the client connection to a request.
+///
+/// The client IP is appended to `X-Forwarded-For`, so the headers of earlier proxies
+/// are kept. `X-Forwarded-Proto` and `X-Forwarded-Host` are replaced.
+///
+/// # Arguments
+///
+/// * `parts` - The HTTP request header parts
+/// * `from` - Address of the client
+/// * `tls` - Whether the client connected over TLS
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::{hyper::Request, utils::append_forwarded};
+///
+/// let (mut first_hop, _) = Request::get("/").header("host", "example.com").body(()).unwrap().into_parts();
+/// append_forwarded(&mut first_hop, &"192.168.1.10:50000".parse().unwrap(), true);
+/// assert_eq!(first_hop.headers["x-forwarded-for"], "192.168.1.10");
+/// assert_eq!(first_hop.headers["x-forwarded-proto"], "https");
+/// assert_eq!(first_hop.headers["x-forwarded-host"], "example.com");
+///
+/// let (mut second_hop, _) = Request::get("/")
+///     .header("host", "internal:8080")
+///     .header("x-forwarded-for", "203.0.113.7")
+///     .header("x-forwarded-for", "198.51.100.1")
+///     .body(())
+///     .unwrap()
+///     .into_parts();
+/// append_forwarded(&mut second_hop, &"[2001:db8::1]:50000".parse().unwrap(), false);
+/// assert_eq!(second_hop.headers["x-forwarded-for"], "203.0.113.7, 198.51.100.1, 2001:db8::1");
+/// assert_eq!(second_hop.headers["x-forwarded-proto"], "http");
+/// assert_eq!(second_hop.headers["x-forwarded-host"], "internal:8080");
+/// ```
+pub fn append_forwarded(parts: &mut http::request::Parts, from: &SocketAddr, tls: bool) {
+    let client = from.ip().to_canonical().to_string();
+    let forwarded_for = parts
+        .headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain(std::iter::once(client.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+        parts.headers.insert(X_FORWARDED_FOR, forwarded_for);
+    }
+
+    parts.headers.insert(
+        X_FORWARDED_PROTO,
+        HeaderValue::from_static(if tls { "https" } else { "http" }),
+    );
+
+    let host = parts.headers.get(http::header::HOST).cloned().or_else(|| {
+        parts
+            .uri
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    });
+    if let Some(host) = host {
+        parts.headers.insert(X_FORWARDED_HOST, host);
+    }
+}
+
+/// Builds the HTTPS URL of a request, for redirecting plaintext requests.
+///
+/// The host is taken from the URI, or the `Host` header for origin-form requests,
+/// and its port is replaced with `port`.
+///
+/// # Arguments
+///
+/// * `parts` - The HTTP request header parts
+/// * `port` - Port of the TLS listener, omitted from the URL when it's `443`
+///
+/// # Returns
+///
+/// Returns the URL as a `Location` header value, or `None` if the request has no valid host.
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::{hyper::Request, utils::https_location};
+///
+/// let (request, _) = Request::get("/users?page=2").header("host", "example.com:80").body(()).unwrap().into_parts();
+/// assert_eq!(https_location(&request, 443).unwrap(), "https://example.com/users?page=2");
+/// assert_eq!(https_location(&request, 8443).unwrap(), "https://example.com:8443/users?page=2");
+///
+/// let (request, _) = Request::post("http://[::1]:8080/submit").body(()).unwrap().into_parts();
+/// assert_eq!(https_location(&request, 443).unwrap(), "https://[::1]/submit");
+///
+/// let (request, _) = Request::get("/").body(()).unwrap().into_parts();
+/// assert!(https_location(&request, 443).is_none());
+/// ```
+pub fn https_location(parts: &http::request::Parts, port: u16) -> Option<HeaderValue> {
+    let authority = match parts.uri.authority() {
+        Some(authority) => authority.clone(),
+        None => parts
+            .headers
+            .get(http::header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<http::uri::Authority>()
+            .ok()?,
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location = if port == 443 {
+        format!("https://{}{}", authority.host(), path)
+    } else {
+        format!("https://{}:{}{}", authority.host(), port, path)
+    };
+    HeaderValue::from_str(&location).ok()
+}
+
+/// Describes a client certificate, to forward it to the upstreams in a header.
+///
+/// The value lists the subject, then every subject alternative name, e.g.
+/// `Subject="CN=client.test";DNS=client.test;IP=127.0.0.1`. Names are prefixed
+/// with `DNS`, `URI`, `Email` or `IP`, other kinds of names are skipped.
+///
+/// # Arguments
+///
+/// * `certificate` - The DER encoded certificate
+///
+/// # Returns
+///
+/// Returns the description, or `None` if the certificate can't be parsed or the
+/// description isn't a valid header value.
+///
+/// # Examples
+///
+/// ```
+/// use broxy_core::utils::describe_certificate;
+///
+/// let pem = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/client.pem")).unwrap();
+/// let certificate = rustls_pemfile::certs(&mut &pem[..]).next().unwrap().unwrap();
+/// assert_eq!(describe_certificate(&certificate).unwrap(), r#"Subject="CN=client.test";DNS=client.test"#);
+///
+/// assert!(describe_certificate(b"not a certificate").is_none());
+/// ```
+pub fn describe_certificate(certificate: &[u8]) -> Option<HeaderValue> {
+    use x509_parser::prelude::{GeneralName, parse_x509_certificate};
+
+    let (_, certificate) = parse_x509_certificate(certificate).ok()?;
+    let mut description = format!(
+        "Subject=\"{}\"",
+        certificate.subject().to_string().replace('"', "\\\"")
+    );
+    let names = certificate.subject_alternative_name().ok()?;
+    for name in names.iter().flat_map(|names| &names.value.general_names) {
+        let (kind, value) = match name {
+            GeneralName::DNSName(name) => ("DNS", name.to_string()),
+            GeneralName::URI(uri) => ("URI", uri.to_string()),
+            GeneralName::RFC822Name(email) => ("Email", email.to_string()),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => (
+                    "IP",
+                    std::net::Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string(),
+                ),
+                16 => (
+                    "IP",
+                    std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string(),
+                ),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        description.push_str(&format!(";{kind}={value}"));
+    }
+    HeaderValue::from_str(&description).ok()
+}
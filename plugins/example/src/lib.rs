@@ -0,0 +1,76 @@
+//! Example plugin for Broxy, built as a dynamic library.
+//!
+//! It exports the functions loaded by
+//! - `ExternalBodyFilter::load(path, b"filter_body")`: rejects bodies containing `forbidden`
+//! - `ExternalMiddleware::load(path, INCOMING_SYMBOL)`: adds `x-plugin: incoming` to requests
+//! - `ExternalMiddleware::load(path, OUTGOING_SYMBOL)`: adds `x-plugin: outgoing` to responses
+//!
+//! The plugin only depends on the C ABI, not on `broxy-core`.
+
+use std::ffi::c_void;
+
+/// Callback replacing the message, see `broxy_core::middleware::MiddlewareWrite`.
+type Write = unsafe extern "C" fn(*mut c_void, *const u8, u64);
+
+/// Accepts bodies that don't contain `forbidden`.
+///
+/// # Safety
+///
+/// `body` must point to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn filter_body(body: *const u8, len: u64) -> bool {
+    let body = unsafe { std::slice::from_raw_parts(body, len as usize) };
+    !body
+        .windows(b"forbidden".len())
+        .any(|window| window == b"forbidden")
+}
+
+/// Adds `x-plugin: incoming` to the request.
+///
+/// # Safety
+///
+/// `message` must point to `len` readable bytes, `context` must be the context
+/// expected by `write`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn broxy_middleware_incoming(
+    message: *const u8,
+    len: u64,
+    context: *mut c_void,
+    write: Write,
+) -> i32 {
+    unsafe { add_header(message, len, context, write, b"x-plugin: incoming\r\n") }
+}
+
+/// Adds `x-plugin: outgoing` to the response.
+///
+/// # Safety
+///
+/// `message` must point to `len` readable bytes, `context` must be the context
+/// expected by `write`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn broxy_middleware_outgoing(
+    message: *const u8,
+    len: u64,
+    context: *mut c_void,
+    write: Write,
+) -> i32 {
+    unsafe { add_header(message, len, context, write, b"x-plugin: outgoing\r\n") }
+}
+
+/// Inserts a header line right after the start line of the message.
+unsafe fn add_header(
+    message: *const u8,
+    len: u64,
+    context: *mut c_void,
+    write: Write,
+    header: &[u8],
+) -> i32 {
+    let message = unsafe { std::slice::from_raw_parts(message, len as usize) };
+    let Some(start_line) = message.windows(2).position(|window| window == b"\r\n") else {
+        return 1;
+    };
+    let (start_line, rest) = message.split_at(start_line + 2);
+    let modified = [start_line, header, rest].concat();
+    unsafe { write(context, modified.as_ptr(), modified.len() as u64) };
+    0
+}
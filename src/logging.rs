@@ -1,76 +1,220 @@
-use tracing::Level;
 use tracing_subscriber::{
-    fmt::{format::FmtSpan, time::LocalTime},
-    FmtSubscriber,
+    EnvFilter, Registry,
+    fmt::{MakeWriter, format::FmtSpan, time::LocalTime},
+    layer::SubscriberExt as _,
+    reload,
 };
 
-/// Initialize the logging system with pretty console output
-pub fn init_logging() -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_file(true)
-        .with_line_number(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_timer(LocalTime::rfc_3339())
-        .with_ansi(true)
-        .pretty()
-        .with_level(true)
-        .with_target(false)
-        .finish();
+/// Filter applied by `SIGUSR1` to bump verbosity during an incident
+#[cfg(unix)]
+const DEBUG_FILTER: &str = "debug";
 
-    tracing::subscriber::set_global_default(subscriber)?;
-    
-    tracing::info!("Logging system initialized");
-    Ok(())
+/// Environment variable selecting the output format, `pretty` (default) or `json`
+const LOG_FORMAT_VAR: &str = "LOG_FORMAT";
+
+/// Output format of the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, multi-line output
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
 }
 
-/// Initialize logging with a specific log level
-pub fn init_logging_with_level(level: Level) -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(level)
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_file(true)
-        .with_line_number(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_timer(LocalTime::rfc_3339())
-        .with_ansi(true)
-        .pretty()
-        .with_level(true)
-        .with_target(false)
-        .finish();
+impl LogFormat {
+    /// Reads the format from the `LOG_FORMAT` environment variable, pretty if unset.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        match std::env::var(LOG_FORMAT_VAR) {
+            Ok(format) if format.eq_ignore_ascii_case("json") => Ok(LogFormat::Json),
+            Ok(format) if format.eq_ignore_ascii_case("pretty") => Ok(LogFormat::Pretty),
+            Ok(format) => Err(format!("unknown {LOG_FORMAT_VAR} {format:?}").into()),
+            Err(_) => Ok(LogFormat::Pretty),
+        }
+    }
+}
 
-    tracing::subscriber::set_global_default(subscriber)?;
-    
-    tracing::info!("Logging system initialized with level: {}", level);
-    Ok(())
+/// Handle changing the log filter of the running process.
+///
+/// The handle is cheap to clone and can be shared between threads, changes are
+/// serialized by an internal lock. A new filter applies to every event and span
+/// recorded after the change, including spans that are already open.
+#[derive(Clone)]
+pub struct LogHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// Filter the logging system was initialized with
+    initial: String,
 }
 
-/// Initialize logging from environment variable RUST_LOG
-pub fn init_logging_from_env() -> Result<(), Box<dyn std::error::Error>> {
-    let env_filter = tracing_subscriber::EnvFilter::from_default_env();
-    
-    let subscriber = FmtSubscriber::builder()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_file(true)
-        .with_line_number(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_timer(LocalTime::rfc_3339())
-        .with_ansi(true)
-        .pretty()
-        .with_level(true)
-        .with_target(false)
-        .finish();
+impl LogHandle {
+    /// Replaces the current log filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `directives` - Filter directives, in the `RUST_LOG` syntax (e.g. `info,broxy_core=debug`)
+    pub fn set_filter(&self, directives: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.handle.reload(filter)?;
+        tracing::info!("Log filter changed to: {}", directives);
+        Ok(())
+    }
+
+    /// Restores the filter the logging system was initialized with.
+    pub fn reset(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_filter(&self.initial)
+    }
+}
 
+/// Builds a subscriber writing to `writer` in the given format, with a reloadable filter
+fn subscriber<W>(
+    filter: EnvFilter,
+    format: LogFormat,
+    writer: W,
+) -> (impl tracing::Subscriber + Send + Sync + 'static, LogHandle)
+where
+    W: for<'a> MakeWriter<'a> + Clone + Send + Sync + 'static,
+{
+    let initial = filter.to_string();
+    let (filter, handle) = reload::Layer::new(filter);
+
+    // Event fields are nested under `fields`, those of the current span under `span`
+    let json = (format == LogFormat::Json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer.clone())
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_timer(LocalTime::rfc_3339())
+            .with_target(true)
+            .with_thread_ids(true)
+            .with_file(true)
+            .with_line_number(true)
+    });
+    let pretty = (format == LogFormat::Pretty).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_target(false)
+            .with_thread_ids(true)
+            .with_thread_names(true)
+            .with_file(true)
+            .with_line_number(true)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_timer(LocalTime::rfc_3339())
+            .with_ansi(true)
+            .pretty()
+            .with_level(true)
+            .with_target(false)
+    });
+
+    let subscriber = Registry::default().with(filter).with(pretty).with(json);
+    (subscriber, LogHandle { handle, initial })
+}
+
+/// Installs the global subscriber with console output in the given format and a reloadable filter
+fn init_reloadable(
+    filter: EnvFilter,
+    format: LogFormat,
+) -> Result<LogHandle, Box<dyn std::error::Error>> {
+    let (subscriber, handle) = subscriber(filter, format, std::io::stdout);
     tracing::subscriber::set_global_default(subscriber)?;
-    
+    Ok(handle)
+}
+
+/// Initialize logging from environment variables, RUST_LOG for the filter
+/// and LOG_FORMAT for the output format
+pub fn init_logging_from_env() -> Result<LogHandle, Box<dyn std::error::Error>> {
+    let format = LogFormat::from_env()?;
+    let handle = init_reloadable(EnvFilter::from_default_env(), format)?;
+
     tracing::info!("Logging system initialized from environment");
+    Ok(handle)
+}
+
+/// Changes the log filter on signals: `SIGUSR1` switches to `debug`,
+/// `SIGUSR2` restores the initial filter.
+///
+/// Must be called from within a tokio runtime.
+#[cfg(unix)]
+pub fn reload_on_signal(handle: LogHandle) -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+
+    tokio::spawn(async move {
+        loop {
+            let result = tokio::select! {
+                Some(()) = usr1.recv() => handle.set_filter(DEBUG_FILTER),
+                Some(()) = usr2.recv() => handle.reset(),
+                else => break,
+            };
+            if let Err(e) = result {
+                tracing::error!("Failed to change log filter: {}", e);
+            }
+        }
+    });
     Ok(())
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Log output collected in memory
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Logs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_the_level_and_the_message() {
+        let logs = Logs::default();
+        let (subscriber, handle) = subscriber(
+            EnvFilter::new("info,noisy=off"),
+            LogFormat::Json,
+            logs.clone(),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(upstream = "10.0.0.1:80", "Request forwarded");
+            tracing::debug!("Filtered out by level");
+            tracing::warn!(target: "noisy", "Filtered out by target");
+            handle.set_filter("debug").unwrap();
+            tracing::debug!("Let through once reloaded");
+        });
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3, "{output}");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"]["message"], "Request forwarded");
+        assert_eq!(lines[0]["fields"]["upstream"], "10.0.0.1:80");
+        assert_eq!(
+            lines[1]["fields"]["message"],
+            "Log filter changed to: debug"
+        );
+        assert_eq!(lines[2]["level"], "DEBUG");
+        assert_eq!(lines[2]["fields"]["message"], "Let through once reloaded");
+    }
+}
@@ -1,66 +1,132 @@
-use std::{net::SocketAddr, str::FromStr as _};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr as _,
+    sync::Arc,
+    time::Duration,
+};
 
-use broxy_core::filter::{BodyFilter, Filter};
-use broxy_core::server::Server;
+use broxy_core::config::Config;
+use broxy_core::filter::{BodyFilter, Filter, FilterOutcome};
+use broxy_core::hyper::body::Bytes;
+use broxy_core::server::{Server, ServerGroup};
 use broxy_core::service::{Service, ServiceBundle};
-use tracing::{debug, error, info, info_span, instrument};
+use http::{Response, StatusCode, header};
+use http_body_util::{BodyExt as _, Full};
+use tracing::{error, info, info_span, instrument};
 
 mod logging;
 
+/// How long outstanding connections get to finish after a shutdown signal
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[tokio::main]
 async fn main() {
     // Initialize logging system
-    if let Err(e) = logging::init_logging_from_env() {
-        eprintln!("Failed to initialize logging: {}", e);
-        std::process::exit(1);
+    let log_handle = match logging::init_logging_from_env() {
+        Ok(log_handle) => log_handle,
+        Err(e) => {
+            eprintln!("Failed to initialize logging: {}", e);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    if let Err(e) = logging::reload_on_signal(log_handle) {
+        error!("Failed to install log level signal handlers: {}", e);
     }
+    #[cfg(not(unix))]
+    drop(log_handle);
 
     let _span = info_span!("broxy_startup");
     let _enter = _span.enter();
 
     info!("Starting Broxy proxy server");
 
-    let load_balancer = broxy_core::load_balancer::LoadBalancer::new(vec![
-        broxy_core::upstream::Upstream {
-            address: SocketAddr::from_str("0.0.0.0:9944").unwrap(),
-            use_ssl: false,
-        },
-        broxy_core::upstream::Upstream {
-            address: SocketAddr::from_str("0.0.0.0:9945").unwrap(),
-            use_ssl: false,
-        },
-        broxy_core::upstream::Upstream {
-            address: SocketAddr::from_str("0.0.0.0:9946").unwrap(),
-            use_ssl: false,
-        },
-        broxy_core::upstream::Upstream {
-            address: SocketAddr::from_str("0.0.0.0:9947").unwrap(),
-            use_ssl: false,
-        },
-        broxy_core::upstream::Upstream {
-            address: SocketAddr::from_str("0.0.0.0:9948").unwrap(),
-            use_ssl: false,
-        },
-    ]);
-
-    let filters = vec![Filter::Method(broxy_core::hyper::Method::POST)];
-    let body_filters = vec![BodyFilter::InternalFullBody(|_, body| {
-        let serialized = serde_json::from_slice::<serde_json::Value>(body);
-        if let Ok(serialized) = serialized {
-            let method = serialized.get("method").and_then(|m| m.as_str());
-            if let Some(method) = method {
-                if method.eq("eth_sendTransaction") || method.eq("eth_sendRawTransaction") {
-                    Ok(false)
-                } else {
-                    Ok(true)
+    let config_path = std::env::args_os().nth(1).map(PathBuf::from);
+    let servers = match &config_path {
+        Some(path) => {
+            info!("Loading configuration from {}", path.display());
+            let config = Config::load(path).unwrap_or_else(|e| exit_with(e));
+            if let Err(errors) = config.validate() {
+                for e in &errors {
+                    error!("Invalid configuration: {}", e);
                 }
-            } else {
-                Ok(false)
+                std::process::exit(1);
             }
+            // SAFETY: the middleware libraries are trusted like the configuration listing them
+            let services = unsafe { config.services() }.unwrap_or_else(|e| exit_with(e));
+            let servers = config
+                .build(&services)
+                .await
+                .unwrap_or_else(|e| exit_with(e));
+            ServerGroup::from(servers)
+        }
+        None => {
+            let server_addr = SocketAddr::from_str("0.0.0.0:8546").unwrap();
+            info!("Starting server on {}", server_addr);
+            let server = Server::builder()
+                .address(server_addr)
+                .services(ServiceBundle::new(vec![default_service()]))
+                .build()
+                .await
+                .unwrap();
+            ServerGroup::new().with_server("default", server)
+        }
+    };
+
+    info!("Server started successfully, accepting connections");
+
+    // Drop the span before entering the main loop
+    drop(_enter);
+    drop(_span);
+
+    run_servers(servers, config_path).await;
+}
+
+/// Logs a startup error and exits.
+fn exit_with(error: impl std::fmt::Display) -> ! {
+    error!("Failed to start: {}", error);
+    std::process::exit(1);
+}
+
+/// Builds the service used without a configuration file.
+fn default_service() -> Service {
+    let load_balancer = Arc::new(broxy_core::load_balancer::LoadBalancer::new(vec![
+        broxy_core::upstream::Upstream::new(SocketAddr::from_str("0.0.0.0:9944").unwrap(), false),
+        broxy_core::upstream::Upstream::new(SocketAddr::from_str("0.0.0.0:9945").unwrap(), false),
+        broxy_core::upstream::Upstream::new(SocketAddr::from_str("0.0.0.0:9946").unwrap(), false),
+        broxy_core::upstream::Upstream::new(SocketAddr::from_str("0.0.0.0:9947").unwrap(), false),
+        broxy_core::upstream::Upstream::new(SocketAddr::from_str("0.0.0.0:9948").unwrap(), false),
+    ]));
+
+    let body_filter = BodyFilter::InternalFullBodyWithResponse(|_, body| {
+        let serialized = serde_json::from_slice::<serde_json::Value>(body)?;
+        let Some(method) = serialized.get("method").and_then(|m| m.as_str()) else {
+            return Ok(FilterOutcome::Reject);
+        };
+        if method.eq("eth_sendTransaction") || method.eq("eth_sendRawTransaction") {
+            let error = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": serialized.get("id").cloned().unwrap_or_default(),
+                "error": {
+                    "code": -32601,
+                    "message": format!("Method {} is not allowed", method),
+                },
+            });
+            let response = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(
+                    Full::new(Bytes::from(error.to_string()))
+                        .map_err(|never| match never {})
+                        .boxed(),
+                )?;
+            Ok(FilterOutcome::RejectWith(response))
         } else {
-            Err(unsafe { serialized.unwrap_err_unchecked() }.into())
+            Ok(FilterOutcome::Pass)
         }
-    })];
+    });
     let middleware = broxy_core::middleware::Middleware::new(
         vec![],
         vec![
@@ -79,40 +145,102 @@ async fn main() {
             ),
         ],
     );
-    let service = Service::new(
-        filters,
-        body_filters,
-        Some(middleware),
-        &load_balancer,
-        None,
-    );
+    Service::builder()
+        .filter(Filter::Method(broxy_core::hyper::Method::POST))
+        .body_filter(body_filter)
+        .middleware(middleware)
+        .load_balancer(load_balancer)
+        .build()
+        .unwrap()
+}
 
-    let services = vec![service];
-    let bundle = ServiceBundle::new(&services);
+/// Runs the servers until a shutdown signal, then waits for their connections to finish.
+#[instrument(skip(servers))]
+async fn run_servers(servers: ServerGroup, config_path: Option<PathBuf>) {
+    let _span = info_span!("server_loop");
+    let _enter = _span.enter();
 
-    let server_addr = SocketAddr::from_str("0.0.0.0:8546").unwrap();
-    info!("Starting server on {}", server_addr);
+    serve_until_shutdown(&servers, config_path.as_deref()).await;
 
-    let server = Server::new(server_addr, bundle, None).await.unwrap();
+    info!("Shutdown signal received, no longer accepting connections");
+    servers.shutdown(SHUTDOWN_TIMEOUT).await;
+}
 
-    info!("Server started successfully, accepting connections");
+/// Accepts connections until a shutdown signal, reloading the configuration on `SIGHUP`.
+async fn serve_until_shutdown(servers: &ServerGroup, config_path: Option<&Path>) {
+    let accept_loops = servers.serve();
+    let shutdown = tokio::signal::ctrl_c();
+    let mut reload = ReloadSignal::new(config_path.is_some());
+    tokio::pin!(accept_loops, shutdown);
+    loop {
+        tokio::select! {
+            _ = &mut accept_loops => break,
+            result = &mut shutdown => {
+                if let Err(e) = result {
+                    error!("Failed to listen for the shutdown signal: {}", e);
+                }
+                break;
+            }
+            () = reload.recv() => {
+                if let Some(path) = config_path {
+                    reload_config(path, servers.servers());
+                }
+            }
+        }
+    }
+}
 
-    // Drop the span before entering the main loop
-    drop(_enter);
-    drop(_span);
+/// Reloads the routing and certificates of the servers from the configuration file,
+/// keeping the current one if the file is invalid.
+fn reload_config(path: &Path, servers: &HashMap<String, Server>) {
+    info!("Reloading configuration from {}", path.display());
+    // SAFETY: the middleware libraries are trusted like the configuration listing them
+    let result = Config::load(path).and_then(|config| unsafe { config.reload(servers) });
+    if let Err(e) = result {
+        error!(
+            "Failed to reload configuration, keeping the current one: {}",
+            e
+        );
+    }
+}
 
-    run_server(server).await;
+/// Signal requesting a configuration reload, `SIGHUP`.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
 }
 
-#[instrument(skip(server))]
-async fn run_server(server: Server) {
-    let _span = info_span!("server_loop");
-    let _enter = _span.enter();
+impl ReloadSignal {
+    /// Listens for the signal, unless reloading is disabled.
+    fn new(enabled: bool) -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
 
-    loop {
-        match server.accept().await {
-            Ok(_) => debug!("Accepted new connection"),
-            Err(e) => error!("Failed to accept connection: {}", e),
+            let hangup = enabled
+                .then(|| signal(SignalKind::hangup()))
+                .transpose()
+                .unwrap_or_else(|e| {
+                    error!("Failed to install the reload signal handler: {}", e);
+                    None
+                });
+            Self { hangup }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = enabled;
+            Self {}
+        }
+    }
+
+    /// Waits for the next reload request, forever if reloading is disabled.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup
+            && hangup.recv().await.is_some()
+        {
+            return;
         }
+        std::future::pending().await
     }
 }